@@ -1,14 +1,30 @@
 use api::ApiConfig;
 use clap::{Parser, Subcommand};
 use futures::future::join_all;
-use std::{net::Ipv4Addr, process::ExitCode};
+use libmemex::db::{collection, create_connection_by_uri};
+use libmemex::llm::embedding::{EmbeddingDevice, EmbeddingsModelType, ModelConfig, SegmentPolicy};
+use libmemex::llm::openai::OpenAIOptions;
+use libmemex::llm::prompter;
+use libmemex::storage::{get_vector_storage_with_dimension, StoreHealth, StoreStatus};
+use opentelemetry::{
+    sdk::{
+        trace::{self, Tracer},
+        Resource,
+    },
+    trace::TraceError,
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use std::{net::Ipv4Addr, path::Path, process::ExitCode, time::Duration};
+use strum::VariantNames;
 use strum_macros::{Display, EnumString};
 use tracing_log::LogTracer;
 use tracing_subscriber::{
     fmt::{self, format::FmtSpan},
     layer::SubscriberExt,
-    EnvFilter,
+    EnvFilter, Layer,
 };
+use worker::{reindex::ReindexConfig, WorkerConfig};
 
 const LOG_LEVEL: tracing::Level = tracing::Level::INFO;
 
@@ -28,8 +44,171 @@ pub struct Args {
     vector_connection: Option<String>,
     #[clap(long, value_parser, value_name = "OPENAI_API_KEY", env)]
     openai_api_key: Option<String>,
+    /// OpenAI-compatible API to use instead of OpenAI's, e.g. http://localhost:11434/v1
+    #[clap(long, value_parser, value_name = "OPENAI_BASE_URL", env)]
+    openai_base_url: Option<String>,
+    /// Sent as the OpenAI-Organization header, for accounts in several organizations.
+    #[clap(long, value_parser, value_name = "OPENAI_ORGANIZATION", env)]
+    openai_organization: Option<String>,
+    /// Extra headers sent to the OpenAI API, e.g. "X-Team: search, X-Env: prod"
+    #[clap(long, value_parser, value_name = "OPENAI_EXTRA_HEADERS", env)]
+    openai_extra_headers: Option<String>,
+    /// OpenAI model used when a request doesn't ask for one, e.g. gpt-4
+    #[clap(long, value_parser, value_name = "OPENAI_MODEL", env)]
+    openai_model: Option<String>,
+    /// Start serving w/o making sure the LLM is usable first.
+    #[clap(long, value_parser, value_name = "SKIP_LLM_CHECK", env)]
+    skip_llm_check: bool,
     #[clap(long, value_parser, value_name = "LOCAL_LLM_CONFIG", env)]
     local_llm_config: Option<String>,
+    #[clap(long, value_parser, value_name = "UPLOAD_DIR", env)]
+    upload_dir: Option<String>,
+    #[clap(long, value_parser, value_name = "PDFTOTEXT_PATH", env)]
+    pdftotext_path: Option<String>,
+    /// Max number of tasks from a single collection the worker runs at once.
+    #[clap(long, value_parser, value_name = "MAX_TASKS_PER_COLLECTION", env)]
+    max_tasks_per_collection: Option<usize>,
+    /// Comma separated collections the worker runs jobs from, all of them when unset.
+    #[clap(long, value_parser, value_name = "WORKER_COLLECTIONS", env)]
+    worker_collections: Option<String>,
+    /// Days finished tasks are kept for before the worker deletes them, forever when unset.
+    #[clap(long, value_parser, value_name = "QUEUE_RETENTION_DAYS", env)]
+    queue_retention_days: Option<u64>,
+    /// Only serve API routes that don't add, change or delete anything.
+    #[clap(long, value_parser, value_name = "READ_ONLY", env)]
+    read_only: bool,
+    /// L2 normalize embeddings before they're stored.
+    #[clap(long, value_parser, value_name = "NORMALIZE_EMBEDDINGS", env)]
+    normalize_embeddings: bool,
+    /// Max number of segments encoded at once when generating embeddings.
+    #[clap(long, value_parser, value_name = "EMBEDDING_BATCH_SIZE", env)]
+    embedding_batch_size: Option<usize>,
+    /// How documents longer than a segment are embedded: Split, TruncateHead or MeanPool.
+    #[clap(long, value_parser, value_name = "EMBEDDING_SEGMENT_POLICY", env)]
+    embedding_segment_policy: Option<SegmentPolicy>,
+    /// Shortest delay (in ms) between checks of the job queue.
+    #[clap(long, value_parser, value_name = "QUEUE_POLL_INTERVAL_MS", env)]
+    queue_poll_interval_ms: Option<u64>,
+    /// Prepended to search queries before they're embedded, e.g. "query: "
+    #[clap(long, value_parser, value_name = "EMBEDDING_QUERY_PREFIX", env)]
+    embedding_query_prefix: Option<String>,
+    /// Prepended to document segments before they're embedded, e.g. "passage: "
+    #[clap(long, value_parser, value_name = "EMBEDDING_DOC_PREFIX", env)]
+    embedding_doc_prefix: Option<String>,
+    /// Embedding model used by the API & worker, e.g. AllMiniLmL6V2
+    #[clap(long, value_parser, value_name = "EMBEDDING_MODEL", env)]
+    embedding_model: Option<String>,
+    /// Expected SHA-256 of the embedding model's weights, checked before it's loaded.
+    #[clap(long, value_parser, value_name = "EMBEDDING_MODEL_SHA256", env)]
+    embedding_model_sha256: Option<String>,
+    /// Device embedding models run on: cpu, cuda or cuda:<index>.
+    #[clap(long, value_parser, value_name = "EMBEDDING_DEVICE", env)]
+    embedding_device: Option<EmbeddingDevice>,
+    /// Directory w/ prompt templates that override the built-in ones.
+    #[clap(long, value_parser, value_name = "PROMPT_DIR", env)]
+    prompt_dir: Option<String>,
+    /// Max number of characters in a single document.
+    #[clap(long, value_parser, value_name = "MAX_DOCUMENT_CHARS", env)]
+    max_document_chars: Option<usize>,
+    /// Seconds a fetched URL has to respond.
+    #[clap(long, value_parser, value_name = "FETCH_TIMEOUT_SECS", env)]
+    fetch_timeout_secs: Option<u64>,
+    /// Max number of redirects followed when fetching a URL.
+    #[clap(long, value_parser, value_name = "FETCH_MAX_REDIRECTS", env)]
+    fetch_max_redirects: Option<usize>,
+    /// Max size, in bytes, of a fetched response.
+    #[clap(long, value_parser, value_name = "FETCH_MAX_BYTES", env)]
+    fetch_max_bytes: Option<usize>,
+    /// Comma separated hosts URLs can be fetched from, any host when unset.
+    #[clap(long, value_parser, value_name = "FETCH_ALLOWED_HOSTS", env)]
+    fetch_allowed_hosts: Option<String>,
+    /// Comma separated hosts that can be fetched from even though they're on
+    /// a private network, internal addresses are blocked otherwise.
+    #[clap(long, value_parser, value_name = "FETCH_INTERNAL_HOSTS", env)]
+    fetch_internal_hosts: Option<String>,
+    /// OTLP collector to export traces to, e.g. http://localhost:4317
+    #[clap(long, value_parser, value_name = "OTEL_EXPORTER_OTLP_ENDPOINT", env)]
+    otel_exporter_otlp_endpoint: Option<String>,
+    /// Reject documents added to a collection that hasn't been created,
+    /// instead of creating it w/ the default settings.
+    #[clap(long, value_parser, value_name = "REQUIRE_EXISTING_COLLECTIONS", env)]
+    require_existing_collections: bool,
+    /// Cache search results for this many seconds, disabled when unset.
+    #[clap(long, value_parser, value_name = "SEARCH_CACHE_TTL_SECS", env)]
+    search_cache_ttl_secs: Option<u64>,
+    /// Max number of cached search results.
+    #[clap(long, value_parser, value_name = "SEARCH_CACHE_SIZE", env)]
+    search_cache_size: Option<usize>,
+    /// Log output format, either pretty (human-readable) or json.
+    #[clap(long, value_parser, value_name = "LOG_FORMAT", env, default_value_t = LogFormat::Pretty)]
+    log_format: LogFormat,
+}
+
+impl Args {
+    /// The configured embedding model, if one was set.
+    fn embedding_model(&self) -> Result<Option<EmbeddingsModelType>, String> {
+        let Some(model) = &self.embedding_model else {
+            return Ok(None);
+        };
+
+        model.parse().map(Some).map_err(|_| {
+            format!(
+                "Invalid EMBEDDING_MODEL: {model}, must be one of {}",
+                EmbeddingsModelType::VARIANTS.join(", ")
+            )
+        })
+    }
+
+    /// Apply the embedding settings to a model config.
+    fn model_config(&self, config: ModelConfig) -> ModelConfig {
+        let mut config = config.normalized(self.normalize_embeddings).with_prefixes(
+            self.embedding_query_prefix.clone(),
+            self.embedding_doc_prefix.clone(),
+        );
+        if let Some(batch_size) = self.embedding_batch_size {
+            config = config.with_batch_size(batch_size);
+        }
+        if let Some(segment_policy) = self.embedding_segment_policy {
+            config = config.with_segment_policy(segment_policy);
+        }
+        if let Some(device) = self.embedding_device {
+            config = config.with_device(device);
+        }
+        // Only pinned for the configured model, not e.g. one being reindexed to.
+        let pinned_model = self
+            .embedding_model()
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| ModelConfig::default().model());
+        if config.model() == pinned_model {
+            config = config.with_weights_sha256(self.embedding_model_sha256.clone());
+        }
+        config
+    }
+}
+
+/// Export spans to an OpenTelemetry collector over OTLP/gRPC.
+fn otel_tracer(endpoint: &str) -> Result<Tracer, TraceError> {
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_resource(Resource::new(vec![KeyValue::new("service.name", "memex")])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)
+}
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum LogFormat {
+    Pretty,
+    /// One JSON object per line, for log pipelines.
+    Json,
 }
 
 #[derive(Debug, Display, Clone, PartialEq, EnumString)]
@@ -40,16 +219,78 @@ pub enum Roles {
 
 #[derive(Subcommand, PartialEq)]
 enum Command {
-    Debug,
+    /// Check the vector store backing a collection is reachable & consistent.
+    Debug {
+        /// Collection to check
+        #[arg(short, long)]
+        collection: String,
+    },
     Serve {
         #[arg(short, long, default_values_t = vec![Roles::Api, Roles::Worker])]
         roles: Vec<Roles>,
     },
+    /// Re-embed all the documents in a collection w/ a different embedding model.
+    Reindex {
+        /// Collection to reindex
+        #[arg(short, long)]
+        collection: String,
+        /// Embedding model to use, e.g. AllMiniLmL6V2
+        #[arg(short, long)]
+        model: EmbeddingsModelType,
+        /// Write to a new collection instead of reindexing in place
+        #[arg(short, long)]
+        target: Option<String>,
+        /// Resume an interrupted run, skipping documents up to & including this id
+        #[arg(long)]
+        resume_from: Option<i64>,
+    },
+}
+
+/// Health of the vector store backing a collection.
+async fn store_health(
+    db_uri: &str,
+    vector_uri: &str,
+    collection: &str,
+    model_config: &ModelConfig,
+) -> anyhow::Result<StoreHealth> {
+    let db = create_connection_by_uri(db_uri, false).await?;
+    // Connecting creates the store if it's missing, don't for typos.
+    if !collection::exists(&db, collection).await? {
+        anyhow::bail!("Collection {collection} doesn't exist");
+    }
+
+    let index = collection::vector_index(&db, collection).await?;
+    let model_config = collection::model_config(&db, collection, model_config.clone()).await?;
+    let client =
+        get_vector_storage_with_dimension(vector_uri, &index, model_config.model().dimension())
+            .await?;
+    Ok(client.health().await?)
+}
+
+/// Split a comma separated list, e.g. from an env var.
+fn parse_list(list: Option<&str>) -> Vec<String> {
+    list.unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
 }
 
 #[tokio::main]
 async fn main() -> ExitCode {
     dotenv::dotenv().ok();
+    let args = Args::parse();
+
+    let (otel_layer, otel_error) = match args.otel_exporter_otlp_endpoint.as_deref() {
+        Some(endpoint) if !endpoint.is_empty() => match otel_tracer(endpoint) {
+            Ok(tracer) => (
+                Some(tracing_opentelemetry::layer().with_tracer(tracer)),
+                None,
+            ),
+            Err(err) => (None, Some(err)),
+        },
+        _ => (None, None),
+    };
 
     let subscriber = tracing_subscriber::registry()
         .with(
@@ -62,15 +303,93 @@ async fn main() -> ExitCode {
                 .add_directive("cached_path=WARN".parse().expect("invalid log filter"))
                 .add_directive("hnsw_rs=WARN".parse().expect("invalid log filter")),
         )
-        .with(
-            fmt::Layer::new()
+        .with(match args.log_format {
+            LogFormat::Pretty => fmt::Layer::new()
                 .with_writer(std::io::stdout)
-                .with_span_events(FmtSpan::CLOSE),
-        );
+                .with_span_events(FmtSpan::CLOSE)
+                .boxed(),
+            LogFormat::Json => fmt::Layer::new()
+                .json()
+                .with_writer(std::io::stdout)
+                .with_span_events(FmtSpan::CLOSE)
+                .boxed(),
+        })
+        .with(otel_layer);
     tracing::subscriber::set_global_default(subscriber).expect("Unable to set a global subscriber");
     let _ = LogTracer::init();
+    if let Some(err) = otel_error {
+        log::error!("Unable to set up OpenTelemetry exporter: {err}");
+    }
 
-    let args = Args::parse();
+    if let Command::Reindex {
+        collection,
+        model,
+        target,
+        resume_from,
+    } = &args.command
+    {
+        let model_config = args.model_config(ModelConfig::with_model(*model));
+        let cfg = ReindexConfig {
+            db_uri: args
+                .database_connection
+                .expect("DATABASE_CONNECTION not set"),
+            vector_uri: args.vector_connection.expect("VECTOR_CONNECTION not set"),
+            collection: collection.clone(),
+            target: target.clone(),
+            model_config,
+            resume_from: *resume_from,
+        };
+
+        let result = worker::reindex::reindex_collection(cfg).await;
+        opentelemetry::global::shutdown_tracer_provider();
+        return match result {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => {
+                log::error!("Unable to reindex collection: {err}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let model_config = match args.embedding_model() {
+        Ok(Some(model)) => args.model_config(ModelConfig::with_model(model)),
+        Ok(None) => args.model_config(ModelConfig::default()),
+        Err(err) => {
+            log::error!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if let Command::Debug { collection } = &args.command {
+        let result = store_health(
+            args.database_connection
+                .as_deref()
+                .expect("DATABASE_CONNECTION not set"),
+            args.vector_connection
+                .as_deref()
+                .expect("VECTOR_CONNECTION not set"),
+            collection,
+            &model_config,
+        )
+        .await;
+        return match result {
+            Ok(health) => {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&health).unwrap_or_default()
+                );
+                if health.status == StoreStatus::Red {
+                    ExitCode::FAILURE
+                } else {
+                    ExitCode::SUCCESS
+                }
+            }
+            Err(err) => {
+                log::error!("Unable to check vector store: {err}");
+                ExitCode::FAILURE
+            }
+        };
+    }
 
     if let Command::Serve { roles } = args.command {
         if roles.is_empty() {
@@ -78,6 +397,13 @@ async fn main() -> ExitCode {
             return ExitCode::FAILURE;
         }
 
+        if let Some(prompt_dir) = &args.prompt_dir {
+            if let Err(err) = prompter::load_templates(Path::new(prompt_dir)) {
+                log::error!("{err}");
+                return ExitCode::FAILURE;
+            }
+        }
+
         log::info!("starting server with roles: {roles:?}");
         let host = match std::env::var("HOST")
             .expect("HOST not set")
@@ -110,6 +436,22 @@ async fn main() -> ExitCode {
             return ExitCode::FAILURE;
         }
 
+        let openai_options = match OpenAIOptions::new(
+            args.openai_base_url.as_deref(),
+            args.openai_organization.as_deref(),
+            args.openai_extra_headers.as_deref(),
+        )
+        .and_then(|options| match args.openai_model.as_deref() {
+            Some(model) => options.with_default_model(model),
+            None => Ok(options),
+        }) {
+            Ok(options) => options,
+            Err(err) => {
+                log::error!("Invalid OpenAI settings: {err}");
+                return ExitCode::FAILURE;
+            }
+        };
+
         if roles.contains(&Roles::Api) {
             let db_uri = db_uri.clone();
             let cfg = ApiConfig {
@@ -117,17 +459,59 @@ async fn main() -> ExitCode {
                 port,
                 db_uri,
                 open_ai_key: args.openai_api_key,
+                open_ai_options: openai_options.clone(),
                 local_llm_config: args.local_llm_config,
+                upload_dir: args.upload_dir,
+                pdftotext_path: args.pdftotext_path,
+                model_config: model_config.clone(),
+                max_document_chars: args.max_document_chars,
+                auto_create_collections: !args.require_existing_collections,
+                search_cache_ttl: args
+                    .search_cache_ttl_secs
+                    .filter(|secs| *secs > 0)
+                    .map(Duration::from_secs),
+                search_cache_size: args.search_cache_size,
+                read_only: args.read_only,
+                fetch_timeout: args.fetch_timeout_secs.map(Duration::from_secs),
+                fetch_max_redirects: args.fetch_max_redirects,
+                fetch_max_bytes: args.fetch_max_bytes,
+                fetch_allowed_hosts: parse_list(args.fetch_allowed_hosts.as_deref()),
+                fetch_internal_hosts: parse_list(args.fetch_internal_hosts.as_deref()),
+                skip_llm_check: args.skip_llm_check,
             };
-            handles.push(tokio::spawn(api::start(cfg)));
+            handles.push(tokio::spawn(async move {
+                if let Err(err) = api::start(cfg).await {
+                    log::error!("Unable to start api server: {err}");
+                    std::process::exit(1);
+                }
+            }));
         }
 
         if roles.contains(&Roles::Worker) {
-            let db_uri = db_uri.clone();
-            handles.push(tokio::spawn(worker::start(db_uri)));
+            let cfg = WorkerConfig {
+                db_uri: db_uri.clone(),
+                max_per_collection: args.max_tasks_per_collection,
+                model_config,
+                poll_interval: args.queue_poll_interval_ms.map(Duration::from_millis),
+                openai_options,
+                collections: parse_list(args.worker_collections.as_deref()),
+                queue_retention: args
+                    .queue_retention_days
+                    // Too many days to count is the same as keeping tasks forever.
+                    .and_then(|days| days.checked_mul(24 * 60 * 60))
+                    .map(Duration::from_secs),
+            };
+            handles.push(tokio::spawn(async move {
+                if let Err(err) = worker::start(cfg).await {
+                    log::error!("Unable to start worker: {err}");
+                    std::process::exit(1);
+                }
+            }));
         }
 
         let _ = join_all(handles).await;
+        // Flush any spans that haven't been exported yet.
+        opentelemetry::global::shutdown_tracer_provider();
         ExitCode::SUCCESS
     } else {
         ExitCode::FAILURE
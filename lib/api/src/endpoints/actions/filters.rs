@@ -1,7 +1,7 @@
 use std::sync::Arc;
 
-use crate::{endpoints::json_body, with_db, with_llm};
-use libmemex::llm::LLM;
+use crate::{endpoints::json_body, with_db, with_llm, with_tenant, with_trace_id};
+use libmemex::llm::{SamplerPreset, LLM};
 use sea_orm::DatabaseConnection;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -16,6 +16,15 @@ pub struct AskRequest {
     pub query: String,
     /// Output schema (if provided).
     pub json_schema: Option<Value>,
+    /// Maximum number of tokens to generate.
+    pub max_tokens: Option<usize>,
+    /// Sampling temperature.
+    pub temperature: Option<f32>,
+    /// Named sampler settings (greedy, precise or creative).
+    pub preset: Option<SamplerPreset>,
+    /// Stop generating once any of these sequences is produced.
+    #[serde(default)]
+    pub stop: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -23,6 +32,14 @@ pub struct AskRequest {
 pub struct SummarizeRequest {
     /// Input text to summarize
     pub text: String,
+    /// Maximum number of tokens to generate per summarized segment.
+    pub max_tokens: Option<usize>,
+    /// Sampling temperature.
+    pub temperature: Option<f32>,
+    /// Named sampler settings (greedy, precise or creative).
+    pub preset: Option<SamplerPreset>,
+    /// Number of times the summarize task is retried on failure.
+    pub max_retries: Option<i32>,
 }
 
 fn extract(
@@ -35,6 +52,16 @@ fn extract(
         .and_then(super::handlers::handle_extract)
 }
 
+fn ask_stream(
+    llm: &Arc<Box<dyn LLM>>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("action" / "ask" / "stream")
+        .and(warp::post())
+        .and(with_llm(llm.clone()))
+        .and(json_body::<AskRequest>(1024 * 1024 * 10))
+        .and_then(super::handlers::handle_ask_stream)
+}
+
 fn summarize(
     db: &DatabaseConnection,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
@@ -42,12 +69,21 @@ fn summarize(
         .and(warp::post())
         .and(with_db(db.clone()))
         .and(json_body::<SummarizeRequest>(1024 * 1024 * 10))
+        .and(with_trace_id())
+        .and(with_tenant())
         .and_then(super::handlers::handle_summarize)
 }
 
-pub fn build(
+/// Actions that only generate a response.
+pub fn build_reads(
     llm: &Arc<Box<dyn LLM>>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    extract(llm).or(ask_stream(llm))
+}
+
+/// Actions that queue tasks.
+pub fn build_writes(
     db: &DatabaseConnection,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    extract(llm).or(summarize(db))
+    summarize(db)
 }
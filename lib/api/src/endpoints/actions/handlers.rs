@@ -1,17 +1,21 @@
+use std::convert::Infallible;
 use std::sync::Arc;
 
 use crate::{
-    schema::{ApiResponse, TaskResult},
+    schema::{ApiResponse, ErrorMessage, TaskResult},
+    tenant::Tenant,
     ServerError,
 };
 use jsonschema::JSONSchema;
 use sea_orm::DatabaseConnection;
-use warp::reject::Rejection;
+use serde_json::json;
+use warp::{reject::Rejection, sse::Event};
 
 use super::filters;
+use crate::endpoints::{check_max_retries, check_stop};
 use libmemex::{
     db::queue,
-    llm::{prompter, LLM},
+    llm::{complete_within_context, prompter, repair_json, ChatCompletionOptions, LLM},
 };
 
 pub async fn handle_extract(
@@ -22,43 +26,189 @@ pub async fn handle_extract(
 
     let (content, model) = llm.truncate_text(&request.text);
 
-    // Build prompt
-    let prompt = if let Some(schema) = &request.json_schema {
-        JSONSchema::options()
-            .compile(schema)
-            .map_err(|err| ServerError::ClientRequestError(err.to_string()))?;
-        prompter::json_schema_extraction(&content, &request.query, &schema.to_string())
-    } else {
-        prompter::quick_question(&request.query)
+    let validator = match &request.json_schema {
+        Some(schema) => Some(
+            JSONSchema::options()
+                .compile(schema)
+                .map_err(|err| ServerError::BadRequest(err.to_string()))?,
+        ),
+        None => None,
     };
 
-    let response = llm
-        .chat_completion(model.as_ref(), &prompt)
-        .await
-        .map_err(|err| ServerError::Other(err.to_string()))?;
+    check_stop(&request.stop)?;
+    let options = ChatCompletionOptions {
+        max_tokens: request.max_tokens,
+        temperature: request.temperature,
+        preset: request.preset,
+        stop: request.stop.clone(),
+    };
+
+    let (response, content) = complete_within_context(
+        llm.as_ref().as_ref(),
+        &model,
+        content,
+        &options,
+        |content| match &request.json_schema {
+            Some(schema) => {
+                prompter::json_schema_extraction(content, &request.query, &schema.to_string())
+                    .map_err(ServerError::from)
+            }
+            None => Ok(prompter::quick_question(content, &request.query)),
+        },
+    )
+    .await?;
 
     log::debug!("llm response: {response}");
-    let val = serde_json::from_str::<serde_json::Value>(&response)
-        .map_err(|err| ServerError::Other(err.to_string()))?;
+    // Only schema extraction asks for JSON, anything else is a plain answer.
+    let result = if let (Some(schema), Some(validator)) = (&request.json_schema, &validator) {
+        let val = match repair_json(&response).filter(|val| validator.is_valid(val)) {
+            Some(val) => val,
+            None => {
+                log::warn!("LLM returned invalid JSON, retrying w/ a reminder");
+                let prompt = prompter::json_reminder(
+                    prompter::json_schema_extraction(&content, &request.query, &schema.to_string())
+                        .map_err(ServerError::from)?,
+                    &response,
+                );
+                let response = llm
+                    .chat_completion(model.as_ref(), &prompt, &options)
+                    .await
+                    .map_err(|err| ServerError::Upstream(err.to_string()))?;
+                log::debug!("llm response: {response}");
+
+                let val = repair_json(&response).ok_or_else(|| {
+                    ServerError::Upstream("LLM returned invalid JSON".to_string())
+                })?;
+                if !validator.is_valid(&val) {
+                    return Err(ServerError::Upstream(
+                        "LLM returned JSON that doesn't match the schema".to_string(),
+                    )
+                    .into());
+                }
+                val
+            }
+        };
+        serde_json::json!({ "jsonResponse": val })
+    } else {
+        serde_json::json!({ "answer": response.trim() })
+    };
 
     Ok(warp::reply::json(&ApiResponse::success(
         time.elapsed(),
-        Some(serde_json::json!({ "jsonResponse": val })),
+        Some(result),
     )))
 }
 
+pub async fn handle_ask_stream(
+    llm: Arc<Box<dyn LLM>>,
+    request: filters::AskRequest,
+) -> Result<impl warp::Reply, Rejection> {
+    let time = std::time::Instant::now();
+    if request.json_schema.is_some() {
+        return Err(ServerError::BadRequest(
+            "jsonSchema can't be streamed, use action/ask instead".into(),
+        )
+        .into());
+    }
+
+    let (content, model) = llm.truncate_text(&request.text);
+    check_stop(&request.stop)?;
+    let options = ChatCompletionOptions {
+        max_tokens: request.max_tokens,
+        temperature: request.temperature,
+        preset: request.preset,
+        stop: request.stop.clone(),
+    };
+
+    let prompt = prompter::quick_question(&content, &request.query);
+    let tokens = llm
+        .chat_completion_stream(model.as_ref(), &prompt, &options)
+        .await
+        .map_err(|err| ServerError::Upstream(err.to_string()))?;
+
+    // A "token" event for each piece of the answer, then either "done" w/ the
+    // full answer & stats or "error" if the LLM fails partway through.
+    let events = futures_util::stream::unfold(Some((tokens, String::new())), move |state| {
+        let llm = llm.clone();
+        let model = model.clone();
+        async move {
+            let (mut tokens, mut answer) = state?;
+            let event = match tokens.recv().await {
+                Some(Ok(token)) => {
+                    let event = Event::default()
+                        .event("token")
+                        .data(json!({ "token": token }).to_string());
+                    answer.push_str(&token);
+                    return Some((Ok::<_, Infallible>(event), Some((tokens, answer))));
+                }
+                Some(Err(err)) => {
+                    log::warn!("streamed completion failed: {err}");
+                    let error = ServerError::Upstream(err.to_string());
+                    let response = ApiResponse::error(ErrorMessage {
+                        code: error.status().as_u16(),
+                        error: error.error_code().to_string(),
+                        message: err.to_string(),
+                    });
+                    Event::default()
+                        .event("error")
+                        .data(json!(response).to_string())
+                }
+                None => {
+                    let result = json!({
+                        "answer": answer.trim(),
+                        "model": model,
+                        "completionTokens": llm.count_tokens(&answer),
+                    });
+                    Event::default()
+                        .event("done")
+                        .data(json!(ApiResponse::success(time.elapsed(), Some(result))).to_string())
+                }
+            };
+
+            Some((Ok(event), None))
+        }
+    });
+
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
+
 pub async fn handle_summarize(
     db: DatabaseConnection,
     request: filters::SummarizeRequest,
+    trace_id: String,
+    tenant: Tenant,
 ) -> Result<impl warp::Reply, Rejection> {
     let time = std::time::Instant::now();
+    let collection = tenant.collection("tasks")?;
+    let max_retries = check_max_retries(request.max_retries)?;
+    let payload = queue::TaskPayload {
+        content: request.text,
+        llm_options: Some(ChatCompletionOptions {
+            max_tokens: request.max_tokens,
+            temperature: request.temperature,
+            preset: request.preset,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
     // Add to job queue
-    let task = match queue::enqueue(&db, "tasks", &request.text, queue::TaskType::Summarize).await {
+    let task = match queue::enqueue_payload(
+        &db,
+        &collection,
+        payload,
+        queue::TaskType::Summarize,
+        Some(&trace_id),
+        max_retries,
+    )
+    .await
+    {
         Ok(model) => model,
         Err(err) => return Err(warp::reject::custom(ServerError::DatabaseError(err))),
     };
+    log::info!("[trace={trace_id}] queued summarize task {}", task.id);
 
-    let result = TaskResult::from(task);
+    let result = TaskResult::from(task).for_tenant(&tenant);
     Ok(warp::reply::json(&ApiResponse::success(
         time.elapsed(),
         Some(result),
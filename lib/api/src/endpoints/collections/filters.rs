@@ -1,42 +1,332 @@
+use std::sync::Arc;
+
+use futures_util::Stream;
+use libmemex::llm::{embedding::ModelConfig, LLM};
 use sea_orm::DatabaseConnection;
+use warp::hyper::body::Buf;
 use warp::Filter;
 
 use super::handlers;
-use crate::endpoints::{json_body, LIMIT_10_MB, LIMIT_1_MB};
-use crate::{schema, with_db};
+use crate::endpoints::{
+    fetch::filters::ParseRequest, json_body, IngestConfig, UploadConfig, LIMIT_10_MB, LIMIT_1_GB,
+    LIMIT_1_MB,
+};
+use crate::{
+    schema, with_db, with_ingest_config, with_llm, with_model_config, with_tenant, with_trace_id,
+    with_upload_config,
+};
 
 fn add_document(
     db: &DatabaseConnection,
+    ingest_config: &IngestConfig,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path!("collections" / String)
         .and(warp::post())
+        .and(with_tenant())
         .and(json_body::<schema::InsertDocumentRequest>(LIMIT_10_MB))
         .and(with_db(db.clone()))
+        .and(with_ingest_config(ingest_config.clone()))
+        .and(with_trace_id())
+        .and(add_document_options())
         .and_then(handlers::handle_add_document)
 }
 
-fn delete_collection() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
-{
+fn add_segments(
+    db: &DatabaseConnection,
+    ingest_config: &IngestConfig,
+    model_config: &ModelConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("collections" / String / "segments")
+        .and(warp::post())
+        .and(with_tenant())
+        .and(json_body::<schema::InsertSegmentsRequest>(LIMIT_10_MB))
+        .and(with_db(db.clone()))
+        .and(with_ingest_config(ingest_config.clone()))
+        .and(with_model_config(model_config.clone()))
+        .and(with_trace_id())
+        .and_then(handlers::handle_add_segments)
+}
+
+fn add_document_options(
+) -> impl Filter<Extract = (handlers::AddDocumentOptions,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("idempotency-key")
+        .and(warp::query::<schema::WaitQuery>())
+        .map(|idempotency_key, wait| handlers::AddDocumentOptions {
+            idempotency_key,
+            wait,
+        })
+}
+
+fn upload_request(
+) -> impl Filter<Extract = (handlers::UploadRequest,), Error = warp::Rejection> + Clone {
+    warp::query::<ParseRequest>()
+        .and(warp::multipart::form().max_length(50_000_000))
+        .map(|query, form| handlers::UploadRequest { query, form })
+}
+
+fn upload_document(
+    db: &DatabaseConnection,
+    upload_config: &UploadConfig,
+    ingest_config: &IngestConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("collections" / String / "upload")
+        .and(warp::post())
+        .and(with_tenant())
+        .and(upload_request())
+        .and(with_db(db.clone()))
+        .and(with_upload_config(upload_config.clone()))
+        .and(with_ingest_config(ingest_config.clone()))
+        .and(with_trace_id())
+        .and_then(handlers::handle_upload)
+}
+
+fn get_collection(
+    db: &DatabaseConnection,
+    model_config: &ModelConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("collections" / String)
+        .and(with_tenant())
+        .and(warp::get())
+        .and(with_db(db.clone()))
+        .and(with_model_config(model_config.clone()))
+        .and_then(handlers::handle_get_collection)
+}
+
+fn configure_collection(
+    db: &DatabaseConnection,
+    model_config: &ModelConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("collections" / String)
+        .and(with_tenant())
+        .and(warp::put())
+        .and(json_body::<schema::ConfigureCollectionRequest>(LIMIT_1_MB))
+        .and(with_db(db.clone()))
+        .and(with_model_config(model_config.clone()))
+        .and_then(handlers::handle_configure_collection)
+}
+
+fn delete_collection(
+    db: &DatabaseConnection,
+    model_config: &ModelConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path!("collections" / String)
+        .and(with_tenant())
         .and(warp::delete())
+        .and(with_db(db.clone()))
+        .and(with_model_config(model_config.clone()))
         .and_then(handlers::handle_delete_collection)
 }
 
+fn compact_collection(
+    db: &DatabaseConnection,
+    model_config: &ModelConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("collections" / String / "compact")
+        .and(with_tenant())
+        .and(warp::post())
+        .and(with_db(db.clone()))
+        .and(with_model_config(model_config.clone()))
+        .and_then(handlers::handle_compact)
+}
+
+fn delete_documents(
+    db: &DatabaseConnection,
+    model_config: &ModelConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("collections" / String / "documents")
+        .and(with_tenant())
+        .and(warp::delete())
+        .and(warp::query::<schema::DeleteDocumentsRequest>())
+        .and(with_db(db.clone()))
+        .and(with_model_config(model_config.clone()))
+        .and_then(handlers::handle_delete_documents)
+}
+
+fn get_document(
+    db: &DatabaseConnection,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("collections" / String / "documents" / String)
+        .and(with_tenant())
+        .and(warp::get())
+        .and(with_db(db.clone()))
+        .and_then(handlers::handle_get_document)
+}
+
+fn get_document_segments(
+    db: &DatabaseConnection,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("collections" / String / "documents" / String / "segments")
+        .and(with_tenant())
+        .and(warp::get())
+        .and(warp::query::<schema::DocumentSegmentsQuery>())
+        .and(with_db(db.clone()))
+        .and_then(handlers::handle_get_document_segments)
+}
+
+fn delete_segment(
+    db: &DatabaseConnection,
+    model_config: &ModelConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("collections" / String / "segments" / String)
+        .and(with_tenant())
+        .and(warp::delete())
+        .and(with_db(db.clone()))
+        .and(with_model_config(model_config.clone()))
+        .and_then(handlers::handle_delete_segment)
+}
+
+fn export_collection(
+    db: &DatabaseConnection,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("collections" / String / "export")
+        .and(with_tenant())
+        .and(warp::get())
+        .and(with_db(db.clone()))
+        .and_then(handlers::handle_export)
+}
+
+fn import_body() -> impl Filter<
+    Extract = (handlers::ImportBody<impl Stream<Item = Result<impl Buf, warp::Error>> + Send>,),
+    Error = warp::Rejection,
+> + Clone {
+    warp::query::<schema::ImportRequest>()
+        .and(warp::body::content_length_limit(LIMIT_1_GB))
+        .and(warp::body::stream())
+        .map(|req, body| handlers::ImportBody { req, body })
+}
+
+fn import_collection(
+    db: &DatabaseConnection,
+    ingest_config: &IngestConfig,
+    model_config: &ModelConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("collections" / String / "import")
+        .and(with_tenant())
+        .and(warp::post())
+        .and(import_body())
+        .and(with_db(db.clone()))
+        .and(with_model_config(model_config.clone()))
+        .and(with_ingest_config(ingest_config.clone()))
+        .and(with_trace_id())
+        .and_then(handlers::handle_import)
+}
+
 fn search_docs(
     db: &DatabaseConnection,
+    model_config: &ModelConfig,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path!("collections" / String / "search")
+        .and(with_tenant())
         .and(warp::get())
+        .and(warp::query::<schema::SearchScoreQuery>())
         .and(json_body::<schema::SearchDocsRequest>(LIMIT_1_MB))
         .and(with_db(db.clone()))
+        .and(with_model_config(model_config.clone()))
         .and_then(handlers::handle_search_docs)
 }
 
-pub fn build(
+fn ask_collection(
+    db: &DatabaseConnection,
+    model_config: &ModelConfig,
+    llm: &Arc<Box<dyn LLM>>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("collections" / String / "ask")
+        .and(with_tenant())
+        .and(warp::post())
+        .and(json_body::<schema::AskCollectionRequest>(LIMIT_1_MB))
+        .and(with_db(db.clone()))
+        .and(with_model_config(model_config.clone()))
+        .and(with_llm(llm.clone()))
+        .and_then(handlers::handle_ask_collection)
+}
+
+fn reindex_collection(
+    db: &DatabaseConnection,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("collections" / String / "reindex")
+        .and(with_tenant())
+        .and(warp::post())
+        .and(json_body::<schema::ReindexRequest>(LIMIT_1_MB))
+        .and(with_db(db.clone()))
+        .and(with_trace_id())
+        .and_then(handlers::handle_reindex)
+}
+
+fn search_vector(
+    db: &DatabaseConnection,
+    model_config: &ModelConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("collections" / String / "search" / "vector")
+        .and(with_tenant())
+        .and(warp::post())
+        .and(warp::query::<schema::SearchScoreQuery>())
+        .and(json_body::<schema::SearchVectorRequest>(LIMIT_1_MB))
+        .and(with_db(db.clone()))
+        .and(with_model_config(model_config.clone()))
+        .and_then(handlers::handle_search_vector)
+}
+
+fn search_batch(
+    db: &DatabaseConnection,
+    model_config: &ModelConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("collections" / String / "search" / "batch")
+        .and(with_tenant())
+        .and(warp::post())
+        .and(warp::query::<schema::SearchScoreQuery>())
+        .and(json_body::<schema::SearchBatchRequest>(LIMIT_1_MB))
+        .and(with_db(db.clone()))
+        .and(with_model_config(model_config.clone()))
+        .and_then(handlers::handle_search_batch)
+}
+
+fn search_count(
+    db: &DatabaseConnection,
+    model_config: &ModelConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("collections" / String / "search" / "count")
+        .and(with_tenant())
+        .and(warp::get())
+        .and(json_body::<schema::SearchCountRequest>(LIMIT_1_MB))
+        .and(with_db(db.clone()))
+        .and(with_model_config(model_config.clone()))
+        .and_then(handlers::handle_search_count)
+}
+
+/// Routes that leave collections as they are.
+pub fn build_reads(
+    db: &DatabaseConnection,
+    llm: &Arc<Box<dyn LLM>>,
+    model_config: &ModelConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    get_collection(db, model_config)
+        .or(get_document(db))
+        .or(get_document_segments(db))
+        .or(search_docs(db, model_config))
+        .or(search_vector(db, model_config))
+        .or(search_batch(db, model_config))
+        .or(search_count(db, model_config))
+        .or(ask_collection(db, model_config, llm))
+        .or(export_collection(db))
+        .boxed()
+}
+
+/// Routes that add to, change or delete collections.
+pub fn build_writes(
     db: &DatabaseConnection,
+    upload_config: &UploadConfig,
+    ingest_config: &IngestConfig,
+    model_config: &ModelConfig,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    add_document(db)
-        .or(delete_collection())
-        .or(search_docs(db))
+    add_document(db, ingest_config)
+        .or(add_segments(db, ingest_config, model_config))
+        .or(upload_document(db, upload_config, ingest_config))
+        .or(configure_collection(db, model_config))
+        .or(delete_collection(db, model_config))
+        .or(delete_documents(db, model_config))
+        .or(delete_segment(db, model_config))
+        .or(compact_collection(db, model_config))
+        .or(reindex_collection(db))
+        .or(import_collection(db, ingest_config, model_config))
         .boxed()
 }
@@ -1,109 +1,2261 @@
 use crate::{
+    endpoints::{
+        check_max_retries, check_stop,
+        fetch::{filters::ParseRequest, handlers::parse_upload},
+        invalidate_search_cache, search_cache, IngestConfig, UploadConfig, LIMIT_10_MB,
+    },
     schema::{self, ApiResponse, DocumentSegment},
+    tenant::Tenant,
     ServerError,
 };
+use futures_util::{Stream, TryStreamExt};
+use jsonschema::JSONSchema;
 use libmemex::{
-    db::{embedding, queue},
-    llm::embedding::{ModelConfig, SentenceEmbedder},
-    storage::get_vector_storage,
+    cache::SearchKey,
+    db::{self, document, embedding, queue},
+    llm::{
+        context_budget,
+        embedding::{
+            cosine_similarity, segment_overlap, segment_text, token_counter, EmbedderRegistry,
+            EmbeddingResult, EmbeddingsModelType, ModelConfig, SentenceEmbedder, CHUNK_SEPARATOR,
+            DEFAULT_MAX_RESIDENT_MODELS,
+        },
+        fit_context, prompter, ChatCompletionOptions, DEFAULT_MAX_RESPONSE_TOKENS, LLM,
+    },
+    storage::{
+        check_dimension, get_vector_storage_with_dimension, mmr_rerank,
+        scoring::{RecencyBoost, ScoreModifier},
+        similarity_from_distance, supports_index_swap, DistanceMetric, StoreHealth, VectorStorage,
+    },
+};
+use sea_orm::{
+    ActiveModelBehavior, ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait,
+    PaginatorTrait, QueryFilter, QueryOrder, QuerySelect, Set,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+use strum::VariantNames;
+use warp::{
+    filters::multipart::FormData,
+    http::StatusCode,
+    hyper::body::{Body, Buf},
 };
-use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
 
-pub async fn handle_add_document(
+// Number of documents read from the db at a time when exporting.
+const EXPORT_PAGE_SIZE: u64 = 100;
+// Number of rows looked up or removed at a time by id, keeps `IN (...)`
+// lists bounded.
+const ID_PAGE_SIZE: u64 = 500;
+// Max # of nearest neighbors a search count considers.
+const MAX_SEARCH_COUNT: u64 = 10_000;
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+// Documents are only auto-split into at most this many parts, anything larger
+// should be split up by the client.
+const MAX_SPLIT_PARTS: usize = 10;
+// Max # of pre-chunked segments in a single document.
+const MAX_INGEST_SEGMENTS: usize = 1000;
+// Candidates fetched per result when re-ranking w/ MMR.
+const MMR_CANDIDATE_FACTOR: u64 = 4;
+// How long (in seconds) adding a document w/ `wait` waits for by default, &
+// the most it can be asked to wait for.
+const DEFAULT_WAIT_SECS: u64 = 30;
+const MAX_WAIT_SECS: u64 = 300;
+// Max # of segments on either side of a search result to return as context.
+const MAX_CONTEXT_WINDOW: usize = 10;
+// Max # of queries in a single batched search.
+const MAX_BATCH_QUERIES: usize = 32;
+// Max # of segments retrieved as context when asking a collection.
+const MAX_ASK_SEGMENTS: u64 = 50;
+// Share of text adjacent segments need in common to be collapsed into one
+// result, segments w/ the default stride share about a third.
+const MIN_COLLAPSE_OVERLAP: f32 = 0.25;
+// Longest segment the supported models can embed.
+const MAX_SEGMENT_TOKENS: usize = 512;
+
+/// Collections currently being compacted.
+static COMPACTING: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+/// Marks a collection as being compacted until dropped.
+struct CompactionGuard(String);
+
+impl CompactionGuard {
+    fn acquire(collection: &str) -> Option<Self> {
+        let mut running = COMPACTING.get_or_init(Default::default).lock().ok()?;
+        running
+            .insert(collection.to_string())
+            .then(|| Self(collection.to_string()))
+    }
+}
+
+impl Drop for CompactionGuard {
+    fn drop(&mut self) {
+        if let Some(Ok(mut running)) = COMPACTING.get().map(|running| running.lock()) {
+            running.remove(&self.0);
+        }
+    }
+}
+
+/// Embedders used for search queries, shared across requests.
+static EMBEDDERS: OnceLock<EmbedderRegistry> = OnceLock::new();
+
+/// Embedder for a collection's search queries. Queries aren't segmented, so
+/// collections using the same model share an embedder.
+pub fn query_embedder(model_config: &ModelConfig) -> SentenceEmbedder {
+    EMBEDDERS
+        .get_or_init(|| EmbedderRegistry::new(model_config.clone(), DEFAULT_MAX_RESIDENT_MODELS))
+        .get(model_config.model())
+}
+
+/// Connect to the vector index currently backing a collection, which changes
+/// once a collection has been reindexed. `dimension` is only used if the
+/// index has to be created.
+async fn collection_storage(
+    db: &DatabaseConnection,
+    collection: &str,
+    dimension: usize,
+) -> Result<VectorStorage, ServerError> {
+    let vector_uri = std::env::var("VECTOR_CONNECTION").expect("VECTOR_CONNECTION env var not set");
+    let index = db::collection::vector_index(db, collection).await?;
+    get_vector_storage_with_dimension(&vector_uri, &index, dimension)
+        .await
+        .map_err(|err| ServerError::VectorStore(format!("Unable to connect to vector db: {err}")))
+}
+
+/// Connect to a collection's vector index, sized for the collection's model
+/// in case the index has to be created.
+async fn collection_model_storage(
+    db: &DatabaseConnection,
+    collection: &str,
+    model_config: ModelConfig,
+) -> Result<VectorStorage, ServerError> {
+    let model_config = collection_model_config(db, collection, model_config).await?;
+    collection_storage(db, collection, model_config.model().dimension()).await
+}
+
+/// Health of the vector store backing a collection, for readiness checks.
+pub async fn collection_health(
+    db: &DatabaseConnection,
+    collection: &str,
+    model_config: ModelConfig,
+) -> Result<StoreHealth, ServerError> {
+    if !db::collection::exists(db, collection).await? {
+        return Err(ServerError::NotFound(format!(
+            "Collection {collection} doesn't exist"
+        )));
+    }
+
+    let client = collection_model_storage(db, collection, model_config).await?;
+    client
+        .health()
+        .await
+        .map_err(|err| ServerError::VectorStore(format!("Vector store is unhealthy: {err}")))
+}
+
+/// Queries need to be embedded w/ the same model as the collection's documents.
+async fn collection_model_config(
+    db: &DatabaseConnection,
+    collection: &str,
+    model_config: ModelConfig,
+) -> Result<ModelConfig, ServerError> {
+    Ok(db::collection::model_config(db, collection, model_config).await?)
+}
+
+/// Make sure a collection exists before documents are added to it, creating
+/// it w/ the default settings unless auto-creation is turned off. Called once
+/// the request is validated so rejected requests don't leave collections behind.
+async fn ensure_collection(
+    db: &DatabaseConnection,
+    tenant: &Tenant,
+    collection: &str,
+    ingest_config: &IngestConfig,
+) -> Result<(), ServerError> {
+    if ingest_config.auto_create_collections {
+        db::collection::create_default(db, collection)
+            .await
+            .map_err(ServerError::DatabaseError)?;
+    } else if !db::collection::exists(db, collection)
+        .await
+        .map_err(ServerError::DatabaseError)?
+    {
+        return Err(ServerError::NotFound(format!(
+            "Collection {} doesn't exist",
+            tenant.local_name(collection)
+        )));
+    }
+
+    Ok(())
+}
+
+pub async fn handle_get_collection(
     collection: String,
-    req: schema::InsertDocumentRequest,
+    tenant: Tenant,
     db: DatabaseConnection,
+    model_config: ModelConfig,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let time = std::time::Instant::now();
-    // Add to job queue
-    let task = match queue::enqueue(&db, &collection, &req.content, queue::TaskType::Ingest).await {
-        Ok(model) => model,
-        Err(err) => return Err(warp::reject::custom(ServerError::DatabaseError(err))),
+    let name = tenant.collection(&collection)?;
+    if !db::collection::exists(&db, &name)
+        .await
+        .map_err(ServerError::DatabaseError)?
+    {
+        return Err(warp::reject::custom(ServerError::NotFound(format!(
+            "Collection {collection} doesn't exist"
+        ))));
+    }
+
+    let settings = db::collection::get(&db, &name)
+        .await
+        .map_err(ServerError::DatabaseError)?;
+    let model_config = match &settings {
+        Some(settings) => settings.model_config(model_config),
+        None => model_config,
     };
 
-    // Create an UUID for this document & add to queue
     Ok(warp::reply::json(&ApiResponse::success(
         time.elapsed(),
-        Some(schema::TaskResult::from(task)),
+        Some(schema::CollectionResult::new(
+            collection,
+            settings.as_ref(),
+            &model_config,
+        )),
     )))
 }
 
-pub async fn handle_delete_collection(
+pub async fn handle_configure_collection(
     collection: String,
+    tenant: Tenant,
+    req: schema::ConfigureCollectionRequest,
+    db: DatabaseConnection,
+    model_config: ModelConfig,
 ) -> Result<impl warp::Reply, warp::Rejection> {
+    let time = std::time::Instant::now();
+    let name = tenant.new_collection(&db, &collection).await?;
+
+    let model = match &req.model {
+        Some(model) => Some(model.parse::<EmbeddingsModelType>().map_err(|_| {
+            ServerError::BadRequest(format!(
+                "Unknown embedding model: {model}, must be one of {}",
+                EmbeddingsModelType::VARIANTS.join(", ")
+            ))
+        })?),
+        None => None,
+    };
+    let metric = match &req.metric {
+        Some(metric) => metric.parse::<DistanceMetric>().map_err(|_| {
+            ServerError::BadRequest(format!(
+                "Unsupported metric: {metric}, must be one of {}",
+                DistanceMetric::VARIANTS.join(", ")
+            ))
+        })?,
+        None => DistanceMetric::default(),
+    };
+
+    let config = db::collection::CollectionConfig {
+        embedding_model: model,
+        metric,
+        segmentation: match (req.max_length, req.stride) {
+            (None, None) => None,
+            (max_length, stride) => Some((
+                max_length.unwrap_or(model_config.max_length()),
+                stride.unwrap_or(model_config.stride()),
+            )),
+        },
+        store_content: req.store_content.unwrap_or(true),
+        normalize: req.normalize,
+    };
+
+    let mut new_config = model_config
+        .clone()
+        .for_model(model.unwrap_or(model_config.model()));
+    if let Some((max_length, stride)) = config.segmentation {
+        if max_length == 0 || max_length > MAX_SEGMENT_TOKENS || stride >= max_length {
+            return Err(warp::reject::custom(ServerError::BadRequest(format!(
+                "maxLength must be between 1 and {MAX_SEGMENT_TOKENS} & stride less than maxLength"
+            ))));
+        }
+        new_config = new_config.with_segmentation(max_length, stride);
+    }
+    if let Some(normalize) = req.normalize {
+        new_config = new_config.normalized(normalize);
+    }
+    segment_text(&new_config, "").map_err(|err| ServerError::BadRequest(err.to_string()))?;
     let vector_uri = std::env::var("VECTOR_CONNECTION").expect("VECTOR_CONNECTION env var not set");
-    let client = match get_vector_storage(&vector_uri, &collection).await {
-        Ok(client) => client,
-        Err(err) => {
-            return Err(warp::reject::custom(ServerError::Other(format!(
-                "Unable to connect to vector db: {err}"
-            ))))
+    check_dimension(&vector_uri, new_config.model().dimension())
+        .await
+        .map_err(|err| {
+            ServerError::BadRequest(format!("Unable to use {}: {err}", new_config.model()))
+        })?;
+
+    // Existing vectors would no longer match the queries.
+    let current = collection_model_config(&db, &name, model_config.clone()).await?;
+    if current.model() != new_config.model()
+        || current.is_normalized() != new_config.is_normalized()
+    {
+        let documents = document::Entity::find()
+            .inner_join(queue::Entity)
+            .filter(queue::Column::Collection.eq(name.clone()))
+            .count(&db)
+            .await
+            .map_err(ServerError::DatabaseError)?;
+        if documents > 0 && current.model() != new_config.model() {
+            return Err(warp::reject::custom(ServerError::Conflict(format!(
+                "Collection {collection} already has documents embedded w/ {}, reindex it to change models",
+                current.model()
+            ))));
+        }
+        if documents > 0 {
+            return Err(warp::reject::custom(ServerError::Conflict(format!(
+                "Collection {collection} already has documents, reindex it into a new collection to change normalization"
+            ))));
+        }
+    }
+
+    let settings = db::collection::configure(&db, &name, config)
+        .await
+        .map_err(ServerError::DatabaseError)?;
+    invalidate_search_cache(&name);
+    log::info!("configured <{name}> w/ {}", new_config.model());
+
+    Ok(warp::reply::json(&ApiResponse::success(
+        time.elapsed(),
+        Some(schema::CollectionResult::new(
+            collection,
+            Some(&settings),
+            &settings.model_config(model_config),
+        )),
+    )))
+}
+
+/// Request options for adding a document that aren't part of the document.
+pub struct AddDocumentOptions {
+    pub idempotency_key: Option<String>,
+    pub wait: schema::WaitQuery,
+}
+
+#[tracing::instrument(skip_all, fields(collection = %collection))]
+pub async fn handle_add_document(
+    collection: String,
+    tenant: Tenant,
+    req: schema::InsertDocumentRequest,
+    db: DatabaseConnection,
+    ingest_config: IngestConfig,
+    trace_id: String,
+    options: AddDocumentOptions,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collection = tenant.new_collection(&db, &collection).await?;
+    let time = std::time::Instant::now();
+    let AddDocumentOptions {
+        idempotency_key,
+        wait,
+    } = options;
+    let wait_timeout = match wait.timeout {
+        Some(secs) if !(1..=MAX_WAIT_SECS).contains(&secs) => {
+            return Err(warp::reject::custom(ServerError::BadRequest(format!(
+                "timeout must be between 1 and {MAX_WAIT_SECS} seconds"
+            ))));
+        }
+        Some(secs) => Duration::from_secs(secs),
+        None => Duration::from_secs(DEFAULT_WAIT_SECS),
+    };
+    if let Some(key) = &idempotency_key {
+        if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+            return Err(warp::reject::custom(ServerError::BadRequest(format!(
+                "Idempotency-Key must be between 1 and {MAX_IDEMPOTENCY_KEY_LEN} characters"
+            ))));
+        }
+    }
+    if let Some(schema) = &req.metadata_schema {
+        JSONSchema::options()
+            .compile(schema)
+            .map_err(|err| ServerError::BadRequest(format!("Invalid metadata schema: {err}")))?;
+    }
+    let max_retries = check_max_retries(req.max_retries)?;
+    if req.content.trim().is_empty() {
+        return Err(warp::reject::custom(ServerError::BadRequest(
+            "Document has no content".into(),
+        )));
+    }
+    if let Some(threshold) = req.dedup_threshold {
+        if !(0.0..=1.0).contains(&threshold) {
+            return Err(warp::reject::custom(ServerError::BadRequest(
+                "dedupThreshold must be between 0 and 1".into(),
+            )));
+        }
+    }
+
+    let max_chars = ingest_config.max_document_chars;
+    let num_chars = req.content.chars().count();
+    let parts = if num_chars <= max_chars {
+        vec![req.content]
+    } else if req.auto_split && num_chars <= max_chars.saturating_mul(MAX_SPLIT_PARTS) {
+        split_content(&req.content, max_chars)
+    } else {
+        return Err(warp::reject::custom(too_large(num_chars, max_chars)));
+    };
+    ensure_collection(&db, &tenant, &collection, &ingest_config).await?;
+
+    let is_split = parts.len() > 1;
+    let mut tasks = Vec::new();
+    for (idx, content) in parts.into_iter().enumerate() {
+        // Keep ids & keys unique per part
+        let suffix = |value: &String| match is_split {
+            true => format!("{value}-{idx}"),
+            false => value.clone(),
+        };
+        let payload = queue::TaskPayload {
+            content,
+            document_id: req.id.as_ref().map(suffix),
+            metadata_schema: req.metadata_schema.clone(),
+            dedup_threshold: req.dedup_threshold,
+            ..Default::default()
+        };
+
+        // Add to job queue
+        let task = queue::new_task(
+            &collection,
+            payload,
+            queue::TaskType::Ingest,
+            Some(&trace_id),
+            max_retries,
+        );
+        let task = match idempotency_key.as_ref().map(suffix) {
+            Some(key) => {
+                let (task, created) = queue::enqueue_idempotent(&db, task, &key)
+                    .await
+                    .map_err(ServerError::DatabaseError)?;
+                if !created {
+                    log::info!(
+                        "[trace={trace_id}] idempotency key already used by task {}",
+                        task.id
+                    );
+                }
+                task
+            }
+            None => {
+                let task = queue::enqueue_task(&db, task)
+                    .await
+                    .map_err(ServerError::DatabaseError)?;
+                log::info!("[trace={trace_id}] queued ingest task {}", task.id);
+                task
+            }
+        };
+        tasks.push(task);
+    }
+
+    // Tasks that are still running once the wait is over are returned as-is
+    // w/ a 202.
+    let mut status = StatusCode::OK;
+    if wait.wait {
+        let deadline = std::time::Instant::now() + wait_timeout;
+        for task in tasks.iter_mut() {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if let Some(finished) = queue::wait_for_task(&db, task.id, remaining)
+                .await
+                .map_err(ServerError::DatabaseError)?
+            {
+                *task = finished;
+            }
+            if !task.status.is_finished() {
+                status = StatusCode::ACCEPTED;
+            }
         }
+    }
+
+    let mut tasks: Vec<_> = tasks
+        .into_iter()
+        .map(|task| schema::TaskResult::from(task).for_tenant(&tenant))
+        .collect();
+    if is_split {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&ApiResponse::success(
+                time.elapsed(),
+                Some(schema::SplitDocumentResult { tasks }),
+            )),
+            status,
+        ));
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&ApiResponse::success(time.elapsed(), tasks.pop())),
+        status,
+    ))
+}
+
+/// Add a document that's already been chunked, each segment is embedded as-is.
+#[tracing::instrument(skip_all, fields(collection = %collection))]
+pub async fn handle_add_segments(
+    collection: String,
+    tenant: Tenant,
+    req: schema::InsertSegmentsRequest,
+    db: DatabaseConnection,
+    ingest_config: IngestConfig,
+    model_config: ModelConfig,
+    trace_id: String,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collection = tenant.new_collection(&db, &collection).await?;
+    let time = std::time::Instant::now();
+    let max_retries = check_max_retries(req.max_retries)?;
+    let model_config = collection_model_config(&db, &collection, model_config).await?;
+    let count_tokens =
+        token_counter(&model_config).map_err(|err| ServerError::Other(err.to_string()))?;
+    check_segments(
+        &req.segments,
+        ingest_config.max_document_chars,
+        model_config.segment_length(&count_tokens),
+        count_tokens,
+    )?;
+    ensure_collection(&db, &tenant, &collection, &ingest_config).await?;
+
+    // The joined chunks are stored as the document's content, the segments
+    // themselves are what gets embedded.
+    let payload = queue::TaskPayload {
+        content: req.segments.join(CHUNK_SEPARATOR),
+        segments: Some(req.segments),
+        document_id: req.id,
+        ..Default::default()
     };
+    let task = queue::new_task(
+        &collection,
+        payload,
+        queue::TaskType::Ingest,
+        Some(&trace_id),
+        max_retries,
+    );
+    let task = queue::enqueue_task(&db, task)
+        .await
+        .map_err(ServerError::DatabaseError)?;
+    log::info!("[trace={trace_id}] queued ingest task {}", task.id);
+
+    let result = schema::TaskResult::from(task).for_tenant(&tenant);
+    Ok(warp::reply::json(&ApiResponse::success(
+        time.elapsed(),
+        Some(result),
+    )))
+}
+
+/// Make sure pre-chunked segments aren't empty & fit within the size limits.
+/// Each segment is embedded as-is, so it also has to fit in `max_tokens`
+/// rather than be silently truncated.
+fn check_segments<F>(
+    segments: &[String],
+    max_chars: usize,
+    max_tokens: usize,
+    count_tokens: F,
+) -> Result<(), ServerError>
+where
+    F: Fn(&str) -> usize,
+{
+    if segments.is_empty() {
+        return Err(ServerError::BadRequest("No segments to add".into()));
+    }
+    if segments.len() > MAX_INGEST_SEGMENTS {
+        return Err(ServerError::BadRequest(format!(
+            "Too many segments, the limit is {MAX_INGEST_SEGMENTS}"
+        )));
+    }
+    if let Some(idx) = segments
+        .iter()
+        .position(|segment| segment.trim().is_empty())
+    {
+        return Err(ServerError::BadRequest(format!("Segment {idx} is empty")));
+    }
+
+    let num_chars: usize = segments.iter().map(|segment| segment.chars().count()).sum();
+    if num_chars > max_chars {
+        return Err(too_large(num_chars, max_chars));
+    }
+
+    for (idx, segment) in segments.iter().enumerate() {
+        let tokens = count_tokens(segment);
+        if tokens > max_tokens {
+            return Err(ServerError::BadRequest(format!(
+                "Segment {idx} is {tokens} tokens, the limit is {max_tokens}"
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Error for a document over the `max_chars` limit.
+fn too_large(num_chars: usize, max_chars: usize) -> ServerError {
+    ServerError::PayloadTooLarge(format!(
+        "Document is {num_chars} characters, the limit is {max_chars}. \
+        Please split it into smaller documents."
+    ))
+}
+
+/// Split `content` into parts of at most `max_chars` characters, preferring to
+/// break between paragraphs, then between words. Content up to
+/// `MAX_SPLIT_PARTS` times the limit is split into at most that many parts.
+fn split_content(content: &str, max_chars: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut rest = content;
+    let mut rest_chars = rest.chars().count();
+    while rest_chars > max_chars {
+        let limit = rest
+            .char_indices()
+            .nth(max_chars)
+            .map(|(idx, _)| idx)
+            .unwrap_or(rest.len());
+        let window = &rest[..limit];
+        // Only break early if it doesn't leave a tiny part behind, or more
+        // than the parts that are left can hold.
+        let slots = MAX_SPLIT_PARTS.saturating_sub(parts.len() + 1);
+        let min_chars = rest_chars.saturating_sub(slots.saturating_mul(max_chars));
+        let min_break = window
+            .char_indices()
+            .nth(min_chars)
+            .map(|(idx, _)| idx)
+            .unwrap_or(window.len())
+            .max(window.len() / 2);
+        let split_at = window
+            .rfind("\n\n")
+            .filter(|idx| *idx > min_break)
+            .or_else(|| {
+                window
+                    .rfind(char::is_whitespace)
+                    .filter(|idx| *idx > min_break)
+            })
+            .unwrap_or(limit);
+
+        let (part, remaining) = rest.split_at(split_at);
+        if !part.trim().is_empty() {
+            parts.push(part.trim().to_string());
+        }
+        rest_chars -= part.chars().count();
+        rest = remaining;
+    }
+
+    if !rest.trim().is_empty() {
+        parts.push(rest.trim().to_string());
+    }
+
+    parts
+}
+
+/// An uploaded file & how to parse it.
+pub struct UploadRequest {
+    pub query: ParseRequest,
+    pub form: FormData,
+}
+
+pub async fn handle_upload(
+    collection: String,
+    tenant: Tenant,
+    upload: UploadRequest,
+    db: DatabaseConnection,
+    upload_config: UploadConfig,
+    ingest_config: IngestConfig,
+    trace_id: String,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collection = tenant.new_collection(&db, &collection).await?;
+    let time = std::time::Instant::now();
+
+    let UploadRequest { query, form } = upload;
+    let content = parse_upload(&query, form, &upload_config).await?;
+    if content.trim().is_empty() {
+        return Err(warp::reject::custom(ServerError::BadRequest(
+            "No text found in file".into(),
+        )));
+    }
+    let num_chars = content.chars().count();
+    if num_chars > ingest_config.max_document_chars {
+        return Err(warp::reject::custom(too_large(
+            num_chars,
+            ingest_config.max_document_chars,
+        )));
+    }
+    ensure_collection(&db, &tenant, &collection, &ingest_config).await?;
+
+    let payload = queue::TaskPayload {
+        content,
+        ..Default::default()
+    };
+
+    let task = queue::enqueue_payload(
+        &db,
+        &collection,
+        payload,
+        queue::TaskType::Ingest,
+        Some(&trace_id),
+        None,
+    )
+    .await
+    .map_err(ServerError::DatabaseError)?;
+    log::info!(
+        "[trace={trace_id}] queued ingest task {} from upload",
+        task.id
+    );
+
+    Ok(warp::reply::json(&ApiResponse::success(
+        time.elapsed(),
+        Some(schema::TaskResult::from(task).for_tenant(&tenant)),
+    )))
+}
+
+pub async fn handle_delete_collection(
+    collection: String,
+    tenant: Tenant,
+    db: DatabaseConnection,
+    model_config: ModelConfig,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collection = tenant.collection(&collection)?;
+    let client = collection_model_storage(&db, &collection, model_config).await?;
 
     match client.delete_collection().await {
-        Ok(()) => Ok(warp::reply::with_status(
-            warp::reply(),
-            warp::http::StatusCode::OK,
-        )),
-        Err(err) => Err(warp::reject::custom(ServerError::Other(format!(
+        Ok(()) => {
+            db::embedding::mark_unindexed(&db, &collection)
+                .await
+                .map_err(ServerError::DatabaseError)?;
+            db::collection::remove(&db, &collection)
+                .await
+                .map_err(ServerError::DatabaseError)?;
+            invalidate_search_cache(&collection);
+            Ok(warp::reply::with_status(
+                warp::reply(),
+                warp::http::StatusCode::OK,
+            ))
+        }
+        Err(err) => Err(warp::reject::custom(ServerError::VectorStore(format!(
             "Unable to remove collection {collection}: {err}"
         )))),
     }
 }
 
+pub async fn handle_compact(
+    collection: String,
+    tenant: Tenant,
+    db: DatabaseConnection,
+    model_config: ModelConfig,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collection = tenant.collection(&collection)?;
+    let time = std::time::Instant::now();
+    let Some(_guard) = CompactionGuard::acquire(&collection) else {
+        return Err(warp::reject::custom(ServerError::Conflict(format!(
+            "Collection {} is already being compacted",
+            tenant.local_name(&collection)
+        ))));
+    };
+
+    let client = collection_model_storage(&db, &collection, model_config).await?;
+
+    let result = client.compact().await.map_err(|err| {
+        ServerError::VectorStore(format!("Unable to compact collection {collection}: {err}"))
+    })?;
+
+    log::info!(
+        "compacted <{collection}> in {:?}, reclaimed {:?} points",
+        time.elapsed(),
+        result.reclaimed
+    );
+    Ok(warp::reply::json(&ApiResponse::success(
+        time.elapsed(),
+        Some(serde_json::json!({ "reclaimed": result.reclaimed })),
+    )))
+}
+
+pub async fn handle_reindex(
+    collection: String,
+    tenant: Tenant,
+    req: schema::ReindexRequest,
+    db: DatabaseConnection,
+    trace_id: String,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collection = tenant.collection(&collection)?;
+    let time = std::time::Instant::now();
+    let model = req
+        .model
+        .parse::<EmbeddingsModelType>()
+        .map_err(|_| ServerError::BadRequest(format!("Unknown embedding model: {}", req.model)))?;
+
+    let vector_uri = std::env::var("VECTOR_CONNECTION").expect("VECTOR_CONNECTION env var not set");
+    if !supports_index_swap(&vector_uri) {
+        return Err(warp::reject::custom(ServerError::BadRequest(
+            "Reindexing in the background isn't supported w/ pgvector, use `memex reindex` instead"
+                .to_string(),
+        )));
+    }
+
+    if queue::active_task(&db, &collection, queue::TaskType::Reindex)
+        .await
+        .map_err(ServerError::DatabaseError)?
+        .is_some()
+    {
+        return Err(warp::reject::custom(ServerError::Conflict(format!(
+            "Collection {} is already being reindexed",
+            tenant.local_name(&collection)
+        ))));
+    }
+
+    // Documents are re-segmented from their full content.
+    let without_content = document::Entity::find()
+        .inner_join(queue::Entity)
+        .filter(queue::Column::Collection.eq(collection.clone()))
+        .filter(document::Column::Content.eq(""))
+        .count(&db)
+        .await
+        .map_err(ServerError::DatabaseError)?;
+    if without_content > 0 {
+        return Err(warp::reject::custom(ServerError::Conflict(format!(
+            "Collection {} has {without_content} documents w/o their full content, they can't be reindexed",
+            tenant.local_name(&collection)
+        ))));
+    }
+
+    let payload = queue::TaskPayload {
+        model: Some(model.to_string()),
+        ..Default::default()
+    };
+    let task = queue::enqueue_payload(
+        &db,
+        &collection,
+        payload,
+        queue::TaskType::Reindex,
+        Some(&trace_id),
+        None,
+    )
+    .await
+    .map_err(ServerError::DatabaseError)?;
+    log::info!(
+        "[trace={trace_id}] queued reindex task {} for <{collection}> w/ {model}",
+        task.id
+    );
+
+    Ok(warp::reply::json(&ApiResponse::success(
+        time.elapsed(),
+        Some(schema::TaskResult::from(task).for_tenant(&tenant)),
+    )))
+}
+
+pub async fn handle_get_document(
+    collection: String,
+    document_id: String,
+    tenant: Tenant,
+    db: DatabaseConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collection = tenant.collection(&collection)?;
+    let time = std::time::Instant::now();
+
+    let document = document::Entity::find()
+        .inner_join(queue::Entity)
+        .filter(queue::Column::Collection.eq(collection))
+        .filter(document::Column::Uuid.eq(document_id.clone()))
+        .one(&db)
+        .await
+        .map_err(ServerError::DatabaseError)?
+        .ok_or_else(|| ServerError::NotFound(format!("Document {document_id}")))?;
+    if !document.has_content() {
+        return Err(warp::reject::custom(ServerError::Conflict(format!(
+            "Full content of document {document_id} wasn't retained, only its segments are stored. \
+            Fetch them from documents/{document_id}/segments instead."
+        ))));
+    }
+
+    Ok(warp::reply::json(&ApiResponse::success(
+        time.elapsed(),
+        Some(schema::DocumentResult::from(document)),
+    )))
+}
+
+pub async fn handle_get_document_segments(
+    collection: String,
+    document_id: String,
+    tenant: Tenant,
+    query: schema::DocumentSegmentsQuery,
+    db: DatabaseConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collection = tenant.collection(&collection)?;
+    let time = std::time::Instant::now();
+
+    let exists = document::Entity::find()
+        .inner_join(queue::Entity)
+        .filter(queue::Column::Collection.eq(collection))
+        .filter(document::Column::Uuid.eq(document_id.clone()))
+        .count(&db)
+        .await
+        .map_err(ServerError::DatabaseError)?;
+    if exists == 0 {
+        return Err(warp::reject::custom(ServerError::NotFound(format!(
+            "Document {document_id}"
+        ))));
+    }
+
+    let segments = embedding::Entity::find()
+        .filter(embedding::Column::DocumentId.eq(document_id))
+        .order_by_asc(embedding::Column::Segment)
+        .all(&db)
+        .await
+        .map_err(ServerError::DatabaseError)?
+        .into_iter()
+        .map(|segment| schema::StoredSegment::new(segment, query.include_vectors))
+        .collect::<Vec<_>>();
+
+    Ok(warp::reply::json(&ApiResponse::success(
+        time.elapsed(),
+        Some(segments),
+    )))
+}
+
+pub async fn handle_delete_documents(
+    collection: String,
+    tenant: Tenant,
+    req: schema::DeleteDocumentsRequest,
+    db: DatabaseConnection,
+    model_config: ModelConfig,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collection = tenant.collection(&collection)?;
+    let time = std::time::Instant::now();
+    // A zero duration would remove everything in the collection.
+    let cutoff = parse_duration(&req.older_than)
+        .filter(|older_than| older_than.num_seconds() > 0)
+        .and_then(|older_than| chrono::Utc::now().checked_sub_signed(older_than))
+        .ok_or_else(|| ServerError::BadRequest(format!("Invalid duration: {}", req.older_than)))?;
+
+    let client = collection_model_storage(&db, &collection, model_config).await?;
+
+    // Removed a page at a time to keep queries bounded, each page is removed
+    // from the vector store before the db so nothing is left unreachable.
+    let mut deleted = 0;
+    loop {
+        let documents: Vec<(i64, String)> = document::Entity::find()
+            .select_only()
+            .column(document::Column::Id)
+            .column(document::Column::Uuid)
+            .inner_join(queue::Entity)
+            .filter(queue::Column::Collection.eq(collection.clone()))
+            .filter(document::Column::CreatedAt.lt(cutoff))
+            .order_by_asc(document::Column::Id)
+            .limit(ID_PAGE_SIZE)
+            .into_tuple()
+            .all(&db)
+            .await
+            .map_err(ServerError::DatabaseError)?;
+        if documents.is_empty() {
+            break;
+        }
+
+        // Remove the page's segments from the vector store at once, some
+        // stores save after each delete.
+        let (ids, uuids): (Vec<i64>, Vec<String>) = documents.into_iter().unzip();
+        let segments: Vec<String> = embedding::Entity::find()
+            .select_only()
+            .column(embedding::Column::Uuid)
+            .filter(embedding::Column::DocumentId.is_in(uuids.clone()))
+            .into_tuple()
+            .all(&db)
+            .await
+            .map_err(ServerError::DatabaseError)?;
+
+        if let Err(err) = client.delete_document(&segments).await {
+            return Err(warp::reject::custom(ServerError::VectorStore(format!(
+                "Unable to remove documents from vector db: {err}"
+            ))));
+        }
+
+        embedding::Entity::delete_many()
+            .filter(embedding::Column::DocumentId.is_in(uuids))
+            .exec(&db)
+            .await
+            .map_err(ServerError::DatabaseError)?;
+        deleted += document::Entity::delete_many()
+            .filter(document::Column::Id.is_in(ids))
+            .exec(&db)
+            .await
+            .map_err(ServerError::DatabaseError)?
+            .rows_affected;
+        invalidate_search_cache(&collection);
+    }
+
+    log::info!("removed {deleted} documents older than {cutoff} from <{collection}>");
+    Ok(warp::reply::json(&ApiResponse::success(
+        time.elapsed(),
+        Some(serde_json::json!({ "deleted": deleted })),
+    )))
+}
+
+pub async fn handle_delete_segment(
+    collection: String,
+    segment_id: String,
+    tenant: Tenant,
+    db: DatabaseConnection,
+    model_config: ModelConfig,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collection = tenant.collection(&collection)?;
+    let time = std::time::Instant::now();
+
+    let not_found = || ServerError::NotFound(format!("Segment {segment_id}"));
+    let segment = embedding::Entity::find()
+        .filter(embedding::Column::Uuid.eq(segment_id.clone()))
+        .one(&db)
+        .await
+        .map_err(ServerError::DatabaseError)?
+        .ok_or_else(not_found)?;
+
+    // Segments are only reachable through the collection their document is in.
+    let in_collection = document::Entity::find()
+        .inner_join(queue::Entity)
+        .filter(queue::Column::Collection.eq(collection.clone()))
+        .filter(document::Column::Uuid.eq(segment.document_id.clone()))
+        .count(&db)
+        .await
+        .map_err(ServerError::DatabaseError)?;
+    if in_collection == 0 {
+        return Err(warp::reject::custom(not_found()));
+    }
+
+    let client = collection_model_storage(&db, &collection, model_config).await?;
+    if let Err(err) = client.delete_segment(&segment.uuid).await {
+        return Err(warp::reject::custom(ServerError::VectorStore(format!(
+            "Unable to remove segment {segment_id} from vector db: {err}"
+        ))));
+    }
+
+    embedding::Entity::delete_by_id(segment.id)
+        .exec(&db)
+        .await
+        .map_err(ServerError::DatabaseError)?;
+
+    invalidate_search_cache(&collection);
+    log::info!(
+        "removed segment {segment_id} of document {} from <{collection}>",
+        segment.document_id
+    );
+    Ok(warp::reply::json(&ApiResponse::success(
+        time.elapsed(),
+        Some(schema::StoredSegment::new(segment, false)),
+    )))
+}
+
+/// Parse a duration such as "90s", "15m", "12h", "30d", or "2w".
+fn parse_duration(duration: &str) -> Option<chrono::Duration> {
+    let duration = duration.trim();
+    let unit_idx = duration.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = duration.split_at(unit_idx);
+    let amount: u64 = amount.parse().ok()?;
+
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+
+    let secs = amount.checked_mul(unit_secs)?;
+    chrono::Duration::from_std(std::time::Duration::from_secs(secs)).ok()
+}
+
+#[tracing::instrument(skip_all, fields(collection = %collection))]
 pub async fn handle_search_docs(
     collection: String,
+    tenant: Tenant,
+    query: schema::SearchScoreQuery,
     req: schema::SearchDocsRequest,
     db: DatabaseConnection,
+    model_config: ModelConfig,
 ) -> Result<impl warp::Reply, warp::Rejection> {
+    let collection = tenant.collection(&collection)?;
     let time = std::time::Instant::now();
-    let (_handle, embedder) = SentenceEmbedder::spawn(&ModelConfig::default());
-    let vector_uri = std::env::var("VECTOR_CONNECTION").expect("VECTOR_CONNECTION env var not set");
-    let client = match get_vector_storage(&vector_uri, &collection).await {
-        Ok(client) => client,
-        Err(err) => {
-            return Err(warp::reject::custom(ServerError::Other(format!(
-                "Unable to connect to vector db: {err}"
-            ))))
+
+    let cache_key = search_cache().map(|_| {
+        let options = format!(
+            "{}:{:?}:{:?}:{}:{:?}:{:?}:{}:{}:{:?}:{}",
+            req.limit,
+            req.diversity,
+            req.snippet_length,
+            req.highlight,
+            req.context_window,
+            req.scoring,
+            req.collapse_overlapping,
+            req.diagnostics,
+            query.score,
+            query.include_vector
+        );
+        SearchKey::new(&collection, &req.query, options)
+    });
+    if let Some((cache, key)) = search_cache().zip(cache_key.as_ref()) {
+        if let Some(result) = cache.get(key) {
+            return Ok(warp::reply::json(&ApiResponse::success(
+                time.elapsed(),
+                Some(result),
+            )));
+        }
+    }
+
+    let model_config = collection_model_config(&db, &collection, model_config).await?;
+    let embedder = query_embedder(&model_config);
+    let client = collection_storage(&db, &collection, model_config.model().dimension()).await?;
+
+    let vector = match embedder.encode_query(req.query.clone()).await {
+        Ok(Some(vector)) => vector,
+        _ => {
+            return Err(warp::reject::custom(ServerError::BadRequest(
+                "Invalid query".into(),
+            )))
         }
     };
 
-    let vector = match embedder.encode_single(req.query).await {
+    let modifier = score_modifier(req.scoring.as_ref())?;
+    if req.context_window.unwrap_or_default() > MAX_CONTEXT_WINDOW {
+        return Err(warp::reject::custom(ServerError::BadRequest(format!(
+            "context_window must be at most {MAX_CONTEXT_WINDOW}"
+        ))));
+    }
+    let segments = search_segments(
+        &db,
+        &client,
+        &vector.vector,
+        req.limit,
+        req.diversity,
+        modifier.as_deref(),
+    )
+    .await?;
+    let segments = match req.collapse_overlapping {
+        true => collapse_overlapping(segments),
+        false => segments.into_iter().map(|seg| (seg, Vec::new())).collect(),
+    };
+    let mut results = Vec::new();
+    let mut scores = Vec::new();
+    for ((internal_id, score, segment), merged) in segments {
+        let context = match req.context_window {
+            Some(window) if window > 0 => Some(
+                embedding::segment_context(&db, &segment.document_id, segment.segment, window)
+                    .await
+                    .map_err(ServerError::DatabaseError)?
+                    .into_iter()
+                    .map(|seg| schema::ContextSegment {
+                        segment: seg.segment,
+                        content: seg.content,
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        };
+
+        let (offset_start, offset_end) = segment.offsets().unzip();
+        let content = match req.snippet_length {
+            Some(max_len) => snippet(&segment.content, &req.query, max_len),
+            None => segment.content,
+        };
+
+        scores.push(score);
+        results.push(DocumentSegment {
+            _id: internal_id,
+            document_id: segment.document_id,
+            segment: segment.segment,
+            offset_start,
+            offset_end,
+            content,
+            score: query.score.convert(score),
+            highlights: None,
+            context,
+            merged: (!merged.is_empty()).then_some(merged),
+        });
+    }
+    if req.highlight {
+        let contents: Vec<_> = results
+            .iter()
+            .zip(scores)
+            .map(|(result, score)| (result.content.as_str(), score))
+            .collect();
+        let highlights = highlight(&embedder, &vector.vector, &contents).await?;
+        for (result, highlights) in results.iter_mut().zip(highlights) {
+            result.highlights = Some(highlights);
+        }
+    }
+
+    let mut result = schema::SearchResult::new(results);
+    if needs_diagnostics(&req, result.results.len()) {
+        result.diagnostics =
+            Some(search_diagnostics(&db, &client, &collection, &vector.vector, req.limit).await?);
+    }
+    if query.include_vector {
+        result.vector = Some(vector.vector);
+    }
+    let result = serde_json::to_value(result).map_err(|err| ServerError::Other(err.to_string()))?;
+    if let Some((cache, key)) = search_cache().zip(cache_key) {
+        cache.insert(key, result.clone());
+    }
+    Ok(warp::reply::json(&ApiResponse::success(
+        time.elapsed(),
+        Some(result),
+    )))
+}
+
+/// Answer a question w/ the segments that best match it as context, as many as
+/// fit in the LLM's context window.
+#[tracing::instrument(skip_all, fields(collection = %collection))]
+pub async fn handle_ask_collection(
+    collection: String,
+    tenant: Tenant,
+    req: schema::AskCollectionRequest,
+    db: DatabaseConnection,
+    model_config: ModelConfig,
+    llm: Arc<Box<dyn LLM>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collection = tenant.collection(&collection)?;
+    let time = std::time::Instant::now();
+    if req.limit == 0 || req.limit > MAX_ASK_SEGMENTS {
+        return Err(warp::reject::custom(ServerError::BadRequest(format!(
+            "limit must be between 1 and {MAX_ASK_SEGMENTS}"
+        ))));
+    }
+    check_stop(&req.stop)?;
+
+    let model_config = collection_model_config(&db, &collection, model_config).await?;
+    let embedder = query_embedder(&model_config);
+    let client = collection_storage(&db, &collection, model_config.model().dimension()).await?;
+
+    let vector = match embedder.encode_query(req.query.clone()).await {
         Ok(Some(vector)) => vector,
         _ => {
-            return Err(warp::reject::custom(ServerError::Other(
+            return Err(warp::reject::custom(ServerError::BadRequest(
                 "Invalid query".into(),
             )))
         }
     };
+    let segments = search_segments(&db, &client, &vector.vector, req.limit, None, None).await?;
 
-    let search_result = match client.search(&vector.vector, req.limit as usize).await {
-        Ok(result) => result,
-        Err(err) => return Err(warp::reject::custom(ServerError::Other(err.to_string()))),
+    let model = llm.default_model().to_string();
+    let max_response_tokens = req.max_tokens.unwrap_or(DEFAULT_MAX_RESPONSE_TOKENS);
+    let budget = context_budget(
+        llm.as_ref().as_ref(),
+        &model,
+        &prompter::rag("", &req.query).map_err(ServerError::from)?,
+        max_response_tokens,
+    );
+    let contents = segments
+        .iter()
+        .map(|(_, _, segment)| segment.content.clone())
+        .collect::<Vec<_>>();
+    let (context, used) = fit_context(&contents, budget, |text| llm.count_tokens(text));
+    log::debug!(
+        "using {} of {} segments as context ({budget} token budget)",
+        used.len(),
+        segments.len()
+    );
+
+    let options = ChatCompletionOptions {
+        max_tokens: Some(max_response_tokens),
+        temperature: req.temperature,
+        preset: req.preset,
+        stop: req.stop,
     };
+    let prompt = prompter::rag(&context, &req.query).map_err(ServerError::from)?;
+    let answer = llm
+        .chat_completion(&model, &prompt, &options)
+        .await
+        .map_err(|err| ServerError::Upstream(err.to_string()))?;
+
+    // Sources have the content the LLM actually saw, which is cut short for
+    // the last one if only part of it fit.
+    let sources = segments
+        .into_iter()
+        .zip(used)
+        .map(|((internal_id, score, segment), content)| {
+            let (offset_start, offset_end) = segment.offsets().unzip();
+            DocumentSegment {
+                _id: internal_id,
+                document_id: segment.document_id,
+                segment: segment.segment,
+                offset_start,
+                offset_end,
+                content,
+                score,
+                highlights: None,
+                context: None,
+                merged: None,
+            }
+        })
+        .collect();
+
+    Ok(warp::reply::json(&ApiResponse::success(
+        time.elapsed(),
+        Some(schema::AskCollectionResult {
+            answer: answer.trim().to_string(),
+            model,
+            sources,
+        }),
+    )))
+}
+
+#[tracing::instrument(skip_all, fields(collection = %collection))]
+pub async fn handle_search_vector(
+    collection: String,
+    tenant: Tenant,
+    query: schema::SearchScoreQuery,
+    req: schema::SearchVectorRequest,
+    db: DatabaseConnection,
+    model_config: ModelConfig,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collection = tenant.collection(&collection)?;
+    let time = std::time::Instant::now();
+    let dimension = collection_model_config(&db, &collection, model_config)
+        .await?
+        .model()
+        .dimension();
+
+    if req.vector.len() != dimension {
+        return Err(warp::reject::custom(ServerError::BadRequest(format!(
+            "Query vector has {} dimensions, the collection uses {dimension}",
+            req.vector.len()
+        ))));
+    }
+    if req.vector.iter().any(|x| !x.is_finite()) {
+        return Err(warp::reject::custom(ServerError::BadRequest(
+            "Query vector contains NaN or infinite values".into(),
+        )));
+    }
+
+    let modifier = score_modifier(req.scoring.as_ref())?;
+    let client = collection_storage(&db, &collection, dimension).await?;
+    let segments = search_segments(
+        &db,
+        &client,
+        &req.vector,
+        req.limit,
+        req.diversity,
+        modifier.as_deref(),
+    )
+    .await?;
+    let results = segments
+        .into_iter()
+        .map(|(internal_id, score, segment)| {
+            let (offset_start, offset_end) = segment.offsets().unzip();
+            DocumentSegment {
+                _id: internal_id,
+                document_id: segment.document_id,
+                segment: segment.segment,
+                offset_start,
+                offset_end,
+                content: segment.content,
+                score: query.score.convert(score),
+                highlights: None,
+                context: None,
+                merged: None,
+            }
+        })
+        .collect();
+
+    Ok(warp::reply::json(&ApiResponse::success(
+        time.elapsed(),
+        Some(schema::SearchResult::new(results)),
+    )))
+}
+
+#[tracing::instrument(skip_all, fields(collection = %collection, queries = req.queries.len()))]
+pub async fn handle_search_batch(
+    collection: String,
+    tenant: Tenant,
+    query: schema::SearchScoreQuery,
+    req: schema::SearchBatchRequest,
+    db: DatabaseConnection,
+    model_config: ModelConfig,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collection = tenant.collection(&collection)?;
+    let time = std::time::Instant::now();
+    if req.queries.is_empty() || req.queries.len() > MAX_BATCH_QUERIES {
+        return Err(warp::reject::custom(ServerError::BadRequest(format!(
+            "queries must have between 1 and {MAX_BATCH_QUERIES} queries"
+        ))));
+    }
+    if req.queries.iter().any(|query| query.trim().is_empty()) {
+        return Err(warp::reject::custom(ServerError::BadRequest(
+            "Invalid query".into(),
+        )));
+    }
+    let modifier = score_modifier(req.scoring.as_ref())?;
+
+    let model_config = collection_model_config(&db, &collection, model_config).await?;
+    let embedder = query_embedder(&model_config);
+    let client = collection_storage(&db, &collection, model_config.model().dimension()).await?;
+
+    let vectors = embedder
+        .encode_batch(req.queries.clone())
+        .await
+        .map_err(|err| ServerError::Other(format!("Unable to embed queries: {err}")))?;
+    if vectors.len() != req.queries.len() {
+        return Err(warp::reject::custom(ServerError::Other(
+            "# of embeddings doesn't match # of queries".into(),
+        )));
+    }
 
-    // Grab the document data for each search result
     let mut results = Vec::new();
-    for (internal_id, score) in search_result.iter() {
+    for vector in vectors {
+        let segments = search_segments(
+            &db,
+            &client,
+            &vector.vector,
+            req.limit,
+            req.diversity,
+            modifier.as_deref(),
+        )
+        .await?;
+
+        let segments = segments
+            .into_iter()
+            .map(|(internal_id, score, segment)| {
+                let (offset_start, offset_end) = segment.offsets().unzip();
+                DocumentSegment {
+                    _id: internal_id,
+                    document_id: segment.document_id,
+                    segment: segment.segment,
+                    offset_start,
+                    offset_end,
+                    content: segment.content,
+                    score: query.score.convert(score),
+                    highlights: None,
+                    context: None,
+                    merged: None,
+                }
+            })
+            .collect();
+
+        let mut result = schema::SearchResult::new(segments);
+        if query.include_vector {
+            result.vector = Some(vector.vector);
+        }
+        results.push(result);
+    }
+
+    Ok(warp::reply::json(&ApiResponse::success(
+        time.elapsed(),
+        Some(schema::SearchBatchResult { results }),
+    )))
+}
+
+/// Build the score modifier requested for a search, if any.
+fn score_modifier(
+    scoring: Option<&schema::ScoringRequest>,
+) -> Result<Option<Box<dyn ScoreModifier>>, ServerError> {
+    let Some(scoring) = scoring else {
+        return Ok(None);
+    };
+
+    match scoring {
+        schema::ScoringRequest::Recency { half_life, weight } => {
+            let half_life = parse_duration(half_life)
+                .filter(|duration| duration.num_seconds() > 0)
+                .ok_or_else(|| ServerError::BadRequest(format!("Invalid halfLife: {half_life}")))?;
+            if !(0.0..=1.0).contains(weight) {
+                return Err(ServerError::BadRequest(
+                    "weight must be between 0 and 1".into(),
+                ));
+            }
+
+            Ok(Some(Box::new(RecencyBoost::new(half_life, *weight))))
+        }
+    }
+}
+
+/// Drop segments that mostly overlap w/ a better scoring, adjacent segment from
+/// the same document, noting the segment #s merged into each one that's kept.
+/// Expects segments ordered by score.
+fn collapse_overlapping(
+    segments: Vec<(String, f32, embedding::Model)>,
+) -> Vec<((String, f32, embedding::Model), Vec<i64>)> {
+    let mut kept: Vec<((String, f32, embedding::Model), Vec<i64>)> = Vec::new();
+    for candidate in segments {
+        let (_, _, segment) = &candidate;
+        let overlapping = kept.iter_mut().find(|((_, _, other), _)| {
+            if other.document_id != segment.document_id {
+                return false;
+            }
+            let overlap = match segment.segment - other.segment {
+                1 => segment_overlap(&other.content, &segment.content),
+                -1 => segment_overlap(&segment.content, &other.content),
+                _ => return false,
+            };
+            overlap >= MIN_COLLAPSE_OVERLAP
+        });
+
+        match overlapping {
+            Some((_, merged)) => merged.push(segment.segment),
+            None => kept.push((candidate, Vec::new())),
+        }
+    }
+    kept
+}
+
+/// Find the segments nearest to a query vector w/ their similarity scores,
+/// reweighted by `modifier` & re-ranked w/ MMR if `diversity` is set.
+async fn search_segments(
+    db: &DatabaseConnection,
+    client: &VectorStorage,
+    vector: &[f32],
+    limit: u64,
+    diversity: Option<f32>,
+    modifier: Option<&dyn ScoreModifier>,
+) -> Result<Vec<(String, f32, embedding::Model)>, ServerError> {
+    if let Some(diversity) = diversity {
+        if !(0.0..=1.0).contains(&diversity) {
+            return Err(ServerError::BadRequest(
+                "diversity must be between 0 and 1".into(),
+            ));
+        }
+    }
+    // MMR & score modifiers need extra candidates to pick from.
+    let num_candidates = match diversity.is_some() || modifier.is_some() {
+        true => limit.saturating_mul(MMR_CANDIDATE_FACTOR),
+        false => limit,
+    };
+
+    let search_result = client
+        .search(vector, num_candidates as usize)
+        .await
+        .map_err(|err| ServerError::VectorStore(err.to_string()))?;
+
+    // Grab the document data for each search result
+    let mut segments = Vec::new();
+    for (internal_id, score) in search_result {
         if let Ok(Some(segment)) = embedding::Entity::find()
-            .filter(embedding::Column::Uuid.eq(internal_id))
-            .one(&db)
+            .filter(embedding::Column::Uuid.eq(&internal_id))
+            .one(db)
             .await
         {
-            results.push(DocumentSegment {
-                _id: internal_id.to_string(),
-                document_id: segment.document_id,
+            segments.push((internal_id, score, segment));
+        }
+    }
+
+    if let Some(modifier) = modifier {
+        let ids: HashSet<String> = segments
+            .iter()
+            .map(|(_, _, segment)| segment.document_id.clone())
+            .collect();
+        let documents: HashMap<String, document::Model> = document::Entity::find()
+            .filter(document::Column::Uuid.is_in(ids))
+            .all(db)
+            .await?
+            .into_iter()
+            .map(|doc| (doc.uuid.clone(), doc))
+            .collect();
+
+        let now = chrono::Utc::now();
+        for (_, score, segment) in segments.iter_mut() {
+            if let Some(doc) = documents.get(&segment.document_id) {
+                *score = modifier.modify(*score, doc, now);
+            }
+        }
+        segments.sort_by(|a, b| b.1.total_cmp(&a.1));
+    }
+
+    if let Some(diversity) = diversity {
+        // Vectors are stored alongside each segment, so there's no need to
+        // get them back from the vector store.
+        let candidates: Vec<(f32, Vec<f32>)> = segments
+            .iter()
+            .map(|(_, score, segment)| {
+                let vector = serde_json::from_value(segment.vector.clone()).unwrap_or_default();
+                (*score, vector)
+            })
+            .collect();
+
+        let order = mmr_rerank(&candidates, 1.0 - diversity, limit as usize);
+        let mut picked: Vec<_> = segments.into_iter().map(Some).collect();
+        segments = order
+            .into_iter()
+            .filter_map(|idx| picked[idx].take())
+            .collect();
+    }
+
+    segments.truncate(limit as usize);
+    Ok(segments)
+}
+
+/// Diagnostics are only worth the extra queries when asked for & there are
+/// fewer results than `limit`.
+fn needs_diagnostics(req: &schema::SearchDocsRequest, results: usize) -> bool {
+    req.diagnostics && (results as u64) < req.limit
+}
+
+/// Look into why a search came back w/ fewer than `limit` results, i.e. whether
+/// there's anything to find at all or the results were filtered out.
+async fn search_diagnostics(
+    db: &DatabaseConnection,
+    client: &VectorStorage,
+    collection: &str,
+    vector: &[f32],
+    limit: u64,
+) -> Result<schema::SearchDiagnostics, ServerError> {
+    let documents = document::Entity::find()
+        .inner_join(queue::Entity)
+        .filter(queue::Column::Collection.eq(collection))
+        .count(db)
+        .await?;
+    let neighbors = client
+        .search(vector, limit as usize)
+        .await
+        .map_err(|err| ServerError::VectorStore(err.to_string()))?;
+
+    Ok(schema::SearchDiagnostics {
+        documents,
+        neighbors: neighbors.len(),
+        top_score: neighbors.iter().map(|(_, score)| *score).reduce(f32::max),
+    })
+}
+
+/// Find the sentence in each of `contents` most similar to the query, w/ the
+/// sentences of every result embedded in one batch. A result's score is used
+/// as-is if it's only the one sentence.
+async fn highlight(
+    embedder: &SentenceEmbedder,
+    query: &[f32],
+    contents: &[(&str, f32)],
+) -> Result<Vec<Vec<schema::Highlight>>, ServerError> {
+    let spans: Vec<_> = contents
+        .iter()
+        .map(|(content, _)| sentences(content))
+        .collect();
+    // Sentences are embedded like the segments they came from.
+    let sentences: Vec<String> = contents
+        .iter()
+        .zip(&spans)
+        .filter(|(_, spans)| spans.len() > 1)
+        .flat_map(|((content, _), spans)| {
+            spans
+                .iter()
+                .map(|(start, end)| content[*start..*end].to_string())
+        })
+        .collect();
+    let num_sentences = sentences.len();
+    let embeddings = embedder
+        .encode_chunks(sentences)
+        .await
+        .map_err(|err| ServerError::Other(format!("Unable to embed sentences: {err}")))?;
+    if embeddings.len() != num_sentences {
+        return Err(ServerError::Other(
+            "# of embeddings doesn't match # of sentences".into(),
+        ));
+    }
+
+    let mut embeddings = embeddings.into_iter();
+    let mut highlights = Vec::new();
+    for ((content, score), spans) in contents.iter().zip(spans) {
+        let mut best: Option<(usize, usize, f32)> = None;
+        for (start, end) in spans.iter().copied() {
+            let sentence_score = if spans.len() == 1 {
+                *score
+            } else {
+                let Some(embedding) = embeddings.next() else {
+                    continue;
+                };
+                similarity_from_distance(1.0 - cosine_similarity(query, &embedding.vector))
+            };
+
+            match best {
+                Some((_, _, best_score)) if best_score >= sentence_score => {}
+                _ => best = Some((start, end, sentence_score)),
+            }
+        }
+
+        highlights.push(
+            best.map(|(start, end, score)| schema::Highlight {
+                text: content[start..end].to_string(),
+                start: content[..start].chars().count(),
+                end: content[..end].chars().count(),
+                score,
+            })
+            .into_iter()
+            .collect(),
+        );
+    }
+
+    Ok(highlights)
+}
+
+/// Byte ranges of the sentences in `content`, w/o surrounding whitespace.
+fn sentences(content: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut start = 0;
+    let mut chars = content.char_indices().peekable();
+    while let Some((idx, c)) = chars.next() {
+        let at_break = match c {
+            '\n' => true,
+            '.' | '!' | '?' => chars
+                .peek()
+                .map(|(_, next)| next.is_whitespace())
+                .unwrap_or(true),
+            _ => false,
+        };
+
+        if at_break {
+            let end = idx + c.len_utf8();
+            spans.push((start, end));
+            start = end;
+        }
+    }
+    spans.push((start, content.len()));
+
+    spans
+        .into_iter()
+        .filter_map(|(start, end)| {
+            let text = &content[start..end];
+            let trimmed = text.trim();
+            if trimmed.is_empty() {
+                return None;
+            }
+
+            let start = start + (text.len() - text.trim_start().len());
+            Some((start, start + trimmed.len()))
+        })
+        .collect()
+}
+
+/// Cut `content` down to roughly `max_len` characters around the first query
+/// term it contains (or its start), adding an ellipsis wherever it was cut.
+fn snippet(content: &str, query: &str, max_len: usize) -> String {
+    let chars: Vec<char> = content.chars().collect();
+    if chars.len() <= max_len {
+        return content.to_string();
+    }
+
+    let lower: Vec<char> = chars.iter().map(|c| c.to_ascii_lowercase()).collect();
+    let first_match = query
+        .split_whitespace()
+        .map(|term| {
+            term.trim_matches(|c: char| !c.is_alphanumeric())
+                .to_ascii_lowercase()
+                .chars()
+                .collect::<Vec<_>>()
+        })
+        // Skip short words, they'll match almost anywhere.
+        .filter(|term| term.len() > 2)
+        .filter_map(|term| lower.windows(term.len()).position(|window| window == term))
+        .min()
+        .unwrap_or_default();
+
+    // Center the window on the match, keeping it inside the content.
+    let mut start = first_match
+        .saturating_sub(max_len / 2)
+        .min(chars.len() - max_len);
+    let mut end = start + max_len;
+
+    // Avoid cutting words in half if there's a nearby space.
+    let slack = max_len / 5;
+    if start > 0 {
+        if let Some(offset) = chars[start..start + slack]
+            .iter()
+            .position(|c| c.is_whitespace())
+        {
+            start += offset + 1;
+        }
+    }
+    if end < chars.len() {
+        if let Some(offset) = chars[end - slack..end]
+            .iter()
+            .rposition(|c| c.is_whitespace())
+        {
+            end = end - slack + offset;
+        }
+    }
+
+    let mut snippet: String = chars[start..end].iter().collect();
+    snippet = snippet.trim().to_string();
+    if start > 0 {
+        snippet.insert(0, '…');
+    }
+    if end < chars.len() {
+        snippet.push('…');
+    }
+
+    snippet
+}
+
+#[tracing::instrument(skip_all, fields(collection = %collection))]
+pub async fn handle_search_count(
+    collection: String,
+    tenant: Tenant,
+    req: schema::SearchCountRequest,
+    db: DatabaseConnection,
+    model_config: ModelConfig,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collection = tenant.collection(&collection)?;
+    let time = std::time::Instant::now();
+    if !(1..=MAX_SEARCH_COUNT).contains(&req.limit) {
+        return Err(warp::reject::custom(ServerError::BadRequest(format!(
+            "limit must be between 1 and {MAX_SEARCH_COUNT}"
+        ))));
+    }
+    let model_config = collection_model_config(&db, &collection, model_config).await?;
+    let embedder = query_embedder(&model_config);
+    let client = collection_storage(&db, &collection, model_config.model().dimension()).await?;
+
+    let vector = match embedder.encode_query(req.query).await {
+        Ok(Some(vector)) => vector,
+        _ => {
+            return Err(warp::reject::custom(ServerError::BadRequest(
+                "Invalid query".into(),
+            )))
+        }
+    };
+
+    let search_result = match client.search(&vector.vector, req.limit as usize).await {
+        Ok(result) => result,
+        Err(err) => {
+            return Err(warp::reject::custom(ServerError::VectorStore(
+                err.to_string(),
+            )))
+        }
+    };
+
+    let matches: Vec<String> = search_result
+        .into_iter()
+        .filter(|(_, score)| *score >= req.min_score)
+        .map(|(id, _)| id)
+        .collect();
+    // Only the document ids are needed, not the segments themselves.
+    let mut documents = HashSet::new();
+    for page in matches.chunks(ID_PAGE_SIZE as usize) {
+        let ids: Vec<String> = embedding::Entity::find()
+            .select_only()
+            .column(embedding::Column::DocumentId)
+            .filter(embedding::Column::Uuid.is_in(page.to_vec()))
+            .into_tuple()
+            .all(&db)
+            .await
+            .map_err(ServerError::DatabaseError)?;
+        documents.extend(ids);
+    }
+
+    Ok(warp::reply::json(&ApiResponse::success(
+        time.elapsed(),
+        Some(schema::SearchCountResult {
+            count: documents.len(),
+            segments: matches.len(),
+            truncated: matches.len() as u64 == req.limit,
+        }),
+    )))
+}
+
+pub async fn handle_export(
+    collection: String,
+    tenant: Tenant,
+    db: DatabaseConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collection = tenant.collection(&collection)?;
+    // Stream the collection a page at a time so memory usage stays bounded.
+    let lines = futures_util::stream::try_unfold(Some(0), move |last_id| {
+        let db = db.clone();
+        let collection = collection.clone();
+        async move {
+            match last_id {
+                Some(last_id) => export_page(&db, &collection, last_id).await,
+                None => Ok(None),
+            }
+        }
+    });
+
+    warp::http::Response::builder()
+        .header(warp::http::header::CONTENT_TYPE, "application/x-ndjson")
+        .body(Body::wrap_stream(lines))
+        .map_err(|err| warp::reject::custom(ServerError::Other(err.to_string())))
+}
+
+/// Serialize the next page of documents after `last_id` as JSONL.
+async fn export_page(
+    db: &DatabaseConnection,
+    collection: &str,
+    last_id: i64,
+) -> Result<Option<(String, Option<i64>)>, ServerError> {
+    let page = document::Entity::find()
+        .inner_join(queue::Entity)
+        .filter(queue::Column::Collection.eq(collection))
+        .filter(document::Column::Id.gt(last_id))
+        .order_by_asc(document::Column::Id)
+        .limit(EXPORT_PAGE_SIZE)
+        .all(db)
+        .await?;
+
+    let Some(next_id) = page.last().map(|doc| doc.id) else {
+        return Ok(None);
+    };
+
+    let uuids = page.iter().map(|doc| doc.uuid.clone()).collect::<Vec<_>>();
+    let mut segments: HashMap<String, Vec<schema::ExportedSegment>> = HashMap::new();
+    for segment in embedding::Entity::find()
+        .filter(embedding::Column::DocumentId.is_in(uuids))
+        .order_by_asc(embedding::Column::Segment)
+        .all(db)
+        .await?
+    {
+        let (offset_start, offset_end) = segment.offsets().unzip();
+        segments
+            .entry(segment.document_id)
+            .or_default()
+            .push(schema::ExportedSegment {
+                uuid: Some(segment.uuid),
                 segment: segment.segment,
                 content: segment.content,
-                score: *score,
+                vector: serde_json::from_value(segment.vector).ok(),
+                offset_start,
+                offset_end,
             });
+    }
+
+    let mut lines = String::new();
+    for doc in page {
+        let exported = schema::ExportedDocument {
+            segments: segments.remove(&doc.uuid).unwrap_or_default(),
+            uuid: Some(doc.uuid),
+            content: doc.content,
+            metadata: doc.metadata,
+        };
+
+        let line = serde_json::to_string(&exported)
+            .map_err(|err| ServerError::Other(format!("Unable to serialize document: {err}")))?;
+        lines.push_str(&line);
+        lines.push('\n');
+    }
+
+    Ok(Some((lines, Some(next_id))))
+}
+
+/// An import's options & its JSONL body.
+pub struct ImportBody<S> {
+    pub req: schema::ImportRequest,
+    pub body: S,
+}
+
+#[tracing::instrument(skip_all, fields(collection = %collection))]
+pub async fn handle_import<S, B>(
+    collection: String,
+    tenant: Tenant,
+    import: ImportBody<S>,
+    db: DatabaseConnection,
+    model_config: ModelConfig,
+    ingest_config: IngestConfig,
+    trace_id: String,
+) -> Result<impl warp::Reply, warp::Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send,
+    B: Buf,
+{
+    let ImportBody { req, body } = import;
+    let collection = tenant.new_collection(&db, &collection).await?;
+    let time = std::time::Instant::now();
+    // Importing restores a collection, so it's always created if needed.
+    db::collection::create_default(&db, &collection)
+        .await
+        .map_err(ServerError::DatabaseError)?;
+    let model_config = collection_model_config(&db, &collection, model_config).await?;
+    let client = collection_storage(&db, &collection, model_config.model().dimension()).await?;
+    let importer = Importer {
+        db: &db,
+        client: &client,
+        collection: &collection,
+        req: &req,
+        trace_id: &trace_id,
+        max_chars: ingest_config.max_document_chars,
+    };
+
+    let mut result = schema::ImportResult {
+        imported: 0,
+        queued: 0,
+    };
+
+    // Process the body line by line as it comes in.
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut body = Box::pin(body);
+    while let Some(mut chunk) = body
+        .try_next()
+        .await
+        .map_err(|err| ServerError::BadRequest(format!("Unable to read body: {err}")))?
+    {
+        while chunk.has_remaining() {
+            let bytes = chunk.chunk();
+            buffer.extend_from_slice(bytes);
+            let len = bytes.len();
+            chunk.advance(len);
+        }
+
+        while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+            let line = buffer.drain(..=end).collect::<Vec<_>>();
+            importer.import(&line, &mut result).await?;
+        }
+
+        if buffer.len() as u64 > LIMIT_10_MB {
+            return Err(warp::reject::custom(ServerError::BadRequest(
+                "Document too large".into(),
+            )));
         }
     }
 
-    let result = schema::SearchResult { results };
+    // Last line may not have a trailing newline
+    importer.import(&buffer, &mut result).await?;
+
+    invalidate_search_cache(&collection);
+    log::info!(
+        "[trace={trace_id}] imported {} documents into <{collection}>, {} queued for embedding",
+        result.imported,
+        result.queued
+    );
     Ok(warp::reply::json(&ApiResponse::success(
         time.elapsed(),
         Some(result),
     )))
 }
+
+/// Where & how the documents of an import are added.
+struct Importer<'a> {
+    db: &'a DatabaseConnection,
+    client: &'a VectorStorage,
+    collection: &'a str,
+    req: &'a schema::ImportRequest,
+    trace_id: &'a str,
+    max_chars: usize,
+}
+
+impl Importer<'_> {
+    /// Import a single JSONL line, using the exported vectors when available.
+    async fn import(
+        &self,
+        line: &[u8],
+        result: &mut schema::ImportResult,
+    ) -> Result<(), ServerError> {
+        let Importer {
+            db,
+            client,
+            collection,
+            req,
+            trace_id,
+            max_chars,
+        } = *self;
+        if line.iter().all(|byte| byte.is_ascii_whitespace()) {
+            return Ok(());
+        }
+
+        let mut doc: schema::ExportedDocument = serde_json::from_slice(line)
+            .map_err(|err| ServerError::BadRequest(format!("Invalid document: {err}")))?;
+        let num_chars = doc.content.chars().count();
+        if num_chars > max_chars {
+            return Err(too_large(num_chars, max_chars));
+        }
+
+        // Exported from a collection that doesn't keep full content. These can
+        // only be imported w/ their vectors & are keyed by their original UUID.
+        let has_content = !doc.content.trim().is_empty();
+        let payload = queue::TaskPayload {
+            content: doc.content,
+            metadata: doc.metadata,
+            document_id: if has_content { None } else { doc.uuid },
+            ..Default::default()
+        };
+
+        let has_vectors =
+            !doc.segments.is_empty() && doc.segments.iter().all(|seg| seg.vector.is_some());
+        if req.reembed || !has_vectors {
+            if !has_content {
+                return Err(ServerError::BadRequest(
+                    "Documents w/o their full content can't be re-embedded".into(),
+                ));
+            }
+            queue::enqueue_payload(
+                db,
+                collection,
+                payload,
+                queue::TaskType::Ingest,
+                Some(trace_id),
+                None,
+            )
+            .await?;
+            result.queued += 1;
+            return Ok(());
+        }
+
+        doc.segments.sort_by_key(|seg| seg.segment);
+        let embeddings = doc
+            .segments
+            .into_iter()
+            .map(|seg| {
+                let offsets = seg.offset_start.zip(seg.offset_end);
+                EmbeddingResult::new(seg.content, seg.vector.unwrap_or_default(), false)
+                    .map(|result| result.with_offsets(offsets))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| ServerError::BadRequest(err.to_string()))?;
+
+        // Nothing left to process, add as a completed task.
+        let mut task = queue::ActiveModel::new();
+        task.collection = Set(collection.to_string());
+        task.task_type = Set(queue::TaskType::Ingest);
+        task.status = Set(queue::JobStatus::Completed);
+        task.payload = Set(payload);
+        task.trace_id = Set(Some(trace_id.to_string()));
+        let task = task.insert(db).await?;
+
+        let document = document::upsert_from_task(db, &task).await?;
+
+        embedding::persist_embeddings(db, client, &document, &embeddings)
+            .await
+            .map_err(|err| ServerError::Other(format!("Unable to save embeddings: {err}")))?;
+        result.imported += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        check_segments, handle_add_document, handle_delete_documents, needs_diagnostics,
+        parse_duration, snippet, split_content, AddDocumentOptions, MAX_INGEST_SEGMENTS,
+        MAX_SPLIT_PARTS,
+    };
+    use crate::{endpoints::IngestConfig, schema::SearchDocsRequest, tenant::Tenant, ServerError};
+    use libmemex::{
+        db::{collection, create_connection_by_uri},
+        llm::embedding::ModelConfig,
+    };
+
+    fn count_words(text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+
+    #[test]
+    fn test_check_segments() {
+        let segments = vec!["one two".to_string(), "three four five".to_string()];
+        assert!(check_segments(&segments, 100, 3, count_words).is_ok());
+
+        // Too long for the model
+        let err = check_segments(&segments, 100, 2, count_words).unwrap_err();
+        assert!(
+            matches!(err, ServerError::BadRequest(msg) if msg.starts_with("Segment 1 is 3 tokens"))
+        );
+        // Too long overall
+        let err = check_segments(&segments, 10, 3, count_words).unwrap_err();
+        assert!(matches!(err, ServerError::PayloadTooLarge(_)));
+
+        let too_many = vec!["one".to_string(); MAX_INGEST_SEGMENTS + 1];
+        for invalid in [vec![], vec!["one".into(), " \n".into()], too_many] {
+            let err = check_segments(&invalid, usize::MAX, 3, count_words).unwrap_err();
+            assert!(matches!(err, ServerError::BadRequest(_)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_document_rejected() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .unwrap();
+        let req = serde_json::from_value(serde_json::json!({ "content": " " })).unwrap();
+        let options = AddDocumentOptions {
+            idempotency_key: None,
+            wait: Default::default(),
+        };
+
+        let res = handle_add_document(
+            "docs".into(),
+            Tenant::default(),
+            req,
+            db.clone(),
+            IngestConfig::default(),
+            "trace".into(),
+            options,
+        )
+        .await;
+        assert!(res.is_err());
+        // Invalid requests shouldn't leave an empty collection behind
+        assert!(!collection::exists(&db, "docs").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_delete_documents_zero_duration() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .unwrap();
+        for older_than in ["0s", "0d"] {
+            let req =
+                serde_json::from_value(serde_json::json!({ "older_than": older_than })).unwrap();
+            let res = handle_delete_documents(
+                "docs".into(),
+                Tenant::default(),
+                req,
+                db.clone(),
+                ModelConfig::default(),
+            )
+            .await;
+            assert!(res.is_err(), "{older_than}");
+        }
+    }
+
+    #[test]
+    fn test_needs_diagnostics() {
+        let req: SearchDocsRequest = serde_json::from_str(r#"{ "query": "test" }"#).unwrap();
+        assert!(!req.diagnostics);
+        assert!(!needs_diagnostics(&req, 0));
+
+        let req: SearchDocsRequest =
+            serde_json::from_str(r#"{ "query": "test", "limit": 2, "diagnostics": true }"#)
+                .unwrap();
+        assert!(needs_diagnostics(&req, 1));
+        assert!(!needs_diagnostics(&req, 2));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s"), Some(chrono::Duration::seconds(30)));
+        assert_eq!(parse_duration("15m"), Some(chrono::Duration::minutes(15)));
+        assert_eq!(parse_duration(" 12h "), Some(chrono::Duration::hours(12)));
+        assert_eq!(parse_duration("7d"), Some(chrono::Duration::days(7)));
+        assert_eq!(parse_duration("2w"), Some(chrono::Duration::weeks(2)));
+
+        for invalid in [
+            "",
+            "d",
+            "30",
+            "5y",
+            "-5d",
+            "1.5h",
+            &format!("{}w", u64::MAX),
+        ] {
+            assert_eq!(parse_duration(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_snippet() {
+        let content = "one two three four five six seven eight nine ten";
+        assert_eq!(snippet("short text", "text", 20), "short text");
+        // W/o a match the snippet starts at the beginning
+        assert_eq!(snippet(content, "xyz", 20), "one two three four…");
+        // Centered on the first match, w/o cutting words in half
+        assert_eq!(snippet(content, "Seven?", 24), "…five six seven eight…");
+        assert_eq!(snippet(content, "is it ten", 20), "…seven eight nine ten");
+
+        // Cut by characters rather than bytes
+        assert_eq!(
+            snippet("héllo wörld ünïcode everywhere", "ünïcode", 10),
+            "…örld ünïco…"
+        );
+    }
+
+    #[test]
+    fn test_split_content() {
+        assert_eq!(split_content("short", 10), vec!["short"]);
+        assert!(split_content("   ", 10).is_empty());
+
+        // Paragraphs first, then words, then anywhere
+        assert_eq!(
+            split_content("aaaa bbbb\n\ncccc dddd", 15),
+            vec!["aaaa bbbb", "cccc dddd"]
+        );
+        assert_eq!(
+            split_content("aaaa bbbb cccc dddd", 12),
+            vec!["aaaa bbbb", "cccc dddd"]
+        );
+        assert_eq!(split_content("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+        // Limits are in characters rather than bytes
+        assert_eq!(split_content("ééééé", 2), vec!["éé", "éé", "é"]);
+
+        // Breaking early never takes more than `MAX_SPLIT_PARTS` parts
+        let content = "abcdef ".repeat(14);
+        let parts = split_content(&content, 10);
+        assert_eq!(parts.len(), MAX_SPLIT_PARTS);
+        assert!(parts.iter().all(|part| part.chars().count() <= 10));
+        assert_eq!(parts.concat().replace(' ', ""), content.replace(' ', ""));
+    }
+}
@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
 use warp::Filter;
 
+use crate::endpoints::{Fetcher, UploadConfig};
+use crate::with_upload_config;
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct FetchRequest {
@@ -8,20 +11,63 @@ pub struct FetchRequest {
     pub url: Option<String>,
 }
 
-fn fetch_url() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+/// Controls how pdftotext orders the extracted text.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum LayoutMode {
+    /// Reading order, the pdftotext default.
+    #[default]
+    Default,
+    /// Maintain the original physical layout, useful for tables/columns.
+    Layout,
+    /// Keep text in content stream order.
+    Raw,
+}
+
+impl LayoutMode {
+    /// Extra pdftotext flags for this mode.
+    pub fn pdftotext_args(&self) -> &'static [&'static str] {
+        match self {
+            LayoutMode::Default => &[],
+            LayoutMode::Layout => &["-layout"],
+            LayoutMode::Raw => &["-raw"],
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ParseRequest {
+    /// Text extraction mode used for PDFs
+    #[serde(default)]
+    pub layout: LayoutMode,
+}
+
+fn fetch_url(
+    fetcher: &Fetcher,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let fetcher = fetcher.clone();
     warp::path!("fetch")
         .and(warp::get())
         .and(warp::query::<FetchRequest>())
+        .and(warp::any().map(move || fetcher.clone()))
         .and_then(super::handlers::handle_fetch)
 }
 
-pub fn parse_file() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+pub fn parse_file(
+    upload_config: &UploadConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path!("fetch" / "parse")
         .and(warp::post())
+        .and(warp::query::<ParseRequest>())
         .and(warp::multipart::form().max_length(50_000_000))
+        .and(with_upload_config(upload_config.clone()))
         .and_then(super::handlers::handle_parse)
 }
 
-pub fn build() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    fetch_url().or(parse_file()).boxed()
+pub fn build(
+    upload_config: &UploadConfig,
+    fetcher: &Fetcher,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    fetch_url(fetcher).or(parse_file(upload_config)).boxed()
 }
@@ -1,35 +1,189 @@
 use crate::{schema::ApiResponse, ServerError};
 use futures_util::TryStreamExt;
-use warp::filters::multipart::FormData;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use std::error::Error as _;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use url::{Host, Url};
+use warp::filters::multipart::{FormData, Part};
+use warp::hyper::client::connect::dns::Name;
 use warp::Buf;
 
 use super::filters;
-use crate::endpoints::UPLOAD_DATA_DIR;
+use crate::endpoints::{FetchConfig, Fetcher, UploadConfig};
 
-// When memex is running inside the docker image.
-#[cfg(not(debug_assertions))]
-const EXE_NAME: &str = "/usr/local/bin/pdftotext";
+/// Reasons a URL can't be fetched.
+#[derive(Clone, Debug, Error)]
+pub enum FetchError {
+    #[error("Only http & https URLs can be fetched")]
+    UnsupportedScheme,
+    #[error("Fetching from {0} isn't allowed")]
+    BlockedHost(String),
+    #[error("Fetching from {0} isn't allowed, it's a private, loopback or link-local address")]
+    InternalAddress(String),
+}
+
+impl From<FetchError> for ServerError {
+    fn from(err: FetchError) -> Self {
+        match err {
+            FetchError::UnsupportedScheme => ServerError::BadRequest(err.to_string()),
+            FetchError::BlockedHost(_) | FetchError::InternalAddress(_) => {
+                ServerError::Forbidden(err.to_string())
+            }
+        }
+    }
+}
+
+/// Client used to fetch URLs, redirects are checked the same way as the
+/// original URL.
+pub fn fetch_client(config: &FetchConfig) -> reqwest::Result<reqwest::Client> {
+    let policy_config = config.clone();
+    let redirects = Policy::custom(move |attempt| {
+        if attempt.previous().len() > policy_config.max_redirects {
+            let max = policy_config.max_redirects;
+            attempt.error(format!("more than {max} redirects"))
+        } else if let Err(err) = check_url(&policy_config, attempt.url()) {
+            attempt.error(err)
+        } else {
+            attempt.follow()
+        }
+    });
+
+    reqwest::Client::builder()
+        .timeout(config.timeout)
+        .redirect(redirects)
+        // A proxy would resolve & connect to the host itself, skipping the
+        // checks below.
+        .no_proxy()
+        .dns_resolver(Arc::new(PublicResolver {
+            internal_hosts: config.internal_hosts.clone(),
+        }))
+        .build()
+}
+
+/// Resolves hosts to their public addresses only, unless they're one of the
+/// `internal_hosts`. Checking the resolved addresses rather than the host
+/// name keeps DNS records (& redirects) from pointing a fetch at an internal
+/// service. Only works as long as the client doesn't go through a proxy.
+struct PublicResolver {
+    internal_hosts: Vec<String>,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_lowercase();
+        let allow_internal = is_internal_host(&self.internal_hosts, &host);
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            let public: Vec<SocketAddr> = addrs
+                .iter()
+                .filter(|addr| allow_internal || !is_internal(addr.ip()))
+                .copied()
+                .collect();
+            if public.is_empty() && !addrs.is_empty() {
+                return Err(FetchError::InternalAddress(host).into());
+            }
+
+            let addrs: Addrs = Box::new(public.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/// Whether `ip` is on a private, loopback, link-local or otherwise internal
+/// network, e.g. the `169.254.169.254` metadata service.
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                // 0.0.0.0/8, "this" network
+                || octets[0] == 0
+                // 100.64.0.0/10, carrier-grade NAT
+                || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+                // 192.0.0.0/24, IETF protocol assignments
+                || octets[..3] == [192, 0, 0]
+                // 198.18.0.0/15, benchmarking
+                || (octets[0] == 198 && octets[1] & 0xfe == 18)
+                // 192.0.2.0/24, 198.51.100.0/24 & 203.0.113.0/24, documentation
+                || octets[..3] == [192, 0, 2]
+                || octets[..3] == [198, 51, 100]
+                || octets[..3] == [203, 0, 113]
+                // 240.0.0.0/4, reserved (incl. broadcast)
+                || octets[0] >= 240
+        }
+        // Both IPv4-mapped (::ffff:a.b.c.d) & IPv4-compatible (::a.b.c.d)
+        // addresses reach the IPv4 address.
+        IpAddr::V6(ip) => match ip.to_ipv4() {
+            Some(ip) => is_internal(IpAddr::V4(ip)),
+            None => {
+                let segments = ip.segments();
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // fc00::/7, unique local
+                    || segments[0] & 0xfe00 == 0xfc00
+                    // fe80::/10, link-local
+                    || segments[0] & 0xffc0 == 0xfe80
+                    // 64:ff9b::/96, NAT64, & 2002::/16, 6to4, both reach IPv4
+                    // addresses through a gateway
+                    || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0]
+                    || segments[0] == 0x2002
+            }
+        },
+    }
+}
+
+fn is_internal_host(internal_hosts: &[String], host: &str) -> bool {
+    internal_hosts
+        .iter()
+        .any(|internal| internal.eq_ignore_ascii_case(host))
+}
+
+/// Make sure `url` is an http(s) URL to one of the allowed hosts.
+fn check_url(config: &FetchConfig, url: &Url) -> Result<(), FetchError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(FetchError::UnsupportedScheme);
+    }
 
-// In debug mode or running locally
-#[cfg(all(target_os = "windows", debug_assertions))]
-const EXE_NAME: &str = "./resources/utils/win/pdftotext.exe";
-#[cfg(all(target_os = "macos", debug_assertions))]
-const EXE_NAME: &str = "./resources/utils/mac/pdftotext";
-#[cfg(all(target_os = "linux", debug_assertions))]
-const EXE_NAME: &str = "./resources/utils/linux/pdftotext";
+    let host = url.host_str().unwrap_or_default().to_lowercase();
+    // IP addresses aren't resolved, check them up front.
+    let ip = match url.host() {
+        Some(Host::Ipv4(ip)) => Some(IpAddr::V4(ip)),
+        Some(Host::Ipv6(ip)) => Some(IpAddr::V6(ip)),
+        _ => None,
+    };
+    if ip.is_some_and(is_internal) && !is_internal_host(&config.internal_hosts, &host) {
+        return Err(FetchError::InternalAddress(host));
+    }
+
+    let allowed = config.allowed_hosts.is_empty()
+        || config.allowed_hosts.iter().any(|allowed| {
+            let allowed = allowed.to_lowercase();
+            host == allowed || host.ends_with(&format!(".{allowed}"))
+        });
+    match allowed {
+        true => Ok(()),
+        false => Err(FetchError::BlockedHost(host)),
+    }
+}
 
 pub async fn handle_fetch(
     query: filters::FetchRequest,
+    fetcher: Fetcher,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let time = std::time::Instant::now();
 
     if let Some(url) = query.url {
-        let content = reqwest::get(url)
-            .await
-            .map_err(|err| ServerError::Other(err.to_string()))?
-            .text()
-            .await
-            .map_err(|err| ServerError::Other(err.to_string()))?;
+        let content = fetch(&fetcher.client, &fetcher.config, &url).await?;
 
         Ok(warp::reply::json(&ApiResponse::success(
             time.elapsed(),
@@ -40,67 +194,163 @@ pub async fn handle_fetch(
     }
 }
 
-pub async fn handle_parse(form: FormData) -> Result<impl warp::Reply, warp::Rejection> {
+/// Fetch the body of `url`, giving up once it's over `config.max_bytes`.
+async fn fetch(
+    client: &reqwest::Client,
+    config: &FetchConfig,
+    url: &str,
+) -> Result<String, ServerError> {
+    let url =
+        Url::parse(url).map_err(|err| ServerError::BadRequest(format!("Invalid url: {err}")))?;
+    check_url(config, &url)?;
+
+    let too_large = || {
+        ServerError::PayloadTooLarge(format!(
+            "Response from {url} is over {} bytes",
+            config.max_bytes
+        ))
+    };
+    let mut response = client
+        .get(url.clone())
+        .send()
+        .await
+        .map_err(request_error)?;
+    if response
+        .content_length()
+        .is_some_and(|len| len > config.max_bytes as u64)
+    {
+        return Err(too_large());
+    }
+
+    // Content-Length can be missing or wrong, count what's actually read.
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(request_error)? {
+        if bytes.len() + chunk.len() > config.max_bytes {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(String::from_utf8_lossy(&bytes).to_string())
+}
+
+fn request_error(err: reqwest::Error) -> ServerError {
+    if err.is_timeout() {
+        return ServerError::UpstreamTimeout(err.to_string());
+    }
+
+    // Redirects to a blocked host fail inside the redirect policy, & hosts
+    // w/ only internal addresses when they're resolved.
+    let mut source = err.source();
+    while let Some(inner) = source {
+        if let Some(err) = inner.downcast_ref::<FetchError>() {
+            return err.clone().into();
+        }
+        source = inner.source();
+    }
+
+    ServerError::Upstream(err.to_string())
+}
+
+pub async fn handle_parse(
+    query: filters::ParseRequest,
+    form: FormData,
+    upload_config: UploadConfig,
+) -> Result<impl warp::Reply, warp::Rejection> {
     let time = std::time::Instant::now();
+    let parsed = parse_upload(&query, form, &upload_config).await?;
 
-    let field_names: Vec<_> = form
-        .and_then(|mut field| async move {
-            let mut bytes: Vec<u8> = Vec::new();
-            let content_type = field.content_type().map(|f| f.to_string());
-
-            // field.data() only returns a piece of the content, you should call over it until it replies None
-            // TODO we should stream directly to s3 instead of memory
-            while let Some(content) = field.data().await {
-                let content = content.unwrap();
-                let chunk: &[u8] = content.chunk();
-                bytes.extend_from_slice(chunk);
-            }
+    Ok(warp::reply::json(&ApiResponse::success(
+        time.elapsed(),
+        Some(serde_json::json!({ "parsed": parsed })),
+    )))
+}
 
-            Ok((field.name().to_string(), content_type, bytes))
-        })
-        .try_collect()
-        .await
-        .map_err(|e| ServerError::Other(e.to_string()))?;
+/// File types text can be extracted from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FileKind {
+    Pdf,
+    /// Plain text & markdown, used as-is.
+    Text,
+    Html,
+}
 
-    let (content_type, data) = field_names
-        .iter()
-        .find_map(|(field, content_type, data)| {
-            if field == "file" {
-                Some((content_type, data))
-            } else {
-                None
+impl FileKind {
+    /// Detect the file type from the content type, falling back to the file
+    /// extension since clients often send `application/octet-stream`.
+    fn detect(content_type: Option<&str>, filename: Option<&str>) -> Option<Self> {
+        let from_type = content_type.and_then(|content_type| {
+            match content_type.split(';').next().unwrap_or_default().trim() {
+                "application/pdf" | "application" => Some(FileKind::Pdf),
+                "text/plain" | "text/markdown" | "text/x-markdown" => Some(FileKind::Text),
+                "text/html" => Some(FileKind::Html),
+                _ => None,
             }
-        })
-        .ok_or(ServerError::Other("Invalid request".to_string()))?;
+        });
 
-    let file_ending = match content_type {
-        Some(content) => {
-            if content == "application/pdf" || content == "application" {
-                "pdf"
-            } else {
-                return Err(ServerError::Other("File type not supported".to_string()).into());
+        from_type.or_else(|| {
+            let extension = Path::new(filename?).extension()?.to_str()?.to_lowercase();
+            match extension.as_str() {
+                "pdf" => Some(FileKind::Pdf),
+                "txt" | "md" | "markdown" => Some(FileKind::Text),
+                "html" | "htm" => Some(FileKind::Html),
+                _ => None,
             }
+        })
+    }
+}
+
+/// Extract the text from the "file" field of an upload.
+pub async fn parse_upload(
+    query: &filters::ParseRequest,
+    mut form: FormData,
+    upload_config: &UploadConfig,
+) -> Result<String, ServerError> {
+    while let Some(mut field) = form
+        .try_next()
+        .await
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?
+    {
+        if field.name() != "file" {
+            continue;
         }
-        _ => {
-            return Err(ServerError::Other("File type not supported".to_string()).into());
-        }
-    };
 
+        let kind = FileKind::detect(field.content_type(), field.filename())
+            .ok_or_else(|| ServerError::BadRequest("File type not supported".to_string()))?;
+
+        return match kind {
+            FileKind::Pdf => parse_pdf(query, &mut field, upload_config).await,
+            FileKind::Text => read_field(&mut field).await,
+            FileKind::Html => Ok(html_to_text(&read_field(&mut field).await?)),
+        };
+    }
+
+    Err(ServerError::BadRequest("Invalid request".to_string()))
+}
+
+async fn parse_pdf(
+    query: &filters::ParseRequest,
+    field: &mut Part,
+    upload_config: &UploadConfig,
+) -> Result<String, ServerError> {
     let file_id = uuid::Uuid::new_v4();
-    let filename = format!("{UPLOAD_DATA_DIR}/{}.{}", file_id, file_ending);
-    let parsed_output = format!("{UPLOAD_DATA_DIR}/{}.txt", file_id);
+    let filename = upload_config.upload_dir.join(format!("{}.pdf", file_id));
 
-    log::debug!("saving file to {filename}");
-    tokio::fs::write(&filename, data)
-        .await
-        .map_err(|e| ServerError::Other(e.to_string()))?;
+    log::debug!("saving file to {filename:?}");
+    if let Err(err) = save_field(field, &filename).await {
+        let _ = tokio::fs::remove_file(&filename).await;
+        return Err(err);
+    }
+
+    let parsed_output = upload_config.upload_dir.join(format!("{}.txt", file_id));
 
     // Run pdftotext on the sucker
-    let mut cmd = tokio::process::Command::new(EXE_NAME);
+    let mut cmd = tokio::process::Command::new(&upload_config.pdftotext_path);
     cmd.arg("-q")
         .arg("-nopgbrk")
         .arg("-enc")
         .arg("UTF-8")
+        .args(query.layout.pdftotext_args())
         .arg(filename.clone())
         .arg(parsed_output.clone());
 
@@ -108,7 +358,7 @@ pub async fn handle_parse(form: FormData) -> Result<impl warp::Reply, warp::Reje
     let parsed = match cmd.spawn() {
         Ok(mut child) => {
             if let Err(err) = child.wait().await {
-                return Err(ServerError::Other(err.to_string()).into());
+                return Err(ServerError::Other(err.to_string()));
             } else {
                 // Read results
                 let bytes = tokio::fs::read(parsed_output.clone())
@@ -118,7 +368,7 @@ pub async fn handle_parse(form: FormData) -> Result<impl warp::Reply, warp::Reje
             }
         }
         Err(err) => {
-            return Err(ServerError::Other(err.to_string()).into());
+            return Err(ServerError::Other(err.to_string()));
         }
     };
 
@@ -126,8 +376,211 @@ pub async fn handle_parse(form: FormData) -> Result<impl warp::Reply, warp::Reje
     let _ = std::fs::remove_file(filename);
     let _ = std::fs::remove_file(parsed_output);
 
-    Ok(warp::reply::json(&ApiResponse::success(
-        time.elapsed(),
-        Some(serde_json::json!({ "parsed": parsed })),
-    )))
+    Ok(parsed)
+}
+
+/// Read the contents of a multipart field into memory.
+async fn read_field(field: &mut Part) -> Result<String, ServerError> {
+    let mut bytes = Vec::new();
+    while let Some(content) = field.data().await {
+        let content = content.map_err(|e| ServerError::BadRequest(e.to_string()))?;
+        bytes.extend_from_slice(content.chunk());
+    }
+
+    Ok(String::from_utf8_lossy(&bytes).to_string())
+}
+
+/// Naive HTML to text conversion, drops tags along w/ any scripts & styles.
+fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let tag = rest[1..]
+            .split(|c: char| c.is_whitespace() || c == '>' || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        // Skip everything up to the closing tag for non-content elements
+        let skip_to = match tag.as_str() {
+            "script" | "style" => rest
+                .to_ascii_lowercase()
+                .find(&format!("</{tag}"))
+                .unwrap_or(rest.len()),
+            _ => 0,
+        };
+        rest = &rest[skip_to..];
+
+        match rest.find('>') {
+            Some(end) => {
+                rest = &rest[end + 1..];
+                // Keep words in separate elements apart
+                text.push(' ');
+            }
+            None => rest = "",
+        }
+    }
+    text.push_str(rest);
+
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Write the contents of a multipart field to disk as each chunk arrives.
+async fn save_field(field: &mut Part, path: &Path) -> Result<(), ServerError> {
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .map_err(|e| ServerError::Other(e.to_string()))?;
+
+    // field.data() only returns a piece of the content, call it until it replies None
+    while let Some(content) = field.data().await {
+        let content = content.map_err(|e| ServerError::BadRequest(e.to_string()))?;
+        file.write_all(content.chunk())
+            .await
+            .map_err(|e| ServerError::Other(e.to_string()))?;
+    }
+
+    file.flush()
+        .await
+        .map_err(|e| ServerError::Other(e.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_url, html_to_text, is_internal, FetchError, FileKind};
+    use crate::endpoints::FetchConfig;
+    use url::Url;
+
+    #[test]
+    fn test_is_internal() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "100.64.0.1",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::127.0.0.1",
+            "::a9fe:a9fe",
+            "::",
+            "192.0.0.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "192.0.2.1",
+            "198.51.100.1",
+            "203.0.113.1",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+            "ff02::1",
+            "64:ff9b::7f00:1",
+            "2002:a00:1::1",
+        ] {
+            assert!(is_internal(ip.parse().unwrap()), "{ip}");
+        }
+
+        for ip in [
+            "1.1.1.1",
+            "100.128.0.1",
+            "192.0.1.1",
+            "198.20.0.1",
+            "198.51.101.1",
+            "203.0.114.1",
+            "::ffff:1.1.1.1",
+            "223.255.255.255",
+            "2606:4700:4700::1111",
+            "64:ff9b:1::1",
+        ] {
+            assert!(!is_internal(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn test_check_url() {
+        let mut config = FetchConfig::default();
+        let check = |config: &FetchConfig, url: &str| check_url(config, &Url::parse(url).unwrap());
+
+        assert!(check(&config, "https://example.com/page").is_ok());
+        assert!(matches!(
+            check(&config, "file:///etc/passwd"),
+            Err(FetchError::UnsupportedScheme)
+        ));
+        assert!(matches!(
+            check(&config, "http://169.254.169.254/latest/meta-data"),
+            Err(FetchError::InternalAddress(_))
+        ));
+        assert!(matches!(
+            check(&config, "http://[::1]:8080"),
+            Err(FetchError::InternalAddress(_))
+        ));
+
+        config.internal_hosts = vec!["10.0.0.5".into()];
+        assert!(check(&config, "http://10.0.0.5/").is_ok());
+        assert!(check(&config, "http://10.0.0.6/").is_err());
+
+        config.allowed_hosts = vec!["example.com".into()];
+        assert!(check(&config, "https://docs.example.com").is_ok());
+        assert!(matches!(
+            check(&config, "https://notexample.com"),
+            Err(FetchError::BlockedHost(_))
+        ));
+    }
+
+    #[test]
+    fn test_detect_file_kind() {
+        let cases = [
+            (Some("application/pdf"), None, Some(FileKind::Pdf)),
+            (Some("text/html; charset=utf-8"), None, Some(FileKind::Html)),
+            // The content type wins over the extension
+            (Some("text/plain"), Some("notes.pdf"), Some(FileKind::Text)),
+            (
+                Some("application/octet-stream"),
+                Some("Notes.MD"),
+                Some(FileKind::Text),
+            ),
+            (None, Some("page.htm"), Some(FileKind::Html)),
+            (Some("image/png"), Some("image.png"), None),
+            (None, Some("README"), None),
+            (None, None, None),
+        ];
+        for (content_type, filename, expected) in cases {
+            assert_eq!(
+                FileKind::detect(content_type, filename),
+                expected,
+                "{content_type:?} {filename:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_html_to_text() {
+        let html = "<html><head><style>p { color: red; }</style>\
+            <script>alert('<b>hi</b>')</script></head>\
+            <body><p>Hello&nbsp;<b>world</b> &amp; more</p>\n\
+            <div>Line&lt;2&gt; &amp;lt;</div></body></html>";
+        assert_eq!(html_to_text(html), "Hello world & more\nLine<2> &lt;");
+
+        assert_eq!(html_to_text("no tags"), "no tags");
+        assert_eq!(html_to_text("cut <b off"), "cut");
+        assert_eq!(html_to_text("<SCRIPT>x</SCRIPT>after"), "after");
+    }
 }
@@ -1,22 +1,179 @@
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
-use libmemex::llm::LLM;
+use libmemex::{
+    cache::SearchCache,
+    db::queue,
+    llm::{self, embedding::ModelConfig, LLM},
+};
 use sea_orm::DatabaseConnection;
 use serde::de::DeserializeOwned;
-use warp::Filter;
+use warp::{filters::BoxedFilter, Filter, Reply};
+
+use crate::ServerError;
 
 mod actions;
 mod collections;
 mod fetch;
+mod segment;
+mod similarity;
 mod tasks;
 
+pub use collections::handlers::collection_health;
+
 const LIMIT_1_MB: u64 = 1000 * 1024;
 const LIMIT_10_MB: u64 = 10 * LIMIT_1_MB;
+const LIMIT_1_GB: u64 = 1000 * LIMIT_1_MB;
 
 #[cfg(not(debug_assertions))]
-pub const UPLOAD_DATA_DIR: &str = "/tmp";
+pub const DEFAULT_UPLOAD_DIR: &str = "/tmp";
 #[cfg(debug_assertions)]
-pub const UPLOAD_DATA_DIR: &str = "./uploads";
+pub const DEFAULT_UPLOAD_DIR: &str = "./uploads";
+
+// When memex is running inside the docker image.
+#[cfg(not(debug_assertions))]
+pub const DEFAULT_PDFTOTEXT_PATH: &str = "/usr/local/bin/pdftotext";
+
+// In debug mode or running locally
+#[cfg(all(target_os = "windows", debug_assertions))]
+pub const DEFAULT_PDFTOTEXT_PATH: &str = "./resources/utils/win/pdftotext.exe";
+#[cfg(all(target_os = "macos", debug_assertions))]
+pub const DEFAULT_PDFTOTEXT_PATH: &str = "./resources/utils/mac/pdftotext";
+#[cfg(all(target_os = "linux", debug_assertions))]
+pub const DEFAULT_PDFTOTEXT_PATH: &str = "./resources/utils/linux/pdftotext";
+
+/// Where uploaded files are stored & the tools used to parse them.
+#[derive(Clone, Debug)]
+pub struct UploadConfig {
+    pub upload_dir: PathBuf,
+    pub pdftotext_path: PathBuf,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            upload_dir: DEFAULT_UPLOAD_DIR.into(),
+            pdftotext_path: DEFAULT_PDFTOTEXT_PATH.into(),
+        }
+    }
+}
+
+/// Make sure a caller supplied retry limit is within `queue::MAX_RETRIES_LIMIT`.
+fn check_max_retries(max_retries: Option<i32>) -> Result<Option<i32>, ServerError> {
+    match max_retries {
+        Some(max) if !(0..=queue::MAX_RETRIES_LIMIT).contains(&max) => {
+            Err(ServerError::BadRequest(format!(
+                "maxRetries must be between 0 and {}",
+                queue::MAX_RETRIES_LIMIT
+            )))
+        }
+        _ => Ok(max_retries),
+    }
+}
+
+/// Make sure caller supplied stop sequences are usable, see `llm::MAX_STOP_SEQUENCES`.
+fn check_stop(stop: &[String]) -> Result<(), ServerError> {
+    if stop.len() > llm::MAX_STOP_SEQUENCES {
+        return Err(ServerError::BadRequest(format!(
+            "stop can have at most {} sequences",
+            llm::MAX_STOP_SEQUENCES
+        )));
+    }
+    if stop.iter().any(|seq| seq.is_empty()) {
+        return Err(ServerError::BadRequest(
+            "stop sequences can't be empty".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Default max # of characters in a single document, roughly 250k tokens.
+pub const DEFAULT_MAX_DOCUMENT_CHARS: usize = 1_000_000;
+
+/// Limits on documents added to a collection.
+#[derive(Clone, Debug)]
+pub struct IngestConfig {
+    pub max_document_chars: usize,
+    /// Create missing collections w/ the default settings instead of
+    /// returning a 404.
+    pub auto_create_collections: bool,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        Self {
+            max_document_chars: DEFAULT_MAX_DOCUMENT_CHARS,
+            auto_create_collections: true,
+        }
+    }
+}
+
+// How long a fetch can take, how many redirects it follows & how big a
+// response it reads by default.
+pub const DEFAULT_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_FETCH_MAX_REDIRECTS: usize = 5;
+pub const DEFAULT_FETCH_MAX_BYTES: usize = 10 * 1024 * 1024;
+
+/// Limits on URLs fetched by the API.
+#[derive(Clone, Debug)]
+pub struct FetchConfig {
+    pub timeout: Duration,
+    pub max_redirects: usize,
+    /// Responses larger than this are aborted.
+    pub max_bytes: usize,
+    /// Hosts (& their subdomains) that can be fetched from, any host can be
+    /// when empty.
+    pub allowed_hosts: Vec<String>,
+    /// Hosts (or IP addresses) that can be fetched from even though they're
+    /// on a private, loopback or link-local network.
+    pub internal_hosts: Vec<String>,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_FETCH_TIMEOUT,
+            max_redirects: DEFAULT_FETCH_MAX_REDIRECTS,
+            max_bytes: DEFAULT_FETCH_MAX_BYTES,
+            allowed_hosts: Vec::new(),
+            internal_hosts: Vec::new(),
+        }
+    }
+}
+
+/// Client used to fetch URLs, built once at startup from its `FetchConfig`.
+#[derive(Clone)]
+pub struct Fetcher {
+    pub client: reqwest::Client,
+    pub config: FetchConfig,
+}
+
+impl Fetcher {
+    pub fn new(config: FetchConfig) -> reqwest::Result<Self> {
+        let client = fetch::handlers::fetch_client(&config)?;
+        Ok(Self { client, config })
+    }
+}
+
+/// Cached search responses, only set when search caching is enabled.
+static SEARCH_CACHE: OnceLock<SearchCache<serde_json::Value>> = OnceLock::new();
+
+/// Cache search results for up to `ttl`, keeping at most `capacity` results.
+pub fn enable_search_cache(ttl: Duration, capacity: usize) {
+    let _ = SEARCH_CACHE.set(SearchCache::new(ttl, capacity));
+}
+
+pub fn search_cache() -> Option<&'static SearchCache<serde_json::Value>> {
+    SEARCH_CACHE.get()
+}
+
+/// Drop any cached search results for a collection after it's changed.
+pub fn invalidate_search_cache(collection: &str) {
+    if let Some(cache) = search_cache() {
+        cache.invalidate(collection);
+    }
+}
 
 pub fn json_body<T: std::marker::Send + DeserializeOwned>(
     limit: u64,
@@ -24,12 +181,151 @@ pub fn json_body<T: std::marker::Send + DeserializeOwned>(
     warp::body::content_length_limit(limit).and(warp::body::json())
 }
 
+/// Build the API's routes. In `read_only` mode routes that add, change or
+/// delete anything are left out, so requests to them get a `405`/`404`.
 pub fn build(
     db: &DatabaseConnection,
     llm: &Arc<Box<dyn LLM>>,
-) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    actions::filters::build(llm, db)
-        .or(collections::filters::build(db))
-        .or(fetch::filters::build())
-        .or(tasks::filters::build(db))
+    upload_config: &UploadConfig,
+    ingest_config: &IngestConfig,
+    fetcher: &Fetcher,
+    model_config: &ModelConfig,
+    read_only: bool,
+) -> BoxedFilter<(Box<dyn Reply>,)> {
+    let reads = actions::filters::build_reads(llm)
+        .or(collections::filters::build_reads(db, llm, model_config))
+        .or(fetch::filters::build(upload_config, fetcher))
+        .or(segment::filters::build(llm, model_config))
+        .or(similarity::filters::build(model_config))
+        .or(tasks::filters::build_reads(db));
+
+    if read_only {
+        return reads.map(boxed_reply).boxed();
+    }
+
+    reads
+        .or(actions::filters::build_writes(db))
+        .or(collections::filters::build_writes(
+            db,
+            upload_config,
+            ingest_config,
+            model_config,
+        ))
+        .or(tasks::filters::build_writes(db))
+        .map(boxed_reply)
+        .boxed()
+}
+
+fn boxed_reply(reply: impl Reply + 'static) -> Box<dyn Reply> {
+    Box::new(reply)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{build, FetchConfig, Fetcher, IngestConfig, UploadConfig, LIMIT_1_GB};
+    use libmemex::{
+        db::create_connection_by_uri,
+        llm::{
+            embedding::ModelConfig,
+            openai::{OpenAIClient, OpenAIOptions},
+            LLM,
+        },
+    };
+    use std::{convert::Infallible, sync::Arc};
+    use warp::{http::StatusCode, Filter, Reply};
+
+    /// All the API's routes w/ the default settings & an in-memory db.
+    async fn test_routes(
+        read_only: bool,
+    ) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .unwrap();
+        let llm: Arc<Box<dyn LLM>> = Arc::new(Box::new(OpenAIClient::new(
+            "test",
+            &OpenAIOptions::default(),
+        )));
+        build(
+            &db,
+            &llm,
+            &UploadConfig::default(),
+            &IngestConfig::default(),
+            &Fetcher::new(FetchConfig::default()).unwrap(),
+            &ModelConfig::default(),
+            read_only,
+        )
+        .recover(crate::handle_rejection)
+    }
+
+    #[tokio::test]
+    async fn test_read_only() {
+        let read_only = test_routes(true).await;
+
+        let writes = [
+            ("POST", "/collections/test"),
+            ("PUT", "/collections/test"),
+            ("DELETE", "/collections/test"),
+            ("POST", "/collections/test/segments"),
+            ("POST", "/collections/test/upload"),
+            ("DELETE", "/collections/test/documents"),
+            ("DELETE", "/collections/test/segments/1"),
+            ("POST", "/collections/test/compact"),
+            ("POST", "/collections/test/reindex"),
+            ("POST", "/collections/test/import"),
+            ("POST", "/action/summarize/task"),
+            ("DELETE", "/tasks/1"),
+        ];
+        for (method, path) in writes {
+            let response = warp::test::request()
+                .method(method)
+                .path(path)
+                .reply(&read_only)
+                .await;
+            assert!(
+                matches!(
+                    response.status(),
+                    StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED
+                ),
+                "{method} {path} returned {}",
+                response.status()
+            );
+        }
+
+        // Bad requests make it to the routes that are still there
+        let reads = [
+            ("GET", "/collections/test/search"),
+            ("POST", "/action/ask"),
+            ("POST", "/segment/preview"),
+        ];
+        for (method, path) in reads {
+            let response = warp::test::request()
+                .method(method)
+                .path(path)
+                .body("not json")
+                .reply(&read_only)
+                .await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{path}");
+        }
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/action/summarize/task")
+            .body("not json")
+            .reply(&test_routes(false).await)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_import_limit() {
+        let routes = test_routes(false).await;
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/collections/test/import")
+            .header("content-length", LIMIT_1_GB + 1)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
 }
@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use libmemex::llm::{embedding::ModelConfig, LLM};
+use serde::{Deserialize, Serialize};
+use warp::Filter;
+
+use crate::endpoints::{json_body, LIMIT_10_MB};
+use crate::{with_llm, with_model_config};
+
+/// Segment content the same way the LLM does for summarization, etc.
+pub const LLM_MODEL: &str = "llm";
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SegmentPreviewRequest {
+    /// Text to segment
+    pub content: String,
+    /// Embedding model to segment for, or "llm" to use the LLM's segmentation.
+    /// Defaults to the configured embedding model.
+    pub model: Option<String>,
+}
+
+fn preview(
+    llm: &Arc<Box<dyn LLM>>,
+    model_config: &ModelConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("segment" / "preview")
+        .and(warp::post())
+        .and(json_body::<SegmentPreviewRequest>(LIMIT_10_MB))
+        .and(with_llm(llm.clone()))
+        .and(with_model_config(model_config.clone()))
+        .and_then(super::handlers::handle_preview)
+}
+
+pub fn build(
+    llm: &Arc<Box<dyn LLM>>,
+    model_config: &ModelConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    preview(llm, model_config).boxed()
+}
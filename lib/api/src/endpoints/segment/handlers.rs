@@ -0,0 +1,86 @@
+use std::sync::Arc;
+
+use crate::{
+    schema::{ApiResponse, PreviewSegment, SegmentPreviewResult},
+    ServerError,
+};
+use libmemex::llm::{
+    embedding::{segment_text_with_tokens, EmbeddingsModelType, ModelConfig},
+    LLM,
+};
+
+use super::filters;
+
+pub async fn handle_preview(
+    request: filters::SegmentPreviewRequest,
+    llm: Arc<Box<dyn LLM>>,
+    model_config: ModelConfig,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let time = std::time::Instant::now();
+
+    let (model, segments) = match request.model.as_deref() {
+        Some(model) if model.eq_ignore_ascii_case(filters::LLM_MODEL) => {
+            let (segments, model) = llm.segment_text(&request.content);
+            let segments = segments
+                .into_iter()
+                .map(|content| {
+                    let tokens = llm.count_tokens(&content);
+                    (content, tokens)
+                })
+                .collect::<Vec<_>>();
+
+            // Local LLMs don't report a model name
+            let model = if model.is_empty() {
+                filters::LLM_MODEL.to_string()
+            } else {
+                model
+            };
+            (model, segments)
+        }
+        model => {
+            let model_config = match model {
+                Some(model) => ModelConfig::with_model(
+                    model
+                        .parse::<EmbeddingsModelType>()
+                        .map_err(|_| ServerError::BadRequest(format!("Unknown model: {model}")))?,
+                ),
+                None => model_config,
+            };
+
+            // Loading the tokenizer may hit the network/disk
+            let content = request.content;
+            let model_name = model_config.model().to_string();
+            let segments = tokio::task::spawn_blocking(move || {
+                segment_text_with_tokens(&model_config, &content)
+            })
+            .await
+            .map_err(|err| ServerError::Other(err.to_string()))?
+            .map_err(|err| ServerError::BadRequest(err.to_string()))?;
+
+            (model_name, segments)
+        }
+    };
+
+    let total_tokens = segments.iter().map(|(_, tokens)| tokens).sum();
+    // Only the LLM bills per token, embedding models run locally.
+    let estimated_cost = match request.model.as_deref() {
+        Some(name) if name.eq_ignore_ascii_case(filters::LLM_MODEL) => {
+            llm.prompt_cost(&model, total_tokens)
+        }
+        _ => None,
+    };
+    let result = SegmentPreviewResult {
+        total_tokens,
+        estimated_cost,
+        model,
+        segments: segments
+            .into_iter()
+            .map(|(content, tokens)| PreviewSegment { content, tokens })
+            .collect(),
+    };
+
+    Ok(warp::reply::json(&ApiResponse::success(
+        time.elapsed(),
+        Some(result),
+    )))
+}
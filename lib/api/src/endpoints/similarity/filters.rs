@@ -0,0 +1,34 @@
+use libmemex::llm::embedding::ModelConfig;
+use serde::{Deserialize, Serialize};
+use warp::Filter;
+
+use crate::endpoints::{json_body, LIMIT_10_MB};
+use crate::with_model_config;
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SimilarityRequest {
+    /// First text to compare
+    pub a: String,
+    /// Second text to compare
+    pub b: String,
+    /// Return the embeddings used to calculate the similarity.
+    #[serde(default)]
+    pub include_vectors: bool,
+}
+
+fn similarity(
+    model_config: &ModelConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("similarity")
+        .and(warp::post())
+        .and(json_body::<SimilarityRequest>(LIMIT_10_MB))
+        .and(with_model_config(model_config.clone()))
+        .and_then(super::handlers::handle_similarity)
+}
+
+pub fn build(
+    model_config: &ModelConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    similarity(model_config).boxed()
+}
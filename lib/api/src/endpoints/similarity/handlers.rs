@@ -0,0 +1,35 @@
+use crate::{
+    endpoints::collections::handlers::query_embedder,
+    schema::{ApiResponse, SimilarityResult},
+    ServerError,
+};
+use libmemex::{llm::embedding::ModelConfig, storage::cosine_score};
+
+use super::filters;
+
+pub async fn handle_similarity(
+    request: filters::SimilarityRequest,
+    model_config: ModelConfig,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let time = std::time::Instant::now();
+    let embedder = query_embedder(&model_config);
+
+    let mut vectors = Vec::new();
+    for text in [request.a, request.b] {
+        match embedder.encode_single(text).await {
+            Ok(Some(embedding)) => vectors.push(embedding.vector),
+            Ok(None) => return Err(ServerError::BadRequest("Unable to embed text".into()).into()),
+            Err(err) => return Err(ServerError::Other(err.to_string()).into()),
+        }
+    }
+
+    let result = SimilarityResult {
+        similarity: cosine_score(&vectors[0], &vectors[1]),
+        vectors: request.include_vectors.then_some(vectors),
+    };
+
+    Ok(warp::reply::json(&ApiResponse::success(
+        time.elapsed(),
+        Some(result),
+    )))
+}
@@ -2,13 +2,36 @@ use sea_orm::DatabaseConnection;
 use warp::Filter;
 
 use super::handlers;
-use crate::with_db;
+use crate::{with_db, with_tenant};
 
-pub fn build(
+fn check_task(
     db: &DatabaseConnection,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path!("tasks" / i64)
         .and(warp::get())
         .and(with_db(db.clone()))
+        .and(with_tenant())
         .and_then(handlers::handle_check_task)
 }
+
+fn cancel_task(
+    db: &DatabaseConnection,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("tasks" / i64)
+        .and(warp::delete())
+        .and(with_db(db.clone()))
+        .and(with_tenant())
+        .and_then(handlers::handle_cancel_task)
+}
+
+pub fn build_reads(
+    db: &DatabaseConnection,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    check_task(db)
+}
+
+pub fn build_writes(
+    db: &DatabaseConnection,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    cancel_task(db)
+}
@@ -1,5 +1,6 @@
 use crate::{
     schema::{ApiResponse, TaskResult},
+    tenant::Tenant,
     ServerError,
 };
 use libmemex::db::queue;
@@ -8,6 +9,7 @@ use sea_orm::{DatabaseConnection, EntityTrait};
 pub async fn handle_check_task(
     task_id: i64,
     db: DatabaseConnection,
+    tenant: Tenant,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let time = std::time::Instant::now();
     let result = match queue::Entity::find_by_id(task_id).one(&db).await {
@@ -15,14 +17,51 @@ pub async fn handle_check_task(
         Err(err) => return Err(warp::reject::custom(ServerError::DatabaseError(err))),
     };
 
+    // Tasks from other tenants are treated as if they don't exist.
     match result {
-        Some(result) => {
-            let result = TaskResult::from(result);
+        Some(result) if tenant.owns(&result.collection) => {
+            let result = TaskResult::from(result).for_tenant(&tenant);
             Ok(warp::reply::json(&ApiResponse::success(
                 time.elapsed(),
                 Some(result),
             )))
         }
-        None => Err(warp::reject::not_found()),
+        _ => Err(ServerError::NotFound(format!("Task {task_id}")).into()),
     }
 }
+
+pub async fn handle_cancel_task(
+    task_id: i64,
+    db: DatabaseConnection,
+    tenant: Tenant,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let time = std::time::Instant::now();
+    match queue::Entity::find_by_id(task_id).one(&db).await {
+        Ok(Some(task)) if tenant.owns(&task.collection) => {}
+        Ok(_) => return Err(ServerError::NotFound(format!("Task {task_id}")).into()),
+        Err(err) => return Err(warp::reject::custom(ServerError::DatabaseError(err))),
+    }
+
+    let cancelled = match queue::mark_cancelled(&db, task_id).await {
+        Ok(cancelled) => cancelled,
+        Err(err) => return Err(warp::reject::custom(ServerError::DatabaseError(err))),
+    };
+
+    let result = match queue::Entity::find_by_id(task_id).one(&db).await {
+        Ok(Some(result)) => result,
+        Ok(None) => return Err(ServerError::NotFound(format!("Task {task_id}")).into()),
+        Err(err) => return Err(warp::reject::custom(ServerError::DatabaseError(err))),
+    };
+
+    if !cancelled {
+        return Err(warp::reject::custom(ServerError::BadRequest(format!(
+            "Task {task_id} has already finished ({})",
+            result.status
+        ))));
+    }
+
+    Ok(warp::reply::json(&ApiResponse::success(
+        time.elapsed(),
+        Some(TaskResult::from(result).for_tenant(&tenant)),
+    )))
+}
@@ -1,117 +1,454 @@
 use dotenv_codegen::dotenv;
+use endpoints::{FetchConfig, Fetcher, IngestConfig, UploadConfig};
 use libmemex::{
-    db::create_connection_by_uri,
-    llm::{local::load_from_cfg, openai::OpenAIClient, LLM},
+    cache::DEFAULT_SEARCH_CACHE_SIZE,
+    db::{create_connection_by_uri, queue},
+    llm::{
+        embedding::ModelConfig,
+        local::load_from_cfg,
+        openai::{OpenAIClient, OpenAIOptions},
+        prompter, LLMError, LLM,
+    },
+    status::{worker_status, WorkerStatus},
+    storage::{self, StoreStatus},
 };
-use sea_orm::DatabaseConnection;
+use sea_orm::{DatabaseConnection, EntityTrait};
+use serde::Deserialize;
 use serde_json::json;
-use std::{convert::Infallible, net::Ipv4Addr, path::PathBuf, sync::Arc};
+use std::{convert::Infallible, net::Ipv4Addr, sync::Arc, time::Duration};
 use thiserror::Error;
 use warp::{hyper::StatusCode, reject::Reject, Filter, Rejection, Reply};
 
+const MAX_TRACE_ID_LEN: usize = 128;
+
 pub mod endpoints;
 pub mod schema;
+pub mod tenant;
 use schema::{ApiResponse, ErrorMessage};
+use tenant::Tenant;
 
 #[derive(Error, Debug)]
 pub enum ServerError {
     #[error("Client request error: {0}")]
-    ClientRequestError(String),
+    BadRequest(String),
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+    /// An external service (LLM, fetched URL, etc.) failed.
+    #[error("Upstream error: {0}")]
+    Upstream(String),
+    /// An external service took too long to respond.
+    #[error("Upstream timeout: {0}")]
+    UpstreamTimeout(String),
+    #[error("Vector store error: {0}")]
+    VectorStore(String),
     #[error("Database error: {0}")]
     DatabaseError(#[from] sea_orm::DbErr),
     #[error("Server error: {0}")]
     Other(String),
 }
 
+impl ServerError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ServerError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ServerError::NotFound(_) => StatusCode::NOT_FOUND,
+            ServerError::Conflict(_) => StatusCode::CONFLICT,
+            ServerError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ServerError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ServerError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            ServerError::UpstreamTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ServerError::DatabaseError(sea_orm::DbErr::ConnectionAcquire(_)) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ServerError::VectorStore(_) | ServerError::DatabaseError(_) | ServerError::Other(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// Machine-readable error code, returned as `error` in the response.
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            ServerError::BadRequest(_) => "BAD_REQUEST",
+            ServerError::NotFound(_) => "NOT_FOUND",
+            ServerError::Conflict(_) => "CONFLICT",
+            ServerError::Forbidden(_) => "FORBIDDEN",
+            ServerError::PayloadTooLarge(_) => "PAYLOAD_TOO_LARGE",
+            ServerError::Upstream(_) => "UPSTREAM_ERROR",
+            ServerError::UpstreamTimeout(_) => "UPSTREAM_TIMEOUT",
+            ServerError::VectorStore(_) => "VECTOR_STORE_ERROR",
+            ServerError::DatabaseError(sea_orm::DbErr::ConnectionAcquire(_)) => {
+                "DATABASE_UNAVAILABLE"
+            }
+            ServerError::DatabaseError(_) => "DATABASE_ERROR",
+            ServerError::Other(_) => "INTERNAL_ERROR",
+        }
+    }
+}
+
 impl Reject for ServerError {}
 
+/// Running out of context is up to the caller to fix, anything else is on the LLM.
+impl From<LLMError> for ServerError {
+    fn from(err: LLMError) -> Self {
+        match err {
+            LLMError::ContextLengthExceeded(_) => ServerError::BadRequest(err.to_string()),
+            err => ServerError::Upstream(err.to_string()),
+        }
+    }
+}
+
+/// Templates are checked on startup, so failing to render one is a bug.
+impl From<prompter::PromptError> for ServerError {
+    fn from(err: prompter::PromptError) -> Self {
+        ServerError::Other(err.to_string())
+    }
+}
+
 pub struct ApiConfig {
     pub host: Ipv4Addr,
     pub port: u16,
     pub db_uri: String,
     pub open_ai_key: Option<String>,
+    /// Base URL & extra headers for the OpenAI API.
+    pub open_ai_options: OpenAIOptions,
     pub local_llm_config: Option<String>,
+    /// Where uploaded files are stored, defaults to `endpoints::DEFAULT_UPLOAD_DIR`
+    pub upload_dir: Option<String>,
+    /// Path to the pdftotext binary, defaults to `endpoints::DEFAULT_PDFTOTEXT_PATH`
+    pub pdftotext_path: Option<String>,
+    /// Embedding settings used for search queries, should match the worker's.
+    pub model_config: ModelConfig,
+    /// Max # of characters in a single document, defaults to `endpoints::DEFAULT_MAX_DOCUMENT_CHARS`
+    pub max_document_chars: Option<usize>,
+    /// Create collections w/ the default settings when documents are added to
+    /// them, otherwise they have to be created first.
+    pub auto_create_collections: bool,
+    /// Cache search results for this long, search results aren't cached
+    /// when unset.
+    pub search_cache_ttl: Option<Duration>,
+    /// Max # of cached search results, defaults to `libmemex::cache::DEFAULT_SEARCH_CACHE_SIZE`
+    pub search_cache_size: Option<usize>,
+    /// Only serve routes that don't add, change or delete anything.
+    pub read_only: bool,
+    /// Limits on URLs fetched w/ `/api/fetch`, see `endpoints::FetchConfig`.
+    pub fetch_timeout: Option<Duration>,
+    pub fetch_max_redirects: Option<usize>,
+    pub fetch_max_bytes: Option<usize>,
+    pub fetch_allowed_hosts: Vec<String>,
+    pub fetch_internal_hosts: Vec<String>,
+    /// Don't make sure the LLM is usable before serving requests.
+    pub skip_llm_check: bool,
 }
 
 // Handle custom errors/rejections
 async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
     let code;
+    let error: &str;
     let message: String;
 
     if err.is_not_found() {
         code = StatusCode::NOT_FOUND;
+        error = "NOT_FOUND";
         message = "NOT_FOUND".into();
     } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
         // We can handle a specific error, here METHOD_NOT_ALLOWED,
         // and render it however we want
         code = StatusCode::METHOD_NOT_ALLOWED;
+        error = "METHOD_NOT_ALLOWED";
         message = "METHOD_NOT_ALLOWED".into();
     } else if let Some(err) = err.find::<ServerError>() {
-        (code, message) = match err {
-            ServerError::ClientRequestError(err) => (StatusCode::BAD_REQUEST, err.to_string()),
-            ServerError::DatabaseError(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
-            ServerError::Other(err) => (StatusCode::BAD_REQUEST, err.to_string()),
+        code = err.status();
+        error = err.error_code();
+        message = match err {
+            ServerError::BadRequest(err)
+            | ServerError::NotFound(err)
+            | ServerError::Conflict(err)
+            | ServerError::Forbidden(err)
+            | ServerError::PayloadTooLarge(err)
+            | ServerError::Upstream(err)
+            | ServerError::UpstreamTimeout(err)
+            | ServerError::VectorStore(err)
+            | ServerError::Other(err) => err.to_string(),
+            ServerError::DatabaseError(err) => err.to_string(),
         };
+    } else if let Some(err) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        code = StatusCode::BAD_REQUEST;
+        error = "BAD_REQUEST";
+        message = err.to_string();
+    } else if let Some(err) = err.find::<warp::reject::InvalidQuery>() {
+        code = StatusCode::BAD_REQUEST;
+        error = "BAD_REQUEST";
+        message = err.to_string();
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        code = StatusCode::PAYLOAD_TOO_LARGE;
+        error = "PAYLOAD_TOO_LARGE";
+        message = "PAYLOAD_TOO_LARGE".into();
+    } else if err.find::<warp::reject::LengthRequired>().is_some() {
+        code = StatusCode::LENGTH_REQUIRED;
+        error = "LENGTH_REQUIRED";
+        message = "LENGTH_REQUIRED".into();
     } else {
         // We should have expected this... Just log and say its a 500
         eprintln!("unhandled rejection: {:?}", err);
         code = StatusCode::INTERNAL_SERVER_ERROR;
+        error = "UNHANDLED_REJECTION";
         message = "UNHANDLED_REJECTION".into();
     }
 
     let json = warp::reply::json(&ApiResponse::error(ErrorMessage {
         code: code.as_u16(),
+        error: error.to_string(),
         message,
     }));
 
     Ok(warp::reply::with_status(json, code))
 }
 
+#[derive(Deserialize)]
+struct HealthQuery {
+    /// Also check the vector store backing this collection.
+    collection: Option<String>,
+}
+
 // GET /health
-pub fn health_check() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
-{
-    let version = dotenv!("GIT_HASH");
+pub fn health_check(
+    db: &DatabaseConnection,
+    model_config: &ModelConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path!("api" / "health")
         .and(warp::get())
-        .map(move || warp::reply::json(&json!({ "version": version })))
+        .and(warp::query::<HealthQuery>())
+        .and(with_tenant())
+        .and(with_db(db.clone()))
+        .and(with_model_config(model_config.clone()))
+        .and_then(handle_health_check)
 }
 
-pub async fn start(config: ApiConfig) {
+async fn handle_health_check(
+    query: HealthQuery,
+    tenant: Tenant,
+    db: DatabaseConnection,
+    model_config: ModelConfig,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // Not ready until a worker running alongside the API has loaded its models.
+    let (worker, mut code) = match worker_status() {
+        Some(status) if status != WorkerStatus::Ready => {
+            (Some(status), StatusCode::SERVICE_UNAVAILABLE)
+        }
+        status => (status, StatusCode::OK),
+    };
+
+    let mut json = json!({ "version": dotenv!("GIT_HASH") });
+    if let Some(worker) = worker {
+        json["worker"] = json!(worker);
+    }
+
+    if let Some(collection) = query.collection {
+        let collection = tenant.collection(&collection)?;
+        match endpoints::collection_health(&db, &collection, model_config).await {
+            Ok(health) => {
+                if health.status == StoreStatus::Red {
+                    code = StatusCode::SERVICE_UNAVAILABLE;
+                }
+                json["vectorStore"] = json!(health);
+            }
+            Err(err @ ServerError::NotFound(_)) => return Err(warp::reject::custom(err)),
+            Err(err) => {
+                code = StatusCode::SERVICE_UNAVAILABLE;
+                json["vectorStore"] = json!({ "error": err.to_string() });
+            }
+        }
+    }
+
+    Ok(warp::reply::with_status(warp::reply::json(&json), code))
+}
+
+// GET /api/metrics
+pub fn metrics() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("api" / "metrics").and(warp::get()).map(|| {
+        let search_cache = endpoints::search_cache().map(|cache| cache.stats());
+        warp::reply::json(&json!({ "searchCache": search_cache }))
+    })
+}
+
+// GET /api/model
+pub fn model_info(
+    model_config: &ModelConfig,
+    llm: &Arc<Box<dyn LLM>>,
+    llm_backend: &str,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let info = json!(schema::ModelInfo {
+        embedding: model_config.into(),
+        llm: schema::LlmInfo {
+            backend: llm_backend.to_string(),
+            model: llm.default_model().to_string(),
+            context_window: llm.context_window(""),
+        },
+    });
+
+    warp::path!("api" / "model")
+        .and(warp::get())
+        .map(move || warp::reply::json(&info))
+}
+
+/// Drop cached search results for collections as their tasks finish. Only
+/// sees tasks run by a worker in this process, results from other workers
+/// are reused until they expire.
+async fn invalidate_finished(db: DatabaseConnection) {
+    let mut finished = queue::subscribe_finished();
+    loop {
+        match finished.recv().await {
+            Ok(id) => {
+                if let Ok(Some(task)) = queue::Entity::find_by_id(id).one(&db).await {
+                    endpoints::invalidate_search_cache(&task.collection);
+                }
+            }
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {
+                // Missed some, so don't risk serving stale results.
+                if let Some(cache) = endpoints::search_cache() {
+                    cache.clear();
+                }
+            }
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+pub async fn start(config: ApiConfig) -> anyhow::Result<()> {
     log::info!("starting api server @ {}:{}", config.host, config.port);
 
+    let mut upload_config = UploadConfig::default();
+    if let Some(upload_dir) = config.upload_dir {
+        upload_config.upload_dir = upload_dir.into();
+    }
+    if let Some(pdftotext_path) = config.pdftotext_path {
+        upload_config.pdftotext_path = pdftotext_path.into();
+    }
+
+    let mut ingest_config = IngestConfig {
+        auto_create_collections: config.auto_create_collections,
+        ..Default::default()
+    };
+    if let Some(max_document_chars) = config.max_document_chars {
+        ingest_config.max_document_chars = max_document_chars;
+    }
+
+    let mut fetch_config = FetchConfig {
+        allowed_hosts: config.fetch_allowed_hosts,
+        internal_hosts: config.fetch_internal_hosts,
+        ..Default::default()
+    };
+    if let Some(timeout) = config.fetch_timeout {
+        fetch_config.timeout = timeout;
+    }
+    if let Some(max_redirects) = config.fetch_max_redirects {
+        fetch_config.max_redirects = max_redirects;
+    }
+    if let Some(max_bytes) = config.fetch_max_bytes {
+        fetch_config.max_bytes = max_bytes;
+    }
+    let fetcher = Fetcher::new(fetch_config)
+        .map_err(|err| anyhow::anyhow!("Unable to build fetch client: {err}"))?;
+
     log::info!("checking for upload directory...");
-    let data_dir_path: PathBuf = endpoints::UPLOAD_DATA_DIR.into();
+    let data_dir_path = &upload_config.upload_dir;
     if !data_dir_path.exists() {
         log::info!("creating upload directory @ {data_dir_path:?}");
         let _ = std::fs::create_dir_all(data_dir_path);
     }
 
+    if !data_dir_path.is_dir() {
+        panic!("Upload directory is not a valid directory: {data_dir_path:?}");
+    }
+
+    if !upload_config.pdftotext_path.is_file() {
+        log::error!(
+            "pdftotext binary not found @ {:?}, PDF parsing will be unavailable",
+            upload_config.pdftotext_path
+        );
+    }
+
     // Attempt to connect to db
     let db_connection = create_connection_by_uri(&config.db_uri, true)
         .await
         .unwrap_or_else(|err| panic!("Unable to connect to database: {} - {err}", config.db_uri));
-
-    let llm_client: Arc<Box<dyn LLM>> = if let Some(openai_key) = config.open_ai_key {
-        Arc::new(Box::new(OpenAIClient::new(&openai_key)))
-    } else if let Some(llm_config_path) = config.local_llm_config {
-        let llm = load_from_cfg(llm_config_path.into(), true)
+    // Wait for the vector db here, connecting to it per request only tries once.
+    if let Ok(vector_uri) = std::env::var("VECTOR_CONNECTION") {
+        storage::wait_for_vector_storage(&vector_uri)
             .await
-            .expect("Unable to load local LLM");
-        Arc::new(llm)
-    } else {
-        panic!("Please setup OPENAI_API_KEY or LOCAL_LLM_CONFIG");
-    };
+            .unwrap_or_else(|err| panic!("Unable to connect to vector db: {err}"));
+    }
+
+    let (llm_client, llm_backend): (Arc<Box<dyn LLM>>, _) =
+        if let Some(openai_key) = config.open_ai_key {
+            let client = OpenAIClient::new(&openai_key, &config.open_ai_options);
+            (Arc::new(Box::new(client)), "openai")
+        } else if let Some(llm_config_path) = config.local_llm_config {
+            let llm = load_from_cfg(llm_config_path.into(), true)
+                .await
+                .expect("Unable to load local LLM");
+            (Arc::new(llm), "local")
+        } else {
+            panic!("Please setup OPENAI_API_KEY or LOCAL_LLM_CONFIG");
+        };
+
+    // Catch a bad API key or model now rather than on the first request.
+    if !config.skip_llm_check {
+        let model = llm_client.default_model().to_string();
+        llm_client.check().await.map_err(|err| {
+            anyhow::anyhow!("Unable to use the {llm_backend} LLM w/ {model}: {err}")
+        })?;
+        log::info!("{llm_backend} LLM is ready w/ {model}");
+    }
+
+    if let Some(ttl) = config.search_cache_ttl {
+        let size = config
+            .search_cache_size
+            .unwrap_or(DEFAULT_SEARCH_CACHE_SIZE);
+        log::info!("caching up to {size} search results for {ttl:?}");
+        endpoints::enable_search_cache(ttl, size);
+        tokio::spawn(invalidate_finished(db_connection.clone()));
+    }
+
+    if config.read_only {
+        log::info!("read-only mode, routes that change collections or tasks are disabled");
+    }
 
     let cors = warp::cors()
         .allow_any_origin()
         .allow_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"])
-        .allow_headers(["Authorization", "Content-Type"]);
+        .allow_headers([
+            "Authorization",
+            "Content-Type",
+            "X-Tenant-Id",
+            "Idempotency-Key",
+        ]);
 
     let api = warp::path("api")
-        .and(endpoints::build(&db_connection, &llm_client))
+        .and(endpoints::build(
+            &db_connection,
+            &llm_client,
+            &upload_config,
+            &ingest_config,
+            &fetcher,
+            &config.model_config,
+            config.read_only,
+        ))
         .with(warp::trace::request());
 
-    let filters = health_check().or(api).with(cors).recover(handle_rejection);
+    let filters = health_check(&db_connection, &config.model_config)
+        .or(metrics())
+        .or(model_info(&config.model_config, &llm_client, llm_backend))
+        .or(api)
+        .with(cors)
+        .recover(handle_rejection);
 
     let (_addr, handle) =
         warp::serve(filters).bind_with_graceful_shutdown((config.host, config.port), async move {
@@ -121,6 +458,8 @@ pub async fn start(config: ApiConfig) {
         });
 
     handle.await;
+    libmemex::storage::close_all().await;
+    Ok(())
 }
 
 /// Filter that will clone the db for use in handlers
@@ -135,3 +474,37 @@ pub fn with_llm(
 ) -> impl Filter<Extract = (Arc<Box<dyn LLM>>,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || llm.clone())
 }
+
+/// Filter that grabs the trace id for a request from the `X-Request-Id` header,
+/// generating a new one if it's missing (or unreasonably long).
+pub fn with_trace_id() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("x-request-id").map(|id: Option<String>| match id {
+        Some(id) if !id.is_empty() && id.len() <= MAX_TRACE_ID_LEN => id,
+        _ => uuid::Uuid::new_v4().to_string(),
+    })
+}
+
+/// Filter that grabs the tenant for a request from the `X-Tenant-Id` header,
+/// rejecting invalid tenant ids.
+pub fn with_tenant() -> impl Filter<Extract = (Tenant,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("x-tenant-id")
+        .and_then(|id: Option<String>| async move { Tenant::new(id).map_err(warp::reject::custom) })
+}
+
+pub fn with_model_config(
+    model_config: ModelConfig,
+) -> impl Filter<Extract = (ModelConfig,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || model_config.clone())
+}
+
+pub fn with_ingest_config(
+    ingest_config: IngestConfig,
+) -> impl Filter<Extract = (IngestConfig,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || ingest_config.clone())
+}
+
+pub fn with_upload_config(
+    upload_config: UploadConfig,
+) -> impl Filter<Extract = (UploadConfig,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || upload_config.clone())
+}
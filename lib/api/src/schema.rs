@@ -1,20 +1,69 @@
 use std::time::Duration;
 
 use chrono::Utc;
-use libmemex::db;
+use libmemex::{
+    db,
+    llm::{embedding::ModelConfig, SamplerPreset},
+    storage::{distance_from_similarity, DistanceMetric},
+};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::tenant::Tenant;
+
 /// An API error serializable to JSON.
 #[derive(Serialize)]
 pub struct ErrorMessage {
+    /// HTTP status code
     pub code: u16,
+    /// Machine-readable error code, e.g. "NOT_FOUND"
+    pub error: String,
     pub message: String,
 }
 
 #[derive(Deserialize)]
 pub struct InsertDocumentRequest {
     pub content: String,
+    /// Optional stable id, adding a document w/ the same id replaces the old one.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Optional JSON schema of metadata (title, author, etc.) to extract from
+    /// the document w/ the LLM after it's been embedded.
+    #[serde(default, rename = "metadataSchema")]
+    pub metadata_schema: Option<serde_json::Value>,
+    /// Number of times the ingest task is retried on failure.
+    #[serde(default, rename = "maxRetries")]
+    pub max_retries: Option<i32>,
+    /// Split documents that are over the size limit into multiple documents
+    /// instead of rejecting them.
+    #[serde(default, rename = "autoSplit")]
+    pub auto_split: bool,
+    /// Skip segments that are near-identical to one already in the collection,
+    /// i.e. w/ a similarity in [0, 1] at or above this threshold.
+    #[serde(default, rename = "dedupThreshold")]
+    pub dedup_threshold: Option<f32>,
+}
+
+#[derive(Deserialize)]
+pub struct InsertSegmentsRequest {
+    /// Optional stable id, adding a document w/ the same id replaces the old one.
+    #[serde(default, alias = "document_id")]
+    pub id: Option<String>,
+    /// Chunks to embed as-is, in order, instead of segmenting the document.
+    pub segments: Vec<String>,
+    /// Number of times the ingest task is retried on failure.
+    #[serde(default, rename = "maxRetries")]
+    pub max_retries: Option<i32>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct WaitQuery {
+    /// Respond once the task is finished instead of right after it's queued.
+    #[serde(default)]
+    pub wait: bool,
+    /// Max # of seconds to wait for, defaults to 30.
+    #[serde(default)]
+    pub timeout: Option<u64>,
 }
 
 #[derive(Deserialize, Default)]
@@ -22,6 +71,31 @@ pub struct SearchDocsRequest {
     pub query: String,
     #[serde(default = "SearchDocsRequest::default_limit")]
     pub limit: u64,
+    /// Trim each result's content to roughly this many characters, the full
+    /// content can be fetched w/ the document endpoint.
+    #[serde(default)]
+    pub snippet_length: Option<usize>,
+    /// Re-rank results w/ maximal marginal relevance, from 0.0 (relevance
+    /// only) to 1.0 (diversity only). The MMR lambda is `1.0 - diversity`.
+    #[serde(default)]
+    pub diversity: Option<f32>,
+    /// Mark the sentence in each result that best matches the query.
+    #[serde(default)]
+    pub highlight: bool,
+    /// Reweight results based on their documents, e.g. to favor recent ones.
+    #[serde(default)]
+    pub scoring: Option<ScoringRequest>,
+    /// Also return up to this many segments before & after each result.
+    #[serde(default)]
+    pub context_window: Option<usize>,
+    /// Collapse results that mostly overlap w/ a better scoring, adjacent
+    /// segment from the same document. May return fewer than `limit` results.
+    #[serde(default)]
+    pub collapse_overlapping: bool,
+    /// Say why there are fewer than `limit` results. Costs an extra vector
+    /// search & count, so it's off by default.
+    #[serde(default)]
+    pub diagnostics: bool,
 }
 
 impl SearchDocsRequest {
@@ -30,18 +104,512 @@ impl SearchDocsRequest {
     }
 }
 
+#[derive(Deserialize, Default)]
+pub struct AskCollectionRequest {
+    pub query: String,
+    /// Max # of segments retrieved as context, only as many as fit in the
+    /// LLM's context window are used.
+    #[serde(default = "AskCollectionRequest::default_limit")]
+    pub limit: u64,
+    /// Maximum number of tokens to generate, these are set aside from the
+    /// context window.
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub preset: Option<SamplerPreset>,
+    /// Stop generating once any of these sequences is produced.
+    #[serde(default)]
+    pub stop: Vec<String>,
+}
+
+impl AskCollectionRequest {
+    fn default_limit() -> u64 {
+        20
+    }
+}
+
+#[derive(Serialize)]
+pub struct AskCollectionResult {
+    pub answer: String,
+    pub model: String,
+    /// Segments used as context, best first. The last one may have been
+    /// truncated to fit.
+    pub sources: Vec<DocumentSegment>,
+}
+
+/// How `DocumentSegment::score` is reported.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScoreType {
+    /// Similarity in [0, 1], higher is better.
+    #[default]
+    Similarity,
+    /// Cosine distance in [0, 2], lower is better.
+    Distance,
+}
+
+impl ScoreType {
+    /// Report a similarity score from the vector store as this score type.
+    pub fn convert(&self, similarity: f32) -> f32 {
+        match self {
+            ScoreType::Similarity => similarity,
+            ScoreType::Distance => distance_from_similarity(similarity),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct SearchScoreQuery {
+    #[serde(default)]
+    pub score: ScoreType,
+    /// Return the query's embedding w/ the results, so it can be reused w/
+    /// the vector search endpoint.
+    #[serde(default)]
+    pub include_vector: bool,
+}
+
+#[derive(Deserialize)]
+pub struct SearchVectorRequest {
+    /// Pre-computed query embedding, must match the collection's dimension.
+    pub vector: Vec<f32>,
+    #[serde(default = "SearchDocsRequest::default_limit")]
+    pub limit: u64,
+    /// See `SearchDocsRequest::diversity`
+    #[serde(default)]
+    pub diversity: Option<f32>,
+    /// See `SearchDocsRequest::scoring`
+    #[serde(default)]
+    pub scoring: Option<ScoringRequest>,
+}
+
+#[derive(Deserialize)]
+pub struct SearchBatchRequest {
+    /// Queries to search for, each gets its own set of results.
+    pub queries: Vec<String>,
+    #[serde(default = "SearchDocsRequest::default_limit")]
+    pub limit: u64,
+    /// See `SearchDocsRequest::diversity`
+    #[serde(default)]
+    pub diversity: Option<f32>,
+    /// See `SearchDocsRequest::scoring`
+    #[serde(default)]
+    pub scoring: Option<ScoringRequest>,
+}
+
+#[derive(Serialize)]
+pub struct SearchBatchResult {
+    /// Results for each query, in the same order as the queries.
+    pub results: Vec<SearchResult>,
+}
+
+/// Score modifier applied to search results, see `libmemex::storage::scoring`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ScoringRequest {
+    /// Decay scores by the age of each result's document.
+    Recency {
+        /// Age at which half of the weighted score is lost, e.g. "30d"
+        #[serde(rename = "halfLife")]
+        half_life: String,
+        /// Share of the score subject to decay, from 0.0 to 1.0.
+        #[serde(default = "ScoringRequest::default_weight")]
+        weight: f32,
+    },
+}
+
+impl ScoringRequest {
+    fn default_weight() -> f32 {
+        0.5
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SearchCountRequest {
+    pub query: String,
+    /// Only count results w/ a score at or above this threshold.
+    #[serde(default)]
+    pub min_score: f32,
+    /// Max number of nearest neighbors to consider, at most 10,000.
+    #[serde(default = "SearchCountRequest::default_limit")]
+    pub limit: u64,
+}
+
+impl SearchCountRequest {
+    fn default_limit() -> u64 {
+        1000
+    }
+}
+
+#[derive(Serialize)]
+pub struct SearchCountResult {
+    /// # of documents w/ at least one matching segment.
+    pub count: usize,
+    /// # of matching segments.
+    pub segments: usize,
+    /// Every neighbor considered matched, so there may be more past `limit`.
+    pub truncated: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DeleteDocumentsRequest {
+    /// Remove documents older than this duration, e.g. "30d", "12h", "15m"
+    pub older_than: String,
+}
+
+/// A single line of a collection export.
+#[derive(Serialize, Deserialize)]
+pub struct ExportedDocument {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+    #[serde(default)]
+    pub segments: Vec<ExportedSegment>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportedSegment {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    pub segment: i64,
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vector: Option<Vec<f32>>,
+    /// Character range of the segment in the document's content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset_start: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset_end: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct ImportRequest {
+    /// Ignore any exported vectors & generate new embeddings.
+    #[serde(default)]
+    pub reembed: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ReindexRequest {
+    /// Embedding model to re-embed the collection w/, e.g. "AllMiniLmL6V2"
+    pub model: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigureCollectionRequest {
+    /// Embedding model, e.g. "AllMiniLmL6V2". Defaults to the server's model.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// How vectors are compared, only "cosine" is supported for now.
+    #[serde(default)]
+    pub metric: Option<String>,
+    /// Max # of tokens per segment.
+    #[serde(default)]
+    pub max_length: Option<usize>,
+    /// # of tokens each segment overlaps w/ the previous one.
+    #[serde(default)]
+    pub stride: Option<usize>,
+    /// Keep each document's full content, defaults to true. Set to false to
+    /// only store segments.
+    #[serde(default)]
+    pub store_content: Option<bool>,
+    /// L2 normalize the collection's vectors, defaults to the server's
+    /// `NORMALIZE_EMBEDDINGS` setting.
+    #[serde(default)]
+    pub normalize: Option<bool>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CollectionResult {
+    pub name: String,
+    pub model: String,
+    pub dimension: usize,
+    pub metric: String,
+    pub max_length: usize,
+    pub stride: usize,
+    pub store_content: bool,
+    pub normalize: bool,
+    /// Missing for collections created before settings were stored.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<chrono::DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<chrono::DateTime<Utc>>,
+}
+
+impl CollectionResult {
+    pub fn new(
+        name: String,
+        settings: Option<&db::collection::Model>,
+        model_config: &ModelConfig,
+    ) -> Self {
+        Self {
+            name,
+            model: model_config.model().to_string(),
+            dimension: model_config.model().dimension(),
+            metric: settings
+                .map(|settings| settings.metric.clone())
+                .unwrap_or_else(|| DistanceMetric::default().to_string()),
+            max_length: model_config.max_length(),
+            stride: model_config.stride(),
+            store_content: settings
+                .map(|settings| settings.store_content)
+                .unwrap_or(true),
+            normalize: model_config.is_normalized(),
+            created_at: settings.map(|settings| settings.created_at),
+            updated_at: settings.map(|settings| settings.updated_at),
+        }
+    }
+}
+
+/// Models the server is running w/, for `GET /api/model`.
+#[derive(Serialize)]
+pub struct ModelInfo {
+    /// Default embedding settings, collections may be configured w/ their own.
+    pub embedding: EmbeddingModelInfo,
+    pub llm: LlmInfo,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmbeddingModelInfo {
+    pub model: String,
+    pub dimension: usize,
+    pub max_length: usize,
+    pub stride: usize,
+    /// How text longer than `max_length` is embedded.
+    pub segment_policy: String,
+}
+
+impl From<&ModelConfig> for EmbeddingModelInfo {
+    fn from(model_config: &ModelConfig) -> Self {
+        Self {
+            model: model_config.model().to_string(),
+            dimension: model_config.model().dimension(),
+            max_length: model_config.max_length(),
+            stride: model_config.stride(),
+            segment_policy: model_config.segment_policy().to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LlmInfo {
+    /// "openai" or "local"
+    pub backend: String,
+    pub model: String,
+    pub context_window: usize,
+}
+
+#[derive(Serialize)]
+pub struct ImportResult {
+    /// Documents imported w/ their existing vectors.
+    pub imported: usize,
+    /// Documents queued up to be re-embedded.
+    pub queued: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentResult {
+    pub uuid: String,
+    pub content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+    pub created_at: chrono::DateTime<Utc>,
+}
+
+impl From<db::document::Model> for DocumentResult {
+    fn from(value: db::document::Model) -> Self {
+        DocumentResult {
+            uuid: value.uuid,
+            content: value.content,
+            metadata: value.metadata,
+            created_at: value.created_at,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct DocumentSegmentsQuery {
+    /// Also return each segment's embedding.
+    #[serde(default)]
+    pub include_vectors: bool,
+}
+
+/// A segment stored for a document, as it was embedded.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredSegment {
+    pub uuid: String,
+    pub segment: i64,
+    pub content: String,
+    /// Whether the segment has made it into the vector store.
+    pub indexed: bool,
+    /// Character range (end exclusive) of the segment in the document's
+    /// content, for segments embedded w/ offsets.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset_start: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset_end: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vector: Option<Vec<f32>>,
+}
+
+impl StoredSegment {
+    pub fn new(value: db::embedding::Model, include_vector: bool) -> Self {
+        let (offset_start, offset_end) = value.offsets().unzip();
+        StoredSegment {
+            uuid: value.uuid,
+            segment: value.segment,
+            content: value.content,
+            indexed: value.indexed,
+            offset_start,
+            offset_end,
+            vector: match include_vector {
+                true => serde_json::from_value(value.vector).ok(),
+                false => None,
+            },
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct DocumentSegment {
     pub _id: String,
     pub document_id: String,
+    pub segment: i64,
+    /// Character range (end exclusive) of the segment in the document's
+    /// content, for highlighting it in the full document.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset_start: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset_end: Option<usize>,
+    pub content: String,
+    pub score: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub highlights: Option<Vec<Highlight>>,
+    /// Neighboring segments from the same document, if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<Vec<ContextSegment>>,
+    /// Segments collapsed into this one for overlapping w/ it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merged: Option<Vec<i64>>,
+}
+
+/// A segment surrounding a search result.
+#[derive(Serialize)]
+pub struct ContextSegment {
     pub segment: i64,
     pub content: String,
+}
+
+/// Part of a result's content that matched the query.
+#[derive(Serialize)]
+pub struct Highlight {
+    pub text: String,
+    /// Character offsets of the text within the result's content.
+    pub start: usize,
+    pub end: usize,
+    /// Similarity of the text to the query, in [0, 1].
     pub score: f32,
 }
 
 #[derive(Serialize)]
 pub struct SearchResult {
     pub results: Vec<DocumentSegment>,
+    /// Embedding of the query, if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vector: Option<Vec<f32>>,
+    /// Why there are fewer results than asked for, if there are.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diagnostics: Option<SearchDiagnostics>,
+}
+
+/// Returned w/ sparse search results to tell an empty collection apart from
+/// results that were filtered out.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchDiagnostics {
+    /// # of documents in the collection.
+    pub documents: u64,
+    /// # of nearest neighbors the vector store found, before any filtering.
+    pub neighbors: usize,
+    /// Similarity of the closest neighbor in [0, 1], before any reweighting.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_score: Option<f32>,
+}
+
+impl SearchResult {
+    /// Create a result set from segments already ordered by relevancy. Segments
+    /// w/ the same score are ordered by document & segment so that identical
+    /// queries always return results in the same order.
+    pub fn new(mut results: Vec<DocumentSegment>) -> Self {
+        let mut start = 0;
+        while start < results.len() {
+            let score = results[start].score;
+            let end = results[start..]
+                .iter()
+                .position(|seg| seg.score != score)
+                .map(|offset| start + offset)
+                .unwrap_or(results.len());
+
+            results[start..end].sort_by(|a, b| {
+                a.document_id
+                    .cmp(&b.document_id)
+                    .then(a.segment.cmp(&b.segment))
+            });
+            start = end;
+        }
+
+        Self {
+            results,
+            vector: None,
+            diagnostics: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SimilarityResult {
+    /// Similarity between the two texts in [0, 1], same as search scores.
+    pub similarity: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vectors: Option<Vec<Vec<f32>>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PreviewSegment {
+    pub content: String,
+    pub tokens: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SegmentPreviewResult {
+    /// Model the content was segmented for
+    pub model: String,
+    pub segments: Vec<PreviewSegment>,
+    /// Total # of tokens across all segments, overlapping text is counted once
+    /// per segment.
+    pub total_tokens: usize,
+    /// Estimated cost in USD of sending every segment to the LLM, for LLMs
+    /// billed per token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_cost: Option<f64>,
+}
+
+/// Tasks for each part of a document that was split up.
+#[derive(Serialize)]
+pub struct SplitDocumentResult {
+    pub tasks: Vec<TaskResult>,
 }
 
 #[derive(Serialize)]
@@ -53,16 +621,36 @@ pub struct TaskResult {
     created_at: chrono::DateTime<Utc>,
     #[serde(skip_serializing_if = "Option::is_none")]
     result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace_id: Option<String>,
+    /// UUID the ingested document is stored under, known as soon as it's queued.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    document_id: Option<String>,
+}
+
+impl TaskResult {
+    /// Show the collection as the tenant knows it.
+    pub fn for_tenant(mut self, tenant: &Tenant) -> Self {
+        self.collection = tenant.local_name(&self.collection);
+        self
+    }
 }
 
 impl From<db::queue::Model> for TaskResult {
     fn from(value: db::queue::Model) -> Self {
+        let document_id = match value.task_type {
+            db::queue::TaskType::Ingest => Some(value.ingest_document_uuid()),
+            _ => None,
+        };
+
         TaskResult {
             task_id: value.id,
             collection: value.collection,
             status: value.status.to_string(),
             created_at: value.created_at,
             result: value.task_output,
+            trace_id: value.trace_id,
+            document_id,
         }
     }
 }
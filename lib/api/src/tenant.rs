@@ -0,0 +1,169 @@
+use crate::ServerError;
+use libmemex::db::collection;
+use sea_orm::ConnectionTrait;
+
+/// Separates the tenant id from the collection name in stored collection names.
+pub const TENANT_SEPARATOR: &str = "__";
+const MAX_TENANT_LEN: usize = 64;
+
+/// Tenant a request belongs to, taken from the `X-Tenant-Id` header. Requests
+/// w/o a tenant use collection names as-is.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tenant(Option<String>);
+
+impl Tenant {
+    pub fn new(id: Option<String>) -> Result<Self, ServerError> {
+        let Some(id) = id else {
+            return Ok(Self(None));
+        };
+
+        let valid_char = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-';
+        if id.is_empty()
+            || id.len() > MAX_TENANT_LEN
+            || !id.chars().all(valid_char)
+            || id.starts_with('-')
+        {
+            return Err(ServerError::BadRequest(format!(
+                "Invalid tenant id: {id}, must be lowercase alphanumeric or '-'"
+            )));
+        }
+
+        Ok(Self(Some(id)))
+    }
+
+    /// Name of a collection as stored in the db & vector store. Since document
+    /// uuids are derived from the collection, this also keeps them unique
+    /// across tenants.
+    pub fn collection(&self, name: &str) -> Result<String, ServerError> {
+        // Otherwise a collection could be used to reach into another tenant.
+        if name.contains(TENANT_SEPARATOR) {
+            return Err(ServerError::BadRequest(format!(
+                "Collection names can't contain \"{TENANT_SEPARATOR}\""
+            )));
+        }
+
+        Ok(match &self.0 {
+            Some(tenant) => format!("{tenant}{TENANT_SEPARATOR}{name}"),
+            None => name.to_string(),
+        })
+    }
+
+    /// Like `collection`, for a collection that's about to be created. Names
+    /// are checked up front rather than failing deep inside the vector store,
+    /// but collections from before names were checked stay usable.
+    pub async fn new_collection<C>(&self, db: &C, name: &str) -> Result<String, ServerError>
+    where
+        C: ConnectionTrait,
+    {
+        let stored = self.collection(name)?;
+        if !collection::is_valid_name(name)
+            && !collection::exists(db, &stored)
+                .await
+                .map_err(ServerError::DatabaseError)?
+        {
+            return Err(ServerError::BadRequest(format!(
+                "Invalid collection name: {name:?}, must be 1-{} lowercase alphanumeric, '-' or '_' characters",
+                collection::MAX_NAME_LEN
+            )));
+        }
+
+        Ok(stored)
+    }
+
+    /// Check if a stored collection belongs to this tenant.
+    pub fn owns(&self, stored: &str) -> bool {
+        match &self.0 {
+            Some(tenant) => stored
+                .strip_prefix(tenant.as_str())
+                .and_then(|rest| rest.strip_prefix(TENANT_SEPARATOR))
+                .is_some(),
+            None => !stored.contains(TENANT_SEPARATOR),
+        }
+    }
+
+    /// Collection name as seen by the tenant.
+    pub fn local_name(&self, stored: &str) -> String {
+        match &self.0 {
+            Some(tenant) => stored
+                .strip_prefix(tenant.as_str())
+                .and_then(|rest| rest.strip_prefix(TENANT_SEPARATOR))
+                .unwrap_or(stored)
+                .to_string(),
+            None => stored.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Tenant;
+    use crate::ServerError;
+    use libmemex::db::{create_connection_by_uri, queue};
+
+    #[test]
+    fn test_new() {
+        assert_eq!(Tenant::new(None).unwrap(), Tenant::default());
+        assert!(Tenant::new(Some("acme-2".into())).is_ok());
+
+        let too_long = "a".repeat(65);
+        for id in ["", "Acme", "acme_co", "-acme", "ac me", &too_long] {
+            assert!(
+                matches!(
+                    Tenant::new(Some(id.into())),
+                    Err(ServerError::BadRequest(_))
+                ),
+                "{id} should be invalid"
+            );
+        }
+    }
+
+    #[test]
+    fn test_collection() {
+        let acme = Tenant::new(Some("acme".into())).unwrap();
+        let other = Tenant::new(Some("other".into())).unwrap();
+        let none = Tenant::default();
+
+        assert_eq!(acme.collection("test").unwrap(), "acme__test");
+        assert_eq!(none.collection("test").unwrap(), "test");
+        // Otherwise requests w/o a tenant could reach into one
+        assert!(none.collection("other__test").is_err());
+        assert!(acme.collection("a__b").is_err());
+
+        assert!(acme.owns("acme__test"));
+        assert!(!acme.owns("other__test"));
+        assert!(!acme.owns("acme-2__test"));
+        assert!(!acme.owns("test"));
+        assert!(none.owns("test"));
+        assert!(!none.owns("acme__test"));
+        assert!(!other.owns("acme__test"));
+
+        assert_eq!(acme.local_name("acme__test"), "test");
+        assert_eq!(none.local_name("test"), "test");
+    }
+
+    #[tokio::test]
+    async fn test_new_collection() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .unwrap();
+        let acme = Tenant::new(Some("acme".into())).unwrap();
+
+        assert_eq!(
+            acme.new_collection(&db, "my-docs").await.unwrap(),
+            "acme__my-docs"
+        );
+        assert!(matches!(
+            acme.new_collection(&db, "My Docs").await,
+            Err(ServerError::BadRequest(_))
+        ));
+
+        // Collections from before names were checked keep working
+        queue::enqueue(&db, "acme__My Docs", "content", queue::TaskType::Ingest)
+            .await
+            .unwrap();
+        assert_eq!(
+            acme.new_collection(&db, "My Docs").await.unwrap(),
+            "acme__My Docs"
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Default max # of cached search results.
+pub const DEFAULT_SEARCH_CACHE_SIZE: usize = 1_024;
+
+/// Identifies a search, results are only reused for the exact same search.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SearchKey {
+    collection: String,
+    query: String,
+    /// Everything else that changes the results, e.g. the limit.
+    options: String,
+}
+
+impl SearchKey {
+    pub fn new(collection: &str, query: &str, options: String) -> Self {
+        Self {
+            collection: collection.to_string(),
+            // Extra whitespace doesn't make for a different search.
+            query: query.split_whitespace().collect::<Vec<_>>().join(" "),
+            options,
+        }
+    }
+}
+
+struct Entry<V> {
+    value: V,
+    inserted: Instant,
+    last_used: Instant,
+}
+
+struct CacheState<V> {
+    entries: HashMap<SearchKey, Entry<V>>,
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Share of lookups that were hits, 0.0 before any lookups.
+    pub hit_rate: f64,
+}
+
+/// LRU cache of search results, each kept for at most `ttl`.
+pub struct SearchCache<V> {
+    ttl: Duration,
+    capacity: usize,
+    state: Mutex<CacheState<V>>,
+}
+
+impl<V: Clone> SearchCache<V> {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    pub fn get(&self, key: &SearchKey) -> Option<V> {
+        let mut state = self.state.lock().ok()?;
+        let now = Instant::now();
+
+        let expired = state
+            .entries
+            .get(key)
+            .map(|entry| now.duration_since(entry.inserted) >= self.ttl);
+        let value = match expired {
+            Some(false) => state.entries.get_mut(key).map(|entry| {
+                entry.last_used = now;
+                entry.value.clone()
+            }),
+            Some(true) => {
+                state.entries.remove(key);
+                None
+            }
+            None => None,
+        };
+
+        match value {
+            Some(_) => state.hits += 1,
+            None => state.misses += 1,
+        }
+        value
+    }
+
+    pub fn insert(&self, key: SearchKey, value: V) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        let now = Instant::now();
+
+        state
+            .entries
+            .retain(|_, entry| now.duration_since(entry.inserted) < self.ttl);
+        if !state.entries.contains_key(&key) && state.entries.len() >= self.capacity {
+            let oldest = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                state.entries.remove(&oldest);
+            }
+        }
+
+        if self.capacity > 0 {
+            state.entries.insert(
+                key,
+                Entry {
+                    value,
+                    inserted: now,
+                    last_used: now,
+                },
+            );
+        }
+    }
+
+    /// Drop every cached result for a collection, e.g. once it's changed.
+    pub fn invalidate(&self, collection: &str) {
+        if let Ok(mut state) = self.state.lock() {
+            state.entries.retain(|key, _| key.collection != collection);
+        }
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.entries.clear();
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let Ok(state) = self.state.lock() else {
+            return CacheStats {
+                entries: 0,
+                hits: 0,
+                misses: 0,
+                hit_rate: 0.0,
+            };
+        };
+
+        let lookups = state.hits + state.misses;
+        CacheStats {
+            entries: state.entries.len(),
+            hits: state.hits,
+            misses: state.misses,
+            hit_rate: match lookups {
+                0 => 0.0,
+                _ => state.hits as f64 / lookups as f64,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SearchCache, SearchKey};
+    use std::time::Duration;
+
+    #[test]
+    fn test_search_cache() {
+        let cache = SearchCache::new(Duration::from_secs(60), 2);
+        let key = SearchKey::new("test", "what is  memex?", "10".into());
+        assert_eq!(cache.get(&key), None);
+
+        cache.insert(key.clone(), 1);
+        assert_eq!(cache.get(&key), Some(1));
+        // Same query, different whitespace
+        assert_eq!(
+            cache.get(&SearchKey::new("test", " what is memex? ", "10".into())),
+            Some(1)
+        );
+        // Different options
+        assert_eq!(
+            cache.get(&SearchKey::new("test", "what is memex?", "20".into())),
+            None
+        );
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+        assert_eq!(stats.hit_rate, 0.5);
+
+        // Least recently used entry is evicted
+        let other = SearchKey::new("other", "what is memex?", "10".into());
+        cache.insert(other.clone(), 2);
+        assert_eq!(cache.get(&key), Some(1));
+        cache.insert(SearchKey::new("test", "newest", "10".into()), 3);
+        assert_eq!(cache.get(&other), None);
+        assert_eq!(cache.get(&key), Some(1));
+
+        cache.invalidate("test");
+        assert_eq!(cache.get(&key), None);
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[test]
+    fn test_search_cache_ttl() {
+        let cache = SearchCache::new(Duration::from_millis(20), 10);
+        let key = SearchKey::new("test", "query", "10".into());
+        cache.insert(key.clone(), 1);
+        assert_eq!(cache.get(&key), Some(1));
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(cache.get(&key), None);
+        assert_eq!(cache.stats().entries, 0);
+    }
+}
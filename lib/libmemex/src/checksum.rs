@@ -0,0 +1,92 @@
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ChecksumError {
+    #[error("Unable to read {path}: {error}")]
+    Unreadable {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    #[error("Checksum mismatch for {path}, expected sha256 {expected} but got {actual}. The file may be corrupted or only partially downloaded, delete it & try again.")]
+    Mismatch {
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+}
+
+/// SHA-256 of a file as lowercase hex. Read in chunks, since model files can
+/// be several GBs.
+pub fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Check a file against its expected SHA-256. When there's nothing to check
+/// against, the hash is logged so it can be pinned.
+pub fn verify_sha256(path: &Path, expected: Option<&str>) -> Result<(), ChecksumError> {
+    let actual = sha256_file(path).map_err(|error| ChecksumError::Unreadable {
+        path: path.to_path_buf(),
+        error,
+    })?;
+
+    match expected.map(|expected| expected.trim().to_lowercase()) {
+        Some(expected) if expected != actual => Err(ChecksumError::Mismatch {
+            path: path.to_path_buf(),
+            expected,
+            actual,
+        }),
+        Some(_) => {
+            log::info!("verified sha256 of {}", path.display());
+            Ok(())
+        }
+        None => {
+            log::info!("sha256 of {} is {actual}", path.display());
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sha256_file, verify_sha256, ChecksumError};
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn test_verify_sha256() {
+        let path = std::env::temp_dir().join("memex-checksum-test");
+        std::fs::write(&path, "hello").unwrap();
+
+        assert_eq!(sha256_file(&path).unwrap(), HELLO_SHA256);
+        assert!(verify_sha256(&path, None).is_ok());
+        assert!(verify_sha256(&path, Some(HELLO_SHA256)).is_ok());
+        assert!(verify_sha256(&path, Some(&HELLO_SHA256.to_uppercase())).is_ok());
+
+        // Truncated download
+        std::fs::write(&path, "hel").unwrap();
+        assert!(matches!(
+            verify_sha256(&path, Some(HELLO_SHA256)),
+            Err(ChecksumError::Mismatch { .. })
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            verify_sha256(&path, Some(HELLO_SHA256)),
+            Err(ChecksumError::Unreadable { .. })
+        ));
+    }
+}
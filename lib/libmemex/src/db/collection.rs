@@ -0,0 +1,469 @@
+use sea_orm::entity::prelude::*;
+use sea_orm::{sea_query::OnConflict, ConnectionTrait, PaginatorTrait, Set};
+use serde::Serialize;
+
+use crate::llm::embedding::{EmbeddingsModelType, ModelConfig};
+use crate::storage::DistanceMetric;
+
+/// Longest collection name allowed. Even w/ a tenant prefix this stays well
+/// under OpenSearch's 255 byte limit on index names.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Settings for a collection that differ from the defaults. Collections w/o a
+/// row use a vector index of the same name & the default embedding model.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Eq)]
+#[sea_orm(table_name = "collections")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub name: String,
+    /// Vector index the collection's embeddings are stored in, swapped out
+    /// when the collection is reindexed.
+    pub vector_index: String,
+    /// Model used to embed the collection's documents & queries.
+    pub embedding_model: Option<String>,
+    /// How vectors are compared, see `DistanceMetric`.
+    pub metric: String,
+    /// Segment size & overlap in tokens, overriding the model's defaults.
+    pub max_length: Option<i32>,
+    pub stride: Option<i32>,
+    /// Whether documents keep their full content or only their segments.
+    #[sea_orm(default_value = true)]
+    pub store_content: bool,
+    /// Whether vectors are L2 normalized, overriding the server's default.
+    pub normalize: Option<bool>,
+    pub created_at: DateTimeUtc,
+    pub updated_at: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter)]
+pub enum Relation {}
+
+impl RelationTrait for Relation {
+    fn def(&self) -> RelationDef {
+        unimplemented!("No relations")
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+    /// Apply the collection's model & segmentation settings to `base`.
+    pub fn model_config(&self, base: ModelConfig) -> ModelConfig {
+        let mut config = match self.embedding_model.as_ref().and_then(|m| m.parse().ok()) {
+            Some(model) => base.for_model(model),
+            None => base,
+        };
+
+        if let (Some(max_length), Some(stride)) = (self.max_length, self.stride) {
+            config = config.with_segmentation(max_length as usize, stride as usize);
+        }
+        if let Some(normalize) = self.normalize {
+            config = config.normalized(normalize);
+        }
+
+        config
+    }
+}
+
+/// Settings a collection is created or configured w/, `None` keeps the default.
+#[derive(Clone, Debug)]
+pub struct CollectionConfig {
+    pub embedding_model: Option<EmbeddingsModelType>,
+    pub metric: DistanceMetric,
+    /// Segment size & overlap in tokens.
+    pub segmentation: Option<(usize, usize)>,
+    /// Keep each document's full content. Without it only the segments are
+    /// stored, which saves space but documents can't be fetched in full.
+    pub store_content: bool,
+    /// L2 normalize vectors, `None` uses the server's default.
+    pub normalize: Option<bool>,
+}
+
+impl Default for CollectionConfig {
+    fn default() -> Self {
+        Self {
+            embedding_model: None,
+            metric: DistanceMetric::default(),
+            segmentation: None,
+            store_content: true,
+            normalize: None,
+        }
+    }
+}
+
+/// Whether a collection name is usable as-is in every vector store, i.e. as an
+/// OpenSearch index, a directory or a table name: lowercase alphanumeric, '-'
+/// or '_', starting w/ a letter or digit.
+pub fn is_valid_name(name: &str) -> bool {
+    let valid_char = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_';
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.chars().all(valid_char)
+        && !name.starts_with(['-', '_'])
+}
+
+/// Vector index a reindex task writes to before the collection is swapped
+/// over to it. '.' is never part of a valid collection name, so this can't
+/// be the index of another collection.
+pub fn reindex_index(collection: &str, task_id: i64) -> String {
+    format!("{collection}.reindex-{task_id}")
+}
+
+/// A collection's settings, if it's been created or configured.
+pub async fn get<C>(db: &C, collection: &str) -> Result<Option<Model>, DbErr>
+where
+    C: ConnectionTrait,
+{
+    Entity::find_by_id(collection).one(db).await
+}
+
+/// Whether a collection has been created or had anything added to it.
+pub async fn exists<C>(db: &C, collection: &str) -> Result<bool, DbErr>
+where
+    C: ConnectionTrait,
+{
+    if get(db, collection).await?.is_some() {
+        return Ok(true);
+    }
+
+    // Collections from before settings were stored only exist in the queue.
+    let tasks = super::queue::Entity::find()
+        .filter(super::queue::Column::Collection.eq(collection))
+        .count(db)
+        .await?;
+    Ok(tasks > 0)
+}
+
+/// Whether documents can be added to a collection under `name`: either it's a
+/// valid name or the collection is from before names were checked, in which
+/// case it keeps working.
+pub async fn is_usable_name<C>(db: &C, name: &str) -> Result<bool, DbErr>
+where
+    C: ConnectionTrait,
+{
+    Ok(is_valid_name(name) || exists(db, name).await?)
+}
+
+/// Create or update a collection's settings. The vector index is left alone,
+/// that only changes when the collection is reindexed.
+pub async fn configure<C>(
+    db: &C,
+    collection: &str,
+    config: CollectionConfig,
+) -> Result<Model, DbErr>
+where
+    C: ConnectionTrait,
+{
+    let now = chrono::Utc::now();
+    let (max_length, stride) = match config.segmentation {
+        Some((max_length, stride)) => (Some(max_length as i32), Some(stride as i32)),
+        None => (None, None),
+    };
+    let row = ActiveModel {
+        name: Set(collection.to_string()),
+        vector_index: Set(collection.to_string()),
+        embedding_model: Set(config.embedding_model.map(|model| model.to_string())),
+        metric: Set(config.metric.to_string()),
+        max_length: Set(max_length),
+        stride: Set(stride),
+        store_content: Set(config.store_content),
+        normalize: Set(config.normalize),
+        created_at: Set(now),
+        updated_at: Set(now),
+    };
+
+    Entity::insert(row)
+        .on_conflict(
+            OnConflict::column(Column::Name)
+                .update_columns([
+                    Column::EmbeddingModel,
+                    Column::Metric,
+                    Column::MaxLength,
+                    Column::Stride,
+                    Column::StoreContent,
+                    Column::Normalize,
+                    Column::UpdatedAt,
+                ])
+                .to_owned(),
+        )
+        .exec_without_returning(db)
+        .await?;
+
+    Entity::find_by_id(collection)
+        .one(db)
+        .await?
+        .ok_or_else(|| DbErr::RecordNotFound(collection.to_string()))
+}
+
+/// Create a collection w/ the default settings, if it doesn't have any yet.
+pub async fn create_default<C>(db: &C, collection: &str) -> Result<(), DbErr>
+where
+    C: ConnectionTrait,
+{
+    let now = chrono::Utc::now();
+    let row = ActiveModel {
+        name: Set(collection.to_string()),
+        vector_index: Set(collection.to_string()),
+        embedding_model: Set(None),
+        metric: Set(DistanceMetric::default().to_string()),
+        max_length: Set(None),
+        stride: Set(None),
+        store_content: Set(true),
+        normalize: Set(None),
+        created_at: Set(now),
+        updated_at: Set(now),
+    };
+
+    Entity::insert(row)
+        .on_conflict(OnConflict::column(Column::Name).do_nothing().to_owned())
+        .exec_without_returning(db)
+        .await?;
+
+    Ok(())
+}
+
+/// The embedding settings for a collection, `base` w/ any of the collection's
+/// settings applied.
+pub async fn model_config<C>(
+    db: &C,
+    collection: &str,
+    base: ModelConfig,
+) -> Result<ModelConfig, DbErr>
+where
+    C: ConnectionTrait,
+{
+    Ok(match get(db, collection).await? {
+        Some(model) => model.model_config(base),
+        None => base,
+    })
+}
+
+/// Whether a collection keeps its documents' full content, which is the
+/// default for collections w/o settings.
+pub async fn stores_content<C>(db: &C, collection: &str) -> Result<bool, DbErr>
+where
+    C: ConnectionTrait,
+{
+    Ok(get(db, collection)
+        .await?
+        .map(|model| model.store_content)
+        .unwrap_or(true))
+}
+
+/// Name of the vector index holding a collection's embeddings.
+pub async fn vector_index<C>(db: &C, collection: &str) -> Result<String, DbErr>
+where
+    C: ConnectionTrait,
+{
+    Ok(Entity::find_by_id(collection)
+        .one(db)
+        .await?
+        .map(|model| model.vector_index)
+        .unwrap_or_else(|| collection.to_string()))
+}
+
+/// Embedding model a collection was indexed w/, if it's not the default.
+pub async fn embedding_model<C>(
+    db: &C,
+    collection: &str,
+) -> Result<Option<EmbeddingsModelType>, DbErr>
+where
+    C: ConnectionTrait,
+{
+    Ok(Entity::find_by_id(collection)
+        .one(db)
+        .await?
+        .and_then(|model| model.embedding_model)
+        .and_then(|model| model.parse().ok()))
+}
+
+/// Point a collection at a new vector index & model in one go, returning the
+/// index it used before.
+pub async fn swap_index<C>(
+    db: &C,
+    collection: &str,
+    index: &str,
+    model: EmbeddingsModelType,
+) -> Result<String, DbErr>
+where
+    C: ConnectionTrait,
+{
+    let previous = vector_index(db, collection).await?;
+    let now = chrono::Utc::now();
+    let row = ActiveModel {
+        name: Set(collection.to_string()),
+        vector_index: Set(index.to_string()),
+        embedding_model: Set(Some(model.to_string())),
+        metric: Set(DistanceMetric::default().to_string()),
+        max_length: Set(None),
+        stride: Set(None),
+        store_content: Set(true),
+        normalize: Set(None),
+        created_at: Set(now),
+        updated_at: Set(now),
+    };
+
+    Entity::insert(row)
+        .on_conflict(
+            OnConflict::column(Column::Name)
+                .update_columns([
+                    Column::VectorIndex,
+                    Column::EmbeddingModel,
+                    Column::UpdatedAt,
+                ])
+                .to_owned(),
+        )
+        .exec_without_returning(db)
+        .await?;
+
+    Ok(previous)
+}
+
+/// Forget a collection's settings, e.g. once it's been deleted.
+pub async fn remove<C>(db: &C, collection: &str) -> Result<(), DbErr>
+where
+    C: ConnectionTrait,
+{
+    Entity::delete_by_id(collection).exec(db).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        configure, create_default, embedding_model, exists, get, is_usable_name, is_valid_name,
+        model_config, reindex_index, remove, stores_content, swap_index, vector_index,
+        CollectionConfig,
+    };
+    use crate::db::{create_connection_by_uri, queue};
+    use crate::llm::embedding::{EmbeddingsModelType, ModelConfig};
+
+    #[tokio::test]
+    async fn test_swap_index() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .expect("Unable to connect");
+
+        // Defaults to an index w/ the same name
+        assert_eq!(vector_index(&db, "test").await.unwrap(), "test");
+        assert_eq!(embedding_model(&db, "test").await.unwrap(), None);
+
+        let previous = swap_index(&db, "test", "test-1", EmbeddingsModelType::AllMiniLmL6V2)
+            .await
+            .unwrap();
+        assert_eq!(previous, "test");
+        assert_eq!(vector_index(&db, "test").await.unwrap(), "test-1");
+        assert_eq!(
+            embedding_model(&db, "test").await.unwrap(),
+            Some(EmbeddingsModelType::AllMiniLmL6V2)
+        );
+
+        let previous = swap_index(&db, "test", "test-2", EmbeddingsModelType::AllMiniLmL12V2)
+            .await
+            .unwrap();
+        assert_eq!(previous, "test-1");
+        assert_eq!(vector_index(&db, "test").await.unwrap(), "test-2");
+
+        remove(&db, "test").await.unwrap();
+        assert_eq!(vector_index(&db, "test").await.unwrap(), "test");
+    }
+
+    #[tokio::test]
+    async fn test_configure() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .expect("Unable to connect");
+
+        assert!(!exists(&db, "test").await.unwrap());
+        let config = CollectionConfig {
+            embedding_model: Some(EmbeddingsModelType::AllMiniLmL6V2),
+            segmentation: Some((128, 32)),
+            normalize: Some(true),
+            ..Default::default()
+        };
+        let created = configure(&db, "test", config).await.unwrap();
+        assert!(exists(&db, "test").await.unwrap());
+        assert_eq!(created.vector_index, "test");
+        assert_eq!(created.metric, "cosine");
+        assert!(stores_content(&db, "test").await.unwrap());
+
+        let applied = model_config(&db, "test", ModelConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(applied.model(), EmbeddingsModelType::AllMiniLmL6V2);
+        assert_eq!(applied.max_length(), 128);
+        assert_eq!(applied.stride(), 32);
+        assert!(applied.is_normalized());
+
+        // Reconfiguring keeps the vector index
+        swap_index(&db, "test", "test-1", EmbeddingsModelType::AllMiniLmL6V2)
+            .await
+            .unwrap();
+        let updated = configure(&db, "test", CollectionConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(updated.vector_index, "test-1");
+        assert_eq!(updated.embedding_model, None);
+        assert_eq!(updated.max_length, None);
+        assert_eq!(updated.normalize, None);
+        assert_eq!(updated.created_at, created.created_at);
+
+        let segments_only = CollectionConfig {
+            store_content: false,
+            ..Default::default()
+        };
+        configure(&db, "test", segments_only).await.unwrap();
+        assert!(!stores_content(&db, "test").await.unwrap());
+        let updated = configure(&db, "test", CollectionConfig::default())
+            .await
+            .unwrap();
+
+        // Existing settings aren't overwritten by the defaults
+        create_default(&db, "test").await.unwrap();
+        assert_eq!(get(&db, "test").await.unwrap(), Some(updated));
+
+        // Collections w/ documents from before settings were stored
+        queue::enqueue(&db, "legacy", "some content", queue::TaskType::Ingest)
+            .await
+            .unwrap();
+        assert!(exists(&db, "legacy").await.unwrap());
+        assert_eq!(get(&db, "legacy").await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_is_valid_name() {
+        for name in ["test", "my-docs", "my_docs", "2023-reports"] {
+            assert!(is_valid_name(name), "{name} should be valid");
+        }
+
+        let too_long = "a".repeat(65);
+        for name in [
+            "", "Test", "my docs", "-docs", "_docs", "docs/..", "dócs", &too_long,
+        ] {
+            assert!(!is_valid_name(name), "{name} should be invalid");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_is_usable_name() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .expect("Unable to connect");
+
+        assert!(is_usable_name(&db, "my-docs").await.unwrap());
+        assert!(!is_usable_name(&db, "My Docs").await.unwrap());
+
+        // Collections created before names were checked keep working
+        queue::enqueue(&db, "My Docs", "some content", queue::TaskType::Ingest)
+            .await
+            .unwrap();
+        assert!(is_usable_name(&db, "My Docs").await.unwrap());
+    }
+
+    #[test]
+    fn test_reindex_index() {
+        let index = reindex_index("my-docs", 3);
+        assert_eq!(index, "my-docs.reindex-3");
+        assert!(!is_valid_name(&index));
+    }
+}
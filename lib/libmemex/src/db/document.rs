@@ -1,5 +1,5 @@
 use sea_orm::entity::prelude::*;
-use sea_orm::{ConnectionTrait, Set};
+use sea_orm::{ConnectionTrait, Set, TransactionTrait};
 use serde::Serialize;
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Eq)]
@@ -14,7 +14,8 @@ pub struct Model {
     /// Associated task id from the queue.
     #[sea_orm(indexed)]
     pub task_id: i64,
-    /// The full text context of this document
+    /// The full text context of this document, empty if the collection
+    /// doesn't retain it.
     pub content: String,
     /// Any additional metadata associated with this document.
     pub metadata: Option<Json>,
@@ -69,13 +70,19 @@ impl ActiveModelBehavior for ActiveModel {
     }
 }
 
+impl Model {
+    /// Whether the full content was kept, see `clear_content`.
+    pub fn has_content(&self) -> bool {
+        !self.content.is_empty()
+    }
+}
+
 impl ActiveModel {
     pub fn from_task(task: &super::queue::Model) -> Self {
-        let uuid = uuid::Uuid::new_v5(&crate::NAMESPACE, task.id.to_string().as_bytes());
-
         Self {
-            uuid: Set(uuid.to_string()),
+            uuid: Set(task.ingest_document_uuid()),
             content: Set(task.payload.content.clone()),
+            metadata: Set(task.payload.metadata.clone()),
             task_id: Set(task.id),
             created_at: Set(chrono::Utc::now()),
             updated_at: Set(chrono::Utc::now()),
@@ -83,3 +90,159 @@ impl ActiveModel {
         }
     }
 }
+
+/// Stable UUID for a document, derived from the collection & either the caller
+/// supplied `document_id` or the content itself. Ingesting the same document
+/// again always maps to the same UUID.
+pub fn document_uuid(collection: &str, payload: &super::queue::TaskPayload) -> uuid::Uuid {
+    let key = match &payload.document_id {
+        Some(id) => format!("{collection}/id/{id}"),
+        None => format!("{collection}/content/{}", payload.content),
+    };
+
+    uuid::Uuid::new_v5(&crate::NAMESPACE, key.as_bytes())
+}
+
+/// Save the document for a task, replacing the existing document if it has been
+/// ingested before.
+pub async fn upsert_from_task<C>(db: &C, task: &super::queue::Model) -> Result<Model, DbErr>
+where
+    C: ConnectionTrait,
+{
+    let document = ActiveModel::from_task(task);
+    let uuid = task.ingest_document_uuid();
+
+    match Entity::find().filter(Column::Uuid.eq(uuid)).one(db).await? {
+        Some(existing) => {
+            let mut update: ActiveModel = existing.into();
+            update.task_id = document.task_id;
+            update.content = document.content;
+            update.metadata = document.metadata;
+            update.update(db).await
+        }
+        None => document.insert(db).await,
+    }
+}
+
+/// Drop a document's full content, keeping its segments & metadata. Blank
+/// documents are never stored, so empty content means it wasn't retained.
+/// The copy in the task that ingested it (incl. any pre-chunked segments) is
+/// dropped as well, so the task is marked as completed first rather than be
+/// retried w/o its content.
+pub async fn clear_content<C>(db: &C, document: Model) -> Result<Model, DbErr>
+where
+    C: ConnectionTrait + TransactionTrait,
+{
+    let txn = db.begin().await?;
+    if let Some(task) = super::queue::Entity::find_by_id(document.task_id)
+        .one(&txn)
+        .await?
+    {
+        let mut payload = task.payload.clone();
+        payload.content = String::new();
+        payload.segments = None;
+        let finished = task.status.is_finished();
+        let mut update: super::queue::ActiveModel = task.into();
+        if !finished {
+            update.status = Set(super::queue::JobStatus::Completed);
+        }
+        update.payload = Set(payload);
+        update.update(&txn).await?;
+    }
+
+    let mut update: ActiveModel = document.into();
+    update.content = Set(String::new());
+    let document = update.update(&txn).await?;
+    txn.commit().await?;
+    Ok(document)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{clear_content, upsert_from_task, Entity};
+    use crate::db::{create_connection_by_uri, queue};
+    use sea_orm::{EntityTrait, PaginatorTrait};
+
+    #[tokio::test]
+    async fn test_stable_document_uuid() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .expect("Unable to connect");
+
+        let first = queue::enqueue(&db, "test", "some content", queue::TaskType::Ingest)
+            .await
+            .unwrap();
+        let second = queue::enqueue(&db, "test", "some content", queue::TaskType::Ingest)
+            .await
+            .unwrap();
+        let other = queue::enqueue(&db, "other", "some content", queue::TaskType::Ingest)
+            .await
+            .unwrap();
+
+        // Same content in the same collection maps to the same document.
+        let doc = upsert_from_task(&db, &first).await.unwrap();
+        let updated = upsert_from_task(&db, &second).await.unwrap();
+        assert_eq!(doc.uuid, updated.uuid);
+        assert_eq!(doc.id, updated.id);
+        assert_eq!(updated.task_id, second.id);
+        let doc_id = updated.id;
+
+        // Other collections get their own document
+        let other = upsert_from_task(&db, &other).await.unwrap();
+        assert_ne!(doc.uuid, other.uuid);
+        assert_eq!(Entity::find().count(&db).await.unwrap(), 2);
+
+        // Caller supplied ids take precedence over the content
+        let payload = queue::TaskPayload {
+            content: "new content".into(),
+            document_id: Some("doc-1".into()),
+            ..Default::default()
+        };
+        let with_id =
+            queue::enqueue_payload(&db, "test", payload, queue::TaskType::Ingest, None, None)
+                .await
+                .unwrap();
+        let payload = queue::TaskPayload {
+            content: "updated content".into(),
+            document_id: Some("doc-1".into()),
+            ..Default::default()
+        };
+        let updated_id =
+            queue::enqueue_payload(&db, "test", payload, queue::TaskType::Ingest, None, None)
+                .await
+                .unwrap();
+
+        let doc = upsert_from_task(&db, &with_id).await.unwrap();
+        let updated = upsert_from_task(&db, &updated_id).await.unwrap();
+        assert_eq!(doc.uuid, updated.uuid);
+        assert_eq!(updated.content, "updated content");
+        assert_eq!(Entity::find().count(&db).await.unwrap(), 3);
+
+        // Only the content is dropped, from the document & its task
+        let cleared = clear_content(&db, updated.clone()).await.unwrap();
+        assert!(updated.has_content());
+        assert!(!cleared.has_content());
+        assert_eq!(cleared.uuid, updated.uuid);
+        assert_eq!(cleared.task_id, updated.task_id);
+
+        let task = queue::Entity::find_by_id(cleared.task_id)
+            .one(&db)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(task.payload.content.is_empty());
+        assert_eq!(task.collection, "test");
+        assert_eq!(task.status, queue::JobStatus::Completed);
+
+        // Documents w/o an id still map to the same uuid once their content is gone
+        let doc = Entity::find_by_id(doc_id).one(&db).await.unwrap().unwrap();
+        let cleared = clear_content(&db, doc.clone()).await.unwrap();
+        let task = queue::Entity::find_by_id(cleared.task_id)
+            .one(&db)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(task.payload.content.is_empty());
+        assert_eq!(task.ingest_document_uuid(), doc.uuid);
+    }
+}
@@ -1,6 +1,13 @@
 use sea_orm::entity::prelude::*;
-use sea_orm::{ConnectionTrait, Set};
+use sea_orm::{
+    sea_query::Expr, ConnectionTrait, QueryOrder, QuerySelect, QueryTrait, Set, TransactionTrait,
+};
 use serde::Serialize;
+use std::collections::HashSet;
+
+use crate::llm::embedding::EmbeddingResult;
+use crate::storage::{VectorData, VectorStorage};
+use crate::NAMESPACE;
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
 #[sea_orm(table_name = "embeddings")]
@@ -18,10 +25,15 @@ pub struct Model {
     /// The segmented content.
     pub content: String,
     /// The embeddeding for this segment.
-    /// Stored as a JSON blob to suppport sqlite3
+    /// Stored as a JSON blob to suppport sqlite3. Postgres databases w/ pgvector
+    /// also get a native `embedding` column, see the pgvector migration.
     pub vector: Json,
-    /// Any metadata associated with this segment
+    /// Any metadata associated with this segment, e.g. its `offset_start` &
+    /// `offset_end` in the document.
     pub metadata: Option<Json>,
+    /// Whether the vector has made it into the collection's vector store.
+    #[sea_orm(default_value = true)]
+    pub indexed: bool,
     pub created_at: DateTimeUtc,
     pub updated_at: DateTimeUtc,
 }
@@ -42,6 +54,22 @@ impl Related<super::document::Entity> for Entity {
     }
 }
 
+impl Model {
+    /// Character range (end exclusive) of the segment in its document, if it
+    /// was recorded when the segment was embedded.
+    pub fn offsets(&self) -> Option<(usize, usize)> {
+        let metadata = self.metadata.as_ref()?;
+        let start = metadata.get("offset_start")?.as_u64()?;
+        let end = metadata.get("offset_end")?.as_u64()?;
+        Some((start as usize, end as usize))
+    }
+}
+
+/// Segment metadata recording where the segment is in its document.
+pub fn offsets_metadata(offsets: Option<(usize, usize)>) -> Option<Json> {
+    offsets.map(|(start, end)| serde_json::json!({ "offset_start": start, "offset_end": end }))
+}
+
 #[async_trait::async_trait]
 impl ActiveModelBehavior for ActiveModel {
     fn new() -> Self {
@@ -64,3 +92,258 @@ impl ActiveModelBehavior for ActiveModel {
         Ok(self)
     }
 }
+
+/// Save the segments of a document to the db & vector store. Segments are
+/// only marked as `indexed` once they're in the vector store, so if this is
+/// interrupted, running it again skips the segments that made it into both
+/// & redoes the rest.
+pub async fn persist_embeddings(
+    db: &DatabaseConnection,
+    client: &VectorStorage,
+    document: &super::document::Model,
+    embeddings: &[EmbeddingResult],
+) -> anyhow::Result<()> {
+    let existing = Entity::find()
+        .filter(Column::DocumentId.eq(document.uuid.clone()))
+        .all(db)
+        .await?;
+
+    let mut done = HashSet::new();
+    let mut pending = Vec::new();
+    for (idx, embedding) in embeddings.iter().enumerate() {
+        // Create a unique identifier for this segment w/ the task_id & segment
+        let uuid = uuid::Uuid::new_v5(
+            &NAMESPACE,
+            format!("{}-{idx}", document.uuid.clone()).as_bytes(),
+        )
+        .to_string();
+
+        let persisted = existing.iter().any(|seg| {
+            seg.uuid == uuid
+                && seg.indexed
+                && seg.content == embedding.content
+                && seg.offsets() == embedding.offsets
+                && serde_json::from_value::<Vec<f32>>(seg.vector.clone())
+                    .ok()
+                    .as_ref()
+                    == Some(&embedding.vector)
+        });
+
+        if persisted {
+            done.insert(uuid);
+        } else {
+            pending.push((uuid, idx, embedding));
+        }
+    }
+
+    // Replace any segments left over from a previous ingest of this document.
+    // They're flagged first so a failure partway through doesn't leave them
+    // looking indexed after their vectors are gone.
+    let stale: Vec<String> = existing
+        .into_iter()
+        .map(|seg| seg.uuid)
+        .filter(|uuid| !done.contains(uuid))
+        .collect();
+    if !stale.is_empty() {
+        Entity::update_many()
+            .col_expr(Column::Indexed, Expr::value(false))
+            .filter(Column::Uuid.is_in(stale.clone()))
+            .exec(db)
+            .await?;
+        client.delete_document(&stale).await?;
+    }
+
+    if !done.is_empty() {
+        log::info!(
+            "[doc={}] {} segments already persisted, skipping",
+            document.uuid,
+            done.len()
+        );
+    }
+
+    let txn = db.begin().await?;
+    Entity::delete_many()
+        .filter(Column::Uuid.is_in(stale))
+        .exec(&txn)
+        .await?;
+
+    // Persist vectors to db & vector store
+    let mut vectors = Vec::new();
+    for (uuid, idx, embedding) in pending {
+        let mut new_seg = ActiveModel::new();
+        new_seg.uuid = Set(uuid.clone());
+        new_seg.document_id = Set(document.uuid.clone());
+        new_seg.segment = Set(idx as i64);
+        new_seg.content = Set(embedding.content.clone());
+        new_seg.vector = Set(embedding.vector.clone().into());
+        new_seg.metadata = Set(offsets_metadata(embedding.offsets));
+        new_seg.indexed = Set(false);
+        new_seg.insert(&txn).await?;
+
+        vectors.push(VectorData {
+            _id: uuid.clone(),
+            document_id: document.uuid.clone(),
+            text: embedding.content.clone(),
+            segment_id: idx,
+            vector: embedding.vector.clone(),
+        });
+    }
+
+    // Commit before touching the vector store, db-backed stores (pgvector)
+    // write to the same rows.
+    txn.commit().await?;
+    if vectors.is_empty() {
+        return Ok(());
+    }
+
+    let ids: Vec<String> = vectors.iter().map(|vec| vec._id.clone()).collect();
+    client.add_vectors(vectors).await?;
+    Entity::update_many()
+        .col_expr(Column::Indexed, Expr::value(true))
+        .filter(Column::Uuid.is_in(ids))
+        .exec(db)
+        .await?;
+
+    log::info!("[doc={}] Persisted embeddings", document.uuid);
+    Ok(())
+}
+
+/// Flag every segment in a collection as missing from the vector store, e.g.
+/// after its vectors have been cleared.
+pub async fn mark_unindexed<C>(db: &C, collection: &str) -> Result<(), DbErr>
+where
+    C: ConnectionTrait,
+{
+    let documents = super::document::Entity::find()
+        .select_only()
+        .column(super::document::Column::Uuid)
+        .inner_join(super::queue::Entity)
+        .filter(super::queue::Column::Collection.eq(collection))
+        .into_query();
+
+    Entity::update_many()
+        .col_expr(Column::Indexed, Expr::value(false))
+        .filter(Column::DocumentId.in_subquery(documents))
+        .exec(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Flag a single document's segments as missing from the vector store, so
+/// they're re-added the next time the document is persisted.
+pub async fn mark_document_unindexed<C>(db: &C, document_id: &str) -> Result<(), DbErr>
+where
+    C: ConnectionTrait,
+{
+    Entity::update_many()
+        .col_expr(Column::Indexed, Expr::value(false))
+        .filter(Column::DocumentId.eq(document_id))
+        .exec(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Segments within `window` of `segment` in the same document (not including
+/// `segment` itself), in order.
+pub async fn segment_context<C>(
+    db: &C,
+    document_id: &str,
+    segment: i64,
+    window: usize,
+) -> Result<Vec<Model>, DbErr>
+where
+    C: ConnectionTrait,
+{
+    let window = window as i64;
+    Entity::find()
+        .filter(Column::DocumentId.eq(document_id))
+        .filter(Column::Segment.between(segment - window, segment + window))
+        .filter(Column::Segment.ne(segment))
+        .order_by_asc(Column::Segment)
+        .all(db)
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::{mark_unindexed, segment_context, ActiveModel, Column, Entity};
+    use crate::db::{create_connection_by_uri, document, queue};
+    use sea_orm::{
+        ActiveModelBehavior, ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait,
+        PaginatorTrait, QueryFilter, Set,
+    };
+
+    /// Add a document w/ `segments` segments to `collection`, returning the
+    /// document's id.
+    async fn add_test_document(
+        db: &DatabaseConnection,
+        collection: &str,
+        content: &str,
+        segments: i64,
+        indexed: bool,
+    ) -> String {
+        let task = queue::enqueue(db, collection, content, queue::TaskType::Ingest)
+            .await
+            .unwrap();
+        let doc = document::upsert_from_task(db, &task).await.unwrap();
+        for segment in 0..segments {
+            let mut seg = ActiveModel::new();
+            seg.uuid = Set(format!("{}-{segment}", doc.uuid));
+            seg.document_id = Set(doc.uuid.clone());
+            seg.segment = Set(segment);
+            seg.content = Set(content.to_string());
+            seg.vector = Set(vec![0.0f32; 3].into());
+            seg.indexed = Set(indexed);
+            seg.insert(db).await.unwrap();
+        }
+
+        doc.uuid
+    }
+
+    #[tokio::test]
+    async fn test_mark_unindexed() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .expect("Unable to connect");
+
+        for (collection, content) in [("test", "some content"), ("other", "other content")] {
+            add_test_document(&db, collection, content, 1, true).await;
+        }
+
+        mark_unindexed(&db, "test").await.unwrap();
+
+        // Only segments in the collection are flagged
+        let indexed = Entity::find()
+            .filter(Column::Indexed.eq(true))
+            .all(&db)
+            .await
+            .unwrap();
+        assert_eq!(indexed.len(), 1);
+        assert_eq!(indexed[0].content, "other content");
+        assert_eq!(Entity::find().count(&db).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_segment_context() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .expect("Unable to connect");
+
+        let doc = add_test_document(&db, "test", "some content", 6, false).await;
+        add_test_document(&db, "test", "other content", 2, false).await;
+
+        let context = segment_context(&db, &doc, 3, 1).await.unwrap();
+        let segments: Vec<i64> = context.iter().map(|seg| seg.segment).collect();
+        assert_eq!(segments, vec![2, 4]);
+
+        // Clipped to the start & end of the document
+        let context = segment_context(&db, &doc, 0, 2).await.unwrap();
+        let segments: Vec<i64> = context.iter().map(|seg| seg.segment).collect();
+        assert_eq!(segments, vec![1, 2]);
+        let context = segment_context(&db, &doc, 5, 10).await.unwrap();
+        assert_eq!(context.len(), 5);
+        assert!(context.iter().all(|seg| seg.document_id == doc));
+    }
+}
@@ -1,10 +1,28 @@
 use migration::{Migrator, MigratorTrait};
-use sea_orm::{prelude::*, ConnectOptions, Database};
+use sea_orm::{prelude::*, ConnectOptions, ConnectionTrait, Database, DbBackend, RuntimeErr};
+use std::{str::FromStr, time::Duration};
 
+pub mod collection;
 pub mod document;
 pub mod embedding;
 pub mod queue;
 
+const DEFAULT_MAX_CONNECTIONS: u32 = 10;
+const DEFAULT_MIN_CONNECTIONS: u32 = 2;
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 30;
+
+/// Read a database setting from the env, falling back to the default if it's
+/// missing or invalid.
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            log::warn!("Invalid value for {name}: {value}, using default");
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
 /// Creates a connection based on the database uri
 pub async fn create_connection_by_uri(
     db_uri: &str,
@@ -12,10 +30,16 @@ pub async fn create_connection_by_uri(
 ) -> Result<DatabaseConnection, DbErr> {
     // See https://www.sea-ql.org/SeaORM/docs/install-and-config/connection
     // for more connection options
+    let max_connections = env_or("DB_MAX_CONNECTIONS", DEFAULT_MAX_CONNECTIONS).max(1);
+    let timeout = Duration::from_secs(env_or("DB_CONNECT_TIMEOUT", DEFAULT_CONNECT_TIMEOUT_SECS));
+
     let mut opt = ConnectOptions::new(db_uri.to_owned());
-    opt.max_connections(10)
-        .min_connections(2)
-        .sqlx_logging(false);
+    opt.max_connections(max_connections)
+        .min_connections(DEFAULT_MIN_CONNECTIONS.min(max_connections))
+        .connect_timeout(timeout)
+        // Fail instead of waiting forever when every connection is in use.
+        .acquire_timeout(timeout)
+        .sqlx_logging(env_or("DB_SQL_LOGGING", false));
 
     let db = Database::connect(opt).await?;
     if run_migrations {
@@ -26,3 +50,78 @@ pub async fn create_connection_by_uri(
 
     Ok(db)
 }
+
+/// SQLite's `auto_vacuum` setting for freeing pages w/ `incremental_vacuum`.
+const SQLITE_INCREMENTAL_VACUUM: i32 = 2;
+
+/// Reclaim the space left behind by deleted rows. Only SQLite needs this,
+/// Postgres autovacuums on its own.
+///
+/// Rather than rebuilding the whole database each time, SQLite databases are
+/// switched to incremental vacuuming, which only takes one full `VACUUM`.
+pub async fn vacuum(db: &DatabaseConnection) -> Result<(), DbErr> {
+    if db.get_database_backend() != DbBackend::Sqlite {
+        return Ok(());
+    }
+
+    // Switching modes needs the pragma & vacuum on the same pooled connection.
+    let mut conn = db
+        .get_sqlite_connection_pool()
+        .acquire()
+        .await
+        .map_err(|err| DbErr::Conn(RuntimeErr::SqlxError(err)))?;
+    if sqlite_auto_vacuum(&mut conn).await? != SQLITE_INCREMENTAL_VACUUM {
+        for sql in ["PRAGMA auto_vacuum = INCREMENTAL", "VACUUM"] {
+            sqlx::query(sql)
+                .execute(&mut *conn)
+                .await
+                .map_err(|err| DbErr::Exec(RuntimeErr::SqlxError(err)))?;
+        }
+    }
+    sqlx::query("PRAGMA incremental_vacuum")
+        .execute(&mut *conn)
+        .await
+        .map_err(|err| DbErr::Exec(RuntimeErr::SqlxError(err)))?;
+
+    Ok(())
+}
+
+/// SQLite reads the `auto_vacuum` mode when the pragma is prepared & only
+/// refreshes it once a transaction starts, so a cached statement or an idle
+/// connection would keep reporting the old mode.
+async fn sqlite_auto_vacuum(conn: &mut sqlx::SqliteConnection) -> Result<i32, DbErr> {
+    sqlx::query("SELECT 1 FROM sqlite_master LIMIT 1")
+        .execute(&mut *conn)
+        .await
+        .map_err(|err| DbErr::Query(RuntimeErr::SqlxError(err)))?;
+    sqlx::query_scalar("PRAGMA auto_vacuum")
+        .persistent(false)
+        .fetch_one(conn)
+        .await
+        .map_err(|err| DbErr::Query(RuntimeErr::SqlxError(err)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{create_connection_by_uri, sqlite_auto_vacuum, vacuum, SQLITE_INCREMENTAL_VACUUM};
+
+    #[tokio::test]
+    async fn test_vacuum() {
+        let path = std::env::temp_dir().join("memex-vacuum-test.db");
+        let _ = std::fs::remove_file(&path);
+        let db = create_connection_by_uri(&format!("sqlite://{}?mode=rwc", path.display()), true)
+            .await
+            .expect("Unable to connect");
+
+        // Switches to incremental vacuuming the first time
+        for _ in 0..2 {
+            vacuum(&db).await.unwrap();
+            let mut conn = db.get_sqlite_connection_pool().acquire().await.unwrap();
+            let mode = sqlite_auto_vacuum(&mut conn).await.unwrap();
+            assert_eq!(mode, SQLITE_INCREMENTAL_VACUUM);
+        }
+
+        db.close().await.unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,10 +1,44 @@
 use sea_orm::entity::prelude::*;
 use sea_orm::FromJsonQueryResult;
-use sea_orm::{ConnectionTrait, DatabaseBackend, FromQueryResult, Set, Statement};
+use sea_orm::{
+    sea_query::{Expr, Query},
+    ConnectionTrait, DatabaseBackend, FromQueryResult, QuerySelect, Set, SqlErr, Statement,
+};
 use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 use strum_macros::Display;
+use tokio::sync::broadcast;
 
-const MAX_RETRIES: i32 = 5;
+use crate::llm::ChatCompletionOptions;
+
+/// Upper bound on the retries that can be requested for a single task.
+pub const MAX_RETRIES_LIMIT: i32 = 10;
+/// How long an idempotency key is remembered for.
+pub const IDEMPOTENCY_TTL_HOURS: i64 = 24;
+/// Postgres channel notified whenever a job is queued.
+pub const QUEUE_CHANNEL: &str = "memex_queue";
+/// Postgres channel notified w/ a task's id whenever it finishes.
+pub const FINISHED_CHANNEL: &str = "memex_task_finished";
+/// How often `wait_for_task` checks on a task when it can't be notified, e.g.
+/// w/ the worker running in another process on SQLite.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Longest a failed task is held back before it's retried.
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Tasks finished in this process, so waiting on a task doesn't need to poll
+/// when the API & worker run together.
+static FINISHED: OnceLock<broadcast::Sender<i64>> = OnceLock::new();
+
+fn finished_tasks() -> &'static broadcast::Sender<i64> {
+    FINISHED.get_or_init(|| broadcast::channel(1024).0)
+}
+
+/// Ids of tasks as they finish in this process.
+pub fn subscribe_finished() -> broadcast::Receiver<i64> {
+    finished_tasks().subscribe()
+}
 
 #[derive(Debug, Clone, PartialEq, EnumIter, DeriveActiveEnum, Serialize, Eq, Display)]
 #[sea_orm(rs_type = "String", db_type = "String(None)")]
@@ -17,6 +51,11 @@ pub enum JobStatus {
     Completed,
     #[sea_orm(string_value = "Failed")]
     Failed,
+    #[sea_orm(string_value = "Cancelled")]
+    Cancelled,
+    /// Nothing to do, e.g. every segment of the document was a duplicate.
+    #[sea_orm(string_value = "Skipped")]
+    Skipped,
 }
 
 #[derive(Debug, Clone, PartialEq, EnumIter, DeriveActiveEnum, Serialize, Eq, Display)]
@@ -31,11 +70,65 @@ pub enum TaskType {
     /// Summarizing content
     #[sea_orm(string_value = "Summarize")]
     Summarize,
+    /// Re-embedding every document in a collection w/ a different model
+    #[sea_orm(string_value = "Reindex")]
+    Reindex,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+impl JobStatus {
+    /// Whether the task is done running, successfully or not.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled | JobStatus::Skipped
+        )
+    }
+
+    /// Whether the task was stopped w/o running to completion. These are
+    /// never retried & aren't overwritten once the task stops running.
+    pub fn is_discarded(&self) -> bool {
+        matches!(self, JobStatus::Cancelled | JobStatus::Skipped)
+    }
+}
+
+impl TaskType {
+    /// Number of retries used when one isn't given at enqueue time. Embedding
+    /// is local & deterministic so there's little point retrying it much, while
+    /// LLM calls tend to fail due to transient upstream issues.
+    pub fn default_max_retries(&self) -> i32 {
+        match self {
+            TaskType::Ingest | TaskType::Reindex => 2,
+            TaskType::Extract | TaskType::Summarize => 5,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, FromJsonQueryResult)]
 pub struct TaskPayload {
     pub content: String,
+    /// LLM generation options for tasks that run a chat completion.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub llm_options: Option<ChatCompletionOptions>,
+    /// Metadata attached to the document created by an ingest task.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// Segments chunked by the caller, embedded as-is instead of segmenting
+    /// `content`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub segments: Option<Vec<String>>,
+    /// Caller supplied id used to derive a stable document uuid.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub document_id: Option<String>,
+    /// JSON schema of metadata to extract w/ the LLM once an ingest task has
+    /// been embedded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_schema: Option<serde_json::Value>,
+    /// Skip segments w/ a similarity at or above this to one already stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedup_threshold: Option<f32>,
+    /// Embedding model a reindex task switches the collection to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
@@ -44,7 +137,7 @@ pub struct TaskError {
     pub msg: String,
 }
 
-#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Eq)]
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
 #[sea_orm(table_name = "queue")]
 pub struct Model {
     #[sea_orm(primary_key)]
@@ -61,12 +154,35 @@ pub struct Model {
     /// Number of retries for this task.
     #[sea_orm(default_value = 0)]
     pub num_retries: i32,
+    /// Max number of retries before this task is marked as failed, falls back
+    /// to the task type's default when unset.
+    pub max_retries: Option<i32>,
+    /// ID of the API request that created this task, used to correlate logs.
+    pub trace_id: Option<String>,
+    /// Client supplied key used to dedupe retried requests, unique per collection.
+    pub idempotency_key: Option<String>,
+    /// A requeued task isn't picked up again until this time, e.g. when the
+    /// LLM asked us to back off.
+    pub next_retry_at: Option<DateTimeUtc>,
+    /// UUID of the document an ingest task creates, saved when it's queued
+    /// since the payload's content may be dropped later.
+    pub document_uuid: Option<String>,
     /// When this was first added to the crawl queue.
     pub created_at: DateTimeUtc,
     /// When this task was last updated.
     pub updated_at: DateTimeUtc,
 }
 
+impl Model {
+    /// UUID of the document an ingest task creates, derived from the payload
+    /// for tasks queued before it was saved w/ the task.
+    pub fn ingest_document_uuid(&self) -> String {
+        self.document_uuid.clone().unwrap_or_else(|| {
+            super::document::document_uuid(&self.collection, &self.payload).to_string()
+        })
+    }
+}
+
 #[derive(Copy, Clone, Debug, EnumIter)]
 pub enum Relation {}
 
@@ -102,33 +218,246 @@ impl ActiveModelBehavior for ActiveModel {
 
 pub async fn mark_done(db: &DatabaseConnection, id: i64) -> Option<Model> {
     if let Ok(Some(crawl)) = Entity::find_by_id(id).one(db).await {
+        // Don't clobber a cancellation that came in while the task was finishing up
+        if crawl.status.is_discarded() {
+            return Some(crawl);
+        }
+
         let mut updated: ActiveModel = crawl.into();
         updated.status = Set(JobStatus::Completed);
         updated.updated_at = Set(chrono::Utc::now());
-        updated.update(db).await.ok()
+        let updated = updated.update(db).await.ok();
+        notify_finished(db, id).await;
+        updated
     } else {
         None
     }
 }
 
-pub async fn mark_failed(db: &DatabaseConnection, id: i64, retry: bool, error: Option<TaskError>) {
+/// Requeue a failed task if it's `retry`-able & still has retries left,
+/// otherwise mark it as failed. A requeued task is held back for
+/// `retry_after`, up to `MAX_RETRY_DELAY`.
+pub async fn mark_failed(
+    db: &DatabaseConnection,
+    id: i64,
+    retry: bool,
+    retry_after: Option<Duration>,
+    error: Option<TaskError>,
+) -> Option<Model> {
     if let Ok(Some(crawl)) = Entity::find_by_id(id).one(db).await {
+        if crawl.status.is_discarded() {
+            return Some(crawl);
+        }
+
+        let max_retries = crawl
+            .max_retries
+            .unwrap_or_else(|| crawl.task_type.default_max_retries());
         let mut updated: ActiveModel = crawl.clone().into();
 
         // Bump up number of retries if this failed
-        if retry && crawl.num_retries <= MAX_RETRIES {
+        if retry && crawl.num_retries < max_retries {
             updated.num_retries = Set(crawl.num_retries + 1);
             // Queue again
             updated.status = Set(JobStatus::Queued);
+            updated.next_retry_at = Set(retry_after.and_then(|delay| {
+                chrono::Duration::from_std(delay.min(MAX_RETRY_DELAY))
+                    .ok()
+                    .map(|delay| chrono::Utc::now() + delay)
+            }));
         } else {
             updated.status = Set(JobStatus::Failed);
         }
 
         updated.error = Set(error);
-        let _ = updated.update(db).await;
+        let updated = updated.update(db).await.ok();
+        if matches!(&updated, Some(task) if task.status.is_finished()) {
+            notify_finished(db, id).await;
+        }
+        updated
+    } else {
+        None
     }
 }
 
+/// Cancel a queued or in-flight task. Returns false if the task has already
+/// finished (or doesn't exist).
+pub async fn mark_cancelled(db: &DatabaseConnection, id: i64) -> Result<bool, DbErr> {
+    let res = Entity::update_many()
+        .col_expr(Column::Status, Expr::value(JobStatus::Cancelled))
+        .col_expr(Column::UpdatedAt, Expr::value(chrono::Utc::now()))
+        .filter(Column::Id.eq(id))
+        .filter(Column::Status.is_in([JobStatus::Queued, JobStatus::Processing]))
+        .exec(db)
+        .await?;
+
+    if res.rows_affected > 0 {
+        notify_finished(db, id).await;
+    }
+    Ok(res.rows_affected > 0)
+}
+
+/// Mark a queued or in-flight task as skipped. Returns false if the task has
+/// already finished (or doesn't exist).
+pub async fn mark_skipped(db: &DatabaseConnection, id: i64) -> Result<bool, DbErr> {
+    let res = Entity::update_many()
+        .col_expr(Column::Status, Expr::value(JobStatus::Skipped))
+        .col_expr(Column::UpdatedAt, Expr::value(chrono::Utc::now()))
+        .filter(Column::Id.eq(id))
+        .filter(Column::Status.is_in([JobStatus::Queued, JobStatus::Processing]))
+        .exec(db)
+        .await?;
+
+    if res.rows_affected > 0 {
+        notify_finished(db, id).await;
+    }
+    Ok(res.rows_affected > 0)
+}
+
+/// Let anyone waiting on a task know it's finished.
+async fn notify_finished(db: &DatabaseConnection, id: i64) {
+    // No receivers is fine, nobody is waiting.
+    let _ = finished_tasks().send(id);
+    if db.get_database_backend() == DatabaseBackend::Postgres {
+        if let Err(err) = db
+            .execute_unprepared(&format!("NOTIFY {FINISHED_CHANNEL}, '{id}'"))
+            .await
+        {
+            log::warn!("Unable to notify that task {id} finished: {err}");
+        }
+    }
+}
+
+/// Wait up to `timeout` for a task to finish, returning the task as it was
+/// when it finished (or timed out) & `None` if it doesn't exist. Woken up by
+/// `notify_finished` where possible, otherwise the task is polled.
+pub async fn wait_for_task(
+    db: &DatabaseConnection,
+    id: i64,
+    timeout: Duration,
+) -> Result<Option<Model>, DbErr> {
+    let deadline = Instant::now() + timeout;
+    // Subscribe before checking the task so a notification can't slip by.
+    let mut local = finished_tasks().subscribe();
+    let mut listener = if db.get_database_backend() == DatabaseBackend::Postgres {
+        match PgListener::connect_with(db.get_postgres_connection_pool()).await {
+            Ok(mut listener) => match listener.listen(FINISHED_CHANNEL).await {
+                Ok(()) => Some(listener),
+                Err(err) => {
+                    log::warn!("Unable to listen for finished tasks: {err}");
+                    None
+                }
+            },
+            Err(err) => {
+                log::warn!("Unable to listen for finished tasks: {err}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    loop {
+        let Some(task) = Entity::find_by_id(id).one(db).await? else {
+            return Ok(None);
+        };
+
+        let now = Instant::now();
+        if task.status.is_finished() || now >= deadline {
+            return Ok(Some(task));
+        }
+
+        let poll = WAIT_POLL_INTERVAL.min(deadline - now);
+        tokio::select! {
+            _ = local.recv() => {}
+            recv = async {
+                match listener.as_mut() {
+                    Some(listener) => listener.recv().await.map(|_| ()),
+                    None => std::future::pending().await,
+                }
+            } => {
+                if recv.is_err() {
+                    // Fall back to polling
+                    listener = None;
+                }
+            }
+            _ = tokio::time::sleep(poll) => {}
+        }
+    }
+}
+
+/// Of the given tasks, return the ids of the ones that have been cancelled.
+pub async fn cancelled_tasks(db: &DatabaseConnection, ids: &[i64]) -> Result<Vec<i64>, DbErr> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    Entity::find()
+        .select_only()
+        .column(Column::Id)
+        .filter(Column::Id.is_in(ids.to_vec()))
+        .filter(Column::Status.eq(JobStatus::Cancelled))
+        .into_tuple()
+        .all(db)
+        .await
+}
+
+/// Save a task's output w/o touching the rest of the task, e.g. to record
+/// progress while it's still running.
+pub async fn set_output(db: &DatabaseConnection, id: i64, output: Json) -> Result<(), DbErr> {
+    Entity::update_many()
+        .col_expr(Column::TaskOutput, Expr::value(output))
+        .col_expr(Column::UpdatedAt, Expr::value(chrono::Utc::now()))
+        .filter(Column::Id.eq(id))
+        .exec(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Delete finished tasks last updated more than `retention` ago, returning how
+/// many were deleted. Tasks that documents were created from are kept.
+pub async fn delete_finished(db: &DatabaseConnection, retention: Duration) -> Result<u64, DbErr> {
+    let cutoff = chrono::Duration::from_std(retention)
+        .ok()
+        .and_then(|retention| chrono::Utc::now().checked_sub_signed(retention))
+        .ok_or_else(|| DbErr::Custom(format!("Invalid retention: {retention:?}")))?;
+
+    let result = Entity::delete_many()
+        .filter(Column::Status.is_in([
+            JobStatus::Completed,
+            JobStatus::Failed,
+            JobStatus::Cancelled,
+            JobStatus::Skipped,
+        ]))
+        .filter(Column::UpdatedAt.lt(cutoff))
+        .filter(
+            Column::Id.not_in_subquery(
+                Query::select()
+                    .column(super::document::Column::TaskId)
+                    .from(super::document::Entity)
+                    .to_owned(),
+            ),
+        )
+        .exec(db)
+        .await?;
+
+    Ok(result.rows_affected)
+}
+
+/// Find a queued or in-flight task of the given type in a collection.
+pub async fn active_task(
+    db: &DatabaseConnection,
+    collection: &str,
+    task_type: TaskType,
+) -> Result<Option<Model>, DbErr> {
+    Entity::find()
+        .filter(Column::Collection.eq(collection))
+        .filter(Column::TaskType.eq(task_type))
+        .filter(Column::Status.is_in([JobStatus::Queued, JobStatus::Processing]))
+        .one(db)
+        .await
+}
+
 pub async fn enqueue<C>(
     db: &C,
     collection: &str,
@@ -138,14 +467,111 @@ pub async fn enqueue<C>(
 where
     C: ConnectionTrait,
 {
+    let payload = TaskPayload {
+        content: content.to_string(),
+        ..Default::default()
+    };
+
+    enqueue_payload(db, collection, payload, task_type, None, None).await
+}
+
+/// Build a task to be queued, `max_retries` defaults to `TaskType::default_max_retries`.
+/// Ingest tasks also save the uuid of the document they create.
+pub fn new_task(
+    collection: &str,
+    payload: TaskPayload,
+    task_type: TaskType,
+    trace_id: Option<&str>,
+    max_retries: Option<i32>,
+) -> ActiveModel {
     let mut new = ActiveModel::new();
     new.collection = Set(collection.to_string());
+    new.max_retries = Set(Some(
+        max_retries.unwrap_or_else(|| task_type.default_max_retries()),
+    ));
+    if task_type == TaskType::Ingest {
+        let uuid = super::document::document_uuid(collection, &payload);
+        new.document_uuid = Set(Some(uuid.to_string()));
+    }
     new.task_type = Set(task_type);
-    new.payload = Set(TaskPayload {
-        content: content.to_string(),
-    });
+    new.payload = Set(payload);
+    new.trace_id = Set(trace_id.map(|id| id.to_string()));
+    new
+}
+
+pub async fn enqueue_payload<C>(
+    db: &C,
+    collection: &str,
+    payload: TaskPayload,
+    task_type: TaskType,
+    trace_id: Option<&str>,
+    max_retries: Option<i32>,
+) -> Result<Model, DbErr>
+where
+    C: ConnectionTrait,
+{
+    let new = new_task(collection, payload, task_type, trace_id, max_retries);
+    enqueue_task(db, new).await
+}
+
+/// Queue up a task built w/ `new_task`.
+pub async fn enqueue_task<C>(db: &C, task: ActiveModel) -> Result<Model, DbErr>
+where
+    C: ConnectionTrait,
+{
+    let model = Entity::insert(task).exec_with_returning(db).await?;
+    notify_workers(db).await?;
+    Ok(model)
+}
+
+/// Queue up a task unless one w/ the same idempotency key was queued in the
+/// same collection within `IDEMPOTENCY_TTL_HOURS`. Returns the task & whether
+/// it was newly created.
+pub async fn enqueue_idempotent(
+    db: &DatabaseConnection,
+    mut task: ActiveModel,
+    key: &str,
+) -> Result<(Model, bool), DbErr> {
+    let collection = task.collection.clone().take().unwrap_or_default();
+    if let Some(existing) = find_by_idempotency_key(db, &collection, key).await? {
+        let expires_at = existing.created_at + chrono::Duration::hours(IDEMPOTENCY_TTL_HOURS);
+        if expires_at > chrono::Utc::now() {
+            return Ok((existing, false));
+        }
+
+        // Expired, free up the key for the new task.
+        let mut expired: ActiveModel = existing.into();
+        expired.idempotency_key = Set(None);
+        expired.update(db).await?;
+    }
+
+    task.idempotency_key = Set(Some(key.to_string()));
+    match Entity::insert(task).exec_with_returning(db).await {
+        Ok(model) => {
+            notify_workers(db).await?;
+            Ok((model, true))
+        }
+        // Lost a race w/ a concurrent request using the same key
+        Err(err) if matches!(err.sql_err(), Some(SqlErr::UniqueConstraintViolation(_))) => {
+            find_by_idempotency_key(db, &collection, key)
+                .await?
+                .map(|model| (model, false))
+                .ok_or(err)
+        }
+        Err(err) => Err(err),
+    }
+}
 
-    Entity::insert(new).exec_with_returning(db).await
+async fn find_by_idempotency_key(
+    db: &DatabaseConnection,
+    collection: &str,
+    key: &str,
+) -> Result<Option<Model>, DbErr> {
+    Entity::find()
+        .filter(Column::Collection.eq(collection))
+        .filter(Column::IdempotencyKey.eq(key))
+        .one(db)
+        .await
 }
 
 pub async fn enqueue_many<C>(db: &C, models: &[ActiveModel]) -> Result<(), DbErr>
@@ -155,19 +581,99 @@ where
     Entity::insert_many(models.to_vec())
         .exec_without_returning(db)
         .await?;
+    notify_workers(db).await
+}
+
+/// Wake up any workers waiting on new jobs. When called in a transaction,
+/// Postgres only sends the notification once it's committed.
+async fn notify_workers<C>(db: &C) -> Result<(), DbErr>
+where
+    C: ConnectionTrait,
+{
+    if db.get_database_backend() == DatabaseBackend::Postgres {
+        db.execute_unprepared(&format!("NOTIFY {QUEUE_CHANNEL}"))
+            .await?;
+    }
+
     Ok(())
 }
 
+/// Listens for newly queued jobs so workers don't have to wait for their next
+/// poll. Only available w/ Postgres.
+pub struct JobListener {
+    listener: PgListener,
+}
+
+impl JobListener {
+    /// Start listening, returns `None` if the database doesn't support it.
+    pub async fn connect(db: &DatabaseConnection) -> Result<Option<Self>, DbErr> {
+        if db.get_database_backend() != DatabaseBackend::Postgres {
+            return Ok(None);
+        }
+
+        let mut listener = PgListener::connect_with(db.get_postgres_connection_pool())
+            .await
+            .map_err(|err| DbErr::Custom(format!("Unable to listen for jobs: {err}")))?;
+        listener
+            .listen(QUEUE_CHANNEL)
+            .await
+            .map_err(|err| DbErr::Custom(format!("Unable to listen for jobs: {err}")))?;
+
+        Ok(Some(Self { listener }))
+    }
+
+    /// Wait until a job is queued.
+    pub async fn wait(&mut self) -> Result<(), DbErr> {
+        self.listener
+            .recv()
+            .await
+            .map(|_| ())
+            .map_err(|err| DbErr::Custom(format!("Unable to listen for jobs: {err}")))
+    }
+}
+
 #[derive(Clone, Debug, FromQueryResult)]
 pub struct Job {
     pub id: i64,
+    pub collection: String,
     pub task_type: TaskType,
 }
 
-pub async fn check_for_jobs(db: &DatabaseConnection) -> Result<Option<Job>, DbErr> {
+/// Grab the next queued job, skipping any from the `excluded` collections.
+pub async fn check_for_jobs(
+    db: &DatabaseConnection,
+    excluded: &[String],
+) -> Result<Option<Job>, DbErr> {
+    check_for_jobs_in(db, &[], excluded).await
+}
+
+/// Grab the next queued job from one of `collections`, or any collection if
+/// it's empty, skipping any from the `excluded` collections.
+pub async fn check_for_jobs_in(
+    db: &DatabaseConnection,
+    collections: &[String],
+    excluded: &[String],
+) -> Result<Option<Job>, DbErr> {
     let backend = db.get_database_backend();
+
+    let mut values: Vec<Value> = vec![chrono::Utc::now().into()];
+    let mut filter = String::new();
+    for (names, op) in [(collections, "IN"), (excluded, "NOT IN")] {
+        if names.is_empty() {
+            continue;
+        }
+
+        let placeholders = (0..names.len())
+            .map(|idx| format!("${}", values.len() + idx + 1))
+            .collect::<Vec<_>>()
+            .join(", ");
+        filter.push_str(&format!(" AND collection {op} ({placeholders})"));
+        values.extend(names.iter().map(|col| col.clone().into()));
+    }
+
     let sql: String = match backend {
-        DatabaseBackend::Sqlite => r#"
+        DatabaseBackend::Sqlite => format!(
+            r#"
             UPDATE queue
             SET
                 status = 'Processing',
@@ -177,12 +683,14 @@ pub async fn check_for_jobs(db: &DatabaseConnection) -> Result<Option<Job>, DbEr
                     id
                 FROM queue
                 WHERE status = 'Queued'
+                    AND (next_retry_at IS NULL OR next_retry_at <= $1){filter}
                 ORDER BY queue.created_at ASC
                 LIMIT 1
             )
-            RETURNING queue.id, queue.task_type"#
-            .into(),
-        _ => r#"
+            RETURNING queue.id, queue.collection, queue.task_type"#
+        ),
+        _ => format!(
+            r#"
             UPDATE queue
             SET
                 status = 'Processing',
@@ -192,27 +700,33 @@ pub async fn check_for_jobs(db: &DatabaseConnection) -> Result<Option<Job>, DbEr
                     id
                 FROM queue
                 WHERE status = 'Queued'
+                    AND (next_retry_at IS NULL OR next_retry_at <= $1){filter}
                 ORDER BY queue.created_at ASC
                 LIMIT 1
                 FOR UPDATE
             )
-            RETURNING queue.id, queue.task_type"#
-            .into(),
+            RETURNING queue.id, queue.collection, queue.task_type"#
+        ),
     };
 
-    let query = Statement::from_sql_and_values(backend, &sql, [chrono::Utc::now().into()]);
+    let query = Statement::from_sql_and_values(backend, &sql, values);
 
     Job::find_by_statement(query).one(db).await
 }
 
 #[cfg(test)]
 mod test {
-    use super::{enqueue, Entity};
+    use super::{
+        cancelled_tasks, delete_finished, enqueue, enqueue_idempotent, enqueue_payload,
+        mark_cancelled, mark_done, mark_failed, mark_skipped, new_task, wait_for_task, Entity,
+        JobListener, TaskPayload, TaskType,
+    };
     use crate::db::{
         create_connection_by_uri,
-        queue::{check_for_jobs, JobStatus},
+        queue::{check_for_jobs, check_for_jobs_in, JobStatus},
     };
     use sea_orm::EntityTrait;
+    use std::time::{Duration, Instant};
 
     #[tokio::test]
     async fn test_enqueue_and_dequeue() {
@@ -230,7 +744,7 @@ mod test {
         assert!(res.is_ok());
 
         // Dequeue
-        let job = check_for_jobs(&db).await;
+        let job = check_for_jobs(&db, &[]).await;
         assert!(job.is_ok());
 
         // Make sure job has been updated
@@ -240,4 +754,375 @@ mod test {
         let model = model.unwrap();
         assert_eq!(model.status, JobStatus::Processing);
     }
+
+    #[tokio::test]
+    async fn test_dequeue_excluded_collections() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .expect("Unable to connect");
+
+        for collection in ["busy", "busy", "other"] {
+            enqueue(
+                &db,
+                collection,
+                "this is the content",
+                crate::db::queue::TaskType::Ingest,
+            )
+            .await
+            .expect("Unable to enqueue");
+        }
+
+        let excluded = vec!["busy".to_string()];
+        let job = check_for_jobs(&db, &excluded).await.unwrap().unwrap();
+        assert_eq!(job.collection, "other");
+
+        // Only jobs from excluded collections are left
+        let job = check_for_jobs(&db, &excluded).await.unwrap();
+        assert!(job.is_none());
+
+        let job = check_for_jobs(&db, &[]).await.unwrap().unwrap();
+        assert_eq!(job.collection, "busy");
+    }
+
+    #[tokio::test]
+    async fn test_dequeue_pinned_collections() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .expect("Unable to connect");
+
+        for collection in ["archive", "interactive", "archive", "interactive", "other"] {
+            enqueue(
+                &db,
+                collection,
+                "this is the content",
+                crate::db::queue::TaskType::Ingest,
+            )
+            .await
+            .expect("Unable to enqueue");
+        }
+
+        let pinned = vec!["interactive".to_string(), "other".to_string()];
+        let job = check_for_jobs_in(&db, &pinned, &[]).await.unwrap().unwrap();
+        assert_eq!(job.collection, "interactive");
+
+        // Pinned collections can still be excluded when they're busy
+        let excluded = vec!["interactive".to_string()];
+        let job = check_for_jobs_in(&db, &pinned, &excluded)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(job.collection, "other");
+        let job = check_for_jobs_in(&db, &pinned, &[]).await.unwrap().unwrap();
+        assert_eq!(job.collection, "interactive");
+
+        // Only jobs from other collections are left
+        let job = check_for_jobs_in(&db, &pinned, &[]).await.unwrap();
+        assert!(job.is_none());
+        let job = check_for_jobs(&db, &[]).await.unwrap().unwrap();
+        assert_eq!(job.collection, "archive");
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .expect("Unable to connect");
+
+        let queued = enqueue(&db, "test", "queued", crate::db::queue::TaskType::Ingest)
+            .await
+            .unwrap();
+        let processing = enqueue(
+            &db,
+            "test",
+            "processing",
+            crate::db::queue::TaskType::Ingest,
+        )
+        .await
+        .unwrap();
+        let done = enqueue(&db, "test", "done", crate::db::queue::TaskType::Ingest)
+            .await
+            .unwrap();
+        mark_done(&db, done.id).await.unwrap();
+
+        // Queued tasks are pulled from the queue
+        assert!(mark_cancelled(&db, queued.id).await.unwrap());
+        let job = check_for_jobs(&db, &[]).await.unwrap().unwrap();
+        assert_eq!(job.id, processing.id);
+
+        // In-flight tasks are flagged for the worker & stay cancelled once finished
+        assert!(mark_cancelled(&db, processing.id).await.unwrap());
+        let cancelled = cancelled_tasks(&db, &[processing.id, done.id])
+            .await
+            .unwrap();
+        assert_eq!(cancelled, vec![processing.id]);
+        let model = mark_done(&db, processing.id).await.unwrap();
+        assert_eq!(model.status, JobStatus::Cancelled);
+
+        // Finished tasks can't be cancelled
+        assert!(!mark_cancelled(&db, done.id).await.unwrap());
+        assert!(!mark_cancelled(&db, 1000).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_skip() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .expect("Unable to connect");
+
+        let queued = enqueue(&db, "test", "queued", TaskType::Ingest)
+            .await
+            .unwrap();
+        let processing = enqueue(&db, "test", "processing", TaskType::Ingest)
+            .await
+            .unwrap();
+
+        // Skipped tasks aren't picked up
+        assert!(mark_skipped(&db, queued.id).await.unwrap());
+        let job = check_for_jobs(&db, &[]).await.unwrap().unwrap();
+        assert_eq!(job.id, processing.id);
+        assert!(check_for_jobs(&db, &[]).await.unwrap().is_none());
+
+        // Skipping is final, the task isn't completed or retried afterwards
+        assert!(mark_skipped(&db, processing.id).await.unwrap());
+        let model = mark_done(&db, processing.id).await.unwrap();
+        assert_eq!(model.status, JobStatus::Skipped);
+        let model = mark_failed(&db, processing.id, true, None, None)
+            .await
+            .unwrap();
+        assert_eq!(model.status, JobStatus::Skipped);
+        assert_eq!(model.num_retries, 0);
+        assert!(model.status.is_finished());
+
+        assert!(!mark_skipped(&db, processing.id).await.unwrap());
+        assert!(!mark_cancelled(&db, processing.id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_delete_finished() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .expect("Unable to connect");
+
+        let queued = enqueue(&db, "test", "queued", TaskType::Ingest)
+            .await
+            .unwrap();
+        let done = enqueue(&db, "test", "done", TaskType::Summarize)
+            .await
+            .unwrap();
+        let ingested = enqueue(&db, "test", "ingested", TaskType::Ingest)
+            .await
+            .unwrap();
+        mark_done(&db, done.id).await.unwrap();
+        let ingested = mark_done(&db, ingested.id).await.unwrap();
+        crate::db::document::upsert_from_task(&db, &ingested)
+            .await
+            .unwrap();
+
+        // Nothing's old enough yet
+        let an_hour = Duration::from_secs(60 * 60);
+        assert_eq!(delete_finished(&db, an_hour).await.unwrap(), 0);
+
+        // Queued tasks & ones w/ a document are kept
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(delete_finished(&db, Duration::ZERO).await.unwrap(), 1);
+        assert!(Entity::find_by_id(done.id)
+            .one(&db)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(Entity::find_by_id(queued.id)
+            .one(&db)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(Entity::find_by_id(ingested.id)
+            .one(&db)
+            .await
+            .unwrap()
+            .is_some());
+
+        crate::db::vacuum(&db).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retry_limits() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .expect("Unable to connect");
+
+        // Defaults to the task type's limit
+        let task = enqueue(&db, "test", "content", TaskType::Ingest)
+            .await
+            .unwrap();
+        assert_eq!(
+            task.max_retries,
+            Some(TaskType::Ingest.default_max_retries())
+        );
+
+        let task = enqueue_payload(
+            &db,
+            "test",
+            TaskPayload::default(),
+            TaskType::Summarize,
+            None,
+            Some(1),
+        )
+        .await
+        .unwrap();
+
+        // Requeued until the row's limit is hit
+        let model = mark_failed(&db, task.id, true, None, None).await.unwrap();
+        assert_eq!(model.status, JobStatus::Queued);
+        assert_eq!(model.num_retries, 1);
+        let model = mark_failed(&db, task.id, true, None, None).await.unwrap();
+        assert_eq!(model.status, JobStatus::Failed);
+        assert_eq!(model.num_retries, 1);
+
+        // Non-retriable errors fail right away
+        let task = enqueue(&db, "test", "content", TaskType::Summarize)
+            .await
+            .unwrap();
+        let model = mark_failed(&db, task.id, false, None, None).await.unwrap();
+        assert_eq!(model.status, JobStatus::Failed);
+        assert_eq!(model.num_retries, 0);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .expect("Unable to connect");
+
+        let task = enqueue(&db, "test", "content", TaskType::Summarize)
+            .await
+            .unwrap();
+        let job = check_for_jobs(&db, &[]).await.unwrap().unwrap();
+        assert_eq!(job.id, task.id);
+
+        // Held back until the delay is up
+        let model = mark_failed(&db, task.id, true, Some(Duration::from_secs(60)), None)
+            .await
+            .unwrap();
+        assert_eq!(model.status, JobStatus::Queued);
+        assert!(model.next_retry_at.unwrap() > chrono::Utc::now());
+        assert!(check_for_jobs(&db, &[]).await.unwrap().is_none());
+
+        // Delays are capped
+        let model = mark_failed(&db, task.id, true, Some(Duration::from_secs(86_400)), None)
+            .await
+            .unwrap();
+        assert!(model.next_retry_at.unwrap() <= chrono::Utc::now() + chrono::Duration::hours(1));
+
+        // Retried right away w/o a delay
+        mark_failed(&db, task.id, true, None, None).await.unwrap();
+        let job = check_for_jobs(&db, &[]).await.unwrap().unwrap();
+        assert_eq!(job.id, task.id);
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_idempotent() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .expect("Unable to connect");
+
+        let task = || new_task("test", TaskPayload::default(), TaskType::Ingest, None, None);
+        let (first, created) = enqueue_idempotent(&db, task(), "key-1").await.unwrap();
+        assert!(created);
+
+        // Repeated keys return the original task
+        let (repeat, created) = enqueue_idempotent(&db, task(), "key-1").await.unwrap();
+        assert!(!created);
+        assert_eq!(repeat.id, first.id);
+
+        // Keys are scoped to a collection
+        let other = new_task(
+            "other",
+            TaskPayload::default(),
+            TaskType::Ingest,
+            None,
+            None,
+        );
+        let (other, created) = enqueue_idempotent(&db, other, "key-1").await.unwrap();
+        assert!(created);
+        assert_ne!(other.id, first.id);
+
+        // The db rejects duplicates that sneak past the lookup
+        let mut duplicate = task();
+        duplicate.idempotency_key = sea_orm::Set(Some("key-1".into()));
+        assert!(Entity::insert(duplicate).exec(&db).await.is_err());
+
+        // Expired keys can be reused
+        let mut expired: super::ActiveModel = first.clone().into();
+        expired.created_at = sea_orm::Set(chrono::Utc::now() - chrono::Duration::days(2));
+        sea_orm::ActiveModelTrait::update(expired, &db)
+            .await
+            .unwrap();
+        let (replaced, created) = enqueue_idempotent(&db, task(), "key-1").await.unwrap();
+        assert!(created);
+        assert_ne!(replaced.id, first.id);
+        let first = Entity::find_by_id(first.id)
+            .one(&db)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.idempotency_key, None);
+    }
+
+    #[tokio::test]
+    async fn test_job_listener_sqlite() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .expect("Unable to connect");
+
+        // Falls back to polling
+        assert!(JobListener::connect(&db).await.unwrap().is_none());
+        // Enqueueing doesn't try to notify
+        assert!(enqueue(&db, "test", "content", super::TaskType::Ingest)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_task() {
+        let db = create_connection_by_uri("sqlite::memory:", true)
+            .await
+            .expect("Unable to connect");
+
+        let task = enqueue(&db, "test", "content", TaskType::Ingest)
+            .await
+            .unwrap();
+
+        // Times out w/ the task as-is
+        let waited = wait_for_task(&db, task.id, Duration::from_millis(50))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(waited.status, JobStatus::Queued);
+
+        // Woken up as soon as the task is done, w/o waiting for the next poll
+        let worker_db = db.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            mark_done(&worker_db, task.id).await;
+        });
+        let start = Instant::now();
+        let waited = wait_for_task(&db, task.id, Duration::from_secs(10))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(waited.status, JobStatus::Completed);
+        assert!(start.elapsed() < Duration::from_millis(900));
+
+        // Already finished
+        let waited = wait_for_task(&db, task.id, Duration::from_secs(10))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(waited.status, JobStatus::Completed);
+
+        assert!(wait_for_task(&db, 1234, Duration::from_secs(10))
+            .await
+            .unwrap()
+            .is_none());
+    }
 }
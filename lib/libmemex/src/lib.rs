@@ -1,5 +1,8 @@
+pub mod cache;
+pub mod checksum;
 pub mod db;
 pub mod llm;
+pub mod status;
 pub mod storage;
 
 // Used to generate UUIDs
@@ -1,27 +1,104 @@
-use rust_bert::{
-    pipelines::sentence_embeddings::{SentenceEmbeddingsBuilder, SentenceEmbeddingsModelType},
-    RustBertError,
+use rust_bert::pipelines::sentence_embeddings::{
+    SentenceEmbeddingsBuilder, SentenceEmbeddingsConfig, SentenceEmbeddingsModel,
+    SentenceEmbeddingsModelType,
 };
-use std::{sync::mpsc, thread::JoinHandle};
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt::Display,
+    panic::{self, AssertUnwindSafe},
+    str::FromStr,
+    sync::{mpsc, Arc, Mutex},
+    thread::JoinHandle,
+    time::Instant,
+};
+use strum_macros::{Display, EnumString, EnumVariantNames};
 use thiserror::Error;
 use tokenizers::{Tokenizer, TruncationParams};
 use tokio::{sync::oneshot, task};
 
+use crate::checksum;
+
 #[derive(Debug, Error)]
 pub enum EmbeddingError {
     #[error("Failed to encode string: {0}")]
     EncodingFailure(String),
     #[error("Unable to load model: {0}")]
     SetupError(String),
+    #[error("Embedder is not running: {0}")]
+    RunnerDied(String),
+    #[error("Invalid embedding: {0}")]
+    InvalidVector(String),
+}
+
+impl EmbeddingError {
+    /// Encoding is deterministic, so only a runner/model failure is worth retrying.
+    pub fn is_retriable(&self) -> bool {
+        matches!(
+            self,
+            EmbeddingError::SetupError(_) | EmbeddingError::RunnerDied(_)
+        )
+    }
 }
 
 #[derive(Debug)]
 pub struct EmbeddingResult {
     pub content: String,
     pub vector: Vec<f32>,
+    /// Whether the vector has been L2 normalized.
+    pub normalized: bool,
+    /// Character range (end exclusive) of the content in the original text,
+    /// if it came from a document.
+    pub offsets: Option<(usize, usize)>,
+}
+
+impl EmbeddingResult {
+    /// Validate the embedding for a piece of content, rejecting any NaN/Inf
+    /// components and optionally L2 normalizing the vector.
+    pub fn new(content: String, vector: Vec<f32>, normalize: bool) -> Result<Self, EmbeddingError> {
+        if let Some(idx) = vector.iter().position(|x| !x.is_finite()) {
+            return Err(EmbeddingError::InvalidVector(format!(
+                "non-finite value {} @ index {idx}",
+                vector[idx]
+            )));
+        }
+
+        let mut result = Self {
+            content,
+            vector,
+            normalized: false,
+            offsets: None,
+        };
+
+        if normalize {
+            result.normalized = l2_normalize(&mut result.vector);
+        }
+
+        Ok(result)
+    }
+
+    pub fn with_offsets(mut self, offsets: Option<(usize, usize)>) -> Self {
+        self.offsets = offsets;
+        self
+    }
+}
+
+/// A segment of a document, see `segment_text_with_offsets`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextSegment {
+    pub content: String,
+    /// # of tokens in the segment.
+    pub tokens: usize,
+    /// Character range (end exclusive) of the segment in the original text.
+    /// Windows overlap by the stride, so consecutive ranges do too.
+    pub offsets: (usize, usize),
 }
 
-#[derive(Clone, Copy, Debug)]
+/// Chunks added pre-chunked are stored as one document, joined w/ this.
+pub const CHUNK_SEPARATOR: &str = "\n\n";
+
+#[derive(Clone, Copy, Debug, Display, EnumString, EnumVariantNames, PartialEq, Eq, Hash)]
+#[strum(ascii_case_insensitive)]
 pub enum EmbeddingsModelType {
     DistiluseBaseMultilingualCased,
     BertBaseNliMeanTokens,
@@ -32,6 +109,20 @@ pub enum EmbeddingsModelType {
     SentenceT5Base,
 }
 
+impl EmbeddingsModelType {
+    /// Size of the vectors generated by this model.
+    pub fn dimension(&self) -> usize {
+        match self {
+            EmbeddingsModelType::AllMiniLmL12V2 | EmbeddingsModelType::AllMiniLmL6V2 => 384,
+            EmbeddingsModelType::DistiluseBaseMultilingualCased => 512,
+            EmbeddingsModelType::BertBaseNliMeanTokens
+            | EmbeddingsModelType::AllDistilrobertaV1
+            | EmbeddingsModelType::ParaphraseAlbertSmallV2
+            | EmbeddingsModelType::SentenceT5Base => 768,
+        }
+    }
+}
+
 impl From<EmbeddingsModelType> for SentenceEmbeddingsModelType {
     fn from(val: EmbeddingsModelType) -> Self {
         match val {
@@ -54,11 +145,107 @@ impl From<EmbeddingsModelType> for SentenceEmbeddingsModelType {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+/// How text longer than `max_length` tokens is embedded.
+#[derive(Clone, Copy, Debug, Default, Display, EnumString, EnumVariantNames, PartialEq, Eq)]
+#[strum(ascii_case_insensitive)]
+pub enum SegmentPolicy {
+    /// Overlapping windows, each embedded & stored as its own segment. Best
+    /// recall on long documents, at the cost of a vector per window.
+    #[default]
+    Split,
+    /// Only the first window is embedded & the rest of the text is dropped.
+    /// Cheapest, for text where only the start matters, e.g. titles.
+    TruncateHead,
+    /// Every window is embedded & averaged into one vector w/ the whole text
+    /// as its content. One vector per document, but details in long
+    /// documents get washed out.
+    MeanPool,
+}
+
+/// Where the embedding model is run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EmbeddingDevice {
+    #[default]
+    Cpu,
+    /// GPU w/ this index, falls back to the CPU if it isn't available.
+    Cuda(usize),
+}
+
+impl EmbeddingDevice {
+    /// Device the model is loaded on, w/ a warning if the requested GPU
+    /// isn't available.
+    fn resolve(self) -> tch::Device {
+        match self {
+            EmbeddingDevice::Cpu => tch::Device::Cpu,
+            EmbeddingDevice::Cuda(index) => {
+                let available = if tch::Cuda::is_available() {
+                    tch::Cuda::device_count().max(0) as usize
+                } else {
+                    0
+                };
+
+                if index < available {
+                    tch::Device::Cuda(index)
+                } else {
+                    log::warn!(
+                        "{self} requested but only {available} CUDA device(s) available, falling back to cpu"
+                    );
+                    tch::Device::Cpu
+                }
+            }
+        }
+    }
+}
+
+impl Display for EmbeddingDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmbeddingDevice::Cpu => write!(f, "cpu"),
+            EmbeddingDevice::Cuda(index) => write!(f, "cuda:{index}"),
+        }
+    }
+}
+
+/// Parses `cpu`, `cuda` (the first GPU) or `cuda:<index>`.
+impl FromStr for EmbeddingDevice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let device = s.trim().to_lowercase();
+        match device.split_once(':') {
+            None if device == "cpu" => Ok(EmbeddingDevice::Cpu),
+            None if device == "cuda" => Ok(EmbeddingDevice::Cuda(0)),
+            Some(("cuda", index)) => index
+                .parse()
+                .map(EmbeddingDevice::Cuda)
+                .map_err(|_| format!("Invalid CUDA device index: {index}")),
+            _ => Err(format!(
+                "Invalid device: {s}, must be cpu, cuda or cuda:<index>"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ModelConfig {
     model: EmbeddingsModelType,
     max_length: usize,
     stride: usize,
+    segment_policy: SegmentPolicy,
+    /// L2 normalize vectors, needed by models trained w/ dot-product similarity.
+    normalize: bool,
+    /// Max number of segments encoded at once, bounds peak memory usage for
+    /// large documents.
+    batch_size: usize,
+    /// Max number of requests waiting on the runner thread.
+    channel_bound: usize,
+    /// Prepended to search queries, for asymmetric models such as E5 ("query: ").
+    query_prefix: Option<String>,
+    /// Prepended to each document segment, e.g. "passage: " for E5.
+    doc_prefix: Option<String>,
+    /// Expected SHA-256 of the model's weights, checked before it's loaded.
+    weights_sha256: Option<String>,
+    device: EmbeddingDevice,
 }
 
 impl Default for ModelConfig {
@@ -68,137 +255,853 @@ impl Default for ModelConfig {
             max_length: 256,
             // Overlap roughly a third of the previous text.
             stride: 86,
+            segment_policy: SegmentPolicy::Split,
+            normalize: false,
+            batch_size: 32,
+            channel_bound: 100,
+            query_prefix: None,
+            doc_prefix: None,
+            weights_sha256: None,
+            device: EmbeddingDevice::Cpu,
         }
     }
 }
 
-type Message = (String, bool, oneshot::Sender<Vec<EmbeddingResult>>);
+impl ModelConfig {
+    /// Use a specific model w/ the default segmentation settings.
+    pub fn with_model(model: EmbeddingsModelType) -> Self {
+        Self {
+            model,
+            ..Default::default()
+        }
+    }
 
+    /// Switch to a different model, keeping the rest of the settings. A
+    /// checksum only applies to the model it was pinned for.
+    pub fn for_model(mut self, model: EmbeddingsModelType) -> Self {
+        if model != self.model {
+            self.weights_sha256 = None;
+        }
+        self.model = model;
+        self
+    }
+
+    /// Fail to load the model unless its weights match this SHA-256.
+    pub fn with_weights_sha256(mut self, sha256: Option<String>) -> Self {
+        self.weights_sha256 = sha256.filter(|sha256| !sha256.trim().is_empty());
+        self
+    }
+
+    pub fn model(&self) -> EmbeddingsModelType {
+        self.model
+    }
+
+    /// Max # of tokens in a single segment.
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+
+    /// Max # of tokens in a document's segments, leaving room for the doc
+    /// prefix that's prepended when they're embedded.
+    pub fn segment_length<F>(&self, count_tokens: F) -> usize
+    where
+        F: Fn(&str) -> usize,
+    {
+        let prefix_tokens = self.doc_prefix.as_deref().map(count_tokens);
+        self.max_length
+            .saturating_sub(prefix_tokens.unwrap_or_default())
+            .max(1)
+    }
+
+    /// # of tokens each segment overlaps w/ the previous one.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Segment documents into windows of `max_length` tokens, overlapping by
+    /// `stride` tokens.
+    pub fn with_segmentation(mut self, max_length: usize, stride: usize) -> Self {
+        self.max_length = max_length;
+        self.stride = stride;
+        self
+    }
+
+    pub fn segment_policy(&self) -> SegmentPolicy {
+        self.segment_policy
+    }
+
+    /// Change how documents longer than `max_length` tokens are embedded.
+    pub fn with_segment_policy(mut self, segment_policy: SegmentPolicy) -> Self {
+        self.segment_policy = segment_policy;
+        self
+    }
+
+    /// L2 normalize all vectors generated w/ this config.
+    pub fn normalized(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    pub fn is_normalized(&self) -> bool {
+        self.normalize
+    }
+
+    /// Encode at most `batch_size` segments at a time.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Allow at most `channel_bound` requests to queue up for the runner.
+    pub fn with_channel_bound(mut self, channel_bound: usize) -> Self {
+        self.channel_bound = channel_bound;
+        self
+    }
+
+    /// Prefix queries & documents differently before they're embedded.
+    pub fn with_prefixes(
+        mut self,
+        query_prefix: Option<String>,
+        doc_prefix: Option<String>,
+    ) -> Self {
+        self.query_prefix = query_prefix.filter(|prefix| !prefix.is_empty());
+        self.doc_prefix = doc_prefix.filter(|prefix| !prefix.is_empty());
+        self
+    }
+
+    pub fn device(&self) -> EmbeddingDevice {
+        self.device
+    }
+
+    /// Run the model on a GPU instead of the CPU.
+    pub fn with_device(mut self, device: EmbeddingDevice) -> Self {
+        self.device = device;
+        self
+    }
+
+    fn prefix(&self, mode: EmbedMode) -> Option<&str> {
+        match mode {
+            EmbedMode::Document | EmbedMode::Chunks => self.doc_prefix.as_deref(),
+            EmbedMode::Query => self.query_prefix.as_deref(),
+            EmbedMode::Single => None,
+        }
+    }
+}
+
+/// How text is prepared before it's embedded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EmbedMode {
+    /// Segmented, w/ each segment prefixed by the doc prefix.
+    Document,
+    /// Already segmented by the caller, each prefixed by the doc prefix.
+    Chunks,
+    /// Each text as a single segment prefixed by the query prefix.
+    Query,
+    /// A single segment, as-is.
+    Single,
+}
+
+type Message = (
+    Vec<String>,
+    EmbedMode,
+    Option<Arc<ModelConfig>>,
+    oneshot::Sender<Result<Vec<EmbeddingResult>, EmbeddingError>>,
+);
+
+/// Current state of the embedder's runner thread.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RunnerStatus {
+    /// Model is still being downloaded/loaded.
+    Loading,
+    /// Model is loaded & the runner is accepting requests.
+    Ready,
+    /// Runner exited normally (all senders were dropped).
+    Stopped,
+    /// Runner exited due to an error or panic.
+    Failed(String),
+}
+
+type SharedStatus = Arc<Mutex<RunnerStatus>>;
+
+fn set_status(status: &SharedStatus, new_status: RunnerStatus) {
+    if let Ok(mut status) = status.lock() {
+        *status = new_status;
+    }
+}
+
+/// Handle to the thread running the embedding model. Can be polled to detect
+/// setup failures (e.g. model download failed).
 #[derive(Debug)]
+pub struct EmbedderHandle {
+    handle: JoinHandle<Result<(), EmbeddingError>>,
+    status: SharedStatus,
+}
+
+impl EmbedderHandle {
+    pub fn status(&self) -> RunnerStatus {
+        self.status
+            .lock()
+            .map(|status| status.clone())
+            .unwrap_or_else(|_| RunnerStatus::Failed("status lock poisoned".into()))
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Returns the runner error if the runner thread has died.
+    pub fn check(&self) -> Result<(), EmbeddingError> {
+        match self.status() {
+            RunnerStatus::Failed(err) => Err(EmbeddingError::RunnerDied(err)),
+            RunnerStatus::Stopped => Err(EmbeddingError::RunnerDied("runner stopped".into())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait for the runner thread to exit.
+    pub fn join(self) -> Result<(), EmbeddingError> {
+        match self.handle.join() {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(err)) => Err(EmbeddingError::RunnerDied(err.to_string())),
+            Err(_) => Err(EmbeddingError::RunnerDied(runner_failure(&self.status))),
+        }
+    }
+}
+
+fn runner_failure(status: &SharedStatus) -> String {
+    match status.lock().map(|status| status.clone()) {
+        Ok(RunnerStatus::Failed(err)) => err,
+        _ => "embedder thread exited unexpectedly".into(),
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        format!("runner panicked: {msg}")
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        format!("runner panicked: {msg}")
+    } else {
+        "runner panicked".into()
+    }
+}
+
+/// Cheap to clone, clones share the same runner thread.
+#[derive(Clone, Debug)]
 pub struct SentenceEmbedder {
     sender: mpsc::SyncSender<Message>,
+    status: SharedStatus,
+    model: EmbeddingsModelType,
+    /// Settings used instead of the runner's own, see `EmbedderRegistry::get_for`.
+    settings: Option<Arc<ModelConfig>>,
 }
 
 impl SentenceEmbedder {
     /// spawn the embedder on a separate thread.
-    pub fn spawn(
-        model_config: &ModelConfig,
-    ) -> (JoinHandle<Result<(), RustBertError>>, SentenceEmbedder) {
-        let (sender, receiver) = mpsc::sync_channel(100);
+    pub fn spawn(model_config: &ModelConfig) -> (EmbedderHandle, SentenceEmbedder) {
+        let (sender, receiver) = mpsc::sync_channel(model_config.channel_bound);
+        let model = model_config.model;
         let model_config = model_config.to_owned();
-        let handle = std::thread::spawn(move || Self::runner(receiver, model_config));
-        (handle, SentenceEmbedder { sender })
+        let status: SharedStatus = Arc::new(Mutex::new(RunnerStatus::Loading));
+
+        let runner_status = status.clone();
+        let handle = std::thread::spawn(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                Self::runner(receiver, model_config, &runner_status)
+            }));
+
+            match result {
+                Ok(Ok(())) => {
+                    set_status(&runner_status, RunnerStatus::Stopped);
+                    Ok(())
+                }
+                Ok(Err(err)) => {
+                    log::error!("embedder runner failed: {err}");
+                    set_status(&runner_status, RunnerStatus::Failed(err.to_string()));
+                    Err(err)
+                }
+                Err(panic) => {
+                    let msg = panic_message(panic.as_ref());
+                    log::error!("embedder {msg}");
+                    set_status(&runner_status, RunnerStatus::Failed(msg));
+                    panic::resume_unwind(panic)
+                }
+            }
+        });
+
+        (
+            EmbedderHandle {
+                handle,
+                status: status.clone(),
+            },
+            SentenceEmbedder {
+                sender,
+                status,
+                model,
+                settings: None,
+            },
+        )
     }
 
     /// The sentence embedding runner itself
     fn runner(
         receiver: mpsc::Receiver<Message>,
         model_config: ModelConfig,
-    ) -> Result<(), RustBertError> {
+        status: &SharedStatus,
+    ) -> Result<(), EmbeddingError> {
         // Needs to be in sync runtime, async doesn't work
+        verify_weights(&model_config)?;
+        let device = model_config.device.resolve();
+        log::info!("loading {} on {device:?}", model_config.model);
         let model: rust_bert::pipelines::sentence_embeddings::SentenceEmbeddingsModel =
-            SentenceEmbeddingsBuilder::remote(model_config.model.into()).create_model()?;
-
-        while let Ok((text, segment, sender)) = receiver.recv() {
-            let segments = if segment {
-                segment_text(&model_config, &text).unwrap()
-            } else {
-                vec![text]
-            };
-
-            let embeddings = model.encode(&segments)?;
-            if segments.len() != embeddings.len() {
-                log::error!("# of embeddings doesn't match # of segments");
-                return Err(RustBertError::TokenizerError(
-                    "# of embeddings doesn't match # of segments".into(),
-                ));
-            }
+            SentenceEmbeddingsBuilder::remote(model_config.model.into())
+                .with_device(device)
+                .create_model()
+                .map_err(|err| EmbeddingError::SetupError(err.to_string()))?;
+        set_status(status, RunnerStatus::Ready);
 
-            let results = segments
-                .iter()
-                .enumerate()
-                .map(|(idx, content)| {
-                    let vector = embeddings
-                        .get(idx)
-                        .expect("# of embeddings should match # of segments");
-                    EmbeddingResult {
-                        content: content.to_owned(),
-                        vector: vector.to_owned(),
+        while let Ok((texts, mode, settings, sender)) = receiver.recv() {
+            let config = settings.as_deref().unwrap_or(&model_config);
+            let results = Self::embed(&model, config, texts, mode);
+            // Caller may have gone away, nothing to do in that case.
+            let _ = sender.send(results);
+        }
+
+        Ok(())
+    }
+
+    fn embed(
+        model: &SentenceEmbeddingsModel,
+        model_config: &ModelConfig,
+        texts: Vec<String>,
+        mode: EmbedMode,
+    ) -> Result<Vec<EmbeddingResult>, EmbeddingError> {
+        let mut windows = Vec::new();
+        let mut offsets = Vec::new();
+        let segments = match mode {
+            EmbedMode::Document => {
+                let mut segments = Vec::new();
+                for text in &texts {
+                    let text_segments = segment_text_with_offsets(model_config, text)?;
+                    windows.push(text_segments.len());
+                    for segment in text_segments {
+                        offsets.push(Some(segment.offsets));
+                        segments.push(segment.content);
                     }
-                })
-                .collect::<Vec<EmbeddingResult>>();
+                }
+                segments
+            }
+            EmbedMode::Chunks => {
+                offsets = chunk_offsets(&texts).into_iter().map(Some).collect();
+                texts.clone()
+            }
+            EmbedMode::Query | EmbedMode::Single => {
+                offsets = vec![None; texts.len()];
+                texts.clone()
+            }
+        };
+        if segments.is_empty() {
+            return Ok(Vec::new());
+        }
 
-            sender.send(results).expect("sending results");
+        // Segments are stored w/o the prefix, it's only used for the embedding.
+        let inputs = with_prefix(&segments, model_config.prefix(mode));
+        let embeddings = encode_in_batches(&inputs, model_config.batch_size, |batch| {
+            model
+                .encode(batch)
+                .map_err(|err| EmbeddingError::EncodingFailure(err.to_string()))
+        })?;
+        if segments.len() != embeddings.len() {
+            log::error!("# of embeddings doesn't match # of segments");
+            return Err(EmbeddingError::EncodingFailure(
+                "# of embeddings doesn't match # of segments".into(),
+            ));
         }
 
-        Ok(())
+        if mode == EmbedMode::Document && model_config.segment_policy == SegmentPolicy::MeanPool {
+            return mean_pool(texts, &windows, embeddings, model_config.normalize);
+        }
+
+        segments
+            .into_iter()
+            .zip(embeddings)
+            .zip(offsets)
+            .map(|((content, vector), offsets)| {
+                EmbeddingResult::new(content, vector, model_config.normalize)
+                    .map(|result| result.with_offsets(offsets))
+            })
+            .collect::<Result<Vec<EmbeddingResult>, EmbeddingError>>()
+    }
+
+    /// Same runner, w/ documents segmented & prefixed using `config` instead
+    /// of the settings the runner was spawned w/. The model must match.
+    fn with_settings(mut self, config: &ModelConfig) -> Self {
+        debug_assert_eq!(self.model, config.model());
+        self.settings = Some(Arc::new(config.clone()));
+        self
     }
 
-    /// Encode the sentences and return the results
-    pub async fn encode(&self, text: String) -> anyhow::Result<Vec<EmbeddingResult>> {
+    fn runner_died(&self) -> EmbeddingError {
+        EmbeddingError::RunnerDied(runner_failure(&self.status))
+    }
+
+    #[tracing::instrument(
+        name = "embed",
+        skip_all,
+        fields(
+            model = %self.model,
+            mode = ?mode,
+            chars = texts.iter().map(|text| text.len()).sum::<usize>(),
+            segments = tracing::field::Empty
+        )
+    )]
+    async fn request(
+        &self,
+        texts: Vec<String>,
+        mode: EmbedMode,
+    ) -> Result<Vec<EmbeddingResult>, EmbeddingError> {
         let (sender, receiver) = oneshot::channel();
-        task::block_in_place(|| self.sender.send((text, true, sender)))?;
-        Ok(receiver.await?)
+        task::block_in_place(|| {
+            self.sender
+                .send((texts, mode, self.settings.clone(), sender))
+        })
+        .map_err(|_| self.runner_died())?;
+        let results = receiver.await.map_err(|_| self.runner_died())??;
+        tracing::Span::current().record("segments", results.len());
+        Ok(results)
+    }
+
+    /// Segment a document & encode each segment w/ the doc prefix.
+    pub async fn encode_document(
+        &self,
+        text: String,
+    ) -> Result<Vec<EmbeddingResult>, EmbeddingError> {
+        self.request(vec![text], EmbedMode::Document).await
+    }
+
+    /// Encode segments chunked by the caller w/ the doc prefix, in order.
+    /// Chunks larger than the context size are truncated, check them against
+    /// `token_counter` first.
+    pub async fn encode_chunks(
+        &self,
+        chunks: Vec<String>,
+    ) -> Result<Vec<EmbeddingResult>, EmbeddingError> {
+        if chunks.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.request(chunks, EmbedMode::Chunks).await
+    }
+
+    /// Encode a search query w/ the query prefix. If the query is larger than
+    /// the context size, it will be truncated.
+    pub async fn encode_query(
+        &self,
+        text: String,
+    ) -> Result<Option<EmbeddingResult>, EmbeddingError> {
+        let mut value = self.request(vec![text], EmbedMode::Query).await?;
+        Ok(value.pop())
+    }
+
+    /// Encode several search queries at once, returning their embeddings in
+    /// the same order.
+    pub async fn encode_batch(
+        &self,
+        texts: Vec<String>,
+    ) -> Result<Vec<EmbeddingResult>, EmbeddingError> {
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.request(texts, EmbedMode::Query).await
     }
 
     /// Single shot encoding, no segmentation. If the text is larger than the context size,
     /// it will be truncated.
-    pub async fn encode_single(&self, text: String) -> anyhow::Result<Option<EmbeddingResult>> {
-        let (sender, receiver) = oneshot::channel();
-        task::block_in_place(|| self.sender.send((text, false, sender)))?;
-        let mut value = receiver.await?;
+    pub async fn encode_single(
+        &self,
+        text: String,
+    ) -> Result<Option<EmbeddingResult>, EmbeddingError> {
+        let mut value = self.request(vec![text], EmbedMode::Single).await?;
         Ok(value.pop())
     }
 }
 
+/// Default max # of models an `EmbedderRegistry` keeps loaded at once.
+pub const DEFAULT_MAX_RESIDENT_MODELS: usize = 2;
+
+struct ResidentEmbedder {
+    handle: EmbedderHandle,
+    embedder: SentenceEmbedder,
+    last_used: Instant,
+}
+
+/// Embedders for several models in one process, each model is loaded the
+/// first time it's needed & reused after that. Once more than `capacity`
+/// models are loaded, the least recently used one is dropped. Callers still
+/// holding its embedder keep it running until they're done.
+///
+/// Every embedder shares the base config's settings apart from the model. Use
+/// `get_for` to encode documents w/ other segmentation settings on the same
+/// runner, rather than loading another copy of the model.
+pub struct EmbedderRegistry {
+    base: ModelConfig,
+    capacity: usize,
+    resident: Mutex<HashMap<EmbeddingsModelType, ResidentEmbedder>>,
+}
+
+impl EmbedderRegistry {
+    pub fn new(base: ModelConfig, capacity: usize) -> Self {
+        Self {
+            base,
+            capacity: capacity.max(1),
+            resident: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Settings each embedder is spawned w/, apart from the model.
+    pub fn base(&self) -> &ModelConfig {
+        &self.base
+    }
+
+    /// Embedder for `model`, spawning it if it isn't loaded yet or its
+    /// runner has died.
+    pub fn get(&self, model: EmbeddingsModelType) -> SentenceEmbedder {
+        self.get_or_spawn(model, SentenceEmbedder::spawn)
+    }
+
+    /// Embedder for `config`'s model that segments & prefixes documents w/
+    /// `config`'s settings instead of the base config's.
+    pub fn get_for(&self, config: &ModelConfig) -> SentenceEmbedder {
+        self.get(config.model()).with_settings(config)
+    }
+
+    /// Models currently loaded.
+    pub fn resident(&self) -> Vec<EmbeddingsModelType> {
+        self.resident
+            .lock()
+            .map(|resident| resident.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn get_or_spawn(
+        &self,
+        model: EmbeddingsModelType,
+        spawn: impl FnOnce(&ModelConfig) -> (EmbedderHandle, SentenceEmbedder),
+    ) -> SentenceEmbedder {
+        let mut resident = match self.resident.lock() {
+            Ok(resident) => resident,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if let Some(entry) = resident.get_mut(&model) {
+            if entry.handle.check().is_ok() {
+                entry.last_used = Instant::now();
+                return entry.embedder.clone();
+            }
+            log::warn!("embedder for {model} died, restarting it");
+            resident.remove(&model);
+        }
+
+        while resident.len() >= self.capacity {
+            let oldest = resident
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(model, _)| *model);
+            match oldest {
+                Some(oldest) => {
+                    log::info!("unloading embedding model {oldest}");
+                    resident.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+
+        let (handle, embedder) = spawn(&self.base.clone().for_model(model));
+        resident.insert(
+            model,
+            ResidentEmbedder {
+                handle,
+                embedder: embedder.clone(),
+                last_used: Instant::now(),
+            },
+        );
+        embedder
+    }
+}
+
+/// Cosine similarity between two vectors, returns 0.0 if either vector has no
+/// magnitude or the dimensions don't match.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+
+    dot / (norm_a * norm_b)
+}
+
+/// Average the window embeddings of each text into a single embedding, w/
+/// `windows` holding the # of windows for each text.
+fn mean_pool(
+    texts: Vec<String>,
+    windows: &[usize],
+    embeddings: Vec<Vec<f32>>,
+    normalize: bool,
+) -> Result<Vec<EmbeddingResult>, EmbeddingError> {
+    let mut embeddings = embeddings.into_iter();
+    let mut results = Vec::with_capacity(texts.len());
+    for (text, count) in texts.into_iter().zip(windows) {
+        let mut pooled: Option<Vec<f32>> = None;
+        for vector in embeddings.by_ref().take(*count) {
+            match pooled.as_mut() {
+                Some(sum) if sum.len() == vector.len() => {
+                    sum.iter_mut().zip(&vector).for_each(|(sum, x)| *sum += x);
+                }
+                Some(_) => {
+                    return Err(EmbeddingError::InvalidVector(
+                        "windows have different dimensions".into(),
+                    ))
+                }
+                None => pooled = Some(vector),
+            }
+        }
+
+        if let Some(mut vector) = pooled {
+            vector.iter_mut().for_each(|x| *x /= *count as f32);
+            // The pooled embedding stands in for the whole text.
+            let offsets = (0, text.chars().count());
+            results
+                .push(EmbeddingResult::new(text, vector, normalize)?.with_offsets(Some(offsets)));
+        }
+    }
+
+    Ok(results)
+}
+
+/// Download the model's weights (unless they're already cached) & check them
+/// against the pinned checksum, a truncated download otherwise loads fine &
+/// produces garbage. Hashing the weights is slow, so it's skipped when nothing
+/// is pinned.
+fn verify_weights(model_config: &ModelConfig) -> Result<(), EmbeddingError> {
+    let Some(expected) = model_config.weights_sha256.as_deref() else {
+        return Ok(());
+    };
+
+    let config =
+        SentenceEmbeddingsConfig::from(SentenceEmbeddingsModelType::from(model_config.model));
+    let weights = config
+        .transformer_weights_resource
+        .get_local_path()
+        .map_err(|err| EmbeddingError::SetupError(err.to_string()))?;
+
+    checksum::verify_sha256(&weights, Some(expected))
+        .map_err(|err| EmbeddingError::SetupError(err.to_string()))
+}
+
+/// Encode segments `batch_size` at a time, concatenating the results.
+fn encode_in_batches<F>(
+    segments: &[String],
+    batch_size: usize,
+    mut encode: F,
+) -> Result<Vec<Vec<f32>>, EmbeddingError>
+where
+    F: FnMut(&[String]) -> Result<Vec<Vec<f32>>, EmbeddingError>,
+{
+    let mut embeddings = Vec::with_capacity(segments.len());
+    for batch in segments.chunks(batch_size.max(1)) {
+        embeddings.extend(encode(batch)?);
+    }
+
+    Ok(embeddings)
+}
+
+fn with_prefix(segments: &[String], prefix: Option<&str>) -> Vec<String> {
+    match prefix {
+        Some(prefix) => segments
+            .iter()
+            .map(|segment| format!("{prefix}{segment}"))
+            .collect(),
+        None => segments.to_vec(),
+    }
+}
+
+/// Scale a vector to unit length in place. Returns false if the vector has no
+/// magnitude & can't be normalized.
+pub fn l2_normalize(vec: &mut [f32]) -> bool {
+    let norm = vec.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 || !norm.is_finite() {
+        return false;
+    }
+
+    vec.iter_mut().for_each(|x| *x /= norm);
+    true
+}
+
 /// Segment a doc into the proper windowed
 pub fn segment_text(model_config: &ModelConfig, text: &str) -> Result<Vec<String>, EmbeddingError> {
-    let model_name = match model_config.model {
+    let segments = segment_text_with_offsets(model_config, text)?;
+    Ok(segments
+        .into_iter()
+        .map(|segment| segment.content)
+        .collect())
+}
+
+/// Same as `segment_text`, also returning the # of tokens in each segment.
+pub fn segment_text_with_tokens(
+    model_config: &ModelConfig,
+    text: &str,
+) -> Result<Vec<(String, usize)>, EmbeddingError> {
+    let segments = segment_text_with_offsets(model_config, text)?;
+    Ok(segments
+        .into_iter()
+        .map(|segment| (segment.content, segment.tokens))
+        .collect())
+}
+
+/// Tokenizer for the model's vocabulary.
+fn load_tokenizer(model: EmbeddingsModelType) -> Result<Tokenizer, EmbeddingError> {
+    let model_name = match model {
         EmbeddingsModelType::AllMiniLmL12V2 => "sentence-transformers/all-MiniLM-L12-v2",
         EmbeddingsModelType::AllMiniLmL6V2 => "sentence-transformers/all-MiniLM-L6-v2",
         EmbeddingsModelType::AllDistilrobertaV1 => "sentence-transformers/all-distilroberta-v1",
         _ => return Err(EmbeddingError::SetupError("Model not supported yet".into())),
     };
 
-    let mut tokenizer = match Tokenizer::from_pretrained(model_name, None) {
-        Ok(tokenizer) => tokenizer,
-        Err(_) => {
-            return Err(EmbeddingError::SetupError(format!(
-                "Unable to load model <{}>",
-                model_name
-            )))
-        }
-    };
+    Tokenizer::from_pretrained(model_name, None)
+        .map_err(|_| EmbeddingError::SetupError(format!("Unable to load model <{}>", model_name)))
+}
+
+/// Counts the tokens in a text the way segments are counted, i.e. w/o special
+/// tokens or truncation.
+pub fn token_counter(model_config: &ModelConfig) -> Result<impl Fn(&str) -> usize, EmbeddingError> {
+    let tokenizer = load_tokenizer(model_config.model)?;
+    Ok(move |text: &str| {
+        tokenizer
+            .encode(text, false)
+            .map(|encoding| encoding.get_ids().len())
+            .unwrap_or_default()
+    })
+}
 
+/// Same as `segment_text`, also returning the # of tokens in each segment &
+/// where it is in `text`.
+pub fn segment_text_with_offsets(
+    model_config: &ModelConfig,
+    text: &str,
+) -> Result<Vec<TextSegment>, EmbeddingError> {
+    let mut tokenizer = load_tokenizer(model_config.model)?;
+    let max_length = model_config.segment_length(|text| {
+        tokenizer
+            .encode(text, false)
+            .map(|encoding| encoding.get_ids().len())
+            .unwrap_or_default()
+    });
+    // The stride has to stay under the shortened length or truncation is
+    // refused.
     let _ = tokenizer.with_truncation(Some(TruncationParams {
-        max_length: model_config.max_length,
-        stride: model_config.stride,
+        max_length,
+        stride: model_config.stride.min(max_length - 1),
         ..Default::default()
     }));
 
     let mut segments = Vec::new();
 
-    let encoding = tokenizer.encode(text, false).unwrap();
+    // Offsets are in characters rather than bytes, to match what clients
+    // see when highlighting the text.
+    let encoding = tokenizer
+        .encode_char_offsets(text, false)
+        .map_err(|_| EmbeddingError::EncodingFailure(text.to_string()))?;
     let decoded = match tokenizer.decode(encoding.get_ids(), true) {
         Ok(decoded) => decoded.replace(" ' ", "'"),
         Err(_) => return Err(EmbeddingError::EncodingFailure(text.to_string())),
     };
 
-    segments.push(decoded);
+    // Nothing worth embedding in blank text
+    if !decoded.trim().is_empty() {
+        segments.push(TextSegment {
+            content: decoded,
+            tokens: encoding.get_ids().len(),
+            offsets: encoding_span(encoding.get_offsets()),
+        });
+    }
+    if model_config.segment_policy == SegmentPolicy::TruncateHead {
+        return Ok(segments);
+    }
+
     for encoding in encoding.get_overflowing() {
         let decoded = match tokenizer.decode(encoding.get_ids(), true) {
             Ok(decoded) => decoded,
             Err(_) => return Err(EmbeddingError::EncodingFailure(text.to_string())),
         };
 
-        segments.push(decoded);
+        if !decoded.trim().is_empty() {
+            segments.push(TextSegment {
+                content: decoded,
+                tokens: encoding.get_ids().len(),
+                offsets: encoding_span(encoding.get_offsets()),
+            });
+        }
     }
 
     Ok(segments)
 }
 
+/// Range of text covered by a window's tokens. Each overflowing window keeps
+/// the offsets of its tokens in the full text, including the ones it shares
+/// w/ the previous window.
+fn encoding_span(offsets: &[(usize, usize)]) -> (usize, usize) {
+    let start = offsets.iter().map(|(start, _)| *start).min();
+    let end = offsets.iter().map(|(_, end)| *end).max();
+    (start.unwrap_or_default(), end.unwrap_or_default())
+}
+
+/// Character ranges of `chunks` in the document they're joined into w/
+/// `CHUNK_SEPARATOR`.
+pub fn chunk_offsets(chunks: &[String]) -> Vec<(usize, usize)> {
+    let separator = CHUNK_SEPARATOR.chars().count();
+    let mut start = 0;
+    chunks
+        .iter()
+        .map(|chunk| {
+            let end = start + chunk.chars().count();
+            let offsets = (start, end);
+            start = end + separator;
+            offsets
+        })
+        .collect()
+}
+
+/// Share of the shorter segment, from 0.0 to 1.0, that's repeated where
+/// `earlier` ends & `later` starts, i.e. the text windowed segments have in
+/// common. Compared by word since segments are decoded separately.
+pub fn segment_overlap(earlier: &str, later: &str) -> f32 {
+    let earlier: Vec<&str> = earlier.split_whitespace().collect();
+    let later: Vec<&str> = later.split_whitespace().collect();
+    let shortest = earlier.len().min(later.len());
+    if shortest == 0 {
+        return 0.0;
+    }
+
+    let overlap = (1..=shortest)
+        .rev()
+        .find(|&len| earlier[earlier.len() - len..] == later[..len])
+        .unwrap_or(0);
+    overlap as f32 / shortest as f32
+}
+
 #[cfg(test)]
 mod test {
+    use super::{
+        chunk_offsets, cosine_similarity, encode_in_batches, mean_pool, segment_overlap,
+        segment_text, segment_text_with_offsets, with_prefix, EmbedMode, EmbedderHandle,
+        EmbedderRegistry, EmbeddingDevice, EmbeddingError, EmbeddingResult, EmbeddingsModelType,
+        ModelConfig, RunnerStatus, SegmentPolicy, SentenceEmbedder,
+    };
+    use std::sync::{mpsc, Arc, Mutex};
     use tokenizers::{Tokenizer, TruncationParams};
 
     #[test]
@@ -215,4 +1118,295 @@ mod test {
         let encoding = tokenizer.encode(string, false).unwrap();
         assert_eq!(encoding.len(), 128);
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_encode_dead_runner() {
+        // Simulate a runner that failed to load its model.
+        let (sender, receiver) = mpsc::sync_channel(1);
+        drop(receiver);
+        let status = Arc::new(Mutex::new(RunnerStatus::Failed(
+            "unable to download model".into(),
+        )));
+        let embedder = SentenceEmbedder {
+            sender,
+            status,
+            model: EmbeddingsModelType::AllMiniLmL12V2,
+            settings: None,
+        };
+
+        let err = embedder
+            .encode_document("this is a test".into())
+            .await
+            .unwrap_err();
+        match err {
+            EmbeddingError::RunnerDied(msg) => assert!(msg.contains("unable to download model")),
+            _ => panic!("unexpected error: {err}"),
+        }
+    }
+
+    /// Embedder that never loads a model, w/ its runner in the given state.
+    fn fake_embedder(
+        model_config: &ModelConfig,
+        status: RunnerStatus,
+    ) -> (EmbedderHandle, SentenceEmbedder) {
+        let (sender, _) = mpsc::sync_channel(1);
+        let status = Arc::new(Mutex::new(status));
+        (
+            EmbedderHandle {
+                handle: std::thread::spawn(|| Ok(())),
+                status: status.clone(),
+            },
+            SentenceEmbedder {
+                sender,
+                status,
+                model: model_config.model(),
+                settings: None,
+            },
+        )
+    }
+
+    #[test]
+    fn test_embedder_registry() {
+        let registry = EmbedderRegistry::new(ModelConfig::default(), 2);
+        let spawned = Mutex::new(Vec::new());
+        let spawn = |config: &ModelConfig| {
+            spawned.lock().unwrap().push(config.model());
+            fake_embedder(config, RunnerStatus::Ready)
+        };
+
+        let embedder = registry.get_or_spawn(EmbeddingsModelType::AllMiniLmL6V2, spawn);
+        assert_eq!(embedder.model, EmbeddingsModelType::AllMiniLmL6V2);
+        // Loaded models are reused
+        registry.get_or_spawn(EmbeddingsModelType::AllMiniLmL6V2, spawn);
+        registry.get_or_spawn(EmbeddingsModelType::SentenceT5Base, spawn);
+        assert_eq!(spawned.lock().unwrap().len(), 2);
+
+        // Least recently used model is dropped
+        registry.get_or_spawn(EmbeddingsModelType::AllMiniLmL6V2, spawn);
+        registry.get_or_spawn(EmbeddingsModelType::AllMiniLmL12V2, spawn);
+        let mut resident = registry.resident();
+        resident.sort_by_key(|model| model.to_string());
+        assert_eq!(
+            resident,
+            vec![
+                EmbeddingsModelType::AllMiniLmL12V2,
+                EmbeddingsModelType::AllMiniLmL6V2
+            ]
+        );
+
+        // Dead runners are replaced
+        let registry = EmbedderRegistry::new(ModelConfig::default(), 2);
+        registry.get_or_spawn(EmbeddingsModelType::AllMiniLmL6V2, |config| {
+            fake_embedder(config, RunnerStatus::Failed("oom".into()))
+        });
+        registry.get_or_spawn(EmbeddingsModelType::AllMiniLmL6V2, spawn);
+        assert_eq!(spawned.lock().unwrap().len(), 4);
+
+        // Other segmentation settings share the loaded model
+        let config =
+            ModelConfig::with_model(EmbeddingsModelType::AllMiniLmL6V2).with_segmentation(128, 32);
+        let embedder = registry.get_for(&config);
+        assert_eq!(spawned.lock().unwrap().len(), 4);
+        assert_eq!(embedder.settings.as_deref(), Some(&config));
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        let a = vec![1.0, 2.0, 3.0];
+        assert!((cosine_similarity(&a, &a) - 1.0).abs() < 1e-6);
+        assert!((cosine_similarity(&a, &[2.0, 4.0, 6.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 0.0], &[-1.0, 0.0]) + 1.0).abs() < 1e-6);
+        // Degenerate inputs
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0], &[1.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn test_normalize_embedding() {
+        let result = EmbeddingResult::new("test".into(), vec![3.0, 4.0], true).unwrap();
+        assert!(result.normalized);
+        let norm = result.vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-6);
+        assert!((result.vector[0] - 0.6).abs() < 1e-6);
+
+        // Left as-is when normalization is off
+        let result = EmbeddingResult::new("test".into(), vec![3.0, 4.0], false).unwrap();
+        assert!(!result.normalized);
+        assert_eq!(result.vector, vec![3.0, 4.0]);
+
+        // Zero vectors can't be normalized
+        let result = EmbeddingResult::new("test".into(), vec![0.0, 0.0], true).unwrap();
+        assert!(!result.normalized);
+
+        // NaN/Inf values are rejected
+        let err = EmbeddingResult::new("test".into(), vec![1.0, f32::NAN], true).unwrap_err();
+        assert!(matches!(err, EmbeddingError::InvalidVector(_)));
+        let err = EmbeddingResult::new("test".into(), vec![f32::INFINITY, 1.0], false).unwrap_err();
+        assert!(matches!(err, EmbeddingError::InvalidVector(_)));
+    }
+
+    #[test]
+    fn test_encode_in_batches() {
+        let segments = (0..10_000)
+            .map(|idx| format!("segment {idx}"))
+            .collect::<Vec<_>>();
+
+        let mut num_batches = 0;
+        let mut largest_batch = 0;
+        let embeddings = encode_in_batches(&segments, 32, |batch| {
+            num_batches += 1;
+            largest_batch = largest_batch.max(batch.len());
+            Ok(batch.iter().map(|seg| vec![seg.len() as f32]).collect())
+        })
+        .unwrap();
+
+        assert_eq!(embeddings.len(), segments.len());
+        assert_eq!(largest_batch, 32);
+        assert_eq!(num_batches, 313);
+        // Results are concatenated in order
+        assert_eq!(embeddings[0], vec![9.0]);
+        assert_eq!(embeddings[9_999], vec![12.0]);
+
+        // Errors from any batch are returned
+        let res = encode_in_batches(&segments, 32, |_| {
+            Err(EmbeddingError::EncodingFailure("oom".into()))
+        });
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_segment_policy() {
+        let text = "the quick brown fox jumps over the lazy dog. ".repeat(100);
+        let config = ModelConfig::default();
+        assert!(segment_text(&config, &text).unwrap().len() > 1);
+
+        let config = config.with_segment_policy(SegmentPolicy::TruncateHead);
+        assert_eq!(segment_text(&config, &text).unwrap().len(), 1);
+
+        // Pooling still embeds every window
+        let config = config.with_segment_policy(SegmentPolicy::MeanPool);
+        assert!(segment_text(&config, &text).unwrap().len() > 1);
+    }
+
+    #[test]
+    fn test_segment_blank_text() {
+        let config = ModelConfig::default();
+        assert!(segment_text(&config, "").unwrap().is_empty());
+        assert!(segment_text(&config, " \n\t ").unwrap().is_empty());
+        assert_eq!(segment_text(&config, " test ").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_segment_offsets() {
+        let text = "the quick brown fox jumps over the lazy dog. ".repeat(100);
+        let segments = segment_text_with_offsets(&ModelConfig::default(), &text).unwrap();
+        assert!(segments.len() > 1);
+        assert_eq!(segments[0].offsets.0, 0);
+        assert_eq!(segments.last().unwrap().offsets.1, text.trim_end().len());
+        for pair in segments.windows(2) {
+            // Windows overlap by the stride
+            assert!(pair[1].offsets.0 < pair[0].offsets.1);
+            assert!(pair[1].offsets.1 > pair[0].offsets.1);
+        }
+
+        // Offsets are in characters, not bytes
+        let segments = segment_text_with_offsets(&ModelConfig::default(), "café au lait").unwrap();
+        assert_eq!(segments[0].offsets, (0, 12));
+    }
+
+    #[test]
+    fn test_chunk_offsets() {
+        let chunks = vec!["first".to_string(), "café".to_string(), "x".to_string()];
+        assert_eq!(chunk_offsets(&chunks), vec![(0, 5), (7, 11), (13, 14)]);
+        let joined = chunks.join(super::CHUNK_SEPARATOR);
+        assert_eq!(joined.chars().skip(7).take(4).collect::<String>(), "café");
+        assert!(chunk_offsets(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_segment_overlap() {
+        let earlier = "the quick brown fox jumps over";
+        assert_eq!(segment_overlap(earlier, "fox jumps over the lazy dog"), 0.5);
+        assert_eq!(segment_overlap(earlier, earlier), 1.0);
+        assert_eq!(segment_overlap(earlier, "jumps over"), 1.0);
+        // Order matters, only the end of the earlier segment is repeated.
+        assert_eq!(segment_overlap("fox jumps over the lazy dog", earlier), 0.0);
+        assert_eq!(segment_overlap(earlier, "something else entirely"), 0.0);
+        assert_eq!(segment_overlap("", earlier), 0.0);
+    }
+
+    #[test]
+    fn test_mean_pool() {
+        let texts = vec!["first".to_string(), "second".to_string()];
+        let embeddings = vec![
+            vec![1.0, 0.0],
+            vec![3.0, 2.0],
+            vec![2.0, 4.0],
+            vec![0.0, 3.0],
+            vec![0.0, 3.0],
+        ];
+        let results = mean_pool(texts, &[2, 3], embeddings, false).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].content, "first");
+        assert_eq!(results[0].vector, vec![2.0, 1.0]);
+        assert_eq!(results[1].content, "second");
+        assert_eq!(results[1].vector, vec![2.0 / 3.0, 10.0 / 3.0]);
+
+        let results = mean_pool(vec!["text".into()], &[2], vec![vec![3.0, 0.0]; 2], true).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].vector, vec![1.0, 0.0]);
+
+        let err = mean_pool(
+            vec!["text".into()],
+            &[2],
+            vec![vec![1.0, 0.0], vec![1.0]],
+            false,
+        )
+        .unwrap_err();
+        assert!(matches!(err, EmbeddingError::InvalidVector(_)));
+    }
+
+    #[test]
+    fn test_prefixes() {
+        let config =
+            ModelConfig::default().with_prefixes(Some("query: ".into()), Some("passage: ".into()));
+        assert_eq!(config.prefix(EmbedMode::Query), Some("query: "));
+        assert_eq!(config.prefix(EmbedMode::Document), Some("passage: "));
+        assert_eq!(config.prefix(EmbedMode::Chunks), Some("passage: "));
+        assert_eq!(config.prefix(EmbedMode::Single), None);
+
+        let segments = vec!["first".to_string(), "second".to_string()];
+        assert_eq!(
+            with_prefix(&segments, config.prefix(EmbedMode::Document)),
+            vec!["passage: first", "passage: second"]
+        );
+
+        // The doc prefix counts against each segment's length
+        let count_chars = |text: &str| text.chars().count();
+        let config = config.with_segmentation(128, 16);
+        assert_eq!(config.segment_length(count_chars), 128 - 9);
+        let config = config.with_segmentation(4, 2);
+        assert_eq!(config.segment_length(count_chars), 1);
+
+        // Empty prefixes are ignored
+        let config = ModelConfig::default().with_prefixes(Some(String::new()), None);
+        assert_eq!(config.prefix(EmbedMode::Query), None);
+        assert_eq!(with_prefix(&segments, None), segments);
+    }
+
+    #[test]
+    fn test_parse_device() {
+        assert_eq!("cpu".parse(), Ok(EmbeddingDevice::Cpu));
+        assert_eq!("CUDA".parse(), Ok(EmbeddingDevice::Cuda(0)));
+        assert_eq!("cuda:1".parse(), Ok(EmbeddingDevice::Cuda(1)));
+        assert!("cuda:".parse::<EmbeddingDevice>().is_err());
+        assert!("gpu".parse::<EmbeddingDevice>().is_err());
+        assert_eq!(EmbeddingDevice::Cuda(1).to_string(), "cuda:1");
+
+        // No GPU to run on
+        if !tch::Cuda::is_available() {
+            assert_eq!(EmbeddingDevice::Cuda(0).resolve(), tch::Device::Cpu);
+        }
+    }
 }
@@ -3,20 +3,21 @@ use llm::samplers::llm_samplers::types::SamplerChain;
 use llm::{self, samplers::ConfiguredSamplers, InferenceSessionConfig};
 use llm::{InferenceParameters, LoadProgress};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use tiktoken_rs::cl100k_base;
 use tokio::sync::mpsc;
 
-use crate::llm::{split_text, ChatRole};
+use crate::checksum;
+use crate::llm::{find_stop, split_text, truncate_to_tokens, ChatRole};
 
-use self::schema::{LocalLLMConfig, ModelArch};
+use self::schema::{LocalLLMConfig, ModelArch, ModelConfig, SamplerSettings};
 
-use super::{ChatMessage, LLMError, LLM};
+use super::{ChatCompletionOptions, ChatMessage, LLMError, LLM};
 mod schema;
 use schema::LlmEvent;
 
-pub const MAX_TOKENS: usize = 2_048 - 512 - 100;
+pub const CONTEXT_WINDOW: usize = 2_048;
+pub const MAX_TOKENS: usize = CONTEXT_WINDOW - 512 - 100;
 
 #[derive(Clone)]
 pub struct LocalLLM<T>
@@ -24,6 +25,9 @@ where
     T: llm::KnownModel,
 {
     model: T,
+    /// Name of the loaded model, taken from its file name.
+    name: String,
+    model_config: ModelConfig,
     infer_params: InferenceParameters,
     /// At the moment does nothing but will eventually be used by our internal
     /// sampler to only output JSON/etc.
@@ -34,25 +38,55 @@ impl<T> LocalLLM<T>
 where
     T: llm::KnownModel,
 {
-    fn new(model: T, base_samplers: ConfiguredSamplers) -> Self {
+    fn new(model: T, model_config: ModelConfig) -> Self {
         let bias_sampler = SampleFlatBias::default();
-        // Create sampler chain
-        let mut samplers = SamplerChain::new();
-        samplers += bias_sampler.clone();
-        samplers += base_samplers.builder.into_chain();
-
-        let infer_params = llm::InferenceParameters {
-            sampler: Arc::new(Mutex::new(samplers)), // sampler: llm::samplers::default_samplers(),
-        };
+        let infer_params = build_infer_params(
+            &bias_sampler,
+            model_config.sampler_settings().base_samplers(),
+        );
+        let name = model_config
+            .path
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
 
         Self {
             model,
+            name,
+            model_config,
             infer_params,
             _bias_sampler: Arc::new(Mutex::new(bias_sampler)),
         }
     }
 
-    async fn run_model(&self, prompt: &str) -> anyhow::Result<String, LLMError> {
+    /// Inference parameters w/ any per-request overrides applied. A preset
+    /// replaces the configured sampler settings entirely.
+    fn infer_params(&self, options: &ChatCompletionOptions) -> InferenceParameters {
+        if options.preset.is_none() && options.temperature.is_none() {
+            return self.infer_params.clone();
+        }
+
+        let mut settings = match options.preset {
+            Some(preset) => SamplerSettings::from(preset),
+            None => self.model_config.sampler_settings(),
+        };
+        if let Some(temperature) = options.temperature {
+            settings.temperature = temperature;
+        }
+
+        let bias_sampler = self
+            ._bias_sampler
+            .lock()
+            .map(|sampler| sampler.clone())
+            .unwrap_or_default();
+        build_infer_params(&bias_sampler, settings.base_samplers())
+    }
+
+    async fn run_model(
+        &self,
+        prompt: &str,
+        options: &ChatCompletionOptions,
+    ) -> anyhow::Result<String, LLMError> {
         log::info!("running model w/ prompt: {prompt}");
 
         let buffer = Arc::new(Mutex::new(String::new()));
@@ -87,17 +121,19 @@ where
         };
 
         let config = InferenceSessionConfig::default();
-        let infer_params = self.infer_params.clone();
+        let infer_params = self.infer_params(options);
         let sender = sender.clone();
 
         let prompt_request = llm::InferenceRequest {
             prompt: llm::Prompt::Text(prompt),
-            maximum_token_count: None,
+            maximum_token_count: options.max_tokens,
             parameters: &infer_params,
             play_back_previous_tokens: false,
         };
 
         let channel = sender.clone();
+        let stop = options.stop.clone();
+        let mut output = String::new();
         let mut session = self.model.start_session(config);
         let _stats = session
             .infer::<std::convert::Infallible>(
@@ -108,9 +144,13 @@ where
                 move |t| {
                     match t {
                         llm::InferenceResponse::InferredToken(token) => {
+                            output.push_str(&token);
                             if channel.send(LlmEvent::TokenReceived(token)).is_err() {
                                 return Ok(llm::InferenceFeedback::Halt);
                             }
+                            if find_stop(&output, &stop).is_some() {
+                                return Ok(llm::InferenceFeedback::Halt);
+                            }
                         }
                         llm::InferenceResponse::EotToken => {
                             if channel.send(LlmEvent::InferenceDone).is_err() {
@@ -127,13 +167,13 @@ where
         let _ = sender.send(LlmEvent::InferenceDone);
         // Wait for buffer to finish writing
         let _ = writer_handle.await;
-        // Retrieve buffer and clean up any trailing/leading spaces.
-        let buffer = buffer
-            .lock()
-            .expect("Unable to grab buffer")
-            .trim()
-            .to_string();
-        Ok(buffer)
+        // Retrieve buffer, cut it at any stop sequence and clean up any
+        // trailing/leading spaces.
+        let mut buffer = buffer.lock().expect("Unable to grab buffer").clone();
+        if let Some(idx) = find_stop(&buffer, &options.stop) {
+            buffer.truncate(idx);
+        }
+        Ok(buffer.trim().to_string())
     }
 }
 
@@ -142,12 +182,29 @@ impl<T> LLM for LocalLLM<T>
 where
     T: llm::KnownModel,
 {
+    #[tracing::instrument(
+        name = "llm",
+        skip_all,
+        fields(
+            model = %self.name,
+            prompt_tokens = msgs.iter().map(|msg| self.count_tokens(msg.content())).sum::<usize>(),
+            completion_tokens = tracing::field::Empty
+        )
+    )]
     async fn chat_completion(
         &self,
-        _: &str,
+        model: &str,
         msgs: &[ChatMessage],
+        options: &ChatCompletionOptions,
     ) -> anyhow::Result<String, LLMError> {
         log::info!("LocalLLM running chat_completion");
+        // Only the one model is ever loaded
+        if !model.is_empty() && model != self.name {
+            log::warn!(
+                "requested model {model} isn't loaded, using {} instead",
+                self.name
+            );
+        }
 
         let system_msg = msgs
             .iter()
@@ -166,54 +223,68 @@ where
             prompt.push_str(&format!("{}\n", msg.content));
         }
         prompt.push_str("[/INST]");
-        self.run_model(&prompt).await
+        let result = self.run_model(&prompt, options).await;
+        if let Ok(response) = &result {
+            tracing::Span::current().record("completion_tokens", self.count_tokens(response));
+        }
+
+        result
     }
 
     fn segment_text(&self, text: &str) -> (Vec<String>, String) {
-        let cl = cl100k_base().unwrap();
-        let size = cl.encode_with_special_tokens(text).len();
+        let size = self.count_tokens(text);
         log::debug!("context size: {size}");
 
         if size <= MAX_TOKENS {
-            (vec![text.to_string()], Default::default())
+            (vec![text.to_string()], self.name.clone())
         } else {
-            let splits = split_text(text, MAX_TOKENS);
-            (splits, Default::default())
+            let splits = split_text(text, MAX_TOKENS, |text| self.count_tokens(text));
+            (splits, self.name.clone())
         }
     }
 
     fn truncate_text(&self, text: &str) -> (String, String) {
-        let cl = cl100k_base().unwrap();
-        let total_tokens: usize = cl.encode_with_special_tokens(text).len();
-
-        if total_tokens <= MAX_TOKENS {
-            (text.to_string(), Default::default())
+        if self.count_tokens(text) <= MAX_TOKENS {
+            (text.to_string(), self.name.clone())
         } else {
-            let mut buffer = String::new();
-            for txt in text.split(' ') {
-                let with_txt = buffer.clone() + txt;
-                let current_size = cl.encode_with_special_tokens(&with_txt).len();
-                if current_size > MAX_TOKENS {
-                    break;
-                } else {
-                    buffer.push_str(txt);
-                }
-            }
+            let buffer = truncate_to_tokens(text, MAX_TOKENS, |text| self.count_tokens(text));
+            (buffer, self.name.clone())
+        }
+    }
 
-            (buffer, Default::default())
+    fn default_model(&self) -> &str {
+        &self.name
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        match self.model.tokenizer().tokenize(text, false) {
+            Ok(tokens) => tokens.len(),
+            Err(err) => {
+                // Better a rough estimate than nothing
+                log::warn!("unable to tokenize text, estimating w/ cl100k: {err}");
+                super::count_tokens(text)
+            }
         }
     }
+
+    fn context_window(&self, _model: &str) -> usize {
+        CONTEXT_WINDOW
+    }
 }
 
 pub async fn load_from_cfg(
     llm_config: PathBuf,
     report_progress: bool,
 ) -> anyhow::Result<Box<dyn LLM>> {
-    let config = std::fs::read_to_string(llm_config.clone())?;
-    let config: LocalLLMConfig = toml::from_str(&config)?;
+    let config = std::fs::read_to_string(&llm_config).map_err(|err| {
+        anyhow::anyhow!("Unable to read LLM config {}: {err}", llm_config.display())
+    })?;
+    let config = LocalLLMConfig::parse(&config, &llm_config)?;
 
     let parent_dir = llm_config.parent().unwrap();
     let model_path: PathBuf = parent_dir.join(config.model.path.clone());
+    check_model_file(&model_path, &llm_config)?;
+    checksum::verify_sha256(&model_path, config.model.sha256.as_deref())?;
 
     let model_params = config.to_model_params();
 
@@ -235,32 +306,90 @@ pub async fn load_from_cfg(
         }
     };
 
+    let model_config = config.model.clone();
     match config.model.model_type {
-        ModelArch::GptJ => Ok(Box::new(LocalLLM::new(
-            llm::load::<llm::models::Gpt2>(
-                &model_path,
-                llm::TokenizerSource::Embedded,
-                model_params,
-                progress_cb,
-            )?,
-            config.base_samplers(),
-        ))),
-        ModelArch::Llama => Ok(Box::new(LocalLLM::new(
-            llm::load::<llm::models::Llama>(
-                &model_path,
-                llm::TokenizerSource::Embedded,
-                model_params,
-                progress_cb,
-            )?,
-            config.base_samplers(),
-        ))),
+        ModelArch::Bloom => {
+            load_model::<llm::models::Bloom>(&model_path, model_params, model_config, progress_cb)
+        }
+        ModelArch::Gpt2 => {
+            load_model::<llm::models::Gpt2>(&model_path, model_params, model_config, progress_cb)
+        }
+        ModelArch::GptJ => {
+            load_model::<llm::models::GptJ>(&model_path, model_params, model_config, progress_cb)
+        }
+        ModelArch::GptNeoX => {
+            load_model::<llm::models::GptNeoX>(&model_path, model_params, model_config, progress_cb)
+        }
+        ModelArch::Llama => {
+            load_model::<llm::models::Llama>(&model_path, model_params, model_config, progress_cb)
+        }
+        ModelArch::Mpt => {
+            load_model::<llm::models::Mpt>(&model_path, model_params, model_config, progress_cb)
+        }
+    }
+}
+
+/// Make sure the model file is there before handing it to `llm::load`, which
+/// doesn't say much when it isn't.
+fn check_model_file(model_path: &Path, llm_config: &Path) -> anyhow::Result<()> {
+    match std::fs::File::open(model_path) {
+        Ok(_) if model_path.is_file() => Ok(()),
+        Ok(_) => Err(anyhow::anyhow!(
+            "model path {} is not a file, check `model.path` in {}",
+            model_path.display(),
+            llm_config.display()
+        )),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Err(anyhow::anyhow!(
+            "model file not found at {}, check `model.path` in {}",
+            model_path.display(),
+            llm_config.display()
+        )),
+        Err(err) => Err(anyhow::anyhow!(
+            "Unable to read model file at {}: {err}",
+            model_path.display()
+        )),
+    }
+}
+
+/// Load a model of a specific architecture & wrap it as a LLM
+fn load_model<T>(
+    model_path: &Path,
+    model_params: llm::ModelParameters,
+    model_config: ModelConfig,
+    progress_cb: impl FnMut(LoadProgress),
+) -> anyhow::Result<Box<dyn LLM>>
+where
+    T: llm::KnownModel + 'static,
+{
+    let model = llm::load::<T>(
+        model_path,
+        llm::TokenizerSource::Embedded,
+        model_params,
+        progress_cb,
+    )?;
+
+    Ok(Box::new(LocalLLM::new(model, model_config)))
+}
+
+/// Create the sampler chain used during inference.
+fn build_infer_params(
+    bias_sampler: &SampleFlatBias,
+    base_samplers: ConfiguredSamplers,
+) -> InferenceParameters {
+    let mut samplers = SamplerChain::new();
+    samplers += bias_sampler.clone();
+    samplers += base_samplers.builder.into_chain();
+
+    llm::InferenceParameters {
+        sampler: Arc::new(Mutex::new(samplers)),
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::llm::ChatMessage;
-    use std::path::PathBuf;
+    use super::schema::{LocalLLMConfig, SamplerSettings};
+    use crate::llm::{ChatMessage, SamplerPreset};
+    use std::path::{Path, PathBuf};
 
     // ignoring this for now since we don't want to continually download models
     #[ignore]
@@ -278,8 +407,89 @@ mod test {
             ChatMessage::user("Who won the world series in 2020?"),
         ];
 
-        let result = llm.chat_completion(Default::default(), &msgs).await;
+        let result = llm
+            .chat_completion(llm.default_model(), &msgs, &Default::default())
+            .await;
         assert!(result.is_ok());
         dbg!(result.unwrap());
     }
+
+    #[test]
+    fn test_config_missing_fields() {
+        let config = r#"
+            prompt_template = "templates/clippy_prompt.txt"
+
+            [model]
+            path = "models/model.bin"
+            model_type = "Llama"
+            prefer_mmap = false
+            top_p = 0.95
+            repeat_penalty = 1.30
+            repetition_penalty_last_n = 512
+        "#;
+
+        let err = LocalLLMConfig::parse(config, Path::new("config.toml"))
+            .err()
+            .expect("config should be invalid")
+            .to_string();
+        assert_eq!(
+            err,
+            "LLM config config.toml is missing required field(s): model.top_k, model.temperature"
+        );
+
+        // Knobs are optional w/ a preset to fall back on
+        let config = LocalLLMConfig::parse(
+            &config.replace(
+                "prefer_mmap = false",
+                "prefer_mmap = false\npreset = \"creative\"",
+            ),
+            Path::new("config.toml"),
+        )
+        .expect("config should be valid");
+        let settings = config.model.sampler_settings();
+        assert_eq!(settings.top_p, 0.95);
+        assert_eq!(settings.temperature, 1.0);
+
+        let err = LocalLLMConfig::parse("prompt_template = \"test\"", Path::new("config.toml"))
+            .err()
+            .expect("config should be invalid")
+            .to_string();
+        assert!(err.contains("[model]"));
+    }
+
+    #[tokio::test]
+    async fn test_missing_model_file() {
+        let config = std::fs::read_to_string("../../resources/config.llama2.toml")
+            .expect("Unable to read config")
+            .replace("models/LLaMa2/", "models/missing/");
+        let model_config = std::env::temp_dir().join("memex.test_missing_model_file.toml");
+        std::fs::write(&model_config, config).expect("Unable to write config");
+
+        let err = super::load_from_cfg(model_config.clone(), false)
+            .await
+            .err()
+            .expect("model file shouldn't exist")
+            .to_string();
+        let _ = std::fs::remove_file(&model_config);
+        assert!(err.starts_with("model file not found at "));
+        assert!(err.ends_with(&format!("check `model.path` in {}", model_config.display())));
+    }
+
+    #[test]
+    fn test_sampler_presets() {
+        for preset in [
+            SamplerPreset::Greedy,
+            SamplerPreset::Precise,
+            SamplerPreset::Creative,
+        ] {
+            let settings = SamplerSettings::from(preset);
+            assert_eq!(settings.temperature, preset.temperature());
+            assert!(settings.top_k > 0);
+            assert!(settings.top_p > 0.0 && settings.top_p <= 1.0);
+
+            let mut samplers = settings.base_samplers();
+            samplers.ensure_default_slots();
+            let _chain = samplers.builder.into_chain();
+        }
+    }
 }
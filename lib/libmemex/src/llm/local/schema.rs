@@ -9,7 +9,20 @@ use llm::{
     ModelArchitecture,
 };
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use crate::llm::SamplerPreset;
+
+/// Fields every `[model]` section has to set.
+const REQUIRED_MODEL_FIELDS: [&str; 3] = ["path", "model_type", "prefer_mmap"];
+/// Sampler knobs, only required when there's no `preset` to fall back on.
+const SAMPLER_FIELDS: [&str; 5] = [
+    "top_k",
+    "top_p",
+    "repeat_penalty",
+    "temperature",
+    "repetition_penalty_last_n",
+];
 
 #[derive(Debug)]
 pub enum LlmEvent {
@@ -24,6 +37,51 @@ pub struct LocalLLMConfig {
 }
 
 impl LocalLLMConfig {
+    /// Parse the config @ `config_path`, listing every missing field up front
+    /// rather than failing on the first one serde runs into.
+    pub fn parse(config: &str, config_path: &Path) -> anyhow::Result<Self> {
+        let invalid = |err: toml::de::Error| {
+            anyhow::anyhow!("Invalid LLM config {}: {err}", config_path.display())
+        };
+        let table: toml::Table = toml::from_str(config).map_err(invalid)?;
+
+        let mut missing = Vec::new();
+        if !table.contains_key("prompt_template") {
+            missing.push("prompt_template".to_string());
+        }
+        match table.get("model") {
+            Some(toml::Value::Table(model)) => {
+                let mut required = REQUIRED_MODEL_FIELDS.to_vec();
+                if !model.contains_key("preset") {
+                    required.extend(SAMPLER_FIELDS);
+                }
+                missing.extend(
+                    required
+                        .iter()
+                        .filter(|field| !model.contains_key(**field))
+                        .map(|field| format!("model.{field}")),
+                );
+            }
+            Some(_) => {
+                return Err(anyhow::anyhow!(
+                    "Invalid LLM config {}: `model` should be a [model] section",
+                    config_path.display()
+                ))
+            }
+            None => missing.push("[model]".to_string()),
+        }
+
+        if !missing.is_empty() {
+            return Err(anyhow::anyhow!(
+                "LLM config {} is missing required field(s): {}",
+                config_path.display(),
+                missing.join(", ")
+            ));
+        }
+
+        toml::Value::Table(table).try_into().map_err(invalid)
+    }
+
     pub fn to_model_params(&self) -> llm::ModelParameters {
         llm::ModelParameters {
             prefer_mmap: false,
@@ -32,9 +90,80 @@ impl LocalLLMConfig {
             ..Default::default()
         }
     }
+}
 
+#[derive(Clone, Deserialize)]
+pub struct ModelConfig {
+    pub path: PathBuf,
+    pub model_type: ModelArch,
+    pub prefer_mmap: bool,
+    /// Expected SHA-256 of the model file, checked before it's loaded.
+    pub sha256: Option<String>,
+    /// Sampler settings to start from, any knobs below override it.
+    #[serde(default)]
+    pub preset: Option<SamplerPreset>,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f32>,
+    pub repeat_penalty: Option<f32>,
+    pub temperature: Option<f32>,
+    pub repetition_penalty_last_n: Option<usize>,
+}
+
+impl ModelConfig {
+    /// Sampler settings from the preset (if any) w/ the configured knobs on top.
+    /// Configs are validated on load, so one or the other is always there.
+    pub fn sampler_settings(&self) -> SamplerSettings {
+        let base = SamplerSettings::from(self.preset.unwrap_or(SamplerPreset::Precise));
+        SamplerSettings {
+            top_k: self.top_k.unwrap_or(base.top_k),
+            top_p: self.top_p.unwrap_or(base.top_p),
+            repeat_penalty: self.repeat_penalty.unwrap_or(base.repeat_penalty),
+            temperature: self.temperature.unwrap_or(base.temperature),
+            repetition_penalty_last_n: self
+                .repetition_penalty_last_n
+                .unwrap_or(base.repetition_penalty_last_n),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SamplerSettings {
+    pub top_k: usize,
+    pub top_p: f32,
+    pub repeat_penalty: f32,
+    /// A temperature of 0 (or less) means greedy sampling.
+    pub temperature: f32,
+    pub repetition_penalty_last_n: usize,
+}
+
+impl From<SamplerPreset> for SamplerSettings {
+    fn from(preset: SamplerPreset) -> Self {
+        let (top_k, repeat_penalty, repetition_penalty_last_n) = match preset {
+            SamplerPreset::Greedy => (1, 1.1, 64),
+            SamplerPreset::Precise => (20, 1.3, 512),
+            SamplerPreset::Creative => (100, 1.1, 256),
+        };
+
+        Self {
+            top_k,
+            top_p: preset.top_p(),
+            repeat_penalty,
+            temperature: preset.temperature(),
+            repetition_penalty_last_n,
+        }
+    }
+}
+
+impl SamplerSettings {
     pub fn base_samplers(&self) -> ConfiguredSamplers {
-        let model = self.model.clone();
+        let mut model = self.clone();
+        // Only keeping the most likely token is greedy sampling, w/o dividing
+        // logits by zero.
+        if model.temperature <= 0.0 {
+            model.top_k = 1;
+            model.temperature = 1.0;
+        }
+
         let sampler_builder: SamplerChainBuilder = SamplerChainBuilder::from([
             (
                 "repetition",
@@ -81,41 +210,26 @@ impl LocalLLMConfig {
     }
 }
 
-#[derive(Clone, Deserialize)]
-pub struct ModelConfig {
-    pub path: PathBuf,
-    pub model_type: ModelArch,
-    pub prefer_mmap: bool,
-    pub top_k: usize,
-    pub top_p: f32,
-    pub repeat_penalty: f32,
-    pub temperature: f32,
-    pub repetition_penalty_last_n: usize,
-}
-
-/// TODO: Test on more architectures
+/// NOTE: Only Llama & GptJ based models have been tested.
 #[derive(Clone, Deserialize)]
 pub enum ModelArch {
-    // Bloom,
-    // Gpt2,
+    Bloom,
+    Gpt2,
     GptJ,
-    // GptNeoX,
+    GptNeoX,
     Llama,
-    // Mpt,
+    Mpt,
 }
 
 impl From<ModelArchitecture> for ModelArch {
     fn from(value: ModelArchitecture) -> Self {
         match value {
-            // ModelArchitecture::Bloom => Self::Bloom,
-            // ModelArchitecture::Gpt2 => Self::Gpt2,
+            ModelArchitecture::Bloom => Self::Bloom,
+            ModelArchitecture::Gpt2 => Self::Gpt2,
             ModelArchitecture::GptJ => Self::GptJ,
-            // ModelArchitecture::GptNeoX => Self::GptNeoX,
+            ModelArchitecture::GptNeoX => Self::GptNeoX,
             ModelArchitecture::Llama => Self::Llama,
-            // ModelArchitecture::Mpt => Self::Mpt,
-            _ => {
-                panic!("Model architecture not yet supported");
-            }
+            ModelArchitecture::Mpt => Self::Mpt,
         }
     }
 }
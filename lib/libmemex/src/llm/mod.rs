@@ -1,13 +1,27 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use std::time::Duration;
 use strum_macros::Display;
 use thiserror::Error;
-use tiktoken_rs::cl100k_base;
+use tiktoken_rs::{cl100k_base, CoreBPE};
 
 pub mod embedding;
 pub mod local;
 pub mod openai;
 pub mod prompter;
 
+/// Max number of times a request is retried w/ a smaller context after hitting
+/// a `ContextLengthExceeded` error.
+pub const MAX_CONTEXT_RETRIES: usize = 3;
+/// Tokens set aside for a response when a request doesn't set `max_tokens`.
+pub const DEFAULT_MAX_RESPONSE_TOKENS: usize = 1_024;
+/// Most stop sequences a single completion can have, same as OpenAI.
+pub const MAX_STOP_SEQUENCES: usize = 4;
+// Tokens added to every message in a chat & to prime the reply, see
+// https://github.com/openai/openai-cookbook/blob/main/examples/How_to_count_tokens_with_tiktoken.ipynb
+const TOKENS_PER_MESSAGE: usize = 4;
+const REPLY_PRIMING_TOKENS: usize = 3;
+
 #[derive(Clone, Debug, Serialize, Display, Eq, PartialEq)]
 pub enum ChatRole {
     #[strum(serialize = "system")]
@@ -25,6 +39,10 @@ pub struct ChatMessage {
 }
 
 impl ChatMessage {
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
     pub fn assistant(content: &str) -> Self {
         Self::new(ChatRole::Assistant, content)
     }
@@ -45,6 +63,56 @@ impl ChatMessage {
     }
 }
 
+/// Generation options for a single chat completion. Unset options fall back
+/// to the defaults for the LLM backend.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChatCompletionOptions {
+    /// Maximum number of tokens to generate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+    /// Sampling temperature, higher values produce more random output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    /// Named set of sampler settings, `temperature` still overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<SamplerPreset>,
+    /// Generation halts once any of these is produced, the sequence itself
+    /// isn't included in the response.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+}
+
+/// Sensible combinations of sampler settings, so they don't have to be tuned
+/// by hand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SamplerPreset {
+    /// Always picks the most likely token.
+    Greedy,
+    /// Low randomness, for extraction & other tasks w/ a "right" answer.
+    Precise,
+    /// High randomness, for open-ended writing.
+    Creative,
+}
+
+impl SamplerPreset {
+    pub fn temperature(&self) -> f32 {
+        match self {
+            SamplerPreset::Greedy => 0.0,
+            SamplerPreset::Precise => 0.2,
+            SamplerPreset::Creative => 1.0,
+        }
+    }
+
+    pub fn top_p(&self) -> f32 {
+        match self {
+            SamplerPreset::Greedy => 1.0,
+            SamplerPreset::Precise => 0.8,
+            SamplerPreset::Creative => 0.98,
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum LLMError {
     #[error("Context length exceeded: {0}")]
@@ -57,26 +125,154 @@ pub enum LLMError {
     RequestError(#[from] reqwest::Error),
     #[error("Unable to deserialize: {0}")]
     SerdeError(#[from] serde_json::Error),
+    /// The request was rejected by the provider (e.g. a 400 from OpenAI),
+    /// sending it again won't help.
+    #[error("Bad Request: {0}")]
+    BadRequest(String),
+    /// Too many requests, the provider may say how long to wait before
+    /// trying again.
+    #[error("Rate limited, retry after {retry_after:?}")]
+    RateLimited { retry_after: Option<Duration> },
     #[error("Invalid Request: {0}")]
     Other(String),
 }
 
+impl LLMError {
+    /// Whether the same request might succeed if tried again later.
+    pub fn is_retriable(&self) -> bool {
+        !matches!(
+            self,
+            LLMError::ContextLengthExceeded(_) | LLMError::BadRequest(_) | LLMError::SerdeError(_)
+        )
+    }
+}
+
+/// Text of a completion, sent piece by piece as it's generated. Closes once
+/// the completion is done or after the first error.
+pub type TokenStream = tokio::sync::mpsc::Receiver<Result<String, LLMError>>;
+
 #[async_trait::async_trait]
 pub trait LLM: Send + Sync {
+    /// Run a chat completion w/ `model`, an empty string uses `default_model`.
     async fn chat_completion(
         &self,
         model: &str,
         msgs: &[ChatMessage],
+        options: &ChatCompletionOptions,
     ) -> anyhow::Result<String, LLMError>;
 
+    /// Same as `chat_completion`, but sends tokens as they're generated.
+    /// Backends that can't stream send the whole response at once.
+    async fn chat_completion_stream(
+        &self,
+        model: &str,
+        msgs: &[ChatMessage],
+        options: &ChatCompletionOptions,
+    ) -> anyhow::Result<TokenStream, LLMError> {
+        let response = self.chat_completion(model, msgs, options).await?;
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let _ = sender.send(Ok(response)).await;
+        Ok(receiver)
+    }
+
     fn segment_text(&self, text: &str) -> (Vec<String>, String);
     fn truncate_text(&self, text: &str) -> (String, String);
+    /// Model used when a request doesn't ask for one.
+    fn default_model(&self) -> &str;
+    /// Make sure the default model is usable (e.g. the API key is valid or
+    /// the model loads) by generating a single token.
+    async fn check(&self) -> anyhow::Result<(), LLMError> {
+        let options = ChatCompletionOptions {
+            max_tokens: Some(1),
+            ..Default::default()
+        };
+        self.chat_completion("", &[ChatMessage::user("Hi")], &options)
+            .await
+            .map(|_| ())
+    }
+    /// Number of tokens in a piece of text, w/ the model's own tokenizer.
+    fn count_tokens(&self, text: &str) -> usize;
+    /// Max # of tokens in a prompt & its response for `model`, an empty string
+    /// uses `default_model`.
+    fn context_window(&self, model: &str) -> usize;
+    /// Estimated cost in USD of sending `tokens` prompt tokens to `model`, for
+    /// LLMs that are billed per token.
+    fn prompt_cost(&self, _model: &str, _tokens: usize) -> Option<f64> {
+        None
+    }
+}
+
+/// Tokens left for context in a prompt, i.e. the context window minus the
+/// response & the rest of the prompt (given w/o any context).
+pub fn context_budget(
+    llm: &dyn LLM,
+    model: &str,
+    prompt: &[ChatMessage],
+    max_response_tokens: usize,
+) -> usize {
+    let prompt_overhead = prompt
+        .iter()
+        .map(|msg| llm.count_tokens(msg.content()) + TOKENS_PER_MESSAGE)
+        .sum::<usize>()
+        + REPLY_PRIMING_TOKENS;
+
+    llm.context_window(model)
+        .saturating_sub(max_response_tokens)
+        .saturating_sub(prompt_overhead)
 }
 
-pub fn split_text(text: &str, max_tokens: usize) -> Vec<String> {
-    let cl = cl100k_base().unwrap();
+/// Join as many segments as fit in `budget` tokens, in order, so the best
+/// segments should come first. The first segment that doesn't fit is truncated
+/// to fill whatever's left. Returns the context & the segments in it, as they
+/// appear there.
+pub fn fit_context<F>(segments: &[String], budget: usize, count_tokens: F) -> (String, Vec<String>)
+where
+    F: Fn(&str) -> usize,
+{
+    const SEPARATOR: &str = "\n\n";
 
-    let total_tokens: usize = cl.encode_with_special_tokens(text).len();
+    let mut context = String::new();
+    let mut used = Vec::new();
+    for segment in segments {
+        let prefix = match context.is_empty() {
+            true => String::new(),
+            false => format!("{context}{SEPARATOR}"),
+        };
+
+        let with_segment = format!("{prefix}{segment}");
+        if count_tokens(&with_segment) <= budget {
+            context = with_segment;
+            used.push(segment.clone());
+            continue;
+        }
+
+        let remaining = budget.saturating_sub(count_tokens(&prefix));
+        let truncated = truncate_to_tokens(segment, remaining, &count_tokens);
+        if !truncated.trim().is_empty() {
+            context = format!("{prefix}{truncated}");
+            used.push(truncated);
+        }
+        break;
+    }
+
+    (context, used)
+}
+
+static CL100K: OnceLock<CoreBPE> = OnceLock::new();
+
+/// Number of tokens in a piece of text, as counted by OpenAI models.
+pub fn count_tokens(text: &str) -> usize {
+    let cl = CL100K.get_or_init(|| cl100k_base().unwrap());
+    cl.encode_with_special_tokens(text).len()
+}
+
+/// Split text into overlapping parts of roughly `max_tokens` tokens each, as
+/// counted by `count_tokens`.
+pub fn split_text<F>(text: &str, max_tokens: usize, count_tokens: F) -> Vec<String>
+where
+    F: Fn(&str) -> usize,
+{
+    let total_tokens = count_tokens(text);
     let mut doc_parts = Vec::new();
     if total_tokens <= max_tokens {
         doc_parts.push(text.into());
@@ -115,3 +311,346 @@ pub fn split_text(text: &str, max_tokens: usize) -> Vec<String> {
         .map(|pt| pt.to_string())
         .collect::<Vec<String>>()
 }
+
+/// Cut text down to at most `max_tokens` tokens, as counted by `count_tokens`,
+/// breaking between words.
+pub fn truncate_to_tokens<F>(text: &str, max_tokens: usize, count_tokens: F) -> String
+where
+    F: Fn(&str) -> usize,
+{
+    let mut buffer = String::new();
+    for txt in text.split(' ') {
+        let with_txt = match buffer.is_empty() {
+            true => txt.to_string(),
+            false => format!("{buffer} {txt}"),
+        };
+        if count_tokens(&with_txt) > max_tokens {
+            break;
+        } else {
+            buffer = with_txt;
+        }
+    }
+
+    buffer
+}
+
+/// Re-segment text w/ `split_text` into parts of at most half its tokens. Used
+/// to shrink the context of a request after a `ContextLengthExceeded` error.
+/// Returns the text as-is if it can't be split any further.
+pub fn shrink_text<F>(text: &str, count_tokens: F) -> Vec<String>
+where
+    F: Fn(&str) -> usize,
+{
+    let text = text.trim();
+    let parts: Vec<String> = split_text(text, count_tokens(text) / 2, count_tokens)
+        .into_iter()
+        .filter(|part| !part.trim().is_empty())
+        .collect();
+
+    match parts.len() {
+        0 | 1 => vec![text.to_string()],
+        _ => parts,
+    }
+}
+
+/// Run a chat completion w/ the prompt `prompt` builds from `content`. While
+/// it overflows the model's context, it's retried up to `MAX_CONTEXT_RETRIES`
+/// times w/ the start of `content`, re-segmented w/ `shrink_text`. Returns the
+/// response & the content that was used.
+pub async fn complete_within_context<L, F, E>(
+    llm: &L,
+    model: &str,
+    content: String,
+    options: &ChatCompletionOptions,
+    prompt: F,
+) -> Result<(String, String), E>
+where
+    L: LLM + ?Sized,
+    F: Fn(&str) -> Result<Vec<ChatMessage>, E>,
+    E: From<LLMError>,
+{
+    let mut content = content;
+    let mut retries = 0;
+    loop {
+        let msgs = prompt(&content)?;
+        match llm.chat_completion(model, &msgs, options).await {
+            Ok(response) => return Ok((response, content)),
+            Err(LLMError::ContextLengthExceeded(msg)) if retries < MAX_CONTEXT_RETRIES => {
+                let mut parts = shrink_text(&content, |text| llm.count_tokens(text));
+                if parts.len() < 2 {
+                    return Err(LLMError::ContextLengthExceeded(msg).into());
+                }
+
+                retries += 1;
+                log::warn!(
+                    "context length exceeded, retrying w/ smaller context ({retries}/{MAX_CONTEXT_RETRIES}): {msg}"
+                );
+                content = parts.swap_remove(0);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Pull a JSON value out of an LLM response, which often wraps it in markdown
+/// fences or adds commentary before/after it. Returns `None` if there's no
+/// valid JSON object or array to be found.
+pub fn repair_json(response: &str) -> Option<serde_json::Value> {
+    let text = response.trim();
+    if let Ok(val) = serde_json::from_str(text) {
+        return Some(val);
+    }
+
+    // Strip ```json ... ``` fences
+    let text = match text.find("```") {
+        Some(start) => {
+            let fenced = &text[start + 3..];
+            // Skip the language tag, if any
+            let fenced = fenced
+                .split_once('\n')
+                .map(|(_, rest)| rest)
+                .unwrap_or(fenced);
+            match fenced.find("```") {
+                Some(end) => &fenced[..end],
+                None => fenced,
+            }
+        }
+        None => text,
+    };
+
+    // Look for the first balanced object or array
+    let start = text.find(['{', '['])?;
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (idx, c) in text[start..].char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match c {
+            '"' => in_string = true,
+            '{' | '[' => depth += 1,
+            '}' | ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return serde_json::from_str(&text[start..start + idx + 1]).ok();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Byte offset of the first stop sequence in `output`, if there is one.
+pub fn find_stop(output: &str, stop: &[String]) -> Option<usize> {
+    stop.iter()
+        .filter(|seq| !seq.is_empty())
+        .filter_map(|seq| output.find(seq.as_str()))
+        .min()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        complete_within_context, find_stop, fit_context, repair_json, shrink_text,
+        truncate_to_tokens, ChatCompletionOptions, ChatMessage, LLMError, LLM, MAX_CONTEXT_RETRIES,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Fails w/ `ContextLengthExceeded` for prompts over `limit` words.
+    struct ContextLimit {
+        limit: usize,
+        calls: AtomicUsize,
+    }
+
+    impl ContextLimit {
+        fn new(limit: usize) -> Self {
+            Self {
+                limit,
+                calls: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl LLM for ContextLimit {
+        async fn chat_completion(
+            &self,
+            _model: &str,
+            msgs: &[ChatMessage],
+            _options: &ChatCompletionOptions,
+        ) -> Result<String, LLMError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let words: usize = msgs
+                .iter()
+                .map(|msg| self.count_tokens(msg.content()))
+                .sum();
+            match words > self.limit {
+                true => Err(LLMError::ContextLengthExceeded(format!("{words} words"))),
+                false => Ok("ok".into()),
+            }
+        }
+
+        fn segment_text(&self, text: &str) -> (Vec<String>, String) {
+            (vec![text.to_string()], String::new())
+        }
+
+        fn truncate_text(&self, text: &str) -> (String, String) {
+            (text.to_string(), String::new())
+        }
+
+        fn default_model(&self) -> &str {
+            ""
+        }
+
+        fn count_tokens(&self, text: &str) -> usize {
+            text.split_whitespace().count()
+        }
+
+        fn context_window(&self, _model: &str) -> usize {
+            self.limit
+        }
+    }
+    use serde_json::json;
+
+    #[test]
+    fn test_find_stop() {
+        let stop = vec!["\n\n".to_string(), "END".to_string()];
+        assert_eq!(find_stop("name: memex END\n\nmore", &stop), Some(12));
+        assert_eq!(find_stop("first\n\nEND", &stop), Some(5));
+        assert_eq!(find_stop("no stop here", &stop), None);
+        assert_eq!(find_stop("anything", &[String::new()]), None);
+        assert_eq!(find_stop("anything", &[]), None);
+    }
+
+    #[tokio::test]
+    async fn test_complete_within_context() {
+        let content = "the quick brown fox jumps over the lazy dog".to_string();
+        let prompt = |content: &str| Ok::<_, LLMError>(vec![ChatMessage::user(content)]);
+        let options = ChatCompletionOptions::default();
+
+        // Fits the first time
+        let llm = ContextLimit::new(100);
+        let (response, used) = complete_within_context(&llm, "", content.clone(), &options, prompt)
+            .await
+            .unwrap();
+        assert_eq!(response, "ok");
+        assert_eq!(used, content);
+        assert_eq!(llm.calls.load(Ordering::SeqCst), 1);
+
+        // Retried w/ the start of the content until it fits
+        let llm = ContextLimit::new(3);
+        let (_, used) = complete_within_context(&llm, "", content.clone(), &options, prompt)
+            .await
+            .unwrap();
+        assert!(content.starts_with(&used));
+        assert!(llm.count_tokens(&used) <= 3);
+        assert!(llm.calls.load(Ordering::SeqCst) > 1);
+
+        // Gives up once it can't shrink any further
+        let llm = ContextLimit::new(0);
+        let result = complete_within_context(&llm, "", content.clone(), &options, prompt).await;
+        assert!(matches!(result, Err(LLMError::ContextLengthExceeded(_))));
+        assert!(llm.calls.load(Ordering::SeqCst) <= MAX_CONTEXT_RETRIES + 1);
+
+        // Prompt errors are returned as-is
+        let llm = ContextLimit::new(100);
+        let result = complete_within_context(&llm, "", content, &options, |_| {
+            Err(LLMError::Other("bad template".into()))
+        })
+        .await;
+        assert!(matches!(result, Err(LLMError::Other(_))));
+        assert_eq!(llm.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_shrink_text() {
+        let words = |text: &str| text.split_whitespace().count();
+        let text = "the quick brown fox jumps over the lazy dog";
+        let parts = shrink_text(text, words);
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|part| words(part) <= words(text) / 2));
+        assert_eq!(parts.join(" "), text);
+
+        assert_eq!(
+            shrink_text("  supercalifragilistic ", words),
+            vec!["supercalifragilistic"]
+        );
+        assert_eq!(shrink_text("héllo wörld", words), vec!["héllo", "wörld"]);
+    }
+
+    #[test]
+    fn test_fit_context() {
+        let count_words = |text: &str| text.split_whitespace().count();
+        let segments = vec![
+            "one two three".to_string(),
+            "four five".to_string(),
+            "six seven eight nine".to_string(),
+            "ten".to_string(),
+        ];
+
+        assert_eq!(
+            fit_context(&segments, 100, count_words),
+            (
+                "one two three\n\nfour five\n\nsix seven eight nine\n\nten".into(),
+                segments.clone()
+            )
+        );
+        // Lowest scoring segment that fits is cut down to the remaining budget
+        assert_eq!(
+            fit_context(&segments, 7, count_words),
+            (
+                "one two three\n\nfour five\n\nsix seven".into(),
+                vec![
+                    "one two three".into(),
+                    "four five".into(),
+                    "six seven".into()
+                ]
+            )
+        );
+        assert_eq!(
+            fit_context(&segments, 5, count_words),
+            ("one two three\n\nfour five".into(), segments[..2].to_vec())
+        );
+        assert_eq!(fit_context(&segments, 0, count_words), ("".into(), vec![]));
+
+        assert_eq!(
+            truncate_to_tokens("the quick brown fox", 3, count_words),
+            "the quick brown"
+        );
+    }
+
+    #[test]
+    fn test_repair_json() {
+        let expected = json!({ "title": "a {weird} title", "tags": ["a", "b"] });
+        assert_eq!(
+            repair_json(r#"{"title": "a {weird} title", "tags": ["a", "b"]}"#),
+            Some(expected.clone())
+        );
+        assert_eq!(
+            repair_json("```json\n{\"title\": \"a {weird} title\", \"tags\": [\"a\", \"b\"]}\n```"),
+            Some(expected.clone())
+        );
+        assert_eq!(
+            repair_json(
+                "Sure! Here you go: {\"title\": \"a {weird} title\", \"tags\": [\"a\", \"b\"]} Let me know if you need anything else."
+            ),
+            Some(expected)
+        );
+        assert_eq!(
+            repair_json("The answer is [1, 2, 3]."),
+            Some(json!([1, 2, 3]))
+        );
+        assert_eq!(repair_json("no json here"), None);
+        assert_eq!(repair_json("{\"unbalanced\": true"), None);
+    }
+}
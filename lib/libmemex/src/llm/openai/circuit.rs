@@ -0,0 +1,176 @@
+use std::time::{Duration, Instant};
+
+const DEFAULT_FAILURE_THRESHOLD: usize = 5;
+const DEFAULT_FAILURE_WINDOW: Duration = Duration::from_secs(60);
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests go through as usual.
+    Closed,
+    /// Too many failures, requests fail fast until the cooldown is over.
+    Open { until: Instant },
+    /// Cooldown is over, a single request (started @ `probe`) is let through
+    /// to test recovery.
+    HalfOpen { probe: Instant },
+}
+
+/// Stops sending requests to an upstream service after too many consecutive
+/// failures, giving it time to recover.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    /// # of consecutive failures before the circuit opens.
+    failure_threshold: usize,
+    /// Failures older than this don't count towards the threshold.
+    failure_window: Duration,
+    /// How long the circuit stays open before testing recovery.
+    cooldown: Duration,
+    state: CircuitState,
+    failures: Vec<Instant>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_FAILURE_THRESHOLD,
+            DEFAULT_FAILURE_WINDOW,
+            DEFAULT_COOLDOWN,
+        )
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: usize, failure_window: Duration, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            failure_window,
+            cooldown,
+            state: CircuitState::Closed,
+            failures: Vec::new(),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state.clone()
+    }
+
+    /// Check if a request can be made right now.
+    pub fn allow_request(&mut self, now: Instant) -> bool {
+        match self.state {
+            CircuitState::Closed => true,
+            CircuitState::Open { until } => {
+                if now >= until {
+                    log::info!("[circuit] cooldown over, testing recovery");
+                    self.state = CircuitState::HalfOpen { probe: now };
+                    true
+                } else {
+                    false
+                }
+            }
+            // Only one request at a time while testing recovery. If the probe
+            // never reported back (e.g. the caller was dropped), try another.
+            CircuitState::HalfOpen { probe } => {
+                if now >= probe + self.cooldown {
+                    self.state = CircuitState::HalfOpen { probe: now };
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        if self.state != CircuitState::Closed {
+            log::info!("[circuit] upstream recovered, closing circuit");
+        }
+
+        self.state = CircuitState::Closed;
+        self.failures.clear();
+    }
+
+    pub fn record_failure(&mut self, now: Instant) {
+        match self.state {
+            // Recovery test failed, back to waiting.
+            CircuitState::HalfOpen { .. } => self.open(now),
+            CircuitState::Open { .. } => {}
+            CircuitState::Closed => {
+                self.failures
+                    .retain(|failure| now.duration_since(*failure) <= self.failure_window);
+                self.failures.push(now);
+                if self.failures.len() >= self.failure_threshold {
+                    self.open(now);
+                }
+            }
+        }
+    }
+
+    fn open(&mut self, now: Instant) {
+        log::warn!(
+            "[circuit] opening circuit for {}s after repeated failures",
+            self.cooldown.as_secs()
+        );
+        self.state = CircuitState::Open {
+            until: now + self.cooldown,
+        };
+        self.failures.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CircuitBreaker, CircuitState};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_circuit_opens_after_failures() {
+        let start = Instant::now();
+        let mut breaker = CircuitBreaker::new(3, Duration::from_secs(60), Duration::from_secs(30));
+
+        breaker.record_failure(start);
+        breaker.record_failure(start);
+        assert!(breaker.allow_request(start));
+
+        breaker.record_failure(start);
+        assert!(matches!(breaker.state(), CircuitState::Open { .. }));
+        assert!(!breaker.allow_request(start + Duration::from_secs(10)));
+
+        // Half-open after cooldown, only a single probe is let through.
+        let later = start + Duration::from_secs(30);
+        assert!(breaker.allow_request(later));
+        assert!(!breaker.allow_request(later));
+
+        // Failed probe re-opens the circuit
+        breaker.record_failure(later);
+        assert!(!breaker.allow_request(later + Duration::from_secs(1)));
+
+        // Stale probes are replaced
+        let later = later + Duration::from_secs(30);
+        assert!(breaker.allow_request(later));
+        assert!(!breaker.allow_request(later + Duration::from_secs(1)));
+        assert!(breaker.allow_request(later + Duration::from_secs(30)));
+
+        // Successful probe closes it
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.allow_request(later));
+    }
+
+    #[test]
+    fn test_circuit_failure_window() {
+        let start = Instant::now();
+        let mut breaker = CircuitBreaker::new(3, Duration::from_secs(60), Duration::from_secs(30));
+
+        // Failures spread outside the window don't open the circuit.
+        breaker.record_failure(start);
+        breaker.record_failure(start + Duration::from_secs(61));
+        breaker.record_failure(start + Duration::from_secs(122));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        // A success resets the count
+        breaker.record_failure(start + Duration::from_secs(123));
+        breaker.record_success();
+        breaker.record_failure(start + Duration::from_secs(124));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
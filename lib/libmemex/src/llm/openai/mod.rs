@@ -1,23 +1,37 @@
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use reqwest::{header, Response, StatusCode};
 use serde::Serialize;
 use strum_macros::{AsRefStr, Display, EnumString};
-use tiktoken_rs::cl100k_base;
+use tokio::sync::mpsc;
+use url::Url;
 
-use crate::llm::split_text;
+use crate::llm::{count_tokens, split_text, truncate_to_tokens};
 
-use self::schema::ErrorResponse;
-use super::{ChatMessage, LLMError, LLM};
+use self::circuit::CircuitBreaker;
+use self::schema::{ChatCompletionChunk, ErrorResponse};
+use super::{ChatCompletionOptions, ChatMessage, LLMError, TokenStream, LLM};
 
+pub mod circuit;
 mod schema;
 
+/// Official API, used unless another OpenAI-compatible server is configured.
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+const DEFAULT_MAX_TOKENS: usize = 1024;
+// Make more deterministic
+const DEFAULT_TEMPERATURE: f32 = 0.2;
 const CONTEXT_LENGTH_ERROR: &str = "context_length_exceeded";
+// Returned w/ a 429, but waiting won't help until the account is topped up.
+const QUOTA_ERROR: &str = "insufficient_quota";
 // Max context - response length - prompt length
 pub const MAX_TOKENS: usize = 4_097 - 1_024 - 100;
 pub const MAX_16K_TOKENS: usize = 16_384 - 2_048 - 100;
+// Streamed tokens buffered before reading the response is paused.
+const STREAM_BUFFER: usize = 64;
 
-#[derive(AsRefStr, Display, Clone, EnumString)]
+#[derive(AsRefStr, Display, Clone, Debug, EnumString)]
 pub enum OpenAIModel {
     // Most capable GPT-3.5 model and optimized for chat at 1/10th the cost of text-davinci-003.
     // Will be updated with our latest model iteration 2 weeks after it is released.
@@ -36,10 +50,36 @@ pub enum OpenAIModel {
     GPT4_8K,
 }
 
+static DEFAULT_MODEL: OpenAIModel = OpenAIModel::GPT35;
+
+impl OpenAIModel {
+    /// List price in USD per 1K prompt tokens, responses cost extra.
+    pub fn prompt_price(&self) -> f64 {
+        match self {
+            OpenAIModel::GPT35 | OpenAIModel::GPT35_0613 => 0.0015,
+            OpenAIModel::GPT35_16K => 0.003,
+            OpenAIModel::GPT4_8K => 0.03,
+        }
+    }
+}
+
+/// Model to use for a request, an empty string uses `default`.
+fn parse_model(model: &str, default: &OpenAIModel) -> Result<OpenAIModel, LLMError> {
+    if model.is_empty() {
+        Ok(default.clone())
+    } else {
+        OpenAIModel::from_str(model)
+            .map_err(|_| LLMError::BadRequest(format!("Unknown model: {model}")))
+    }
+}
+
 impl From<ErrorResponse> for LLMError {
     fn from(value: ErrorResponse) -> Self {
-        if value.error.code == CONTEXT_LENGTH_ERROR {
+        let code = value.error.code.as_deref();
+        if code == Some(CONTEXT_LENGTH_ERROR) {
             LLMError::ContextLengthExceeded(value.error.message)
+        } else if code == Some(QUOTA_ERROR) {
+            LLMError::BadRequest(value.error.message)
         } else {
             LLMError::Other(value.error.message)
         }
@@ -51,25 +91,30 @@ struct CompletionRequest {
     max_tokens: i32,
     n: i32,
     temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
     frequency_penalty: f32,
     presence_penalty: f32,
     #[serde(skip_serializing_if = "Option::is_none")]
-    stop: Option<String>,
+    stop: Option<Vec<String>>,
     model: String,
     messages: Vec<ChatMessage>,
     stream: bool,
 }
 
 impl CompletionRequest {
-    pub fn new(model: &OpenAIModel, msgs: &[ChatMessage]) -> Self {
+    pub fn new(model: &OpenAIModel, msgs: &[ChatMessage], options: &ChatCompletionOptions) -> Self {
         Self {
-            max_tokens: 1024,
+            max_tokens: options.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS) as i32,
             n: 1,
-            // Make more deterministic
-            temperature: 0.2,
+            temperature: options
+                .temperature
+                .or(options.preset.map(|preset| preset.temperature()))
+                .unwrap_or(DEFAULT_TEMPERATURE),
+            top_p: options.preset.map(|preset| preset.top_p()),
             frequency_penalty: 0.0,
             presence_penalty: 0.0,
-            stop: None,
+            stop: (!options.stop.is_empty()).then(|| options.stop.clone()),
             model: model.to_string(),
             messages: msgs.to_vec(),
             stream: false,
@@ -91,17 +136,96 @@ async fn check_api_error(response: Response) -> LLMError {
     }
 }
 
+enum StreamEvent {
+    Token(String),
+    Done,
+}
+
+/// Parse a line of a streamed completion, see
+/// https://platform.openai.com/docs/api-reference/chat/streaming
+/// Returns `None` for lines that don't carry any text.
+fn parse_stream_line(line: &str) -> Result<Option<StreamEvent>, LLMError> {
+    let Some(data) = line.trim().strip_prefix("data:").map(str::trim) else {
+        return Ok(None);
+    };
+    if data == "[DONE]" {
+        return Ok(Some(StreamEvent::Done));
+    }
+
+    match serde_json::from_str::<ChatCompletionChunk>(data) {
+        Ok(chunk) => Ok(chunk
+            .content()
+            .filter(|content| !content.is_empty())
+            .map(|content| StreamEvent::Token(content.to_string()))),
+        // Errors partway through are sent as an event too.
+        Err(err) => match serde_json::from_str::<ErrorResponse>(data) {
+            Ok(error) => Err(error.into()),
+            Err(_) => Err(err.into()),
+        },
+    }
+}
+
+/// How long a rate limited response asks us to wait, from either
+/// `retry-after-ms` (Azure) or `Retry-After` in seconds or as an HTTP date.
+fn retry_after(headers: &header::HeaderMap) -> Option<Duration> {
+    let value = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let from_secs =
+        |secs: f64| (secs.is_finite() && secs >= 0.0).then(|| Duration::from_secs_f64(secs));
+
+    if let Some(ms) = value("retry-after-ms").and_then(|ms| ms.trim().parse::<f64>().ok()) {
+        return from_secs(ms / 1000.0);
+    }
+
+    let retry_after = value(header::RETRY_AFTER.as_str())?.trim();
+    match retry_after.parse::<f64>() {
+        Ok(secs) => from_secs(secs),
+        Err(_) => {
+            let date = chrono::DateTime::parse_from_rfc2822(retry_after).ok()?;
+            // Already passed, retry right away.
+            Some(
+                (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+                    .to_std()
+                    .unwrap_or_default(),
+            )
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct OpenAIClient {
     client: reqwest::Client,
+    completions_url: Url,
+    default_model: OpenAIModel,
+    /// Shared between clones so all callers back off during an outage.
+    breaker: Arc<Mutex<CircuitBreaker>>,
+}
+
+// Only outages/overloads should trip the circuit, not bad requests.
+fn is_upstream_failure(err: &LLMError, status: Option<StatusCode>) -> bool {
+    match err {
+        LLMError::RequestError(_) => true,
+        _ => status
+            .map(|status| status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS)
+            .unwrap_or_default(),
+    }
 }
 
 #[async_trait::async_trait]
 impl LLM for OpenAIClient {
+    #[tracing::instrument(
+        name = "llm",
+        skip_all,
+        fields(
+            model = model,
+            prompt_tokens = msgs.iter().map(|msg| self.count_tokens(msg.content())).sum::<usize>(),
+            completion_tokens = tracing::field::Empty
+        )
+    )]
     async fn chat_completion(
         &self,
         model: &str,
         msgs: &[ChatMessage],
+        options: &ChatCompletionOptions,
     ) -> anyhow::Result<String, LLMError> {
         log::debug!(
             "[OpenAI] chat completion w/ {} | {} messages",
@@ -109,40 +233,76 @@ impl LLM for OpenAIClient {
             msgs.len()
         );
 
-        let model: OpenAIModel = OpenAIModel::from_str(model)
-            .map_err(|err| LLMError::Other(format!("Invalid model: {err}")))?;
+        let model = parse_model(model, &self.default_model)?;
+        self.check_circuit()?;
 
-        let request_body = CompletionRequest::new(&model, msgs);
-        let response = self
-            .client
-            .post(&"https://api.openai.com/v1/chat/completions".to_string())
-            .json(&request_body)
-            .send()
-            .await?;
+        let (result, status) = self.send_completion(&model, msgs, options).await;
+        if let Ok(response) = &result {
+            tracing::Span::current().record("completion_tokens", self.count_tokens(response));
+        }
+        self.record_outcome(&result, status);
 
-        let status = &response.status();
-        if StatusCode::is_success(status) {
-            let completion = response
-                .json::<schema::ChatCompletionResponse>()
-                .await
-                .map_err(LLMError::RequestError)?;
-
-            match completion.response() {
-                Some(msg) => Ok(msg),
-                None => Err(LLMError::NoResponse),
+        result
+    }
+
+    async fn chat_completion_stream(
+        &self,
+        model: &str,
+        msgs: &[ChatMessage],
+        options: &ChatCompletionOptions,
+    ) -> anyhow::Result<TokenStream, LLMError> {
+        log::debug!(
+            "[OpenAI] streaming chat completion w/ {} | {} messages",
+            model,
+            msgs.len()
+        );
+
+        let model = parse_model(model, &self.default_model)?;
+        self.check_circuit()?;
+
+        let mut request_body = CompletionRequest::new(&model, msgs, options);
+        request_body.stream = true;
+        let (result, status) = self.send_request(&request_body).await;
+        self.record_outcome(&result, status);
+        let mut response = result?;
+
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        tokio::spawn(async move {
+            let mut buffer = Vec::new();
+            loop {
+                let chunk = match response.chunk().await {
+                    Ok(Some(chunk)) => chunk,
+                    Ok(None) => return,
+                    Err(err) => {
+                        let _ = sender.send(Err(err.into())).await;
+                        return;
+                    }
+                };
+
+                buffer.extend_from_slice(&chunk);
+                while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                    let line = buffer.drain(..=end).collect::<Vec<_>>();
+                    let token = match parse_stream_line(&String::from_utf8_lossy(&line)) {
+                        Ok(Some(StreamEvent::Token(token))) => Ok(token),
+                        Ok(Some(StreamEvent::Done)) => return,
+                        Ok(None) => continue,
+                        Err(err) => Err(err),
+                    };
+
+                    let failed = token.is_err();
+                    // Stop reading once nobody's listening, e.g. the client went away.
+                    if sender.send(token).await.is_err() || failed {
+                        return;
+                    }
+                }
             }
-        } else if StatusCode::is_client_error(status) || StatusCode::is_server_error(status) {
-            Err(check_api_error(response).await)
-        } else {
-            let warning = format!("OpenAI response not currently supported {:?}", response);
-            log::warn!("{}", &warning);
-            Err(LLMError::Other(warning))
-        }
+        });
+
+        Ok(receiver)
     }
 
     fn segment_text(&self, content: &str) -> (Vec<String>, String) {
-        let cl = cl100k_base().unwrap();
-        let size = cl.encode_with_special_tokens(content).len();
+        let size = self.count_tokens(content);
 
         log::debug!("Context Size {:?}", size);
         if size <= MAX_TOKENS {
@@ -155,40 +315,139 @@ impl LLM for OpenAIClient {
                 OpenAIModel::GPT35_16K.to_string(),
             )
         } else {
-            let splits = split_text(content, MAX_16K_TOKENS);
+            let splits = split_text(content, MAX_16K_TOKENS, count_tokens);
             log::debug!("Spliting with 16K model splits {:?}", splits.len());
             (splits, OpenAIModel::GPT35_16K.to_string())
         }
     }
 
     fn truncate_text(&self, text: &str) -> (String, String) {
-        let cl = cl100k_base().unwrap();
-        let total_tokens: usize = cl.encode_with_special_tokens(text).len();
+        let total_tokens = self.count_tokens(text);
 
         if total_tokens <= MAX_TOKENS {
             (text.to_string(), OpenAIModel::GPT35.to_string())
         } else if total_tokens <= MAX_16K_TOKENS {
             (text.to_string(), OpenAIModel::GPT35_16K.to_string())
         } else {
-            let mut buffer = String::new();
-            for txt in text.split(' ') {
-                let with_txt = buffer.clone() + txt;
-                let current_size = cl.encode_with_special_tokens(&with_txt).len();
-                if current_size > MAX_16K_TOKENS {
-                    break;
-                } else {
-                    buffer.push_str(txt);
-                }
+            let buffer = truncate_to_tokens(text, MAX_16K_TOKENS, count_tokens);
+            (buffer, OpenAIModel::GPT35_16K.to_string())
+        }
+    }
+
+    fn default_model(&self) -> &str {
+        self.default_model.as_ref()
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        count_tokens(text)
+    }
+
+    fn context_window(&self, model: &str) -> usize {
+        match parse_model(model, &self.default_model).unwrap_or_else(|_| self.default_model.clone())
+        {
+            OpenAIModel::GPT35 | OpenAIModel::GPT35_0613 => 4_097,
+            OpenAIModel::GPT35_16K => 16_384,
+            OpenAIModel::GPT4_8K => 8_192,
+        }
+    }
+
+    fn prompt_cost(&self, model: &str, tokens: usize) -> Option<f64> {
+        let model = parse_model(model, &self.default_model).ok()?;
+        Some(model.prompt_price() * tokens as f64 / 1000.0)
+    }
+}
+
+/// Chat completions endpoint under an API base URL, keeping any query string
+/// (e.g. Azure's `api-version`).
+fn completions_url(base_url: &str) -> Result<Url, url::ParseError> {
+    let mut url = Url::parse(base_url)?;
+    url.path_segments_mut()
+        .map_err(|_| url::ParseError::RelativeUrlWithCannotBeABaseBase)?
+        .pop_if_empty()
+        .extend(["chat", "completions"]);
+    Ok(url)
+}
+
+/// Where & how to reach the OpenAI API, for proxies, Azure OpenAI &
+/// OpenAI-compatible servers.
+#[derive(Clone, Debug, Default)]
+pub struct OpenAIOptions {
+    completions_url: Option<Url>,
+    headers: header::HeaderMap,
+    default_model: Option<OpenAIModel>,
+}
+
+impl OpenAIOptions {
+    /// `base_url` defaults to `DEFAULT_BASE_URL`. `organization` is sent as
+    /// `OpenAI-Organization` & `headers` is a comma separated list of extra
+    /// `Name: value` headers sent w/ every request.
+    pub fn new(
+        base_url: Option<&str>,
+        organization: Option<&str>,
+        headers: Option<&str>,
+    ) -> Result<Self, LLMError> {
+        let completions_url = base_url
+            .map(|base_url| {
+                completions_url(base_url)
+                    .map_err(|err| LLMError::Other(format!("Invalid base URL {base_url}: {err}")))
+            })
+            .transpose()?;
+
+        let mut header_map = header::HeaderMap::new();
+        if let Some(organization) = organization.filter(|org| !org.is_empty()) {
+            header_map.insert(
+                "OpenAI-Organization",
+                header::HeaderValue::from_str(organization)
+                    .map_err(|err| LLMError::Other(format!("Invalid organization: {err}")))?,
+            );
+        }
+
+        for pair in headers.unwrap_or_default().split(',') {
+            if pair.trim().is_empty() {
+                continue;
             }
 
-            (buffer, OpenAIModel::GPT35_16K.to_string())
+            let Some((name, value)) = pair.split_once(':') else {
+                return Err(LLMError::Other(format!(
+                    "Invalid header {pair:?}, expected \"Name: value\""
+                )));
+            };
+            let name = header::HeaderName::from_str(name.trim())
+                .map_err(|err| LLMError::Other(format!("Invalid header name {name:?}: {err}")))?;
+            let value = header::HeaderValue::from_str(value.trim())
+                .map_err(|err| LLMError::Other(format!("Invalid value for {name}: {err}")))?;
+            header_map.append(name, value);
         }
+
+        Ok(Self {
+            completions_url,
+            headers: header_map,
+            default_model: None,
+        })
+    }
+
+    /// Use `model` for requests that don't ask for one, instead of `gpt-3.5-turbo`.
+    pub fn with_default_model(mut self, model: &str) -> Result<Self, LLMError> {
+        let model = OpenAIModel::from_str(model)
+            .map_err(|_| LLMError::Other(format!("Unknown model: {model}")))?;
+        self.default_model = Some(model);
+        Ok(self)
+    }
+
+    fn completions_url(&self) -> Url {
+        self.completions_url.clone().unwrap_or_else(|| {
+            completions_url(DEFAULT_BASE_URL).expect("Invalid default OpenAI base URL")
+        })
     }
 }
 
 impl OpenAIClient {
-    pub fn new(api_key: &str) -> Self {
-        let mut headers = header::HeaderMap::new();
+    /// Client for the OpenAI API, or any OpenAI-compatible server (a proxy,
+    /// vLLM, Ollama, etc.) configured w/ `options`.
+    pub fn new(api_key: &str, options: &OpenAIOptions) -> Self {
+        let completions_url = options.completions_url();
+
+        let mut headers = options.headers.clone();
         headers.insert(
             header::CONTENT_TYPE,
             header::HeaderValue::from_static("application/json"),
@@ -197,33 +456,433 @@ impl OpenAIClient {
             header::AUTHORIZATION,
             header::HeaderValue::from_str(&format!("Bearer {api_key}")).expect("Invalid api_key"),
         );
+        // Azure OpenAI takes the key in its own header.
+        if completions_url
+            .host_str()
+            .is_some_and(|host| host.ends_with(".openai.azure.com"))
+            && !headers.contains_key("api-key")
+        {
+            headers.insert(
+                "api-key",
+                header::HeaderValue::from_str(api_key).expect("Invalid api_key"),
+            );
+        }
 
         let client = reqwest::Client::builder()
             .default_headers(headers)
             .build()
             .expect("Unable to build HTTP client");
 
-        Self { client }
+        Self {
+            client,
+            completions_url,
+            default_model: options
+                .default_model
+                .clone()
+                .unwrap_or_else(|| DEFAULT_MODEL.clone()),
+            breaker: Default::default(),
+        }
+    }
+
+    fn check_circuit(&self) -> Result<(), LLMError> {
+        let allowed = self
+            .breaker
+            .lock()
+            .map(|mut breaker| breaker.allow_request(Instant::now()))
+            .unwrap_or(true);
+        if allowed {
+            Ok(())
+        } else {
+            Err(LLMError::Other("circuit open".into()))
+        }
+    }
+
+    /// Caller errors (e.g. a bad request) say nothing about the upstream's
+    /// health, so they leave the circuit as-is.
+    fn record_outcome<T>(&self, result: &Result<T, LLMError>, status: Option<StatusCode>) {
+        if let Ok(mut breaker) = self.breaker.lock() {
+            match result {
+                Ok(_) => breaker.record_success(),
+                Err(err) if is_upstream_failure(err, status) => {
+                    breaker.record_failure(Instant::now())
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    async fn send_completion(
+        &self,
+        model: &OpenAIModel,
+        msgs: &[ChatMessage],
+        options: &ChatCompletionOptions,
+    ) -> (Result<String, LLMError>, Option<StatusCode>) {
+        let request_body = CompletionRequest::new(model, msgs, options);
+        let (response, status) = self.send_request(&request_body).await;
+        let result = match response {
+            Ok(response) => match response.json::<schema::ChatCompletionResponse>().await {
+                Ok(completion) => completion.response().ok_or(LLMError::NoResponse),
+                Err(err) => Err(LLMError::RequestError(err)),
+            },
+            Err(err) => Err(err),
+        };
+
+        (result, status)
+    }
+
+    /// Send a completion request, turning any unsuccessful response into an error.
+    async fn send_request(
+        &self,
+        request_body: &CompletionRequest,
+    ) -> (Result<Response, LLMError>, Option<StatusCode>) {
+        let response = match self
+            .client
+            .post(self.completions_url.clone())
+            .json(request_body)
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(err) => return (Err(err.into()), None),
+        };
+
+        let status = &response.status();
+        let result = if StatusCode::is_success(status) {
+            Ok(response)
+        } else if *status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = retry_after(response.headers());
+            match check_api_error(response).await {
+                LLMError::BadRequest(msg) => Err(LLMError::BadRequest(msg)),
+                err => {
+                    log::warn!("[OpenAI] rate limited, retry after {retry_after:?}: {err}");
+                    Err(LLMError::RateLimited { retry_after })
+                }
+            }
+        } else if StatusCode::is_client_error(status) || StatusCode::is_server_error(status) {
+            match check_api_error(response).await {
+                // Client errors won't go away by retrying
+                LLMError::Other(msg) if status.is_client_error() => Err(LLMError::BadRequest(msg)),
+                err => Err(err),
+            }
+        } else {
+            let warning = format!("OpenAI response not currently supported {:?}", response);
+            log::warn!("{}", &warning);
+            Err(LLMError::Other(warning))
+        };
+
+        (result, Some(*status))
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{ChatMessage, OpenAIClient, OpenAIModel, LLM};
+    use super::{
+        circuit::CircuitState, completions_url, retry_after, ChatMessage, CompletionRequest,
+        OpenAIClient, OpenAIModel, OpenAIOptions, DEFAULT_BASE_URL, LLM,
+    };
     use crate::llm::prompter::{json_schema_extraction, summarize};
+    use crate::llm::{ChatCompletionOptions, LLMError, SamplerPreset};
+    use reqwest::{
+        header::{HeaderMap, HeaderValue},
+        StatusCode,
+    };
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Serve a single canned response, returning the mock API's base URL.
+    async fn mock_response(status: &str, headers: &[(&str, &str)], body: &str) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut response = format!(
+            "HTTP/1.1 {status}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n",
+            body.len()
+        );
+        for (name, value) in headers {
+            response.push_str(&format!("{name}: {value}\r\n"));
+        }
+        response.push_str("\r\n");
+        response.push_str(body);
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // Read the whole request before responding.
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                let read = stream.read(&mut buf).await.unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+
+                let text = String::from_utf8_lossy(&request).to_lowercase();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length = text[..end]
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length:"))
+                        .and_then(|length| length.trim().parse::<usize>().ok())
+                        .unwrap_or_default();
+                    if request.len() >= end + 4 + length {
+                        break;
+                    }
+                }
+            }
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        format!("http://{addr}/v1")
+    }
+
+    #[tokio::test]
+    pub async fn test_rate_limited() {
+        let base_url = mock_response(
+            "429 Too Many Requests",
+            &[("retry-after", "20")],
+            r#"{"error": {"message": "Rate limit reached", "type": "requests", "param": null, "code": "rate_limit_exceeded"}}"#,
+        )
+        .await;
+        let options = OpenAIOptions::new(Some(&base_url), None, None).unwrap();
+        let client = OpenAIClient::new("test", &options);
+
+        let err = client
+            .chat_completion("", &[ChatMessage::user("hello")], &Default::default())
+            .await
+            .unwrap_err();
+        assert!(err.is_retriable());
+        match err {
+            LLMError::RateLimited { retry_after } => {
+                assert_eq!(retry_after, Some(Duration::from_secs(20)))
+            }
+            _ => panic!("unexpected error: {err}"),
+        }
+
+        // Out of quota isn't worth retrying
+        let base_url = mock_response(
+            "429 Too Many Requests",
+            &[],
+            r#"{"error": {"message": "You exceeded your current quota", "type": "insufficient_quota", "param": null, "code": "insufficient_quota"}}"#,
+        )
+        .await;
+        let options = OpenAIOptions::new(Some(&base_url), None, None).unwrap();
+        let client = OpenAIClient::new("test", &options);
+        let err = client
+            .chat_completion("", &[ChatMessage::user("hello")], &Default::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, LLMError::BadRequest(_)));
+    }
+
+    #[tokio::test]
+    pub async fn test_chat_completion_stream() {
+        let chunk = |content: &str| {
+            format!(
+                "data: {{\"choices\": [{{\"index\": 0, \"delta\": {{\"content\": \"{content}\"}}}}]}}\n\n"
+            )
+        };
+        let body = [
+            "data: {\"choices\": [{\"index\": 0, \"delta\": {\"role\": \"assistant\"}}]}\n\n"
+                .to_string(),
+            chunk("Hello"),
+            ": keep-alive\n\n".to_string(),
+            chunk(" wörld"),
+            "data: [DONE]\n\n".to_string(),
+            chunk("ignored"),
+        ]
+        .concat();
+        let base_url = mock_response("200 OK", &[], &body).await;
+        let options = OpenAIOptions::new(Some(&base_url), None, None).unwrap();
+        let client = OpenAIClient::new("test", &options);
+
+        let mut stream = client
+            .chat_completion_stream("", &[ChatMessage::user("hello")], &Default::default())
+            .await
+            .unwrap();
+        let mut tokens = Vec::new();
+        while let Some(token) = stream.recv().await {
+            tokens.push(token.unwrap());
+        }
+        assert_eq!(tokens, vec!["Hello", " wörld"]);
+    }
+
+    #[test]
+    pub fn test_record_outcome() {
+        let client = OpenAIClient::new("test", &OpenAIOptions::default());
+        let outage: Result<(), _> = Err(LLMError::NoResponse);
+        for _ in 0..4 {
+            client.record_outcome(&outage, Some(StatusCode::BAD_GATEWAY));
+        }
+        // A bad request isn't a sign the upstream recovered
+        client.record_outcome(&outage, Some(StatusCode::BAD_REQUEST));
+        client.record_outcome(&outage, Some(StatusCode::BAD_GATEWAY));
+        assert!(matches!(
+            client.breaker.lock().unwrap().state(),
+            CircuitState::Open { .. }
+        ));
+    }
+
+    #[test]
+    pub fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert("retry-after", HeaderValue::from_static("1.5"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_millis(1500)));
+
+        headers.insert(
+            "retry-after",
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+
+        let later = (chrono::Utc::now() + chrono::Duration::seconds(120)).to_rfc2822();
+        headers.insert("retry-after", HeaderValue::from_str(&later).unwrap());
+        let delay = retry_after(&headers).unwrap();
+        assert!(delay > Duration::from_secs(110) && delay <= Duration::from_secs(120));
+
+        // Azure's millisecond header takes precedence
+        headers.insert("retry-after-ms", HeaderValue::from_static("250"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_millis(250)));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from_static("soon"));
+        assert_eq!(retry_after(&headers), None);
+        headers.insert("retry-after", HeaderValue::from_static("-1"));
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    pub fn test_completions_url() {
+        assert_eq!(
+            completions_url(DEFAULT_BASE_URL).unwrap().as_str(),
+            "https://api.openai.com/v1/chat/completions"
+        );
+        assert_eq!(
+            completions_url("http://localhost:11434/v1/")
+                .unwrap()
+                .as_str(),
+            "http://localhost:11434/v1/chat/completions"
+        );
+        assert_eq!(
+            completions_url("https://example.openai.azure.com/openai/deployments/gpt?api-version=2023-05-15")
+                .unwrap()
+                .as_str(),
+            "https://example.openai.azure.com/openai/deployments/gpt/chat/completions?api-version=2023-05-15"
+        );
+        assert!(completions_url("localhost:8000").is_err());
+        assert!(completions_url("not a url").is_err());
+    }
+
+    #[test]
+    pub fn test_options() {
+        let options = OpenAIOptions::default();
+        assert_eq!(
+            options.completions_url().as_str(),
+            "https://api.openai.com/v1/chat/completions"
+        );
+        assert!(options.headers.is_empty());
+
+        let options = OpenAIOptions::new(
+            Some("http://localhost:8000/v1"),
+            Some("org-123"),
+            Some("X-Team: search, x-trace:  abc ,"),
+        )
+        .unwrap();
+        assert_eq!(
+            options.completions_url().as_str(),
+            "http://localhost:8000/v1/chat/completions"
+        );
+        assert_eq!(options.headers["openai-organization"], "org-123");
+        assert_eq!(options.headers["x-team"], "search");
+        assert_eq!(options.headers["x-trace"], "abc");
+
+        assert!(OpenAIOptions::new(Some("localhost"), None, None).is_err());
+        assert!(OpenAIOptions::new(None, None, Some("no-value")).is_err());
+        assert!(OpenAIOptions::new(None, None, Some("bad name: value")).is_err());
+        assert!(OpenAIOptions::new(None, Some("org\n"), None).is_err());
+
+        let client = OpenAIClient::new("test", &OpenAIOptions::default());
+        assert_eq!(client.default_model(), "gpt-3.5-turbo");
+        let options = OpenAIOptions::default()
+            .with_default_model("gpt-4")
+            .unwrap();
+        let client = OpenAIClient::new("test", &options);
+        assert_eq!(client.default_model(), "gpt-4");
+        assert_eq!(client.context_window(""), 8_192);
+        let cost = client.prompt_cost("", 2000).unwrap();
+        assert!((cost - 0.06).abs() < 1e-9, "{cost}");
+        let cost = client.prompt_cost("gpt-3.5-turbo-16k", 1000).unwrap();
+        assert!((cost - 0.003).abs() < 1e-9, "{cost}");
+        assert_eq!(client.prompt_cost("gpt-5000", 1000), None);
+        assert!(OpenAIOptions::default()
+            .with_default_model("gpt-5000")
+            .is_err());
+    }
+
+    #[tokio::test]
+    pub async fn test_check() {
+        let base_url = mock_response(
+            "401 Unauthorized",
+            &[],
+            r#"{"error": {"message": "Incorrect API key provided", "type": "invalid_request_error", "param": null, "code": "invalid_api_key"}}"#,
+        )
+        .await;
+        let options = OpenAIOptions::new(Some(&base_url), None, None).unwrap();
+        let client = OpenAIClient::new("bad-key", &options);
+        let err = client.check().await.unwrap_err();
+        assert!(err.to_string().contains("Incorrect API key"));
+    }
+
+    #[test]
+    pub fn test_completion_request_options() {
+        let msgs = vec![ChatMessage::user("hello")];
+
+        let request = CompletionRequest::new(&OpenAIModel::GPT35, &msgs, &Default::default());
+        assert_eq!(request.max_tokens, 1024);
+        assert_eq!(request.temperature, 0.2);
+
+        let options = ChatCompletionOptions {
+            max_tokens: Some(2048),
+            temperature: Some(0.9),
+            ..Default::default()
+        };
+        let request = CompletionRequest::new(&OpenAIModel::GPT35, &msgs, &options);
+        assert_eq!(request.max_tokens, 2048);
+        assert_eq!(request.temperature, 0.9);
+        assert_eq!(request.top_p, None);
+
+        let options = ChatCompletionOptions {
+            preset: Some(SamplerPreset::Greedy),
+            ..Default::default()
+        };
+        let request = CompletionRequest::new(&OpenAIModel::GPT35, &msgs, &options);
+        assert_eq!(request.temperature, 0.0);
+        assert_eq!(request.top_p, Some(1.0));
+        assert_eq!(request.stop, None);
+
+        let options = ChatCompletionOptions {
+            stop: vec!["\n\n".into()],
+            ..Default::default()
+        };
+        let request = CompletionRequest::new(&OpenAIModel::GPT35, &msgs, &options);
+        assert_eq!(request.stop, Some(vec!["\n\n".to_string()]));
+    }
 
     #[ignore]
     #[tokio::test]
     pub async fn test_completion_api() {
         dotenv::dotenv().ok();
-        let client = OpenAIClient::new(&std::env::var("OPENAI_API_KEY").unwrap());
+        let client = OpenAIClient::new(
+            &std::env::var("OPENAI_API_KEY").unwrap(),
+            &Default::default(),
+        );
         let msgs = vec![
             ChatMessage::system("You are a helpful assistant"),
             ChatMessage::user("Who won the world series in 2020?"),
         ];
 
         let resp = client
-            .chat_completion(OpenAIModel::GPT35.as_ref(), &msgs)
+            .chat_completion(OpenAIModel::GPT35.as_ref(), &msgs, &Default::default())
             .await;
         // dbg!(&resp);
         assert!(resp.is_ok());
@@ -233,16 +892,20 @@ mod test {
     #[tokio::test]
     pub async fn test_json_prompting() {
         dotenv::dotenv().ok();
-        let client = OpenAIClient::new(&std::env::var("OPENAI_API_KEY").unwrap());
+        let client = OpenAIClient::new(
+            &std::env::var("OPENAI_API_KEY").unwrap(),
+            &Default::default(),
+        );
 
         let msgs = json_schema_extraction(
             include_str!("../../../../../fixtures/sample_yelp_review.txt"),
             "extract the sentiment and complaints from this review",
             include_str!("../../../../../fixtures/sample_json_schema.json"),
-        );
+        )
+        .unwrap();
 
         let resp = client
-            .chat_completion(OpenAIModel::GPT35.as_ref(), &msgs)
+            .chat_completion(OpenAIModel::GPT35.as_ref(), &msgs, &Default::default())
             .await
             .unwrap();
         dbg!(&resp);
@@ -253,13 +916,16 @@ mod test {
     #[tokio::test]
     pub async fn test_summarize() {
         dotenv::dotenv().ok();
-        let client = OpenAIClient::new(&std::env::var("OPENAI_API_KEY").unwrap());
+        let client = OpenAIClient::new(
+            &std::env::var("OPENAI_API_KEY").unwrap(),
+            &Default::default(),
+        );
 
         let msgs = summarize(include_str!(
             "../../../../../fixtures/sample_yelp_review.txt"
         ));
         let resp = client
-            .chat_completion(OpenAIModel::GPT35.as_ref(), &msgs)
+            .chat_completion(OpenAIModel::GPT35.as_ref(), &msgs, &Default::default())
             .await
             .unwrap();
 
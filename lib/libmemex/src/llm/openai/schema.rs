@@ -18,6 +18,30 @@ impl ChatCompletionResponse {
     }
 }
 
+/// A piece of a streamed completion.
+#[derive(Deserialize, Debug)]
+pub struct ChatCompletionChunk {
+    choices: Vec<ChunkChoice>,
+}
+
+impl ChatCompletionChunk {
+    pub fn content(&self) -> Option<&str> {
+        self.choices
+            .last()
+            .and_then(|choice| choice.delta.content.as_deref())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChunkChoice {
+    delta: Delta,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Delta {
+    content: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Choice {
     message: Message,
@@ -50,8 +74,10 @@ pub struct ApiError {
     pub message: String,
     #[serde(rename = "type")]
     pub error_type: String,
-    pub param: String,
-    pub code: String,
+    #[serde(default)]
+    pub param: Option<String>,
+    #[serde(default)]
+    pub code: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
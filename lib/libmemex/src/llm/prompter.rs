@@ -1,31 +1,168 @@
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 use handlebars::RenderError;
 use serde::Serialize;
+use thiserror::Error;
 
 use super::ChatMessage;
 
+/// Built-in prompt templates, keyed by their path under `prompts/`.
+const DEFAULT_TEMPLATES: [(&str, &str); 6] = [
+    (
+        "summarize/system.txt",
+        include_str!("../../prompts/summarize/system.txt"),
+    ),
+    (
+        "summarize/prompt.txt",
+        include_str!("../../prompts/summarize/prompt.txt"),
+    ),
+    (
+        "json_schema/system.txt",
+        include_str!("../../prompts/json_schema/system.txt"),
+    ),
+    (
+        "json_schema/prompt.txt",
+        include_str!("../../prompts/json_schema/prompt.txt"),
+    ),
+    (
+        "rag/system.txt",
+        include_str!("../../prompts/rag/system.txt"),
+    ),
+    (
+        "rag/prompt.txt",
+        include_str!("../../prompts/rag/prompt.txt"),
+    ),
+];
+
+/// Templates rendered w/ handlebars & the variables they're rendered w/, they're
+/// trial rendered when they're loaded.
+const RENDERED_TEMPLATES: [(&str, &[&str]); 2] = [
+    ("json_schema/prompt.txt", &["user_request", "json_schema"]),
+    ("rag/prompt.txt", &["question"]),
+];
+
+/// Templates loaded from `PROMPT_DIR`, see `load_templates`.
+static TEMPLATES: OnceLock<HashMap<&'static str, String>> = OnceLock::new();
+
+#[derive(Debug, Error)]
+pub enum PromptError {
+    #[error("Prompt directory {0} doesn't exist")]
+    MissingDir(PathBuf),
+    #[error("Unable to read prompt template {path}: {error}")]
+    Unreadable {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    #[error("Invalid prompt template {path}: {error}")]
+    Invalid {
+        path: PathBuf,
+        error: Box<RenderError>,
+    },
+    #[error("Unable to render prompt template {name}: {error}")]
+    Render {
+        name: &'static str,
+        error: Box<RenderError>,
+    },
+}
+
+/// Override the built-in prompt templates w/ the ones in `dir`, which has the
+/// same layout as `lib/libmemex/prompts`. Templates missing from `dir` keep
+/// using the built-in ones. Only the first call has any effect.
+pub fn load_templates(dir: &Path) -> Result<(), PromptError> {
+    let templates = read_templates(dir)?;
+    for name in templates.keys() {
+        log::info!("using prompt template {}", dir.join(name).display());
+    }
+
+    let _ = TEMPLATES.set(templates);
+    Ok(())
+}
+
+fn read_templates(dir: &Path) -> Result<HashMap<&'static str, String>, PromptError> {
+    if !dir.is_dir() {
+        return Err(PromptError::MissingDir(dir.to_path_buf()));
+    }
+
+    let mut templates = HashMap::new();
+    for (name, _) in DEFAULT_TEMPLATES {
+        let path = dir.join(name);
+        let template = match std::fs::read_to_string(&path) {
+            Ok(template) => template,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(error) => return Err(PromptError::Unreadable { path, error }),
+        };
+
+        // Catch syntax errors, unknown helpers & variables now rather than on
+        // the first request.
+        if let Some((_, variables)) = RENDERED_TEMPLATES.iter().find(|(t, _)| *t == name) {
+            let sample: HashMap<&str, &str> =
+                variables.iter().map(|var| (*var, "sample")).collect();
+            build_prompt(&template, &sample).map_err(|error| PromptError::Invalid {
+                path: path.clone(),
+                error: Box::new(error),
+            })?;
+        }
+
+        templates.insert(name, template);
+    }
+
+    Ok(templates)
+}
+
+/// A loaded template, or the built-in one if it wasn't overridden.
+fn template(name: &str) -> &str {
+    TEMPLATES
+        .get()
+        .and_then(|templates| templates.get(name))
+        .map(|template| template.as_str())
+        .or_else(|| {
+            DEFAULT_TEMPLATES
+                .iter()
+                .find(|(default, _)| *default == name)
+                .map(|(_, template)| *template)
+        })
+        .unwrap_or_default()
+}
+
 pub fn build_prompt<T>(template: &str, data: &T) -> Result<String, RenderError>
 where
     T: Serialize,
 {
     let mut reg = handlebars::Handlebars::new();
     reg.register_escape_fn(handlebars::no_escape);
+    // Variables a template uses but isn't given are errors, not blanks.
+    reg.set_strict_mode(true);
     reg.render_template(template, data)
 }
 
-pub fn quick_question(user_request: &str) -> Vec<ChatMessage> {
-    vec![
-        ChatMessage::system("You are a helpful assistant"),
-        ChatMessage::user(user_request),
-    ]
+/// Render one of the templates in `RENDERED_TEMPLATES`.
+fn render<T>(name: &'static str, data: &T) -> Result<String, PromptError>
+where
+    T: Serialize,
+{
+    build_prompt(template(name), data).map_err(|error| PromptError::Render {
+        name,
+        error: Box::new(error),
+    })
+}
+
+/// Answer a question, about `context` if there is any.
+pub fn quick_question(context: &str, user_request: &str) -> Vec<ChatMessage> {
+    let mut prompt = vec![ChatMessage::system("You are a helpful assistant")];
+    if !context.trim().is_empty() {
+        prompt.push(ChatMessage::user(context));
+    }
+    prompt.push(ChatMessage::user(user_request));
+    prompt
 }
 
 pub fn summarize(input_data: &str) -> Vec<ChatMessage> {
     vec![
-        ChatMessage::system(include_str!("../../prompts/summarize/system.txt")),
+        ChatMessage::system(template("summarize/system.txt")),
         ChatMessage::user(input_data),
-        ChatMessage::user(include_str!("../../prompts/summarize/prompt.txt")),
+        ChatMessage::user(template("summarize/prompt.txt")),
     ]
 }
 
@@ -33,16 +170,83 @@ pub fn json_schema_extraction(
     input_data: &str,
     user_request: &str,
     output_schema: &str,
-) -> Vec<ChatMessage> {
+) -> Result<Vec<ChatMessage>, PromptError> {
     let mut data: HashMap<String, String> = HashMap::new();
     data.insert("user_request".to_string(), user_request.to_string());
     data.insert("json_schema".to_string(), output_schema.to_string());
 
-    vec![
-        ChatMessage::system(include_str!("../../prompts/json_schema/system.txt")),
+    Ok(vec![
+        ChatMessage::system(template("json_schema/system.txt")),
         ChatMessage::user(input_data),
-        ChatMessage::user(
-            &build_prompt(include_str!("../../prompts/json_schema/prompt.txt"), &data).unwrap(),
-        ),
-    ]
+        ChatMessage::user(&render("json_schema/prompt.txt", &data)?),
+    ])
+}
+
+/// Answer a question from `context`, i.e. search results for it.
+pub fn rag(context: &str, question: &str) -> Result<Vec<ChatMessage>, PromptError> {
+    let mut data: HashMap<String, String> = HashMap::new();
+    data.insert("question".to_string(), question.to_string());
+
+    Ok(vec![
+        ChatMessage::system(template("rag/system.txt")),
+        ChatMessage::user(context),
+        ChatMessage::user(&render("rag/prompt.txt", &data)?),
+    ])
+}
+
+/// Ask for a response again after the LLM returned something that wasn't
+/// valid JSON.
+pub fn json_reminder(mut prompt: Vec<ChatMessage>, response: &str) -> Vec<ChatMessage> {
+    prompt.push(ChatMessage::assistant(response));
+    prompt.push(ChatMessage::user(
+        "That was not valid JSON. Return only valid JSON matching the JSON Schema, with no other text.",
+    ));
+    prompt
+}
+
+#[cfg(test)]
+mod test {
+    use super::{json_schema_extraction, rag, read_templates, template, PromptError};
+
+    #[test]
+    fn test_read_templates() {
+        let dir = std::env::temp_dir().join("memex-prompts-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(matches!(
+            read_templates(&dir),
+            Err(PromptError::MissingDir(_))
+        ));
+
+        // Only the overridden templates are loaded
+        std::fs::create_dir_all(dir.join("rag")).unwrap();
+        std::fs::write(dir.join("rag/system.txt"), "Answer like a pirate").unwrap();
+        let templates = read_templates(&dir).unwrap();
+        assert_eq!(templates.len(), 1);
+        assert_eq!(templates["rag/system.txt"], "Answer like a pirate");
+        assert!(!template("rag/prompt.txt").is_empty());
+
+        std::fs::write(dir.join("rag/prompt.txt"), "{{#if question}").unwrap();
+        assert!(matches!(
+            read_templates(&dir),
+            Err(PromptError::Invalid { .. })
+        ));
+
+        // Unknown helpers & variables only fail once rendered
+        for invalid in ["{{shout question}}", "{{question}} {{context}}"] {
+            std::fs::write(dir.join("rag/prompt.txt"), invalid).unwrap();
+            assert!(
+                matches!(read_templates(&dir), Err(PromptError::Invalid { .. })),
+                "{invalid}"
+            );
+        }
+
+        std::fs::write(dir.join("rag/prompt.txt"), "Q: {{question}}").unwrap();
+        assert!(read_templates(&dir).is_ok());
+
+        // Built-in templates render w/ what they're given
+        assert!(rag("context", "question").is_ok());
+        assert!(json_schema_extraction("text", "request", "{}").is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
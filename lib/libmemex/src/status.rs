@@ -0,0 +1,29 @@
+use serde::Serialize;
+use std::sync::{Mutex, OnceLock};
+
+/// Readiness of the worker running in this process, if any.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkerStatus {
+    /// Models are still being downloaded/loaded, no jobs are being run.
+    Loading,
+    Ready,
+    /// Unable to start up, e.g. the embedding model failed to load.
+    Failed,
+}
+
+static WORKER_STATUS: OnceLock<Mutex<WorkerStatus>> = OnceLock::new();
+
+pub fn set_worker_status(status: WorkerStatus) {
+    let current = WORKER_STATUS.get_or_init(|| Mutex::new(status));
+    if let Ok(mut current) = current.lock() {
+        *current = status;
+    }
+}
+
+/// `None` if there's no worker running in this process.
+pub fn worker_status() -> Option<WorkerStatus> {
+    WORKER_STATUS
+        .get()
+        .and_then(|status| status.lock().ok().map(|status| *status))
+}
@@ -3,32 +3,79 @@ use hnsw_rs::{
     hnswio::{load_description, load_hnsw},
     prelude::*,
 };
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::File,
     io::{BufReader, Write},
     path::{Path, PathBuf},
     sync::Arc,
 };
 
-use super::{StoreResult, VectorData, VectorStore, VectorStoreError};
+use crate::llm::embedding::l2_normalize;
+
+use super::{
+    similarity_from_distance, CompactResult, StoreHealth, StoreResult, StoreStatus, VectorData,
+    VectorStore, VectorStoreError,
+};
 
 const PREFIX: &str = "vectors";
 const GRAPH_FILE: &str = "vectors.hnsw.graph";
 const DATA_FILE: &str = "vectors.hnsw.data";
 const META_FILE: &str = "vectors.meta.json";
 
+/// Graph of the stored vectors. Normalized stores still use cosine distance,
+/// a plain dot product can round past 1.0 for (near) duplicates of a unit
+/// vector, which `DistDot` rejects.
+pub type Graph = Hnsw<f32, DistCosine>;
+
+fn new_graph() -> Graph {
+    Hnsw::new(16, 100, 16, 200, DistCosine)
+}
+
 pub struct HnswStore {
     pub storage_path: PathBuf,
-    pub hnsw: Arc<Hnsw<f32, DistCosine>>,
+    pub hnsw: Arc<Graph>,
     pub _id_map: HashMap<usize, String>,
+    /// L2 normalize stored & query vectors, for models trained w/
+    /// dot-product objectives.
+    pub normalize: bool,
+    /// Whether there are points that haven't been saved yet.
+    dirty: bool,
+}
+
+/// Contents of the meta file saved alongside the graph.
+#[derive(Deserialize, Serialize)]
+struct StoreMeta {
+    #[serde(default)]
+    normalize: bool,
+    ids: HashMap<usize, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MetaFile {
+    Meta(StoreMeta),
+    /// Older stores only saved the id map.
+    Legacy(HashMap<usize, String>),
 }
 
 #[async_trait]
 impl VectorStore for HnswStore {
-    async fn delete(&mut self, _: &str) -> Result<(), VectorStoreError> {
+    async fn delete(&mut self, id: &str) -> Result<(), VectorStoreError> {
+        // hnsw_lib does not support removing a single point, so instead we unmap
+        // the point & filter it out of search results.
         // TODO: Find (or build) a replacement for hnsw_lib
-        unimplemented!("Currently hnsw_lib does not support removing a single point")
+        self._id_map.retain(|_, segment_id| segment_id != id);
+        self.save(self.storage_path.clone())
+    }
+
+    /// Saving dumps the whole graph, so it's only done once for all of `ids`.
+    async fn delete_many(&mut self, ids: &[String]) -> StoreResult<()> {
+        let ids: HashSet<&String> = ids.iter().collect();
+        self._id_map
+            .retain(|_, segment_id| !ids.contains(segment_id));
+        self.save(self.storage_path.clone())
     }
 
     async fn delete_all(&mut self) -> Result<(), VectorStoreError> {
@@ -45,9 +92,9 @@ impl VectorStore for HnswStore {
             }
         }
 
-        let store = Hnsw::new(16, 100, 16, 200, DistCosine);
-        self.hnsw = Arc::new(store);
+        self.hnsw = Arc::new(new_graph());
         self._id_map.clear();
+        self.dirty = false;
 
         Ok(())
     }
@@ -56,15 +103,19 @@ impl VectorStore for HnswStore {
         for datum in data {
             self.insert(datum).await?;
         }
-        Ok(())
+        // Dumping the graph gets slow as it grows, so only save once per batch.
+        self.flush()
     }
 
+    /// Points inserted on their own are only saved on the next bulk insert,
+    /// delete or `close`.
     async fn insert(&mut self, data: &VectorData) -> Result<(), VectorStoreError> {
-        let next_id = self._id_map.len() + 1;
+        // Ids of deleted points are never reused.
+        let next_id = self._id_map.keys().max().copied().unwrap_or_default() + 1;
         self._id_map.insert(next_id, data._id.to_string());
-        self.hnsw.insert((&data.vector, next_id));
-        // Naively save after each insert
-        let _ = self.save(self.storage_path.clone());
+        let vector = self.prepare(&data.vector);
+        self.hnsw.insert((&vector, next_id));
+        self.dirty = true;
         Ok(())
     }
 
@@ -73,22 +124,97 @@ impl VectorStore for HnswStore {
         vec: &[f32],
         limit: usize,
     ) -> Result<Vec<(String, f32)>, VectorStoreError> {
-        let neighbors = self.hnsw.search(vec, limit, 16 * 2);
+        // Grab extra neighbors to account for any deleted points.
+        let num_deleted = self.hnsw.get_nb_point().saturating_sub(self._id_map.len());
+        let vec = self.prepare(vec);
+        let neighbors = self.hnsw.search(&vec, limit + num_deleted, 16 * 2);
 
         let mut results = Vec::new();
         for x in neighbors.iter() {
-            let doc_id = self
-                ._id_map
-                .get(&x.d_id)
-                .expect("Internal inconsistency. Id from vector store not mapped.");
-            // Calculate similarity score where 1.0 is exact and 0.0 is completely
-            // orthoganal.
-            let similarity = 1.0 - (1.0 / (1.0 / x.distance));
-            results.push((doc_id.to_string(), similarity));
+            // Points w/o a mapping have been deleted
+            let Some(doc_id) = self._id_map.get(&x.d_id) else {
+                continue;
+            };
+
+            if results.len() >= limit {
+                break;
+            }
+
+            results.push((doc_id.to_string(), similarity_from_distance(x.distance)));
         }
 
         Ok(results)
     }
+
+    async fn compact(&mut self) -> StoreResult<CompactResult> {
+        // Rebuild the graph from the points that are still mapped, keeping
+        // their ids so the id map stays valid.
+        let graph = new_graph();
+        let mut reclaimed = 0;
+        for point in self.hnsw.get_point_indexation() {
+            let id = point.get_origin_id();
+            if self._id_map.contains_key(&id) {
+                graph.insert((point.get_v(), id));
+            } else {
+                reclaimed += 1;
+            }
+        }
+
+        self.hnsw = Arc::new(graph);
+        self.save(self.storage_path.clone())?;
+        Ok(CompactResult {
+            reclaimed: Some(reclaimed),
+        })
+    }
+
+    async fn close(&mut self) -> StoreResult<()> {
+        self.flush()
+    }
+
+    async fn health(&self) -> StoreResult<StoreHealth> {
+        let mut health = StoreHealth {
+            vectors: Some(self._id_map.len()),
+            ..Default::default()
+        };
+
+        if !self._id_map.is_empty() {
+            health.dimension = self
+                .hnsw
+                .get_point_indexation()
+                .into_iter()
+                .next()
+                .map(|point| point.get_v().len());
+        }
+
+        let mut missing = false;
+        for file in [GRAPH_FILE, DATA_FILE, META_FILE] {
+            let size = match self.storage_path.join(file).metadata() {
+                Ok(meta) => format!("{} bytes", meta.len()),
+                Err(_) => {
+                    missing = true;
+                    "missing".to_string()
+                }
+            };
+            health.details.insert(file.to_string(), size);
+        }
+
+        let deleted = self.hnsw.get_nb_point().saturating_sub(self._id_map.len());
+        health
+            .details
+            .insert("deleted".to_string(), deleted.to_string());
+
+        // A saved store w/o its graph can't be loaded again, points that
+        // haven't been saved yet are lost if memex doesn't shut down cleanly.
+        health.status = if missing && Self::has_store(&self.storage_path) {
+            StoreStatus::Red
+        } else if self.dirty || (missing && !self._id_map.is_empty()) {
+            StoreStatus::Yellow
+        } else {
+            StoreStatus::Green
+        };
+
+        Ok(health)
+    }
 }
 
 impl HnswStore {
@@ -98,15 +224,31 @@ impl HnswStore {
             storage_path.display()
         );
 
-        let store = Hnsw::new(16, 100, 16, 200, DistCosine);
-
         Self {
             storage_path: storage_path.to_path_buf(),
-            hnsw: Arc::new(store),
+            hnsw: Arc::new(new_graph()),
             _id_map: HashMap::new(),
+            normalize: false,
+            dirty: false,
         }
     }
 
+    /// Normalize vectors in this store, only used when the store is first
+    /// created. Loaded stores use the setting they were saved with.
+    pub fn normalized(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Vector as it's stored in/searched against the graph.
+    fn prepare(&self, vec: &[f32]) -> Vec<f32> {
+        let mut vec = vec.to_vec();
+        if self.normalize {
+            l2_normalize(&mut vec);
+        }
+        vec
+    }
+
     pub fn has_store(store_path: &Path) -> bool {
         let meta_path = store_path.join(META_FILE);
         meta_path.exists()
@@ -115,6 +257,17 @@ impl HnswStore {
     pub fn load(store_path: &Path) -> Result<Self, VectorStoreError> {
         log::info!("Loading vector storage @ \"{}\"", store_path.display());
 
+        let meta_path = store_path.join(META_FILE);
+        let meta_fhand = File::open(meta_path)?;
+        let meta_reader = BufReader::new(meta_fhand);
+        let meta = match serde_json::from_reader(meta_reader)? {
+            MetaFile::Meta(meta) => meta,
+            MetaFile::Legacy(ids) => StoreMeta {
+                normalize: false,
+                ids,
+            },
+        };
+
         let graph_path = store_path.join(GRAPH_FILE);
         let graph_fhand = File::open(graph_path)?;
 
@@ -125,22 +278,26 @@ impl HnswStore {
         let mut data_in = BufReader::new(data_fhand);
 
         let desc = load_description(&mut graph_in).unwrap();
-        let hnsw_loaded: Hnsw<f32, DistCosine> =
-            load_hnsw(&mut graph_in, &desc, &mut data_in).unwrap();
-
-        let meta_path = store_path.join(META_FILE);
-        let meta_fhand = File::open(meta_path)?;
-        let meta_reader = BufReader::new(meta_fhand);
-        let _id_map: HashMap<usize, String> = serde_json::from_reader(meta_reader)?;
+        let hnsw_loaded: Graph = load_hnsw(&mut graph_in, &desc, &mut data_in).unwrap();
 
         Ok(Self {
             storage_path: store_path.to_path_buf(),
             hnsw: Arc::new(hnsw_loaded),
-            _id_map,
+            _id_map: meta.ids,
+            normalize: meta.normalize,
+            dirty: false,
         })
     }
 
-    pub fn save(&self, store_path: PathBuf) -> Result<(), VectorStoreError> {
+    /// Save the store if anything has changed since it was last saved.
+    fn flush(&mut self) -> StoreResult<()> {
+        if self.dirty {
+            self.save(self.storage_path.clone())?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&mut self, store_path: PathBuf) -> Result<(), VectorStoreError> {
         if !store_path.exists() {
             let _ = std::fs::create_dir_all(store_path.clone());
         }
@@ -152,25 +309,93 @@ impl HnswStore {
             .file_dump(&filename)
             .map_err(VectorStoreError::SaveError)?;
 
-        // Save id map as a json file
-        let result = serde_json::to_string(&self._id_map)
+        // Save id map & settings as a json file
+        let meta = StoreMeta {
+            normalize: self.normalize,
+            ids: self._id_map.clone(),
+        };
+        let result = serde_json::to_string(&meta)
             .map_err(|err| VectorStoreError::SaveError(err.to_string()))?;
 
         let id_map = store_path.join(META_FILE);
         let mut f = File::create(id_map)?;
         let _ = f.write(result.as_bytes())?;
         f.flush()?;
+        self.dirty = false;
 
         Ok(())
     }
 }
 
+/// Resolve the root directory that hnsw collections are stored under from a
+/// `hnsw://` URI. Supports relative (`hnsw://data/vdb`), absolute
+/// (`hnsw:///var/lib/memex`), and home-relative (`hnsw://~/memex`) paths.
+/// The directory is created if it doesn't exist yet.
+pub fn resolve_root(uri: &str) -> StoreResult<PathBuf> {
+    let home = std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from);
+    let root = parse_root(uri, home.as_deref())?;
+
+    if !root.exists() {
+        std::fs::create_dir_all(&root).map_err(|err| {
+            VectorStoreError::InvalidPath(format!("unable to create {}: {err}", root.display()))
+        })?;
+    }
+
+    let root = root.canonicalize().map_err(|err| {
+        VectorStoreError::InvalidPath(format!("unable to resolve {}: {err}", root.display()))
+    })?;
+
+    if !root.is_dir() {
+        return Err(VectorStoreError::InvalidPath(format!(
+            "{} is not a directory",
+            root.display()
+        )));
+    }
+
+    if root.metadata()?.permissions().readonly() {
+        return Err(VectorStoreError::InvalidPath(format!(
+            "{} is not writable",
+            root.display()
+        )));
+    }
+
+    Ok(root)
+}
+
+/// Pull the storage path out of a `hnsw://` URI, expanding `~` to `home`.
+fn parse_root(uri: &str, home: Option<&Path>) -> StoreResult<PathBuf> {
+    let path = uri
+        .strip_prefix("hnsw://")
+        .ok_or_else(|| VectorStoreError::InvalidPath(format!("not a hnsw:// uri: {uri}")))?;
+    // Drop any store settings, e.g. `?normalize=true`
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+
+    if path.is_empty() {
+        return Err(VectorStoreError::InvalidPath(format!(
+            "no storage path in {uri}"
+        )));
+    }
+
+    if path == "~" || path.starts_with("~/") {
+        let home = home.ok_or_else(|| {
+            VectorStoreError::InvalidPath("unable to determine home directory".into())
+        })?;
+        return Ok(home.join(path.trim_start_matches('~').trim_start_matches('/')));
+    }
+
+    Ok(PathBuf::from(path))
+}
+
 #[cfg(test)]
 mod test {
     use crate::storage::VectorData;
 
-    use super::{HnswStore, VectorStore};
-    use std::path::Path;
+    use super::{parse_root, resolve_root, HnswStore, StoreStatus, VectorStore, GRAPH_FILE};
+    use crate::llm::embedding::l2_normalize;
+    use crate::storage::{check_scores, score_test_data, similarity_from_distance};
+    use std::path::{Path, PathBuf};
 
     fn test_data() -> Vec<VectorData> {
         vec![
@@ -213,6 +438,15 @@ mod test {
         let _ = store.delete_all();
     }
 
+    #[tokio::test]
+    async fn test_scores() {
+        let path = Path::new("/tmp/vectortest-score");
+        let mut store = HnswStore::new(path);
+        store.bulk_insert(&score_test_data("test")).await.unwrap();
+        check_scores(&store).await;
+        let _ = store.delete_all().await;
+    }
+
     #[tokio::test]
     async fn test_save_load() {
         let path = Path::new("/tmp/vectortest");
@@ -226,6 +460,45 @@ mod test {
         let _ = store.delete_all();
     }
 
+    #[tokio::test]
+    async fn test_normalized_search() {
+        let path = Path::new("/tmp/vectortest-normalized");
+        let mut store = HnswStore::new(path).normalized(true);
+
+        // A long vector that's further away has the larger raw dot product.
+        let mut data = test_data();
+        data[0].vector = vec![10.0, 10.0, 0.0];
+        data[1].vector = vec![1.0, 0.1, 0.0];
+        data.truncate(2);
+        store.bulk_insert(&data).await.unwrap();
+
+        let query = [1.0, 0.0, 0.0];
+        let results = store.search(&query, 2).await.unwrap();
+        let ids: Vec<&str> = results.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, vec!["test-two", "test-one"]);
+
+        // Scores are the cosine similarity of the original vectors
+        for ((_, score), datum) in results.iter().zip([&data[1], &data[0]]) {
+            let mut vector = datum.vector.clone();
+            l2_normalize(&mut vector);
+            let cosine = vector[0];
+            assert!((score - similarity_from_distance(1.0 - cosine)).abs() < 1e-4);
+        }
+
+        // Searching w/ a stored vector finds itself, even though its dot
+        // product w/ itself can round past 1.0 once normalized.
+        for datum in &data {
+            let results = store.search(&datum.vector, 1).await.unwrap();
+            assert_eq!(results[0].0, datum._id);
+            assert!((results[0].1 - 1.0).abs() < 1e-4);
+        }
+
+        // Setting is persisted w/ the store
+        let loaded = HnswStore::load(path).unwrap();
+        assert!(loaded.normalize);
+        let _ = store.delete_all().await;
+    }
+
     #[tokio::test]
     async fn test_delete_all() {
         let path = Path::new("/tmp");
@@ -240,4 +513,156 @@ mod test {
         let res = HnswStore::load(&path);
         assert!(res.is_err());
     }
+
+    #[test]
+    fn test_parse_root() {
+        let home = Path::new("/home/memex");
+
+        assert_eq!(
+            parse_root("hnsw://data/vdb", Some(home)).unwrap(),
+            PathBuf::from("data/vdb")
+        );
+        assert_eq!(
+            parse_root("hnsw://./data", Some(home)).unwrap(),
+            PathBuf::from("./data")
+        );
+        assert_eq!(
+            parse_root("hnsw:///var/lib/memex", Some(home)).unwrap(),
+            PathBuf::from("/var/lib/memex")
+        );
+        assert_eq!(
+            parse_root("hnsw://~/memex", Some(home)).unwrap(),
+            PathBuf::from("/home/memex/memex")
+        );
+        assert_eq!(
+            parse_root("hnsw://~", Some(home)).unwrap(),
+            PathBuf::from("/home/memex")
+        );
+        assert_eq!(
+            parse_root("hnsw://data/vdb?normalize=true", Some(home)).unwrap(),
+            PathBuf::from("data/vdb")
+        );
+
+        assert!(parse_root("hnsw://~/memex", None).is_err());
+        assert!(parse_root("hnsw://", Some(home)).is_err());
+        assert!(parse_root("opensearch+https://localhost", Some(home)).is_err());
+    }
+
+    #[test]
+    fn test_resolve_root() {
+        let root = std::env::temp_dir().join("memex-resolve-root");
+        let _ = std::fs::remove_dir_all(&root);
+
+        let uri = format!("hnsw://{}", root.join("nested").display());
+        let resolved = resolve_root(&uri).unwrap();
+        assert!(resolved.is_absolute());
+        assert!(resolved.is_dir());
+        assert_eq!(resolved, root.join("nested").canonicalize().unwrap());
+
+        // Read-only roots are rejected
+        let mut perms = resolved.metadata().unwrap().permissions();
+        perms.set_readonly(true);
+        std::fs::set_permissions(&resolved, perms.clone()).unwrap();
+        assert!(resolve_root(&uri).is_err());
+
+        #[allow(clippy::permissions_set_readonly_false)]
+        perms.set_readonly(false);
+        std::fs::set_permissions(&resolved, perms).unwrap();
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let path = std::env::temp_dir().join("memex-hnsw-delete");
+        let mut store = HnswStore::new(&path);
+        store.bulk_insert(&test_data()).await.unwrap();
+
+        store.delete("test-two").await.unwrap();
+        let results = store.search(&[0.1, 0.1, 0.1], 3).await.unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|(doc_id, _)| doc_id != "test-two"));
+
+        // New points shouldn't clobber existing ids
+        let mut data = test_data();
+        data[1]._id = "test-four".into();
+        store.insert(&data[1]).await.unwrap();
+        assert_eq!(store._id_map.len(), 3);
+
+        let results = store.search(&[0.1, 0.1, 0.1], 1).await.unwrap();
+        assert_eq!(results[0].0, "test-four");
+
+        store
+            .delete_many(&["test-one".into(), "test-three".into()])
+            .await
+            .unwrap();
+        let results = store.search(&[0.1, 0.1, 0.1], 3).await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, "test-four");
+        let _ = store.delete_all().await;
+    }
+
+    #[tokio::test]
+    async fn test_compact() {
+        let path = std::env::temp_dir().join("memex-hnsw-compact");
+        let mut store = HnswStore::new(&path);
+        store.bulk_insert(&test_data()).await.unwrap();
+        store.delete("test-two").await.unwrap();
+
+        let result = store.compact().await.unwrap();
+        assert_eq!(result.reclaimed, Some(1));
+        assert_eq!(store.hnsw.get_nb_point(), 2);
+
+        let results = store.search(&[0.1, 0.1, 0.1], 3).await.unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|(doc_id, _)| doc_id != "test-two"));
+
+        // Nothing left to reclaim
+        assert_eq!(store.compact().await.unwrap().reclaimed, Some(0));
+        store.delete_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let path = std::env::temp_dir().join("memex-hnsw-health");
+        let _ = std::fs::remove_dir_all(&path);
+        let mut store = HnswStore::new(&path);
+        let health = store.health().await.unwrap();
+        assert_eq!(health.status, StoreStatus::Green);
+        assert_eq!(health.vectors, Some(0));
+        assert_eq!(health.dimension, None);
+
+        // Unsaved points
+        store.insert(&test_data()[0]).await.unwrap();
+        let health = store.health().await.unwrap();
+        assert_eq!(health.status, StoreStatus::Yellow);
+        assert_eq!(health.vectors, Some(1));
+        assert_eq!(health.dimension, Some(3));
+
+        store.close().await.unwrap();
+        let health = store.health().await.unwrap();
+        assert_eq!(health.status, StoreStatus::Green);
+        assert_ne!(health.details.get(GRAPH_FILE).unwrap(), "missing");
+
+        std::fs::remove_file(path.join(GRAPH_FILE)).unwrap();
+        let health = store.health().await.unwrap();
+        assert_eq!(health.status, StoreStatus::Red);
+        let _ = store.delete_all().await;
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let path = std::env::temp_dir().join("memex-hnsw-close");
+        let _ = std::fs::remove_dir_all(&path);
+        let mut store = HnswStore::new(&path);
+        for datum in test_data() {
+            store.insert(&datum).await.unwrap();
+        }
+        assert!(!HnswStore::has_store(&path));
+
+        store.close().await.unwrap();
+        let loaded = HnswStore::load(&path).unwrap();
+        assert_eq!(loaded._id_map.len(), 3);
+        assert_eq!(loaded.hnsw.get_nb_point(), 3);
+        store.delete_all().await.unwrap();
+    }
 }
@@ -1,17 +1,45 @@
 use async_trait::async_trait;
-use std::{path::PathBuf, sync::Arc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::{Duration, Instant};
+use strum_macros::{Display, EnumString, EnumVariantNames};
 use thiserror::Error;
 use tokio::sync::Mutex;
 use url::Url;
 
+use crate::llm::embedding::cosine_similarity;
+
 use self::{
     local::HnswStore,
-    opensearch::{OpenSearchConnectionConfig, OpenSearchStore},
+    opensearch::{KnnMethod, OpenSearchConnectionConfig, OpenSearchStore},
+    pgvector::PgVectorStore,
 };
 
 pub mod local;
 pub mod opensearch;
+pub mod pgvector;
 pub mod qdrant;
+pub mod scoring;
+
+/// Size of the vectors generated by the default embedding model.
+pub const DEFAULT_EMBEDDING_DIMENSION: usize = 384;
+/// How long to keep waiting on startup for a store that isn't up yet, e.g. one
+/// that's still starting up alongside memex in docker-compose.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(8);
+
+/// How vectors in a collection are compared. Every store currently uses
+/// cosine distance.
+#[derive(Clone, Copy, Debug, Default, Display, EnumString, EnumVariantNames, PartialEq, Eq)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum DistanceMetric {
+    #[default]
+    Cosine,
+}
 
 #[derive(Debug, Clone)]
 pub struct VectorData {
@@ -35,6 +63,8 @@ pub enum VectorStoreError {
     DeleteError(String),
     #[error("File IO error: {0}")]
     FileIOError(#[from] std::io::Error),
+    #[error("Invalid storage path: {0}")]
+    InvalidPath(String),
     #[error("Unable to insert vector: {0}")]
     InsertionError(String),
     #[error("Unable to search: {0}")]
@@ -45,17 +75,221 @@ pub enum VectorStoreError {
     SaveError(String),
     #[error("Unsupported vector db: {0}")]
     Unsupported(String),
+    #[error("Wrong vector dimension: {0}")]
+    WrongDimension(String),
 }
 
-// doc_id & score
+/// Segment id & similarity score. Every store reports the score as a similarity
+/// in [0, 1] where 1.0 is an exact match, see `similarity_from_distance`.
 pub type VectorSearchResult = (String, f32);
 pub type StoreResult<T> = Result<T, VectorStoreError>;
 
+/// Convert a cosine distance (0.0 = same direction, 2.0 = opposite) into a
+/// similarity score in [0, 1].
+pub fn similarity_from_distance(distance: f32) -> f32 {
+    (1.0 - distance / 2.0).clamp(0.0, 1.0)
+}
+
+/// Convert a similarity score back into the cosine distance it came from.
+pub fn distance_from_similarity(score: f32) -> f32 {
+    2.0 * (1.0 - score)
+}
+
+/// Score of a stored vector for a query, computed from the vectors themselves
+/// for stores that don't report a usable distance.
+pub fn cosine_score(query: &[f32], vector: &[f32]) -> f32 {
+    similarity_from_distance(1.0 - cosine_similarity(query, vector))
+}
+
+/// Vectors every store's scores are checked against, see `check_scores`.
+#[cfg(test)]
+pub(crate) fn score_test_data(document_id: &str) -> Vec<VectorData> {
+    [[0.0, 0.1, 0.2], [0.1, 0.1, 0.1], [0.3, 0.2, 0.1]]
+        .into_iter()
+        .enumerate()
+        .map(|(idx, vector)| VectorData {
+            _id: format!("score-{idx}"),
+            document_id: document_id.to_string(),
+            text: String::new(),
+            segment_id: idx,
+            vector: vector.to_vec(),
+        })
+        .collect()
+}
+
+/// Check a store w/ `score_test_data` reports the same scores as the others:
+/// ~1.0 for an identical vector & the cosine score for the rest.
+#[cfg(test)]
+pub(crate) async fn check_scores<S: VectorStore + ?Sized>(store: &S) {
+    let data = score_test_data("");
+    let query = [0.3, 0.2, 0.1];
+    let results = store.search(&query, data.len()).await.unwrap();
+    assert_eq!(results.len(), data.len());
+
+    let (id, score) = results.first().unwrap();
+    assert_eq!(id, "score-2");
+    assert!((score - 1.0).abs() < 1e-4, "{score}");
+    for (id, score) in results {
+        let point = data.iter().find(|point| point._id == id).unwrap();
+        let expected = cosine_score(&query, &point.vector);
+        assert!(
+            (score - expected).abs() < 1e-3,
+            "{id}: {score} != {expected}"
+        );
+    }
+}
+
+/// Re-rank search results w/ maximal marginal relevance, trading off each
+/// result's `score` against its similarity to results already picked.
+/// `lambda` of 1.0 ranks by score alone, lower values favor diverse results.
+/// Returns the indices of up to `limit` candidates in their new order.
+pub fn mmr_rerank(candidates: &[(f32, Vec<f32>)], lambda: f32, limit: usize) -> Vec<usize> {
+    let mut remaining: Vec<usize> = (0..candidates.len()).collect();
+    let mut picked: Vec<usize> = Vec::new();
+
+    while picked.len() < limit && !remaining.is_empty() {
+        let mmr = |idx: usize| {
+            let (score, vector) = &candidates[idx];
+            let redundancy = picked
+                .iter()
+                .map(|prev| {
+                    let cosine = cosine_similarity(vector, &candidates[*prev].1);
+                    similarity_from_distance(1.0 - cosine)
+                })
+                .fold(0.0, f32::max);
+            lambda * score - (1.0 - lambda) * redundancy
+        };
+
+        // Ties go to the earlier (more relevant) candidate.
+        let (pos, _) = remaining
+            .iter()
+            .enumerate()
+            .map(|(pos, idx)| (pos, mmr(*idx)))
+            .fold(
+                (0, f32::MIN),
+                |best, cur| if cur.1 > best.1 { cur } else { best },
+            );
+        picked.push(remaining.remove(pos));
+    }
+
+    picked
+}
+
+/// Retry `connect` w/ exponential backoff until it succeeds or `timeout` has
+/// passed, returning the last error.
+pub async fn retry_connect<T, E, F, Fut>(
+    name: &str,
+    timeout: Duration,
+    mut connect: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Display,
+{
+    let start = Instant::now();
+    let mut delay = INITIAL_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let err = match connect().await {
+            Ok(result) => return Ok(result),
+            Err(err) => err,
+        };
+
+        let remaining = timeout.saturating_sub(start.elapsed());
+        if remaining.is_zero() {
+            log::error!("Unable to connect to {name} after {attempt} attempt(s): {err}");
+            return Err(err);
+        }
+
+        let wait = delay.min(remaining);
+        log::warn!("Unable to connect to {name} (attempt {attempt}): {err}, retrying in {wait:?}");
+        tokio::time::sleep(wait).await;
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+        attempt += 1;
+    }
+}
+
+/// Read how long to retry connecting for from a connection url, e.g.
+/// `?connect_timeout=60` (in seconds).
+fn connect_timeout(url: &Url) -> StoreResult<Duration> {
+    match url.query_pairs().find(|(key, _)| key == "connect_timeout") {
+        Some((_, value)) => value.parse().map(Duration::from_secs).map_err(|_| {
+            VectorStoreError::ConnectionError(format!("Invalid connect_timeout: {value}"))
+        }),
+        None => Ok(DEFAULT_CONNECT_TIMEOUT),
+    }
+}
+
+/// OpenSearch's own URL, settings for memex are passed as query params.
+fn opensearch_url(uri: &str) -> StoreResult<Url> {
+    let mut connect_url = Url::parse(uri.strip_prefix("opensearch+").unwrap_or_default())
+        .map_err(|_| VectorStoreError::Unsupported(uri.to_string()))?;
+    connect_url.set_query(None);
+    Ok(connect_url)
+}
+
+/// Wait for the vector store to come up, retrying w/ backoff until its
+/// `connect_timeout`. Called once on startup, connecting to a collection's
+/// index afterwards only tries once so requests don't stall.
+pub async fn wait_for_vector_storage(uri: &str) -> StoreResult<()> {
+    let parsed_uri = Url::parse(uri).map_err(|_| VectorStoreError::Unsupported(uri.to_string()))?;
+    if parsed_uri.scheme() == "opensearch+https" {
+        let timeout = connect_timeout(&parsed_uri)?;
+        opensearch::wait_until_up(opensearch_url(uri)?.as_str(), timeout)
+            .await
+            .map_err(|err| VectorStoreError::ConnectionError(err.to_string()))?;
+    }
+
+    Ok(())
+}
+
+/// Outcome of compacting a vector store.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CompactResult {
+    /// # of deleted points that were cleaned up, if the store can tell.
+    pub reclaimed: Option<usize>,
+}
+
+/// Overall state of a vector store, using OpenSearch's index colors.
+#[derive(Clone, Copy, Debug, Default, Display, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum StoreStatus {
+    /// Everything is reachable & persisted.
+    #[default]
+    Green,
+    /// Usable, but something needs attention, e.g. unsaved points or
+    /// unassigned replicas.
+    Yellow,
+    /// Not usable, e.g. files are missing or the index has unassigned shards.
+    Red,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoreHealth {
+    pub status: StoreStatus,
+    /// # of vectors in the store, excluding deleted ones.
+    pub vectors: Option<usize>,
+    /// Size of the stored vectors, unknown if the store is empty.
+    pub dimension: Option<usize>,
+    /// Backend specific details, e.g. file sizes or index health.
+    pub details: BTreeMap<String, String>,
+}
+
 #[async_trait]
 pub trait VectorStore {
     /// Delete a single document from the vector store.
     /// If a segment is given, that particular segment is deleted.
     async fn delete(&mut self, id: &str) -> StoreResult<()>;
+    /// Delete many segments at once, stores that can batch deletes should.
+    async fn delete_many(&mut self, ids: &[String]) -> StoreResult<()> {
+        for id in ids {
+            self.delete(id).await?;
+        }
+        Ok(())
+    }
     /// Delete ALL documents from the vector store.
     async fn delete_all(&mut self) -> StoreResult<()>;
     /// Bulk insert many documents at a time
@@ -63,11 +297,52 @@ pub trait VectorStore {
     /// Insert a single document
     async fn insert(&mut self, data: &VectorData) -> StoreResult<()>;
     async fn search(&self, vec: &[f32], limit: usize) -> StoreResult<Vec<VectorSearchResult>>;
+    /// Check the store is reachable & consistent. Errors if the store can't
+    /// be reached at all.
+    async fn health(&self) -> StoreResult<StoreHealth>;
+    /// Clean up deleted points. Stores that clean up after themselves have
+    /// nothing to reclaim.
+    async fn compact(&mut self) -> StoreResult<CompactResult> {
+        Ok(CompactResult { reclaimed: Some(0) })
+    }
+    /// Write out anything that hasn't been persisted yet, before shutting
+    /// down. Stores that persist every write have nothing to do.
+    async fn close(&mut self) -> StoreResult<()> {
+        Ok(())
+    }
+}
+
+type SharedStore = Arc<Mutex<dyn VectorStore + Send + Sync>>;
+type WeakStore = Weak<Mutex<dyn VectorStore + Send + Sync>>;
+
+/// Stores that are still in use, so they can be closed on shutdown.
+static OPEN_STORES: OnceLock<std::sync::Mutex<Vec<WeakStore>>> = OnceLock::new();
+
+fn track_store(client: &SharedStore) {
+    let stores = OPEN_STORES.get_or_init(Default::default);
+    if let Ok(mut stores) = stores.lock() {
+        stores.retain(|store| store.strong_count() > 0);
+        stores.push(Arc::downgrade(client));
+    }
+}
+
+/// Close every store that's still in use, called on graceful shutdown.
+pub async fn close_all() {
+    let stores: Vec<SharedStore> = match OPEN_STORES.get().map(|stores| stores.lock()) {
+        Some(Ok(stores)) => stores.iter().filter_map(Weak::upgrade).collect(),
+        _ => return,
+    };
+
+    for store in stores {
+        if let Err(err) = store.lock().await.close().await {
+            log::error!("Unable to close vector store: {err}");
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct VectorStorage {
-    pub client: Arc<Mutex<dyn VectorStore + Send + Sync>>,
+    pub client: SharedStore,
 }
 
 impl VectorStorage {
@@ -77,24 +352,89 @@ impl VectorStorage {
         Ok(())
     }
 
+    /// Remove all the segments of a document from the vector store.
+    pub async fn delete_document(&self, segment_ids: &[String]) -> Result<(), VectorStoreError> {
+        let mut client = self.client.lock().await;
+        client.delete_many(segment_ids).await
+    }
+
+    /// Remove a single segment from the vector store.
+    pub async fn delete_segment(&self, segment_id: &str) -> Result<(), VectorStoreError> {
+        let mut client = self.client.lock().await;
+        client.delete(segment_id).await
+    }
+
     pub async fn delete_collection(&self) -> Result<(), VectorStoreError> {
         let mut client = self.client.lock().await;
         client.delete_all().await
     }
 
+    pub async fn compact(&self) -> Result<CompactResult, VectorStoreError> {
+        let mut client = self.client.lock().await;
+        client.compact().await
+    }
+
+    pub async fn close(&self) -> Result<(), VectorStoreError> {
+        let mut client = self.client.lock().await;
+        client.close().await
+    }
+
+    pub async fn health(&self) -> Result<StoreHealth, VectorStoreError> {
+        let client = self.client.lock().await;
+        client.health().await
+    }
+
+    #[tracing::instrument(
+        name = "vector_search",
+        skip_all,
+        fields(limit = limit, results = tracing::field::Empty)
+    )]
     pub async fn search(
         &self,
         query: &[f32],
         limit: usize,
     ) -> Result<Vec<VectorSearchResult>, VectorStoreError> {
         let client = self.client.lock().await;
-        client.search(query, limit).await
+        let results = client.search(query, limit).await?;
+        tracing::Span::current().record("results", results.len());
+        Ok(results)
+    }
+}
+
+/// Whether a collection's vectors can be written to a separate index & swapped
+/// over to, as reindexing in the background does. pgvector keeps vectors
+/// alongside the segments so there's only ever one index per collection.
+pub fn supports_index_swap(uri: &str) -> bool {
+    !uri.starts_with("pgvector://")
+}
+
+/// Make sure the store at `uri` can hold vectors of `dimension`. pgvector's
+/// column is sized once for every collection, other stores size each
+/// collection's index when it's created.
+pub async fn check_dimension(uri: &str, dimension: usize) -> Result<(), VectorStoreError> {
+    if !uri.starts_with("pgvector://") {
+        return Ok(());
     }
+
+    PgVectorStore::connect(uri, "")
+        .await?
+        .check_dimension(dimension)
+        .await
 }
 
 pub async fn get_vector_storage(
     uri: &str,
     collection: &str,
+) -> Result<VectorStorage, VectorStoreError> {
+    get_vector_storage_with_dimension(uri, collection, DEFAULT_EMBEDDING_DIMENSION).await
+}
+
+/// Connect to a vector store, creating indexes for vectors of `dimension` if
+/// the store needs to know up front.
+pub async fn get_vector_storage_with_dimension(
+    uri: &str,
+    collection: &str,
+    dimension: usize,
 ) -> Result<VectorStorage, VectorStoreError> {
     let parsed_uri = match Url::parse(uri) {
         Ok(uri) => uri,
@@ -103,11 +443,9 @@ pub async fn get_vector_storage(
 
     let scheme = parsed_uri.scheme();
 
-    // Only support one right now
-    let client: Arc<Mutex<dyn VectorStore + Send + Sync>> = if scheme == "hnsw" {
-        let storage: PathBuf = uri.strip_prefix("hnsw://").unwrap_or_default().into();
+    let client: SharedStore = if scheme == "hnsw" {
         // Collections are stored as folders
-        let storage = storage.join(collection);
+        let storage = local::resolve_root(uri)?.join(collection);
         if !storage.exists() {
             std::fs::create_dir_all(storage.clone())?;
         }
@@ -115,25 +453,126 @@ pub async fn get_vector_storage(
         let store = if HnswStore::has_store(&storage) {
             HnswStore::load(&storage)?
         } else {
-            HnswStore::new(&storage)
+            let normalize = parsed_uri
+                .query_pairs()
+                .any(|(key, value)| key == "normalize" && value == "true");
+            HnswStore::new(&storage).normalized(normalize)
         };
 
         Arc::new(Mutex::new(store))
     } else if scheme == "opensearch+https" {
-        let connect_url = uri.strip_prefix("opensearch+").unwrap_or_default();
+        let knn = KnnMethod::from_url(&parsed_uri).map_err(VectorStoreError::ConnectionError)?;
+        let connect_url = opensearch_url(uri)?;
         let config = OpenSearchConnectionConfig {
             index: collection.to_string(),
-            embedding_dimension: 384,
+            embedding_dimension: dimension,
+            knn,
             ..Default::default()
         };
 
-        let store = OpenSearchStore::new(connect_url, config)
+        let store = OpenSearchStore::new(connect_url.as_str(), config)
             .await
             .map_err(|x| VectorStoreError::ConnectionError(x.to_string()))?;
         Arc::new(Mutex::new(store))
+    } else if scheme == "pgvector" {
+        let store = PgVectorStore::connect(uri, collection).await?;
+        Arc::new(Mutex::new(store))
     } else {
         return Err(VectorStoreError::Unsupported(uri.to_string()));
     };
 
+    track_store(&client);
     Ok(VectorStorage { client })
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        connect_timeout, cosine_score, distance_from_similarity, mmr_rerank, retry_connect,
+        similarity_from_distance, DEFAULT_CONNECT_TIMEOUT,
+    };
+    use std::time::Duration;
+    use url::Url;
+
+    #[test]
+    fn test_similarity_from_distance() {
+        // Identical, orthogonal, & opposite vectors
+        assert_eq!(similarity_from_distance(0.0), 1.0);
+        assert_eq!(similarity_from_distance(1.0), 0.5);
+        assert_eq!(similarity_from_distance(2.0), 0.0);
+        // Floating point error stays within bounds
+        assert_eq!(similarity_from_distance(-0.0001), 1.0);
+
+        for distance in [0.0, 0.25, 1.0, 1.5, 2.0] {
+            let score = similarity_from_distance(distance);
+            assert!((distance_from_similarity(score) - distance).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_cosine_score() {
+        let query = [0.3, 0.2, 0.1];
+        assert!((cosine_score(&query, &query) - 1.0).abs() < 1e-6);
+        // Scale doesn't matter, only direction
+        assert!((cosine_score(&query, &[0.6, 0.4, 0.2]) - 1.0).abs() < 1e-6);
+        assert!((cosine_score(&query, &[-0.3, -0.2, -0.1])).abs() < 1e-6);
+        assert!((cosine_score(&[1.0, 0.0], &[0.0, 1.0]) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_mmr_rerank() {
+        // Two near-identical results & a less relevant, but different, one.
+        let candidates = vec![
+            (0.9, vec![1.0, 0.0]),
+            (0.89, vec![1.0, 0.01]),
+            (0.7, vec![0.0, 1.0]),
+        ];
+
+        // Relevance only keeps the original order
+        assert_eq!(mmr_rerank(&candidates, 1.0, 3), vec![0, 1, 2]);
+        // Favoring diversity pushes the duplicate down
+        assert_eq!(mmr_rerank(&candidates, 0.5, 3), vec![0, 2, 1]);
+        assert_eq!(mmr_rerank(&candidates, 0.5, 2), vec![0, 2]);
+        assert!(mmr_rerank(&[], 0.5, 2).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_retry_connect() {
+        // Succeeds once the store is up
+        let mut attempts = 0;
+        let result = retry_connect("test", Duration::from_secs(5), || {
+            attempts += 1;
+            let attempt = attempts;
+            async move {
+                match attempt {
+                    1 => Err("not up yet"),
+                    _ => Ok(attempt),
+                }
+            }
+        })
+        .await;
+        assert_eq!(result, Ok(2));
+
+        // Gives up after the timeout
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_connect("test", Duration::from_millis(50), || {
+            attempts += 1;
+            async { Err("down") }
+        })
+        .await;
+        assert_eq!(result, Err("down"));
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        let url = Url::parse("opensearch+https://localhost:9200").unwrap();
+        assert_eq!(connect_timeout(&url).unwrap(), DEFAULT_CONNECT_TIMEOUT);
+
+        let url = Url::parse("opensearch+https://localhost:9200?connect_timeout=90").unwrap();
+        assert_eq!(connect_timeout(&url).unwrap(), Duration::from_secs(90));
+
+        let url = Url::parse("opensearch+https://localhost:9200?connect_timeout=soon").unwrap();
+        assert!(connect_timeout(&url).is_err());
+    }
+}
@@ -1,4 +1,7 @@
-use super::{StoreResult, VectorData, VectorSearchResult, VectorStore, VectorStoreError};
+use super::{
+    cosine_score, retry_connect, similarity_from_distance, CompactResult, StoreHealth, StoreResult,
+    StoreStatus, VectorData, VectorSearchResult, VectorStore, VectorStoreError,
+};
 use async_trait::async_trait;
 use opensearch::{
     auth::Credentials,
@@ -10,18 +13,128 @@ use opensearch::{
     BulkOperation, BulkOperations, OpenSearch,
 };
 use serde::Deserialize;
-use serde_json::json;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+use strum_macros::{Display, EnumString};
 use url::Url;
 
 pub enum SearchFilter {
     Phrase(String),
 }
 
+#[derive(Clone, Copy, Debug, Default, Display, EnumString, PartialEq, Eq)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum KnnEngine {
+    #[default]
+    Nmslib,
+    Faiss,
+    Lucene,
+}
+
+/// How the knn index is built. Memex reports cosine similarities, so the
+/// space type should stay `cosinesimil` unless vectors are normalized.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KnnMethod {
+    pub engine: KnnEngine,
+    pub space_type: String,
+    /// Size of the candidate list while building the graph, higher values
+    /// improve recall at the cost of slower indexing.
+    pub ef_construction: usize,
+    /// # of links per node in the graph.
+    pub m: usize,
+    /// Whether the stored vectors are known to be L2 normalized, only then
+    /// are knn scores converted rather than scoring the stored vectors.
+    pub normalized: bool,
+}
+
+impl Default for KnnMethod {
+    fn default() -> Self {
+        Self {
+            engine: KnnEngine::default(),
+            space_type: "cosinesimil".into(),
+            ef_construction: 100,
+            m: 16,
+            normalized: false,
+        }
+    }
+}
+
+impl KnnMethod {
+    /// Read overrides from the connection url's query string, e.g.
+    /// `?engine=lucene&ef_construction=256&m=32&normalized=true`.
+    pub fn from_url(url: &Url) -> Result<Self, String> {
+        let mut method = Self::default();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "engine" => {
+                    method.engine = value
+                        .parse()
+                        .map_err(|_| format!("Unknown knn engine: {value}"))?
+                }
+                "space_type" => method.space_type = value.to_string(),
+                "ef_construction" => {
+                    method.ef_construction = value
+                        .parse()
+                        .map_err(|_| format!("Invalid ef_construction: {value}"))?
+                }
+                "m" => method.m = value.parse().map_err(|_| format!("Invalid m: {value}"))?,
+                "normalized" => method.normalized = value == "true",
+                _ => {}
+            }
+        }
+
+        Ok(method)
+    }
+
+    /// Convert a knn `_score` into the similarity every store reports, see
+    /// `similarity_from_distance`. `None` for space types w/o a known
+    /// transform.
+    ///
+    /// - `cosinesimil`: `1 / (2 - cos)`, or `(1 + cos) / 2` w/ lucene.
+    /// - `innerproduct`: `dot + 1` for positive dot products, `1 / (1 - dot)`
+    ///   otherwise.
+    /// - `l2`: `1 / (1 + l2²)`.
+    ///
+    /// Inner product & L2 distances only match cosine similarity when
+    /// vectors are normalized.
+    pub fn similarity(&self, score: f32) -> Option<f32> {
+        if score <= 0.0 {
+            return None;
+        }
+
+        let cosine = match self.space_type.as_str() {
+            "cosinesimil" if self.engine == KnnEngine::Lucene => 2.0 * score - 1.0,
+            "cosinesimil" => 2.0 - 1.0 / score,
+            "innerproduct" if score >= 1.0 => score - 1.0,
+            "innerproduct" => 1.0 - 1.0 / score,
+            // Squared L2 distance between unit vectors is 2 - 2 * cos.
+            "l2" => 1.0 - (1.0 / score - 1.0) / 2.0,
+            _ => return None,
+        };
+
+        Some(similarity_from_distance(1.0 - cosine))
+    }
+
+    fn mapping(&self) -> Value {
+        json!({
+            "name": "hnsw",
+            "engine": self.engine.to_string(),
+            "space_type": self.space_type,
+            "parameters": {
+                "ef_construction": self.ef_construction,
+                "m": self.m
+            }
+        })
+    }
+}
+
 #[derive(Default)]
 pub struct OpenSearchConnectionConfig {
     pub credentials: Option<Credentials>,
     pub index: String,
     pub embedding_dimension: usize,
+    pub knn: KnnMethod,
 }
 
 #[allow(dead_code)]
@@ -55,9 +168,61 @@ pub struct OpenSearchResponse {
     pub took: usize,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ClusterHealth {
+    pub status: String,
+    #[serde(default)]
+    pub unassigned_shards: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CountResponse {
+    pub count: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BulkItem {
+    _id: String,
+    status: u16,
+    error: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BulkResponse {
+    pub errors: bool,
+    /// Each item is keyed by its operation, e.g. "index"
+    pub items: Vec<HashMap<String, BulkItem>>,
+}
+
+impl BulkResponse {
+    /// Items that OpenSearch rejected, w/ the reason they failed.
+    pub fn failures(&self) -> Vec<(String, String)> {
+        if !self.errors {
+            return Vec::new();
+        }
+
+        self.items
+            .iter()
+            .flat_map(|item| item.values())
+            .filter_map(|item| {
+                item.error.as_ref().map(|err| {
+                    let reason = err
+                        .get("reason")
+                        .and_then(|reason| reason.as_str())
+                        .map(|reason| reason.to_string())
+                        .unwrap_or_else(|| err.to_string());
+                    (item._id.clone(), format!("{} - {reason}", item.status))
+                })
+            })
+            .collect()
+    }
+}
+
 pub struct OpenSearchStore {
     pub client: OpenSearch,
     pub index_name: String,
+    /// How the index was built, needed to make sense of its scores.
+    pub knn: KnnMethod,
 }
 
 impl OpenSearchStore {
@@ -67,11 +232,18 @@ impl OpenSearchStore {
     ) -> anyhow::Result<Self> {
         let client = connect(connect_url, config.credentials)?;
         // Make sure index is created
-        create_index(&client, &config.index, config.embedding_dimension).await?;
+        create_index(
+            &client,
+            &config.index,
+            config.embedding_dimension,
+            &config.knn,
+        )
+        .await?;
 
         Ok(Self {
             client,
             index_name: config.index.clone(),
+            knn: config.knn,
         })
     }
 
@@ -148,12 +320,37 @@ impl VectorStore for OpenSearchStore {
             )
             .map_err(|err| VectorStoreError::InsertionError(err.to_string()))?;
         }
-        self.client
+        let response = self
+            .client
             .bulk(opensearch::BulkParts::Index(&self.index_name))
             .body(vec![ops])
             .send()
             .await
+            .map_err(|err| VectorStoreError::InsertionError(err.to_string()))?
+            .error_for_status_code()
+            .map_err(|err| VectorStoreError::InsertionError(err.to_string()))?;
+
+        // A bulk request succeeds even if individual items fail, make sure
+        // everything was actually indexed.
+        let response = response
+            .json::<BulkResponse>()
+            .await
             .map_err(|err| VectorStoreError::InsertionError(err.to_string()))?;
+
+        let failures = response.failures();
+        if !failures.is_empty() {
+            let failures = failures
+                .into_iter()
+                .map(|(id, reason)| format!("{id} ({reason})"))
+                .collect::<Vec<_>>();
+            return Err(VectorStoreError::InsertionError(format!(
+                "{} of {} items failed: {}",
+                failures.len(),
+                data.len(),
+                failures.join(", ")
+            )));
+        }
+
         Ok(())
     }
 
@@ -186,21 +383,138 @@ impl VectorStore for OpenSearchStore {
             .await
             .map_err(|err| VectorStoreError::SearchError(err.to_string()))?;
 
-        let mut results = Vec::new();
-        for hit in response.hits.hits {
-            results.push((hit._id, hit.score))
-        }
+        // OpenSearch's _score depends on the index's space type & only matches
+        // cosine similarity for normalized vectors, otherwise score the stored
+        // vectors so results match the other stores.
+        let mut results = response
+            .hits
+            .hits
+            .into_iter()
+            .map(|hit| {
+                let score = self
+                    .knn
+                    .normalized
+                    .then(|| self.knn.similarity(hit.score))
+                    .flatten()
+                    .unwrap_or_else(|| cosine_score(vec, &hit.source.embedding));
+                (hit._id, score)
+            })
+            .collect::<Vec<_>>();
+        results.sort_by(|a, b| b.1.total_cmp(&a.1));
 
         Ok(results)
     }
+
+    async fn compact(&mut self) -> StoreResult<CompactResult> {
+        // Merge away segments w/ deleted docs. OpenSearch doesn't report how
+        // many docs were removed.
+        self.client
+            .indices()
+            .forcemerge(opensearch::indices::IndicesForcemergeParts::Index(&[
+                &self.index_name
+            ]))
+            .only_expunge_deletes(true)
+            .send()
+            .await
+            .map_err(|err| VectorStoreError::DeleteError(err.to_string()))?
+            .error_for_status_code()
+            .map_err(|err| VectorStoreError::DeleteError(err.to_string()))?;
+
+        Ok(CompactResult { reclaimed: None })
+    }
+
+    async fn health(&self) -> StoreResult<StoreHealth> {
+        let cluster = self
+            .client
+            .cluster()
+            .health(opensearch::cluster::ClusterHealthParts::Index(&[
+                &self.index_name
+            ]))
+            .send()
+            .await
+            .map_err(|err| VectorStoreError::ConnectionError(err.to_string()))?
+            .json::<ClusterHealth>()
+            .await
+            .map_err(|err| VectorStoreError::ConnectionError(err.to_string()))?;
+
+        let count = self
+            .client
+            .count(opensearch::CountParts::Index(&[&self.index_name]))
+            .send()
+            .await
+            .map_err(|err| VectorStoreError::ConnectionError(err.to_string()))?
+            .error_for_status_code()
+            .map_err(|err| VectorStoreError::ConnectionError(err.to_string()))?
+            .json::<CountResponse>()
+            .await
+            .map_err(|err| VectorStoreError::ConnectionError(err.to_string()))?;
+
+        let mapping = self
+            .client
+            .indices()
+            .get_mapping(opensearch::indices::IndicesGetMappingParts::Index(&[
+                &self.index_name
+            ]))
+            .send()
+            .await
+            .map_err(|err| VectorStoreError::ConnectionError(err.to_string()))?
+            .json::<Value>()
+            .await
+            .map_err(|err| VectorStoreError::ConnectionError(err.to_string()))?;
+
+        let status = match cluster.status.as_str() {
+            "green" => StoreStatus::Green,
+            "yellow" => StoreStatus::Yellow,
+            _ => StoreStatus::Red,
+        };
+
+        let mut health = StoreHealth {
+            status,
+            vectors: Some(count.count),
+            dimension: mapping_dimension(&mapping, &self.index_name),
+            ..Default::default()
+        };
+        health
+            .details
+            .insert("index".to_string(), self.index_name.clone());
+        health
+            .details
+            .insert("indexStatus".to_string(), cluster.status);
+        health.details.insert(
+            "unassignedShards".to_string(),
+            cluster.unassigned_shards.to_string(),
+        );
+
+        Ok(health)
+    }
+}
+
+/// Dimension of the `embedding` field from a get mapping response.
+fn mapping_dimension(mapping: &Value, index: &str) -> Option<usize> {
+    mapping
+        .pointer(&format!("/{index}/mappings/properties/embedding/dimension"))
+        .and_then(|dimension| dimension.as_u64())
+        .map(|dimension| dimension as usize)
 }
 
+/// Create the index unless it already exists, so reconnecting to an existing
+/// collection doesn't fail.
 pub async fn create_index(
     client: &OpenSearch,
     name: &str,
     embedding_dim: usize,
+    knn: &KnnMethod,
 ) -> anyhow::Result<()> {
-    client
+    let exists = client
+        .indices()
+        .exists(opensearch::indices::IndicesExistsParts::Index(&[name]))
+        .send()
+        .await?;
+    if exists.status_code() == StatusCode::OK {
+        return Ok(());
+    }
+
+    let response = client
         .indices()
         .create(opensearch::indices::IndicesCreateParts::Index(name))
         .body(serde_json::json!({
@@ -211,7 +525,8 @@ pub async fn create_index(
                 "properties": {
                     "embedding": {
                         "type": "knn_vector",
-                        "dimension": embedding_dim
+                        "dimension": embedding_dim,
+                        "method": knn.mapping()
                     }
                 }
             }
@@ -219,10 +534,35 @@ pub async fn create_index(
         .send()
         .await?;
 
-    Ok(())
+    if response.status_code().is_success() {
+        return Ok(());
+    }
+
+    // Something else may have created it since we checked.
+    let error = response.json::<Value>().await?;
+    if already_exists(&error) {
+        return Ok(());
+    }
+
+    anyhow::bail!("Unable to create index {name}: {error}")
+}
+
+fn already_exists(error: &Value) -> bool {
+    error.pointer("/error/type").and_then(|kind| kind.as_str())
+        == Some("resource_already_exists_exception")
 }
 
 /// Utility method to connect to
+/// Wait for OpenSearch to respond, retrying for up to `timeout`.
+pub async fn wait_until_up(url: &str, timeout: Duration) -> anyhow::Result<()> {
+    let client = connect(url, None)?;
+    retry_connect("OpenSearch", timeout, || async {
+        client.ping().send().await?.error_for_status_code()
+    })
+    .await?;
+    Ok(())
+}
+
 pub fn connect(url: &str, credentials: Option<Credentials>) -> anyhow::Result<OpenSearch> {
     let url = Url::parse(url)?;
 
@@ -243,8 +583,13 @@ pub fn connect(url: &str, credentials: Option<Credentials>) -> anyhow::Result<Op
 
 #[cfg(test)]
 mod test {
-    use super::OpenSearchConnectionConfig;
-    use crate::storage::{opensearch::OpenSearchStore, VectorData, VectorStore};
+    use super::{
+        already_exists, mapping_dimension, BulkResponse, KnnEngine, KnnMethod,
+        OpenSearchConnectionConfig,
+    };
+    use crate::storage::{
+        check_scores, opensearch::OpenSearchStore, score_test_data, VectorData, VectorStore,
+    };
     use opensearch::http::StatusCode;
     use serde_json::Value;
 
@@ -281,6 +626,44 @@ mod test {
         store.delete_index().await.unwrap();
     }
 
+    #[ignore]
+    #[tokio::test]
+    async fn test_initialize_existing() {
+        let config = || OpenSearchConnectionConfig {
+            index: "test-existing".to_string(),
+            embedding_dimension: 3,
+            ..Default::default()
+        };
+
+        let store = OpenSearchStore::new(OPENSEARCH_URL, config())
+            .await
+            .expect("Unable to create client");
+        // Reconnecting to the same index
+        OpenSearchStore::new(OPENSEARCH_URL, config())
+            .await
+            .expect("Unable to reconnect");
+
+        store.delete_index().await.unwrap();
+    }
+
+    #[test]
+    fn test_already_exists() {
+        let error = serde_json::json!({
+            "error": {
+                "type": "resource_already_exists_exception",
+                "reason": "index [test/abc] already exists"
+            },
+            "status": 400
+        });
+        assert!(already_exists(&error));
+
+        let error = serde_json::json!({
+            "error": { "type": "mapper_parsing_exception" },
+            "status": 400
+        });
+        assert!(!already_exists(&error));
+    }
+
     #[ignore]
     #[tokio::test]
     async fn test_delete() {
@@ -376,4 +759,177 @@ mod test {
         assert_eq!(results.len(), 2);
         store.delete_all().await.expect("Unable to delete index");
     }
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_scores() {
+        let config = OpenSearchConnectionConfig {
+            index: "test-scores".to_string(),
+            embedding_dimension: 3,
+            ..Default::default()
+        };
+        let mut store = super::OpenSearchStore::new(OPENSEARCH_URL, config)
+            .await
+            .expect("Unable to create client");
+
+        store.bulk_insert(&score_test_data("test")).await.unwrap();
+        store._wait_for_doc("score-2").await;
+        check_scores(&store).await;
+        store.delete_all().await.expect("Unable to delete index");
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_bulk_insert_failure() {
+        let index_name = "test-bulk-failure";
+        let config = OpenSearchConnectionConfig {
+            index: index_name.to_string(),
+            embedding_dimension: 3,
+            ..Default::default()
+        };
+
+        let mut store = OpenSearchStore::new(OPENSEARCH_URL, config)
+            .await
+            .expect("Unable to create client");
+
+        // Vector doesn't match the index's dimension
+        let res = store
+            .bulk_insert(&[
+                VectorData {
+                    _id: "test-good".into(),
+                    document_id: "test-good".into(),
+                    text: "".into(),
+                    segment_id: 0,
+                    vector: vec![1.5, 2.5, 3.5],
+                },
+                VectorData {
+                    _id: "test-bad".into(),
+                    document_id: "test-bad".into(),
+                    text: "".into(),
+                    segment_id: 0,
+                    vector: vec![1.5, 2.5],
+                },
+            ])
+            .await;
+
+        let err = res.expect_err("bulk insert should fail").to_string();
+        assert!(err.contains("test-bad"));
+        assert!(!err.contains("test-good"));
+        store.delete_index().await.unwrap();
+    }
+
+    #[test]
+    fn test_bulk_response_failures() {
+        let response: BulkResponse = serde_json::from_value(serde_json::json!({
+            "took": 3,
+            "errors": true,
+            "items": [
+                { "index": { "_index": "test", "_id": "test-good", "status": 201 } },
+                { "index": {
+                    "_index": "test",
+                    "_id": "test-bad",
+                    "status": 400,
+                    "error": {
+                        "type": "mapper_parsing_exception",
+                        "reason": "Vector dimension mismatch"
+                    }
+                } }
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            response.failures(),
+            vec![(
+                "test-bad".to_string(),
+                "400 - Vector dimension mismatch".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_knn_method_from_url() {
+        let url = url::Url::parse(OPENSEARCH_URL).unwrap();
+        assert_eq!(KnnMethod::from_url(&url).unwrap(), KnnMethod::default());
+
+        let url = url::Url::parse(&format!(
+            "{OPENSEARCH_URL}?engine=Lucene&ef_construction=256&m=32"
+        ))
+        .unwrap();
+        let method = KnnMethod::from_url(&url).unwrap();
+        assert_eq!(method.engine, KnnEngine::Lucene);
+        assert_eq!(method.space_type, "cosinesimil");
+        assert_eq!(method.ef_construction, 256);
+        assert_eq!(method.m, 32);
+        assert!(!method.normalized);
+
+        let url = url::Url::parse(&format!("{OPENSEARCH_URL}?normalized=true")).unwrap();
+        assert!(KnnMethod::from_url(&url).unwrap().normalized);
+
+        let url = url::Url::parse(&format!("{OPENSEARCH_URL}?engine=annoy")).unwrap();
+        assert!(KnnMethod::from_url(&url).is_err());
+    }
+
+    #[test]
+    fn test_knn_similarity() {
+        let knn = |engine, space_type: &str| KnnMethod {
+            engine,
+            space_type: space_type.to_string(),
+            ..Default::default()
+        };
+
+        // Scores OpenSearch gives unit vectors w/ these cosine similarities.
+        for cosine in [1.0f32, 0.5, 0.0, -0.5, -1.0] {
+            let expected = (1.0 + cosine) / 2.0;
+            let scores = [
+                (knn(KnnEngine::Nmslib, "cosinesimil"), 1.0 / (2.0 - cosine)),
+                (knn(KnnEngine::Faiss, "cosinesimil"), 1.0 / (2.0 - cosine)),
+                (knn(KnnEngine::Lucene, "cosinesimil"), (1.0 + cosine) / 2.0),
+                (
+                    knn(KnnEngine::Nmslib, "innerproduct"),
+                    if cosine >= 0.0 {
+                        cosine + 1.0
+                    } else {
+                        1.0 / (1.0 - cosine)
+                    },
+                ),
+                (
+                    knn(KnnEngine::Nmslib, "l2"),
+                    1.0 / (1.0 + (2.0 - 2.0 * cosine)),
+                ),
+            ];
+
+            for (knn, score) in scores {
+                let Some(similarity) = knn.similarity(score) else {
+                    // Lucene cosine scores bottom out at zero.
+                    assert_eq!(score, 0.0);
+                    continue;
+                };
+                assert!(
+                    (similarity - expected).abs() < 1e-5,
+                    "{} {}: {similarity} != {expected}",
+                    knn.engine,
+                    knn.space_type
+                );
+            }
+        }
+
+        assert_eq!(knn(KnnEngine::Nmslib, "linf").similarity(0.5), None);
+    }
+
+    #[test]
+    fn test_mapping_dimension() {
+        let mapping = serde_json::json!({
+            "memex": {
+                "mappings": {
+                    "properties": {
+                        "embedding": { "type": "knn_vector", "dimension": 384 },
+                        "text": { "type": "text" }
+                    }
+                }
+            }
+        });
+        assert_eq!(mapping_dimension(&mapping, "memex"), Some(384));
+        assert_eq!(mapping_dimension(&mapping, "other"), None);
+    }
 }
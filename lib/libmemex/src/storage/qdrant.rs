@@ -5,6 +5,9 @@ use qdrant_client::{
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
+use super::{retry_connect, DEFAULT_CONNECT_TIMEOUT};
+
+/// Waits for qdrant to come up, so call this once on startup & share the client.
 pub async fn connect_to_qdrant(host: &str, collection: &str) -> Arc<Mutex<QdrantClient>> {
     let config = QdrantClientConfig::from_url(host);
     let client = match qdrant_client::client::QdrantClient::new(Some(config)) {
@@ -12,6 +15,13 @@ pub async fn connect_to_qdrant(host: &str, collection: &str) -> Arc<Mutex<Qdrant
         Err(err) => panic!("Unable to connect to vectordb: {err}"),
     };
 
+    // Wait for qdrant to come up before checking on the collection
+    if let Err(err) =
+        retry_connect("qdrant", DEFAULT_CONNECT_TIMEOUT, || client.health_check()).await
+    {
+        panic!("Unable to connect to vectordb: {err}");
+    }
+
     if client
         .collection_info(collection.to_string())
         .await
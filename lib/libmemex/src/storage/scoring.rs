@@ -0,0 +1,81 @@
+use chrono::{DateTime, Utc};
+
+use crate::db::document;
+
+/// Reweights a search result's similarity score once the document it came
+/// from has been loaded, e.g. to favor recent documents. Results are sorted
+/// by the modified score.
+pub trait ScoreModifier: Send + Sync {
+    fn modify(&self, score: f32, document: &document::Model, now: DateTime<Utc>) -> f32;
+}
+
+/// Favor recently added documents. A document's age decays its share of the
+/// score by half every `half_life`, `weight` controls how much of the score
+/// is subject to decay (0.0 = none, 1.0 = all of it).
+#[derive(Clone, Debug)]
+pub struct RecencyBoost {
+    pub half_life: chrono::Duration,
+    pub weight: f32,
+}
+
+impl RecencyBoost {
+    pub fn new(half_life: chrono::Duration, weight: f32) -> Self {
+        Self {
+            half_life,
+            weight: weight.clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl ScoreModifier for RecencyBoost {
+    fn modify(&self, score: f32, document: &document::Model, now: DateTime<Utc>) -> f32 {
+        let half_life = self.half_life.num_seconds();
+        if half_life <= 0 {
+            return score;
+        }
+
+        // Documents from the "future" (clock skew) count as brand new.
+        let age = (now - document.created_at).num_seconds().max(0);
+        let decay = 0.5f32.powf(age as f32 / half_life as f32);
+        score * ((1.0 - self.weight) + self.weight * decay)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RecencyBoost, ScoreModifier};
+    use crate::db::document;
+
+    fn document_at(created_at: chrono::DateTime<chrono::Utc>) -> document::Model {
+        document::Model {
+            id: 1,
+            uuid: "doc".into(),
+            task_id: 1,
+            content: "some content".into(),
+            metadata: None,
+            created_at,
+            updated_at: created_at,
+        }
+    }
+
+    #[test]
+    fn test_recency_boost() {
+        let now = chrono::Utc::now();
+        let boost = RecencyBoost::new(chrono::Duration::days(7), 0.5);
+
+        let fresh = document_at(now);
+        let week_old = document_at(now - chrono::Duration::days(7));
+        let ancient = document_at(now - chrono::Duration::days(700));
+
+        assert_eq!(boost.modify(0.8, &fresh, now), 0.8);
+        assert!((boost.modify(0.8, &week_old, now) - 0.6).abs() < 1e-6);
+        // Never decays below the un-weighted share of the score
+        assert!((boost.modify(0.8, &ancient, now) - 0.4).abs() < 1e-6);
+
+        // A newer, slightly less similar document wins
+        assert!(boost.modify(0.7, &fresh, now) > boost.modify(0.8, &week_old, now));
+
+        let disabled = RecencyBoost::new(chrono::Duration::days(7), 0.0);
+        assert_eq!(disabled.modify(0.8, &ancient, now), 0.8);
+    }
+}
@@ -1,8 +1,15 @@
-use libmemex::db::create_connection_by_uri;
-use libmemex::db::queue::{self, check_for_jobs, Job, TaskType};
-use libmemex::llm::openai::OpenAIClient;
-use libmemex::storage::get_vector_storage;
+use libmemex::db::queue::{self, check_for_jobs_in, Job, JobListener, TaskType};
+use libmemex::db::{collection, create_connection_by_uri, document};
+use libmemex::llm::embedding::{
+    EmbedderRegistry, EmbeddingsModelType, ModelConfig, DEFAULT_MAX_RESIDENT_MODELS,
+};
+use libmemex::llm::openai::{OpenAIClient, OpenAIOptions};
+use libmemex::llm::LLMError;
+use libmemex::status::{set_worker_status, WorkerStatus};
+use libmemex::storage::{self, get_vector_storage_with_dimension};
+use rand::Rng;
 use sea_orm::{prelude::*, Set};
+use std::collections::HashMap;
 use std::future::Future;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
@@ -10,23 +17,127 @@ use tokio::{
     sync::{broadcast, mpsc},
     time::Duration,
 };
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
+pub mod reindex;
 mod tasks;
 
+// How often in-flight tasks are checked for cancellation.
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Default delay between checks of the job queue.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Longest the scheduler will wait between checks when the queue is empty.
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(2);
+// How often finished tasks past their retention are cleaned up.
+const QUEUE_CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
 #[derive(Debug, Clone)]
 pub enum AppShutdown {
     Now,
 }
 
+/// A job to run, along w/ the slot it was given by the scheduler.
 pub enum WorkerCommand {
-    GenerateEmbedding(Job),
-    LLMExtract(Job),
-    LLMSummarize(Job),
+    GenerateEmbedding(Job, TaskSlot),
+    LLMExtract(Job, TaskSlot),
+    LLMSummarize(Job, TaskSlot),
+    Reindex(Job, TaskSlot),
+}
+
+/// Why a task failed & whether it should be retried.
+#[derive(Debug)]
+pub struct TaskFailure {
+    pub error: anyhow::Error,
+    pub retry: bool,
+    /// Wait at least this long before retrying, e.g. when rate limited.
+    pub retry_after: Option<Duration>,
+}
+
+impl TaskFailure {
+    /// Retry the task only if the error is likely to be transient.
+    pub fn classify(error: anyhow::Error) -> Self {
+        let retry = tasks::is_retriable(&error);
+        let retry_after = match error.downcast_ref::<LLMError>() {
+            Some(LLMError::RateLimited { retry_after }) => *retry_after,
+            _ => None,
+        };
+        Self {
+            error,
+            retry,
+            retry_after,
+        }
+    }
+
+    /// Fail the task immediately.
+    pub fn fatal(error: anyhow::Error) -> Self {
+        Self {
+            error,
+            retry: false,
+            retry_after: None,
+        }
+    }
+}
+
+pub struct WorkerConfig {
+    pub db_uri: String,
+    /// Max number of tasks from a single collection that can run at once.
+    pub max_per_collection: Option<usize>,
+    /// Settings used when generating embeddings.
+    pub model_config: ModelConfig,
+    /// Shortest delay between checks of the job queue, defaults to `DEFAULT_POLL_INTERVAL`.
+    pub poll_interval: Option<Duration>,
+    /// Base URL & extra headers for the OpenAI API.
+    pub openai_options: OpenAIOptions,
+    /// Only run jobs from these collections, e.g. to give a collection its own
+    /// workers. Jobs from every collection are run when empty.
+    pub collections: Vec<String>,
+    /// Delete finished tasks once they're this old, they're kept forever when unset.
+    pub queue_retention: Option<Duration>,
+}
+
+/// Backs off how often the job queue is checked while it's empty, resetting as
+/// soon as a job shows up.
+pub struct PollBackoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl PollBackoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        let min = min.max(Duration::from_millis(1));
+        Self {
+            min,
+            max: max.max(min),
+            current: min,
+        }
+    }
+
+    /// Delay before the next check, based on whether the last one found a job.
+    pub fn next(&mut self, found_job: bool) -> Duration {
+        self.current = if found_job {
+            self.min
+        } else {
+            (self.current * 2).min(self.max)
+        };
+
+        self.current
+    }
+
+    /// Add up to 10% of random jitter so multiple workers don't poll in lockstep.
+    pub fn jittered(delay: Duration) -> Duration {
+        let jitter = rand::thread_rng().gen_range(0.0..=0.1);
+        delay.mul_f64(1.0 + jitter)
+    }
 }
 
 pub struct WorkerInstanceLimits {
     pub num_active: usize,
     pub max_active: usize,
+    /// Number of active tasks per collection.
+    pub active_by_collection: HashMap<String, usize>,
+    pub max_per_collection: Option<usize>,
 }
 
 impl Default for WorkerInstanceLimits {
@@ -34,6 +145,8 @@ impl Default for WorkerInstanceLimits {
         Self {
             num_active: 0,
             max_active: 5,
+            active_by_collection: HashMap::new(),
+            max_per_collection: None,
         }
     }
 }
@@ -42,20 +155,121 @@ impl WorkerInstanceLimits {
     pub fn can_work(&self) -> bool {
         self.num_active < self.max_active
     }
+
+    /// Collections that have hit their concurrency limit.
+    pub fn saturated_collections(&self) -> Vec<String> {
+        match self.max_per_collection {
+            Some(max) => self
+                .active_by_collection
+                .iter()
+                .filter(|(_, active)| **active >= max)
+                .map(|(collection, _)| collection.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn start_task(&mut self, collection: &str) {
+        self.num_active += 1;
+        *self
+            .active_by_collection
+            .entry(collection.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn finish_task(&mut self, collection: &str) {
+        self.num_active = self.num_active.saturating_sub(1);
+        if let Some(active) = self.active_by_collection.get_mut(collection) {
+            *active = active.saturating_sub(1);
+            if *active == 0 {
+                self.active_by_collection.remove(collection);
+            }
+        }
+    }
 }
 
 pub type WorkerLimitMutex = Arc<Mutex<WorkerInstanceLimits>>;
 
-pub async fn start(db_uri: String) {
-    let db = match create_connection_by_uri(&db_uri, false).await {
-        Ok(db) => db,
-        Err(err) => {
-            log::error!("Unable to connect to db: {err}");
-            return;
+/// A dispatched task's share of the worker's limits. Taken by the scheduler
+/// when a job is picked up & released when dropped, however the task ends
+/// (finished, cancelled, panicked, or never started).
+pub struct TaskSlot {
+    limits: WorkerLimitMutex,
+    collection: String,
+}
+
+impl TaskSlot {
+    pub fn acquire(limits: &WorkerLimitMutex, collection: &str) -> Self {
+        if let Ok(mut limits) = limits.lock() {
+            limits.start_task(collection);
         }
-    };
 
-    let limits = Arc::new(Mutex::new(WorkerInstanceLimits::default()));
+        Self {
+            limits: limits.clone(),
+            collection: collection.to_string(),
+        }
+    }
+}
+
+impl Drop for TaskSlot {
+    fn drop(&mut self) {
+        // Still release the slot if another task panicked while holding the lock.
+        let mut limits = match self.limits.lock() {
+            Ok(limits) => limits,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        limits.finish_task(&self.collection);
+    }
+}
+/// Cancellation tokens for in-flight tasks, keyed by task id.
+pub type TaskCancellations = Arc<Mutex<HashMap<i64, CancellationToken>>>;
+
+/// Run the worker until shutdown. Models are loaded before any jobs are
+/// picked up, failing to load them is returned as an error.
+pub async fn start(config: WorkerConfig) -> anyhow::Result<()> {
+    set_worker_status(WorkerStatus::Loading);
+    let db = create_connection_by_uri(&config.db_uri, false)
+        .await
+        .map_err(|err| {
+            set_worker_status(WorkerStatus::Failed);
+            anyhow::anyhow!("Unable to connect to db: {err}")
+        })?;
+    // Wait for the vector db here, connecting to it per task only tries once.
+    if let Ok(vector_uri) = std::env::var("VECTOR_CONNECTION") {
+        storage::wait_for_vector_storage(&vector_uri)
+            .await
+            .map_err(|err| {
+                set_worker_status(WorkerStatus::Failed);
+                anyhow::anyhow!("Unable to connect to vector db: {err}")
+            })?;
+    }
+
+    // Load the model up front so the first job doesn't pay for it.
+    let start = Instant::now();
+    log::info!("loading embedding model {}...", config.model_config.model());
+    let embedders = Arc::new(EmbedderRegistry::new(
+        config.model_config.clone(),
+        DEFAULT_MAX_RESIDENT_MODELS,
+    ));
+    let embedder = embedders.get(config.model_config.model());
+    if let Err(err) = embedder.encode_single("warm up".into()).await {
+        set_worker_status(WorkerStatus::Failed);
+        return Err(anyhow::anyhow!("Unable to load embedding model: {err}"));
+    }
+    log::info!(
+        "embedding model loaded in {}ms, ready for jobs",
+        start.elapsed().as_millis()
+    );
+    set_worker_status(WorkerStatus::Ready);
+
+    let limits = Arc::new(Mutex::new(WorkerInstanceLimits {
+        max_per_collection: config.max_per_collection,
+        ..Default::default()
+    }));
+    let cancellations: TaskCancellations = Default::default();
+    if !config.collections.is_empty() {
+        log::info!("only running jobs from {:?}", config.collections);
+    }
 
     // Create channels for scheduler / crawlers
     let (worker_cmd_tx, worker_cmd_rx) = mpsc::channel::<WorkerCommand>(5);
@@ -67,14 +281,28 @@ pub async fn start(db_uri: String) {
     let scheduler = tokio::spawn(run_scheduler(
         db.clone(),
         limits.clone(),
+        cancellations.clone(),
+        config.poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL),
+        config.collections,
         worker_cmd_tx,
         shutdown_tx.subscribe(),
     ));
 
+    let cleanup = config.queue_retention.map(|retention| {
+        tokio::spawn(run_queue_cleanup(
+            db.clone(),
+            retention,
+            shutdown_tx.subscribe(),
+        ))
+    });
+
     // Work handlers
     let workers = tokio::spawn(run_workers(
         db,
-        limits,
+        cancellations,
+        config.model_config,
+        embedders,
+        config.openai_options,
         worker_cmd_rx,
         shutdown_tx.subscribe(),
     ));
@@ -95,54 +323,108 @@ pub async fn start(db_uri: String) {
     }
 
     let _ = tokio::join!(scheduler, workers);
+    if let Some(cleanup) = cleanup {
+        let _ = cleanup.await;
+    }
+    storage::close_all().await;
+    Ok(())
 }
 
 // Simple wrapper to return early if we're already at our processing limit.
 async fn check_for_jobs_with_limit(
     db: &DatabaseConnection,
     limits: WorkerLimitMutex,
+    collections: &[String],
 ) -> Result<Option<Job>, DbErr> {
-    let can_work = if let Ok(limits) = limits.lock() {
-        limits.can_work()
+    let excluded = if let Ok(limits) = limits.lock() {
+        if limits.can_work() {
+            Some(limits.saturated_collections())
+        } else {
+            None
+        }
     } else {
-        false
+        None
     };
 
-    if can_work {
-        return check_for_jobs(db).await;
+    if let Some(excluded) = excluded {
+        return check_for_jobs_in(db, collections, &excluded).await;
     }
 
     Ok(None)
 }
 
+// Signal any in-flight tasks that have been cancelled through the API.
+async fn check_for_cancellations(db: &DatabaseConnection, cancellations: &TaskCancellations) {
+    let active: Vec<i64> = match cancellations.lock() {
+        Ok(tokens) => tokens.keys().copied().collect(),
+        Err(_) => return,
+    };
+
+    match queue::cancelled_tasks(db, &active).await {
+        Ok(cancelled) => {
+            if let Ok(tokens) = cancellations.lock() {
+                for task_id in cancelled {
+                    if let Some(token) = tokens.get(&task_id) {
+                        log::info!("[job={}] cancelling task", task_id);
+                        token.cancel();
+                    }
+                }
+            }
+        }
+        Err(err) => log::error!("Unable to check for cancelled tasks: {err}"),
+    }
+}
+
+// Span attached to everything logged while running a task, linking it back to
+// the API request that created it.
+fn job_span(task: &queue::Model) -> tracing::Span {
+    tracing::info_span!(
+        "job",
+        id = task.id,
+        collection = %task.collection,
+        task_type = %task.task_type,
+        trace_id = task.trace_id.as_deref().unwrap_or("-")
+    )
+}
+
 pub async fn run_scheduler(
     db: DatabaseConnection,
     limits: WorkerLimitMutex,
+    cancellations: TaskCancellations,
+    poll_interval: Duration,
+    collections: Vec<String>,
     queue: mpsc::Sender<WorkerCommand>,
     mut shutdown_rx: broadcast::Receiver<AppShutdown>,
 ) {
-    let mut queue_check_interval = tokio::time::interval(Duration::from_millis(100));
-    // first tick always completes immediately.
-    queue_check_interval.tick().await;
+    let mut backoff = PollBackoff::new(poll_interval, MAX_POLL_INTERVAL);
+    let mut last_cancel_check = Instant::now();
+    // Postgres can tell us about new jobs right away, polling is still used as
+    // a fallback.
+    let mut listener = match JobListener::connect(&db).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::warn!("{err}, falling back to polling");
+            None
+        }
+    };
     loop {
         tokio::select! {
-            job = check_for_jobs_with_limit(&db, limits.clone()) => {
+            job = check_for_jobs_with_limit(&db, limits.clone(), &collections) => {
+                let found_job = matches!(job, Ok(Some(_)));
                 match job {
                     Ok(Some(job)) => {
                         log::debug!("found task: {:?}", job);
-                        // Update limits
-                        if let Ok(mut limits) = limits.lock() {
-                            limits.num_active += 1;
-                        }
+                        let slot = TaskSlot::acquire(&limits, &job.collection);
 
                         // Map task type to the relevant WorkerCommand
                         let cmd = match job.task_type {
                             TaskType::Ingest => WorkerCommand::GenerateEmbedding,
                             TaskType::Extract => WorkerCommand::LLMExtract,
                             TaskType::Summarize => WorkerCommand::LLMSummarize,
+                            TaskType::Reindex => WorkerCommand::Reindex,
                         };
 
-                        if let Err(err) = queue.send(cmd(job)).await {
+                        if let Err(err) = queue.send(cmd(job, slot)).await {
                             log::error!("Worker channel closed: {err}");
                             return;
                         }
@@ -154,8 +436,27 @@ pub async fn run_scheduler(
                     }
                 }
 
+                if last_cancel_check.elapsed() >= CANCEL_CHECK_INTERVAL {
+                    check_for_cancellations(&db, &cancellations).await;
+                    last_cancel_check = Instant::now();
+                }
+
                 // wait a little before grabbing the next job
-                queue_check_interval.tick().await;
+                let delay = PollBackoff::jittered(backoff.next(found_job));
+                match listener.as_mut() {
+                    Some(active) => {
+                        tokio::select! {
+                            _ = tokio::time::sleep(delay) => {}
+                            res = active.wait() => {
+                                if let Err(err) = res {
+                                    log::warn!("{err}, falling back to polling");
+                                    listener = None;
+                                }
+                            }
+                        }
+                    }
+                    None => tokio::time::sleep(delay).await,
+                }
             }
             _ = shutdown_rx.recv() => {
                 log::info!("🛑 Shutting down scheduler");
@@ -165,18 +466,55 @@ pub async fn run_scheduler(
     }
 }
 
+/// Periodically delete finished tasks older than `retention` so the queue
+/// doesn't grow forever, compacting the database afterwards.
+pub async fn run_queue_cleanup(
+    db: DatabaseConnection,
+    retention: Duration,
+    mut shutdown_rx: broadcast::Receiver<AppShutdown>,
+) {
+    let mut interval = tokio::time::interval(QUEUE_CLEANUP_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                match queue::delete_finished(&db, retention).await {
+                    Ok(0) => {}
+                    Ok(deleted) => {
+                        log::info!("deleted {deleted} finished tasks older than {retention:?}");
+                        if let Err(err) = libmemex::db::vacuum(&db).await {
+                            log::error!("Unable to vacuum db: {err}");
+                        }
+                    }
+                    Err(err) => log::error!("Unable to clean up finished tasks: {err}"),
+                }
+            }
+            _ = shutdown_rx.recv() => {
+                return;
+            }
+        }
+    }
+}
+
 pub async fn run_workers(
     db: DatabaseConnection,
-    limits: WorkerLimitMutex,
+    cancellations: TaskCancellations,
+    model_config: ModelConfig,
+    embedders: Arc<EmbedderRegistry>,
+    openai_options: OpenAIOptions,
     mut task_queue: mpsc::Receiver<WorkerCommand>,
     mut shutdown_rx: broadcast::Receiver<AppShutdown>,
 ) {
+    // Shared across tasks so they all back off when OpenAI is having issues.
+    let openai_client = std::env::var("OPENAI_API_KEY")
+        .ok()
+        .map(|key| OpenAIClient::new(&key, &openai_options));
+
     loop {
         tokio::select! {
             cmd = task_queue.recv() => {
                 if let Some(cmd) = cmd {
                     match cmd {
-                        WorkerCommand::GenerateEmbedding(job) => {
+                        WorkerCommand::GenerateEmbedding(job, slot) => {
                             // Get payload
                             let task = match queue::Entity::find_by_id(job.id).one(&db).await {
                                 Ok(Some(model)) => model,
@@ -184,29 +522,114 @@ pub async fn run_workers(
                             };
 
                             let db = db.clone();
+                            let openai = openai_client.clone();
+                            let embedders = embedders.clone();
+                            let model_config = model_config.clone();
 
-                            tokio::spawn(run_task(task.id, db.clone(), limits.clone(), async move {
+                            let span = job_span(&task);
+                            tokio::spawn(run_task(task.id, slot, db.clone(), cancellations.clone(), async move {
                                 let vector_uri = std::env::var("VECTOR_CONNECTION").expect("VECTOR_CONNECTION env var not set");
-                                let client = match get_vector_storage(&vector_uri, &task.collection).await {
-                                    Ok(client) => client,
-                                    Err(err) => {
-                                        log::error!("Unable to connect to vector db: {err}");
-                                        return;
+                                let index = collection::vector_index(&db, &task.collection)
+                                    .await
+                                    .map_err(|err| TaskFailure::classify(err.into()))?;
+                                let collection_config = collection::model_config(&db, &task.collection, model_config.clone())
+                                    .await
+                                    .map_err(|err| TaskFailure::classify(err.into()))?;
+
+                                let client = get_vector_storage_with_dimension(&vector_uri, &index, collection_config.model().dimension())
+                                    .await
+                                    .map_err(|err| TaskFailure::classify(anyhow::anyhow!("Unable to connect to vector db: {err}")))?;
+
+                                // Models are shared, even w/ a collection's own segmentation settings.
+                                let embedder = embedders.get_for(&collection_config);
+
+                                let (document, deduped, stored) = tasks::process_embeddings(db.clone(), client, &task, &embedder)
+                                    .await
+                                    .map_err(TaskFailure::classify)?;
+
+                                let mut output = serde_json::Map::new();
+                                if task.payload.dedup_threshold.is_some() {
+                                    output.insert("deduped".into(), deduped.into());
+                                }
+
+                                // Optional follow-up, the document is searchable even if this fails.
+                                if let Some(schema) = &task.payload.metadata_schema {
+                                    if let Some(openai) = openai {
+                                        let options = task.payload.llm_options.clone().unwrap_or_default();
+                                        match tasks::extract_metadata(&db, &openai, document.clone(), &task.payload.content, schema, &options).await {
+                                            Ok(metadata) => {
+                                                output.insert("metadata".into(), metadata);
+                                            }
+                                            Err(err) => log::error!("[job={}] Unable to extract metadata: {err}", task.id),
+                                        }
+                                    } else {
+                                        log::warn!("[job={}] OpenAI API key not set, skipping metadata extraction", task.id);
                                     }
-                                };
+                                }
+
+                                let task_id = task.id;
+                                let collection = task.collection.clone();
+                                if !output.is_empty() {
+                                    let mut update: queue::ActiveModel = task.into();
+                                    update.task_output = Set(Some(output.into()));
+                                    let _ = update.save(&db).await;
+                                }
+
+                                // Nothing new was added, every segment was already stored.
+                                if stored == 0 && deduped > 0 {
+                                    log::info!("[job={task_id}] every segment was a duplicate, skipping");
+                                    queue::mark_skipped(&db, task_id)
+                                        .await
+                                        .map_err(|err| TaskFailure::classify(err.into()))?;
+                                }
 
-                                if let Err(err) = tasks::process_embeddings(db, client, &task).await {
-                                    log::error!("[job={}] Unable to process embeddings: {err}", task.id);
+                                // Last, since the task can't be retried once its content is dropped.
+                                let stores_content = collection::stores_content(&db, &collection)
+                                    .await
+                                    .map_err(|err| TaskFailure::classify(err.into()))?;
+                                if !stores_content {
+                                    document::clear_content(&db, document)
+                                        .await
+                                        .map_err(|err| TaskFailure::classify(err.into()))?;
                                 }
-                            }));
+
+                                Ok(())
+                            }).instrument(span));
+                        }
+                        WorkerCommand::Reindex(job, slot) => {
+                            let task = match queue::Entity::find_by_id(job.id).one(&db).await {
+                                Ok(Some(model)) => model,
+                                _ => continue
+                            };
+
+                            let db = db.clone();
+                            let model_config = model_config.clone();
+                            let span = job_span(&task);
+                            tokio::spawn(run_task(task.id, slot, db.clone(), cancellations.clone(), async move {
+                                let model = task.payload.model
+                                    .as_deref()
+                                    .and_then(|model| model.parse::<EmbeddingsModelType>().ok())
+                                    .ok_or_else(|| TaskFailure::fatal(anyhow::anyhow!("Invalid embedding model: {:?}", task.payload.model)))?;
+
+                                // Keep the collection's segmentation settings.
+                                let model_config = collection::model_config(&db, &task.collection, model_config)
+                                    .await
+                                    .map_err(|err| TaskFailure::classify(err.into()))?
+                                    .for_model(model);
+
+                                let vector_uri = std::env::var("VECTOR_CONNECTION").expect("VECTOR_CONNECTION env var not set");
+                                reindex::reindex_task(&db, &vector_uri, &task, &model_config)
+                                    .await
+                                    .map_err(TaskFailure::classify)
+                            }).instrument(span));
                         }
-                        WorkerCommand::LLMExtract(job) => {
+                        WorkerCommand::LLMExtract(job, _slot) => {
                             let _task = match queue::Entity::find_by_id(job.id).one(&db).await {
                                 Ok(Some(model)) => model,
                                 _ => continue
                             };
                         }
-                        WorkerCommand::LLMSummarize(job) => {
+                        WorkerCommand::LLMSummarize(job, slot) => {
                             // Get payload
                             let task = match queue::Entity::find_by_id(job.id).one(&db).await {
                                 Ok(Some(model)) => model,
@@ -215,24 +638,17 @@ pub async fn run_workers(
 
                             {
                                 let db = db.clone();
-                                let content = task.payload.content.clone();
-                                tokio::spawn(run_task(task.id, db.clone(), limits.clone(), async move {
-                                    let client = OpenAIClient::new(
-                                        &std::env::var("OPENAI_API_KEY").expect("OpenAI API key not set")
-                                    );
-
-                                    match tasks::generate_summary(&client, &content).await {
-                                        Ok(summary) => {
-                                            let value = serde_json::json!({ "bullets": summary });
-                                            let mut update: queue::ActiveModel = task.into();
-                                            update.task_output = Set(Some(value));
-                                            let _ = update.save(&db).await;
-                                        },
-                                        Err(err) => {
-                                            log::error!("[job={}] Unable to generate summary: {err}", task.id);
-                                        }
-                                    }
-                                }));
+                                let client = openai_client.clone();
+                                let span = job_span(&task);
+                                tokio::spawn(run_task(task.id, slot, db.clone(), cancellations.clone(), async move {
+                                    let client = client
+                                        .ok_or_else(|| TaskFailure::fatal(anyhow::anyhow!("OpenAI API key not set")))?;
+
+                                    tasks::generate_summary(&db, &client, &task)
+                                        .await
+                                        .map_err(TaskFailure::classify)?;
+                                    Ok(())
+                                }).instrument(span));
                             }
                         }
                     }
@@ -248,26 +664,139 @@ pub async fn run_workers(
 
 pub async fn run_task<T>(
     task_id: i64,
+    slot: TaskSlot,
     db: DatabaseConnection,
-    limits: WorkerLimitMutex,
+    cancellations: TaskCancellations,
     future: T,
-) -> <T as Future>::Output
+) -> Option<Result<(), TaskFailure>>
 where
-    T: Future + Send + 'static,
-    T::Output: Send + 'static,
+    T: Future<Output = Result<(), TaskFailure>> + Send + 'static,
 {
     let start = Instant::now();
     log::info!("[job={}] spawning task", task_id);
-    let res = future.await;
-    log::info!(
-        "[job={}] job finished in {}ms",
-        task_id,
-        start.elapsed().as_millis()
-    );
-    let _ = queue::mark_done(&db, task_id).await;
-    if let Ok(mut limits) = limits.lock() {
-        limits.num_active -= 1;
+
+    let token = CancellationToken::new();
+    if let Ok(mut tokens) = cancellations.lock() {
+        tokens.insert(task_id, token.clone());
+    }
+
+    let res = tokio::select! {
+        res = future => Some(res),
+        _ = token.cancelled() => None,
+    };
+
+    if let Ok(mut tokens) = cancellations.lock() {
+        tokens.remove(&task_id);
+    }
+
+    match &res {
+        Some(Ok(())) => {
+            log::info!(
+                "[job={}] job finished in {}ms",
+                task_id,
+                start.elapsed().as_millis()
+            );
+            let _ = queue::mark_done(&db, task_id).await;
+        }
+        Some(Err(failure)) => {
+            log::error!(
+                "[job={}] job failed after {}ms (retry: {}): {}",
+                task_id,
+                start.elapsed().as_millis(),
+                failure.retry,
+                failure.error
+            );
+            let error = queue::TaskError {
+                error_type: if failure.retry { "Retriable" } else { "Fatal" }.to_string(),
+                msg: failure.error.to_string(),
+            };
+            let _ = queue::mark_failed(
+                &db,
+                task_id,
+                failure.retry,
+                failure.retry_after,
+                Some(error),
+            )
+            .await;
+        }
+        None => {
+            log::info!(
+                "[job={}] job cancelled after {}ms",
+                task_id,
+                start.elapsed().as_millis()
+            );
+        }
     }
 
+    drop(slot);
     res
 }
+
+#[cfg(test)]
+mod test {
+    use super::{PollBackoff, TaskSlot, WorkerInstanceLimits, WorkerLimitMutex};
+    use std::time::Duration;
+
+    #[test]
+    fn test_poll_backoff() {
+        let min = Duration::from_millis(100);
+        let max = Duration::from_millis(1000);
+        let mut backoff = PollBackoff::new(min, max);
+
+        // Grows on empty polls, up to the cap
+        assert_eq!(backoff.next(false), Duration::from_millis(200));
+        assert_eq!(backoff.next(false), Duration::from_millis(400));
+        assert_eq!(backoff.next(false), Duration::from_millis(800));
+        assert_eq!(backoff.next(false), max);
+        assert_eq!(backoff.next(false), max);
+
+        // Resets as soon as a job is found
+        assert_eq!(backoff.next(true), min);
+        assert_eq!(backoff.next(false), Duration::from_millis(200));
+
+        let jittered = PollBackoff::jittered(min);
+        assert!(jittered >= min && jittered <= min.mul_f64(1.1));
+    }
+
+    #[tokio::test]
+    async fn test_task_slots_released() {
+        let limits: WorkerLimitMutex = Default::default();
+
+        let mut handles = Vec::new();
+        for idx in 0..10 {
+            let slot = TaskSlot::acquire(&limits, if idx % 2 == 0 { "even" } else { "odd" });
+            handles.push(tokio::spawn(async move {
+                let _slot = slot;
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                if idx % 3 == 0 {
+                    panic!("task {idx} failed");
+                }
+            }));
+        }
+
+        {
+            let limits = limits.lock().unwrap();
+            assert_eq!(limits.num_active, 10);
+            assert!(!limits.can_work());
+        }
+
+        let mut panicked = 0;
+        for handle in handles {
+            if handle.await.is_err() {
+                panicked += 1;
+            }
+        }
+        assert_eq!(panicked, 4);
+
+        // Slots dropped before a task starts are released too
+        drop(TaskSlot::acquire(&limits, "even"));
+
+        let limits = limits.lock().unwrap();
+        assert_eq!(limits.num_active, 0);
+        assert!(limits.active_by_collection.is_empty());
+        assert_eq!(
+            limits.max_active,
+            WorkerInstanceLimits::default().max_active
+        );
+    }
+}
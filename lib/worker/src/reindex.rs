@@ -0,0 +1,292 @@
+use libmemex::db::embedding::{mark_document_unindexed, persist_embeddings};
+use libmemex::db::{collection, create_connection_by_uri, document, queue};
+use libmemex::llm::embedding::{segment_text, EmbeddingResult, ModelConfig, SentenceEmbedder};
+use libmemex::storage::{
+    check_dimension, get_vector_storage, get_vector_storage_with_dimension, supports_index_swap,
+    wait_for_vector_storage, VectorStorage,
+};
+use sea_orm::{prelude::*, QueryOrder, QuerySelect, Select, Set};
+
+// Number of documents to pull from the db at a time.
+const PAGE_SIZE: u64 = 100;
+// `memex reindex` isn't run as a task, its index is named as if it were task 0.
+const CLI_TASK_ID: i64 = 0;
+
+pub struct ReindexConfig {
+    pub db_uri: String,
+    pub vector_uri: String,
+    /// Collection to read documents from.
+    pub collection: String,
+    /// Collection to write the new embeddings to. If not set, the collection
+    /// is reindexed in place.
+    pub target: Option<String>,
+    /// Embedding model used to generate the new vectors.
+    pub model_config: ModelConfig,
+    /// Skip documents up to & including this id, used to resume an interrupted run.
+    pub resume_from: Option<i64>,
+}
+
+/// Re-embed every document in a collection w/ a (potentially) different model.
+pub async fn reindex_collection(mut config: ReindexConfig) -> anyhow::Result<()> {
+    // Fail early if the model can't be used to segment documents.
+    segment_text(&config.model_config, "")?;
+
+    let db = create_connection_by_uri(&config.db_uri, false).await?;
+    wait_for_vector_storage(&config.vector_uri).await?;
+    let target = config
+        .target
+        .clone()
+        .unwrap_or_else(|| config.collection.clone());
+    let in_place = target == config.collection;
+    if !collection::is_usable_name(&db, &target).await? {
+        anyhow::bail!(
+            "Invalid target collection name: {target}, must be 1-{} lowercase alphanumeric, '-' or '_' characters",
+            collection::MAX_NAME_LEN
+        );
+    }
+    // A target configured to normalize (or not) overrides the server default.
+    if let Some(normalize) = collection::get(&db, &target)
+        .await?
+        .and_then(|settings| settings.normalize)
+    {
+        config.model_config = config.model_config.normalized(normalize);
+    }
+    let previous = collection::vector_index(&db, &target).await?;
+    let dimension = config.model_config.model().dimension();
+    check_dimension(&config.vector_uri, dimension).await?;
+
+    // Reindexing in place writes to a fresh index & swaps to it once done, so
+    // the collection keeps its old vectors if something fails partway.
+    // pgvector can only replace them one document at a time.
+    let index = if in_place && supports_index_swap(&config.vector_uri) {
+        collection::reindex_index(&target, CLI_TASK_ID)
+    } else {
+        previous.clone()
+    };
+
+    if index != previous && config.resume_from.is_none() {
+        // Clear out anything left behind by a previous run.
+        get_vector_storage_with_dimension(&config.vector_uri, &index, dimension)
+            .await?
+            .delete_collection()
+            .await?;
+    }
+    let client = get_vector_storage_with_dimension(&config.vector_uri, &index, dimension).await?;
+
+    let documents = document::Entity::find()
+        .inner_join(queue::Entity)
+        .filter(queue::Column::Collection.eq(config.collection.clone()));
+
+    let mut last_id = config.resume_from.unwrap_or_default();
+    let total = documents.clone().count(&db).await?;
+    let mut processed = documents
+        .clone()
+        .filter(document::Column::Id.lte(last_id))
+        .count(&db)
+        .await?;
+
+    log::info!(
+        "[reindex] {} -> {} w/ {}: {} documents",
+        config.collection,
+        target,
+        config.model_config.model(),
+        total - processed
+    );
+
+    let (_handle, embedder) = SentenceEmbedder::spawn(&config.model_config);
+    loop {
+        let page = documents
+            .clone()
+            .select_also(queue::Entity)
+            .filter(document::Column::Id.gt(last_id))
+            .order_by_asc(document::Column::Id)
+            .limit(PAGE_SIZE)
+            .all(&db)
+            .await?;
+
+        if page.is_empty() {
+            break;
+        }
+
+        // Embed the whole page before touching any stored vectors.
+        let mut embedded = Vec::with_capacity(page.len());
+        for (doc, task) in page {
+            let embeddings = embed_document(&embedder, &doc, task.as_ref()).await?;
+            embedded.push((doc, task, embeddings));
+        }
+
+        let count = embedded.len() as u64;
+        for (doc, task, embeddings) in embedded {
+            if !in_place {
+                let copy = copy_document(&db, &doc, task.as_ref(), &target).await?;
+                persist_embeddings(&db, &client, &copy, &embeddings).await?;
+            } else {
+                if index != previous {
+                    // Segments are flagged as indexed in the old index.
+                    mark_document_unindexed(&db, &doc.uuid).await?;
+                }
+                persist_embeddings(&db, &client, &doc, &embeddings).await?;
+            }
+            last_id = doc.id;
+        }
+
+        processed += count;
+        log::info!("[reindex] {processed}/{total} documents (last id: {last_id})");
+    }
+
+    // Searches need to embed queries w/ the new model.
+    collection::swap_index(&db, &target, &index, config.model_config.model()).await?;
+    if index != previous {
+        get_vector_storage(&config.vector_uri, &previous)
+            .await?
+            .delete_collection()
+            .await?;
+    }
+
+    log::info!("[reindex] finished reindexing {processed} documents into <{target}>");
+    Ok(())
+}
+
+/// Re-embed a collection as a background task. Embeddings are written to a
+/// fresh vector index while the old one keeps serving searches, then the
+/// collection is switched over to the new index & model at once.
+pub async fn reindex_task(
+    db: &DatabaseConnection,
+    vector_uri: &str,
+    task: &queue::Model,
+    model_config: &ModelConfig,
+) -> anyhow::Result<()> {
+    let name = &task.collection;
+    let model = model_config.model();
+    let dimension = model.dimension();
+    // Rewriting vectors in place isn't atomic, searches would see a mix of
+    // both models until it's done.
+    if !supports_index_swap(vector_uri) {
+        return Err(anyhow::anyhow!(
+            "Reindexing in the background isn't supported w/ {vector_uri}, use `memex reindex` instead"
+        ));
+    }
+
+    let previous = collection::vector_index(db, name).await?;
+    let index = collection::reindex_index(name, task.id);
+
+    // Clear out anything left behind by a previous attempt.
+    get_vector_storage_with_dimension(vector_uri, &index, dimension)
+        .await?
+        .delete_collection()
+        .await?;
+    let client = get_vector_storage_with_dimension(vector_uri, &index, dimension).await?;
+
+    let documents = document::Entity::find()
+        .inner_join(queue::Entity)
+        .filter(queue::Column::Collection.eq(name.clone()));
+    let total = documents.clone().count(db).await?;
+    log::info!("[reindex] {name} -> {index} w/ {model}: {total} documents");
+
+    let (_handle, embedder) = SentenceEmbedder::spawn(model_config);
+    let mut processed = 0;
+    let mut last_id = 0;
+    loop {
+        let count = embed_page(db, &client, &embedder, &documents, &mut last_id).await?;
+        if count == 0 {
+            break;
+        }
+
+        processed += count;
+        let mut update: queue::ActiveModel = task.clone().into();
+        update.task_output = Set(Some(serde_json::json!({
+            "processed": processed,
+            "total": total.max(processed),
+        })));
+        update.update(db).await?;
+    }
+
+    collection::swap_index(db, name, &index, model).await?;
+    // Pick up anything ingested into the old index while swapping.
+    while embed_page(db, &client, &embedder, &documents, &mut last_id).await? > 0 {}
+
+    get_vector_storage(vector_uri, &previous)
+        .await?
+        .delete_collection()
+        .await?;
+
+    log::info!("[reindex] finished reindexing {processed} documents into <{index}>");
+    Ok(())
+}
+
+/// Embed the next page of documents after `last_id`, returning how many were embedded.
+async fn embed_page(
+    db: &DatabaseConnection,
+    client: &VectorStorage,
+    embedder: &SentenceEmbedder,
+    documents: &Select<document::Entity>,
+    last_id: &mut i64,
+) -> anyhow::Result<u64> {
+    let page = documents
+        .clone()
+        .select_also(queue::Entity)
+        .filter(document::Column::Id.gt(*last_id))
+        .order_by_asc(document::Column::Id)
+        .limit(PAGE_SIZE)
+        .all(db)
+        .await?;
+
+    let count = page.len() as u64;
+    for (doc, task) in page {
+        let embeddings = embed_document(embedder, &doc, task.as_ref()).await?;
+        // Segments may already be indexed in the old index, always write them
+        // to the new one.
+        mark_document_unindexed(db, &doc.uuid).await?;
+        persist_embeddings(db, client, &doc, &embeddings).await?;
+        *last_id = doc.id;
+    }
+
+    Ok(count)
+}
+
+/// Re-embed a document, keeping the caller's chunks if it was ingested
+/// pre-chunked.
+async fn embed_document(
+    embedder: &SentenceEmbedder,
+    doc: &document::Model,
+    task: Option<&queue::Model>,
+) -> anyhow::Result<Vec<EmbeddingResult>> {
+    match task.and_then(|task| task.payload.segments.clone()) {
+        Some(segments) => Ok(embedder.encode_chunks(segments).await?),
+        None => Ok(embedder.encode_document(full_content(doc)?).await?),
+    }
+}
+
+/// Documents are re-segmented from their full content, which collections can
+/// opt out of keeping.
+fn full_content(doc: &document::Model) -> anyhow::Result<String> {
+    match doc.has_content() {
+        true => Ok(doc.content.clone()),
+        false => Err(anyhow::anyhow!(
+            "Document {} has no stored content to reindex from",
+            doc.uuid
+        )),
+    }
+}
+
+/// Create a completed task & document in the target collection w/ the same content.
+async fn copy_document(
+    db: &DatabaseConnection,
+    doc: &document::Model,
+    source: Option<&queue::Model>,
+    collection: &str,
+) -> anyhow::Result<document::Model> {
+    let mut task = queue::ActiveModel::new();
+    task.collection = Set(collection.to_string());
+    task.task_type = Set(queue::TaskType::Ingest);
+    task.status = Set(queue::JobStatus::Completed);
+    task.payload = Set(queue::TaskPayload {
+        content: doc.content.clone(),
+        metadata: doc.metadata.clone(),
+        segments: source.and_then(|task| task.payload.segments.clone()),
+        ..Default::default()
+    });
+    let task = task.insert(db).await?;
+
+    Ok(document::upsert_from_task(db, &task).await?)
+}
@@ -1,89 +1,299 @@
-use libmemex::db::{document, embedding, queue};
-use libmemex::llm::embedding::{ModelConfig, SentenceEmbedder};
+use jsonschema::JSONSchema;
+use libmemex::db::{document, embedding, embedding::persist_embeddings, queue};
+use libmemex::llm::embedding::{
+    cosine_similarity, EmbeddingError, EmbeddingResult, SentenceEmbedder,
+};
 use libmemex::llm::openai::OpenAIClient;
-use libmemex::llm::{prompter, LLM};
-use libmemex::storage::{VectorData, VectorStorage};
-use libmemex::NAMESPACE;
-use sea_orm::{prelude::*, Set, TransactionTrait};
+use libmemex::llm::{
+    complete_within_context, prompter, repair_json, shrink_text, ChatCompletionOptions, LLMError,
+    LLM, MAX_CONTEXT_RETRIES,
+};
+use libmemex::storage::{similarity_from_distance, VectorStorage};
+use sea_orm::{prelude::*, QuerySelect, Set};
+use serde_json::Value;
+use std::collections::VecDeque;
 
+const METADATA_REQUEST: &str =
+    "Extract the metadata (e.g. title, author, date, summary) for this document.";
+
+/// Whether a task that failed w/ `err` is worth retrying. Bad input (invalid
+/// content, requests rejected by the LLM, etc.) will fail the same way again.
+pub fn is_retriable(err: &anyhow::Error) -> bool {
+    if let Some(err) = err.downcast_ref::<LLMError>() {
+        err.is_retriable()
+    } else if let Some(err) = err.downcast_ref::<EmbeddingError>() {
+        err.is_retriable()
+    } else {
+        !err.is::<serde_json::Error>()
+    }
+}
+
+/// Embed & store the document for an ingest task. Returns the document, the
+/// number of segments skipped as duplicates & the number stored.
 pub async fn process_embeddings(
     db: DatabaseConnection,
     client: VectorStorage,
     task: &queue::Model,
-) -> anyhow::Result<()> {
+    embedder: &SentenceEmbedder,
+) -> anyhow::Result<(document::Model, usize, usize)> {
     let start = std::time::Instant::now();
-    let model_config = ModelConfig::default();
 
-    let (_handle, embedder) = SentenceEmbedder::spawn(&model_config);
     log::info!("[job={}] generating embeddings", task.id);
-    let embeddings = embedder.encode(task.payload.content.clone()).await?;
+    let mut embeddings = match &task.payload.segments {
+        Some(segments) => embedder.encode_chunks(segments.clone()).await?,
+        None => {
+            embedder
+                .encode_document(task.payload.content.clone())
+                .await?
+        }
+    };
     log::info!(
         "[job={}] created {} embeddings in {}ms",
         task.id,
         embeddings.len(),
         start.elapsed().as_millis()
     );
+    if embeddings.is_empty() {
+        log::warn!(
+            "[job={}] no text to embed, storing document w/o segments",
+            task.id
+        );
+    }
 
     // Create a wrapper document w/ all the data from the task
-    let document = document::ActiveModel::from_task(task);
-    let document = document.insert(&db).await?;
-
-    let txn = db.begin().await?;
-    // Persist vectors to db & vector store
-    let mut vectors = Vec::new();
-    for (idx, embedding) in embeddings.iter().enumerate() {
-        // Create a unique identifier for this segment w/ the task_id & segment
-        let uuid = uuid::Uuid::new_v5(
-            &NAMESPACE,
-            format!("{}-{idx}", document.uuid.clone()).as_bytes(),
-        )
-        .to_string();
-
-        let mut new_seg = embedding::ActiveModel::new();
-        new_seg.uuid = Set(uuid.clone());
-        new_seg.document_id = Set(document.uuid.clone());
-        new_seg.segment = Set(idx as i64);
-        new_seg.content = Set(embedding.content.clone());
-        new_seg.vector = Set(embedding.vector.clone().into());
-        new_seg.insert(&txn).await?;
-
-        vectors.push(VectorData {
-            _id: uuid.clone(),
-            document_id: document.uuid.clone(),
-            text: embedding.content.clone(),
-            segment_id: idx,
-            vector: embedding.vector.clone(),
+    let document = document::upsert_from_task(&db, task).await?;
+
+    let mut deduped = 0;
+    if let Some(threshold) = task.payload.dedup_threshold {
+        deduped = dedup_segments(&db, &client, &document, &mut embeddings, threshold).await?;
+        log::info!("[job={}] skipped {deduped} duplicate segments", task.id);
+    }
+
+    persist_embeddings(&db, &client, &document, &embeddings).await?;
+    Ok((document, deduped, embeddings.len()))
+}
+
+/// Drop segments w/ a similarity of at least `threshold` to a segment already
+/// in the store or earlier in the same document. Returns the number dropped.
+async fn dedup_segments(
+    db: &DatabaseConnection,
+    client: &VectorStorage,
+    document: &document::Model,
+    embeddings: &mut Vec<EmbeddingResult>,
+    threshold: f32,
+) -> anyhow::Result<usize> {
+    // Segments from a previous ingest of this document are about to be
+    // replaced, so they don't count as duplicates.
+    let own: Vec<String> = embedding::Entity::find()
+        .select_only()
+        .column(embedding::Column::Uuid)
+        .filter(embedding::Column::DocumentId.eq(document.uuid.clone()))
+        .into_tuple()
+        .all(db)
+        .await?;
+
+    let mut kept: Vec<EmbeddingResult> = Vec::new();
+    let total = embeddings.len();
+    for segment in embeddings.drain(..) {
+        let stored = client
+            .search(&segment.vector, own.len() + 1)
+            .await?
+            .into_iter()
+            .find(|(id, _)| !own.contains(id))
+            .is_some_and(|(_, score)| score >= threshold);
+
+        let repeated = kept.iter().any(|prev| {
+            let distance = 1.0 - cosine_similarity(&prev.vector, &segment.vector);
+            similarity_from_distance(distance) >= threshold
         });
+
+        if !stored && !repeated {
+            kept.push(segment);
+        }
     }
 
-    if let Err(err) = client.add_vectors(vectors).await {
-        log::error!("[job={}] Unable to upsert points: {err}", task.id);
-    } else {
-        log::info!("[job={}] Persisted embeddings", task.id);
+    *embeddings = kept;
+    Ok(total - embeddings.len())
+}
+
+/// Extract metadata matching `schema` from a document w/ the LLM, merging it
+/// into the document's existing metadata. Metadata supplied w/ the document
+/// takes precedence over anything extracted.
+pub async fn extract_metadata(
+    db: &DatabaseConnection,
+    client: &OpenAIClient,
+    document: document::Model,
+    content: &str,
+    schema: &Value,
+    options: &ChatCompletionOptions,
+) -> anyhow::Result<Value> {
+    let validator = JSONSchema::options()
+        .compile(schema)
+        .map_err(|err| anyhow::anyhow!("Invalid metadata schema: {err}"))?;
+
+    // The document may not have kept its content, use the task's.
+    let (content, model) = client.truncate_text(content);
+    // Retries w/ the start of the document, which is where the title, author,
+    // etc. usually are.
+    let (response, _) = complete_within_context(client, &model, content, options, |content| {
+        prompter::json_schema_extraction(content, METADATA_REQUEST, &schema.to_string())
+            .map_err(anyhow::Error::from)
+    })
+    .await?;
+
+    let extracted = repair_json(&response)
+        .ok_or_else(|| anyhow::anyhow!("LLM returned invalid JSON: {response}"))?;
+    if !validator.is_valid(&extracted) {
+        return Err(anyhow::anyhow!(
+            "Extracted metadata doesn't match the schema: {extracted}"
+        ));
     }
-    txn.commit().await?;
-    Ok(())
+
+    let metadata = match (extracted.clone(), document.metadata.clone()) {
+        (Value::Object(mut merged), Some(Value::Object(existing))) => {
+            merged.extend(existing);
+            Value::Object(merged)
+        }
+        (extracted, None) => extracted,
+        // Don't clobber non-object metadata
+        (_, Some(existing)) => existing,
+    };
+
+    let mut update: document::ActiveModel = document.into();
+    update.metadata = Set(Some(metadata));
+    update.update(db).await?;
+
+    Ok(extracted)
 }
 
-pub async fn generate_summary(client: &OpenAIClient, payload: &str) -> anyhow::Result<String> {
+/// Summarize a task's content segment by segment. Progress is saved to the
+/// task's output after each segment, so a failed task keeps the summaries that
+/// succeeded & a retry picks up from the segment that failed.
+pub async fn generate_summary(
+    db: &DatabaseConnection,
+    client: &OpenAIClient,
+    task: &queue::Model,
+) -> anyhow::Result<String> {
+    let options = task.payload.llm_options.clone().unwrap_or_default();
     // Break task content into segments
-    let (splits, model) = client.segment_text(payload);
-    let mut buffer = String::new();
-    for (idx, segment) in splits.iter().enumerate() {
+    let (splits, model) = client.segment_text(&task.payload.content);
+    let total = splits.len();
+
+    let (mut buffer, completed) = resume_summary(task.task_output.as_ref(), total);
+    if completed > 0 {
+        log::info!("resuming summary from segment {} of {total}", completed + 1);
+    }
+
+    // Segments waiting to be summarized, w/ the index of the original segment
+    // & the number of times it has been shrunk.
+    let mut pending: VecDeque<(String, usize, usize)> = splits
+        .into_iter()
+        .enumerate()
+        .skip(completed)
+        .map(|(idx, seg)| (seg, idx, 0))
+        .collect();
+
+    while let Some((segment, idx, retries)) = pending.pop_front() {
         let time = std::time::Instant::now();
-        let request = prompter::summarize(segment);
+        let request = prompter::summarize(&segment);
 
-        if let Ok(content) = client.chat_completion(model.as_ref(), &request).await {
-            buffer.push_str(&content);
+        match client
+            .chat_completion(model.as_ref(), &request, &options)
+            .await
+        {
+            Ok(content) => buffer.push_str(&content),
+            Err(LLMError::ContextLengthExceeded(msg)) if retries < MAX_CONTEXT_RETRIES => {
+                let parts = shrink_text(&segment, |text| client.count_tokens(text));
+                if parts.len() > 1 {
+                    log::warn!(
+                        "context length exceeded, retrying w/ smaller segments ({}/{MAX_CONTEXT_RETRIES}): {msg}",
+                        retries + 1
+                    );
+                    // Keep the parts in order at the front of the queue.
+                    for part in parts.into_iter().rev() {
+                        pending.push_front((part, idx, retries + 1));
+                    }
+                    continue;
+                }
+                log::warn!("unable to summarize segment: {msg}");
+            }
+            // Retrying won't help, move on to the next segment.
+            Err(err) if !err.is_retriable() => log::warn!("unable to summarize segment: {err}"),
+            Err(err) => {
+                log::warn!("summary failed on segment {} of {total}: {err}", idx + 1);
+                return Err(err.into());
+            }
         }
 
         log::info!(
             "segment {} of {} finished in {}ms",
             idx + 1,
-            splits.len(),
+            total,
             time.elapsed().as_millis()
         );
+
+        // Save progress once every piece of the original segment is done.
+        if pending.front().is_some_and(|next| next.1 != idx) {
+            let progress = summary_output(&buffer, idx + 1, total);
+            queue::set_output(db, task.id, progress).await?;
+        }
     }
 
+    queue::set_output(db, task.id, summary_output(&buffer, total, total)).await?;
     Ok(buffer)
 }
+
+/// Task output for a summary that's `completed` out of `total` segments in.
+fn summary_output(bullets: &str, completed: usize, total: usize) -> Value {
+    serde_json::json!({
+        "bullets": bullets,
+        "complete": completed >= total,
+        "completedSegments": completed,
+        "totalSegments": total,
+    })
+}
+
+/// Partial summary & # of completed segments saved by a previous attempt.
+fn resume_summary(output: Option<&Value>, total: usize) -> (String, usize) {
+    let Some(output) = output else {
+        return (String::new(), 0);
+    };
+
+    let bullets = output["bullets"].as_str();
+    let completed = output["completedSegments"].as_u64();
+    match (bullets, completed) {
+        // Only resume if the content was split up the same way.
+        (Some(bullets), Some(completed))
+            if output["totalSegments"].as_u64() == Some(total as u64)
+                && (completed as usize) < total =>
+        {
+            (bullets.to_string(), completed as usize)
+        }
+        _ => (String::new(), 0),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{resume_summary, summary_output};
+
+    #[test]
+    fn test_resume_summary() {
+        assert_eq!(resume_summary(None, 3), (String::new(), 0));
+
+        let output = summary_output("- one\n- two", 2, 3);
+        assert_eq!(
+            resume_summary(Some(&output), 3),
+            ("- one\n- two".to_string(), 2)
+        );
+
+        // Split up differently
+        assert_eq!(resume_summary(Some(&output), 4), (String::new(), 0));
+        // Already complete
+        let output = summary_output("- one", 3, 3);
+        assert_eq!(resume_summary(Some(&output), 3), (String::new(), 0));
+        // Not a partial summary
+        let output = serde_json::json!({ "bullets": "- one" });
+        assert_eq!(resume_summary(Some(&output), 3), (String::new(), 0));
+    }
+}
@@ -4,6 +4,17 @@ mod m20220101_000001_create_table;
 mod m20230919_115012_create_embedding_table;
 mod m20230920_114744_add_task_type_column;
 mod m20231002_201128_add_output_column;
+mod m20231010_000000_add_pgvector_column;
+mod m20231012_000000_add_trace_id_column;
+mod m20231014_000000_add_max_retries_column;
+mod m20231016_000000_add_idempotency_key_column;
+mod m20231018_000000_create_collections_table;
+mod m20231020_000000_add_indexed_column;
+mod m20231021_000000_add_collection_config_columns;
+mod m20231022_000000_add_next_retry_at_column;
+mod m20231023_000000_add_store_content_column;
+mod m20231024_000000_add_normalize_column;
+mod m20231025_000000_add_document_uuid_column;
 
 pub struct Migrator;
 
@@ -15,6 +26,17 @@ impl MigratorTrait for Migrator {
             Box::new(m20230919_115012_create_embedding_table::Migration),
             Box::new(m20230920_114744_add_task_type_column::Migration),
             Box::new(m20231002_201128_add_output_column::Migration),
+            Box::new(m20231010_000000_add_pgvector_column::Migration),
+            Box::new(m20231012_000000_add_trace_id_column::Migration),
+            Box::new(m20231014_000000_add_max_retries_column::Migration),
+            Box::new(m20231016_000000_add_idempotency_key_column::Migration),
+            Box::new(m20231018_000000_create_collections_table::Migration),
+            Box::new(m20231020_000000_add_indexed_column::Migration),
+            Box::new(m20231021_000000_add_collection_config_columns::Migration),
+            Box::new(m20231022_000000_add_next_retry_at_column::Migration),
+            Box::new(m20231023_000000_add_store_content_column::Migration),
+            Box::new(m20231024_000000_add_normalize_column::Migration),
+            Box::new(m20231025_000000_add_document_uuid_column::Migration),
         ]
     }
 }
@@ -0,0 +1,98 @@
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::{ConnectionTrait, DbBackend, Statement};
+
+// Dimension of the vectors produced by the default embedding model (AllMiniLmL12V2).
+const DEFAULT_DIMENSION: usize = 384;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Only postgres w/ the pgvector extension installed supports native
+        // vectors, everything else sticks w/ the JSON blob in `vector`.
+        if manager.get_database_backend() != DbBackend::Postgres {
+            return Ok(());
+        }
+
+        let db = manager.get_connection();
+        let available = db
+            .query_one(Statement::from_string(
+                DbBackend::Postgres,
+                "SELECT 1 FROM pg_available_extensions WHERE name = 'vector'",
+            ))
+            .await?;
+
+        if available.is_none() {
+            return Ok(());
+        }
+
+        // The column is shared by every collection, collections configured w/
+        // a model of another size are rejected (see `PgVectorStore::check_dimension`).
+        let dimension = std::env::var("EMBEDDING_DIMENSION")
+            .ok()
+            .and_then(|dim| dim.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_DIMENSION);
+
+        db.execute_unprepared("CREATE EXTENSION IF NOT EXISTS vector")
+            .await?;
+
+        if !manager.has_column("embeddings", "embedding").await? {
+            db.execute_unprepared(&format!(
+                "ALTER TABLE embeddings ADD COLUMN embedding vector({dimension}) NULL"
+            ))
+            .await?;
+        }
+
+        // HNSW indexes require pgvector >= 0.5.0, fallback to ivfflat for older versions.
+        // Checked upfront since a failed statement aborts the migration transaction.
+        // ivfflat picks its lists from the rows present when it's built, so it
+        // needs a `REINDEX` once data has been loaded to be any use.
+        let version = db
+            .query_one(Statement::from_string(
+                DbBackend::Postgres,
+                "SELECT extversion FROM pg_extension WHERE extname = 'vector'",
+            ))
+            .await?
+            .and_then(|row| row.try_get::<String>("", "extversion").ok())
+            .unwrap_or_default();
+
+        let index_type = if supports_hnsw(&version) {
+            "hnsw"
+        } else {
+            "ivfflat"
+        };
+
+        db.execute_unprepared(&format!(
+            "CREATE INDEX IF NOT EXISTS \"idx-embeddings-embedding\" \
+            ON embeddings USING {index_type} (embedding vector_cosine_ops)"
+        ))
+        .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        if manager.get_database_backend() == DbBackend::Postgres
+            && manager.has_column("embeddings", "embedding").await?
+        {
+            manager
+                .get_connection()
+                .execute_unprepared("ALTER TABLE embeddings DROP COLUMN embedding")
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+// Parse a "major.minor.patch" pgvector version & check if it's >= 0.5.0
+fn supports_hnsw(version: &str) -> bool {
+    let mut parts = version
+        .split('.')
+        .map(|part| part.parse::<u32>().unwrap_or_default());
+    let major = parts.next().unwrap_or_default();
+    let minor = parts.next().unwrap_or_default();
+    (major, minor) >= (0, 5)
+}
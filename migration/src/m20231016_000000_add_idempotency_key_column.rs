@@ -0,0 +1,48 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        if !manager.has_column("queue", "idempotency_key").await? {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Queue::Table)
+                        .add_column(ColumnDef::new(Queue::IdempotencyKey).string().null())
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        // Catches concurrent requests w/ the same key, NULL keys never conflict.
+        manager
+            .create_index(
+                Index::create()
+                    .if_not_exists()
+                    .name("idx-queue-collection-idempotency-key")
+                    .table(Queue::Table)
+                    .col(Queue::Collection)
+                    .col(Queue::IdempotencyKey)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, _: &SchemaManager) -> Result<(), DbErr> {
+        Ok(())
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Queue {
+    Table,
+    Collection,
+    IdempotencyKey,
+}
@@ -0,0 +1,40 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Segments stored before this were written to the vector store as
+        // part of the same job, assume they made it.
+        if !manager.has_column("embeddings", "indexed").await? {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Embeddings::Table)
+                        .add_column(
+                            ColumnDef::new(Embeddings::Indexed)
+                                .boolean()
+                                .not_null()
+                                .default(true),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, _: &SchemaManager) -> Result<(), DbErr> {
+        Ok(())
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Embeddings {
+    Table,
+    Indexed,
+}
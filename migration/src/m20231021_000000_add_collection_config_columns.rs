@@ -0,0 +1,63 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite can only add one column per statement.
+        if !manager.has_column("collections", "metric").await? {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Collections::Table)
+                        .add_column(
+                            ColumnDef::new(Collections::Metric)
+                                .string()
+                                .not_null()
+                                .default("cosine"),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        if !manager.has_column("collections", "max_length").await? {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Collections::Table)
+                        .add_column(ColumnDef::new(Collections::MaxLength).integer().null())
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        if !manager.has_column("collections", "stride").await? {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Collections::Table)
+                        .add_column(ColumnDef::new(Collections::Stride).integer().null())
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, _: &SchemaManager) -> Result<(), DbErr> {
+        Ok(())
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Collections {
+    Table,
+    Metric,
+    MaxLength,
+    Stride,
+}
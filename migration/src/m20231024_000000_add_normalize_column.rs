@@ -0,0 +1,34 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // NULL keeps using the server's default.
+        if !manager.has_column("collections", "normalize").await? {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Collections::Table)
+                        .add_column(ColumnDef::new(Collections::Normalize).boolean().null())
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, _: &SchemaManager) -> Result<(), DbErr> {
+        Ok(())
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Collections {
+    Table,
+    Normalize,
+}